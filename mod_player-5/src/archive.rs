@@ -0,0 +1,446 @@
+use std::convert::TryInto;
+
+use crate::song::LoadError;
+
+/**
+ * The archive formats modules are usually passed around in
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveKind {
+    Zip,
+    Gzip,           // a single compressed file, .mod.gz
+    Lha,            // LHA and LZH archives from the Amiga and DOS
+}
+
+/**
+ * A file unpacked from an archive. The name has '/' between directories, and is empty for gzip files that do not
+ * store the original name
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArchiveEntry {
+    pub name : String,
+    pub data : Vec<u8>,
+}
+
+fn format_error( message : &str ) -> LoadError {
+    LoadError::Format( String::from( message ) )
+}
+
+fn word( data : &[u8], offset : usize ) -> usize {
+    u16::from_le_bytes( [ data[ offset ], data[ offset + 1 ] ] ) as usize
+}
+
+fn double_word( data : &[u8], offset : usize ) -> usize {
+    u32::from_le_bytes( data[ offset..offset + 4 ].try_into().unwrap() ) as usize
+}
+
+/**
+ * The bytes from offset to offset + size, or an error naming what was cut short
+ */
+fn slice<'a>( data : &'a [u8], offset : usize, size : usize, what : &str ) -> Result<&'a [u8], LoadError> {
+    data.get( offset..offset.saturating_add( size ) ).ok_or_else( || LoadError::Format( format!( "{} ends early", what ) ) )
+}
+
+/**
+ * The CRC-32 zip and gzip check their files with
+ */
+fn crc32( data : &[u8] ) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _bit in 0..8 {
+            crc = if crc & 1 != 0 { ( crc >> 1 ) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/**
+ * The CRC-16 LHA checks its files with
+ */
+fn crc16( data : &[u8] ) -> u16 {
+    let mut crc = 0u16;
+    for byte in data {
+        crc ^= *byte as u16;
+        for _bit in 0..8 {
+            crc = if crc & 1 != 0 { ( crc >> 1 ) ^ 0xa001 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+/**
+ * Unpack deflate data that the archive says unpacks to size bytes. Data that would unpack to more is an error
+ */
+#[cfg(feature = "deflate")]
+fn inflate( data : &[u8], size : usize ) -> Result<Vec<u8>, LoadError> {
+    miniz_oxide::inflate::decompress_to_vec_with_limit( data, size ).map_err( | error | LoadError::Format( format!( "Bad deflate data: {}", error ) ) )
+}
+
+#[cfg(not(feature = "deflate"))]
+fn inflate( _data : &[u8], _size : usize ) -> Result<Vec<u8>, LoadError> {
+    Err( format_error( "Unpacking deflate data needs the \"deflate\" feature" ) )
+}
+
+/**
+ * LHA archives have no signature of their own, only a method such as "-lh5-" two bytes into each file header
+ */
+fn is_lha( data : &[u8] ) -> bool {
+    data.len() >= 22 && data[ 2 ] == b'-' && data[ 6 ] == b'-' && ( &data[ 3..5 ] == b"lh" || &data[ 3..5 ] == b"lz" ) && data[ 20 ] <= 2
+}
+
+/**
+ * The kind of archive some data is, or None when it is not one
+ */
+pub fn archive_kind( data : &[u8] ) -> Option<ArchiveKind> {
+    if data.starts_with( b"PK\x03\x04" ) || data.starts_with( b"PK\x05\x06" ) {
+        Some( ArchiveKind::Zip )
+    } else if data.starts_with( &[ 0x1f, 0x8b, 8 ] ) {
+        Some( ArchiveKind::Gzip )
+    } else if is_lha( data ) {
+        Some( ArchiveKind::Lha )
+    } else {
+        None
+    }
+}
+
+/**
+ * Unpack every file in an archive, in the order they are stored. Directories are left out. Files the player can't
+ * unpack, or whose checksum doesn't match, make the whole archive fail to load
+ */
+pub fn read_archive( data : &[u8] ) -> Result<Vec<ArchiveEntry>, LoadError> {
+    match archive_kind( data ) {
+        Some( ArchiveKind::Zip ) => read_zip( data ),
+        Some( ArchiveKind::Gzip ) => read_gzip( data ).map( | entry | vec![ entry ] ),
+        Some( ArchiveKind::Lha ) => read_lha( data ),
+        None => Err( format_error( "Not an archive" ) )
+    }
+}
+
+/**
+ * Zip files are read through the central directory at the end, which has the sizes even when the local headers
+ * leave them out. Files are stored or deflated
+ */
+fn read_zip( data : &[u8] ) -> Result<Vec<ArchiveEntry>, LoadError> {
+    // the end of central directory record is followed by a comment of up to 64k
+    let search_start = data.len().saturating_sub( 22 + 0xffff );
+    let end = ( search_start..data.len().saturating_sub( 21 ) ).rev().find( | offset | data[ *offset..].starts_with( b"PK\x05\x06" ) )
+        .ok_or_else( || format_error( "Zip file has no central directory" ) )?;
+    let num_entries = word( data, end + 10 );
+    let mut offset = double_word( data, end + 16 );
+    let mut entries = Vec::new();
+    for _entry in 0..num_entries {
+        let header = slice( data, offset, 46, "Zip central directory" )?;
+        if &header[ 0..4 ] != b"PK\x01\x02" {
+            return Err( format_error( "Bad zip central directory" ) );
+        }
+        let ( flags, method, crc ) = ( word( header, 8 ), word( header, 10 ), double_word( header, 16 ) as u32 );
+        let ( packed_size, size ) = ( double_word( header, 20 ), double_word( header, 24 ) );
+        let ( name_length, extra_length, comment_length ) = ( word( header, 28 ), word( header, 30 ), word( header, 32 ) );
+        let name = String::from_utf8_lossy( slice( data, offset + 46, name_length, "Zip central directory" )? ).into_owned();
+        let local_offset = double_word( header, 42 );
+        offset += 46 + name_length + extra_length + comment_length;
+        if name.ends_with( '/' ) {
+            continue;
+        }
+        if flags & 1 != 0 {
+            return Err( LoadError::Format( format!( "{} is encrypted", name ) ) );
+        }
+        let local = slice( data, local_offset, 30, "Zip file" )?;
+        let data_start = local_offset + 30 + word( local, 26 ) + word( local, 28 );
+        let packed = slice( data, data_start, packed_size, "Zip file" )?;
+        let unpacked = match method {
+            0 => packed.to_vec(),
+            8 => inflate( packed, size )?,
+            _ => return Err( LoadError::Format( format!( "{} uses zip method {}, which the player can't unpack", name, method ) ) )
+        };
+        if unpacked.len() != size || crc32( &unpacked ) != crc {
+            return Err( LoadError::Format( format!( "{} is damaged", name ) ) );
+        }
+        entries.push( ArchiveEntry{ name, data : unpacked } );
+    }
+    Ok( entries )
+}
+
+/**
+ * A gzip file is a header with optional fields, deflate data and the CRC-32 and length of the original file
+ */
+fn read_gzip( data : &[u8] ) -> Result<ArchiveEntry, LoadError> {
+    if data.len() < 18 {
+        return Err( format_error( "Gzip file ends early" ) );
+    }
+    let flags = data[ 3 ];
+    let mut offset = 10;
+    if flags & 4 != 0 {
+        offset += 2 + word( slice( data, offset, 2, "Gzip header" )?, 0 );
+    }
+    let zero_terminated = | offset : &mut usize | -> Result<Vec<u8>, LoadError> {
+        let length = data.get( *offset.. ).and_then( | rest | rest.iter().position( | byte | *byte == 0 ) ).ok_or_else( || format_error( "Gzip header ends early" ) )?;
+        let field = data[ *offset..*offset + length ].to_vec();
+        *offset += length + 1;
+        Ok( field )
+    };
+    let name = if flags & 8 != 0 { zero_terminated( &mut offset )? } else { Vec::new() };
+    if flags & 16 != 0 {
+        zero_terminated( &mut offset )?;
+    }
+    if flags & 2 != 0 {
+        offset += 2;
+    }
+    let trailer = data.len() - 8;
+    if offset > trailer {
+        return Err( format_error( "Gzip header ends early" ) );
+    }
+    let unpacked = inflate( &data[ offset..trailer ], double_word( data, trailer + 4 ) )?;
+    if crc32( &unpacked ) != double_word( data, trailer ) as u32 || unpacked.len() != double_word( data, trailer + 4 ) {
+        return Err( format_error( "Gzip file is damaged" ) );
+    }
+    // names are Latin-1
+    Ok( ArchiveEntry{ name : name.iter().map( | byte | *byte as char ).collect(), data : unpacked } )
+}
+
+/**
+ * LHA file headers come in three levels. Level 0 has the name in the header, levels 1 and 2 can put it and the
+ * directory in extended headers, which level 1 counts as part of the packed data. Each file is followed by the next
+ * header and a zero byte ends the archive
+ */
+fn read_lha( data : &[u8] ) -> Result<Vec<ArchiveEntry>, LoadError> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() && data[ offset ] != 0 {
+        let header = slice( data, offset, 24, "LHA header" )?;
+        let method : [u8; 5] = header[ 2..7 ].try_into().unwrap();
+        let ( mut packed_size, size, level ) = ( double_word( header, 7 ), double_word( header, 11 ), header[ 20 ] );
+        let mut name = Vec::new();
+        let mut directory = Vec::new();
+        let ( crc, mut extended_offset, mut extended_size, data_start ) = match level {
+            0 | 1 => {
+                let header_size = header[ 0 ] as usize + 2;
+                let name_length = header[ 21 ] as usize;
+                name = slice( data, offset + 22, name_length, "LHA header" )?.to_vec();
+                let crc = word( slice( data, offset + 22 + name_length, 2, "LHA header" )?, 0 ) as u16;
+                let extended_size = if level == 1 { word( slice( data, offset + header_size - 2, 2, "LHA header" )?, 0 ) } else { 0 };
+                ( crc, offset + header_size, extended_size, offset + header_size )
+            }
+            2 => {
+                let header = slice( data, offset, 26, "LHA header" )?;
+                ( word( header, 21 ) as u16, offset + 26, word( header, 24 ), offset + word( header, 0 ) )
+            }
+            _ => return Err( LoadError::Format( format!( "Unknown LHA header level {}", level ) ) )
+        };
+        // each extended header is a type, its contents and the size of the next one
+        let mut data_start = data_start;
+        while extended_size > 0 {
+            let extended = slice( data, extended_offset, extended_size, "LHA extended header" )?;
+            if extended_size < 3 {
+                return Err( format_error( "Bad LHA extended header" ) );
+            }
+            match extended[ 0 ] {
+                1 => name = extended[ 1..extended_size - 2 ].to_vec(),
+                2 => directory = extended[ 1..extended_size - 2 ].to_vec(),
+                _ => ()
+            }
+            if level == 1 {
+                packed_size = packed_size.checked_sub( extended_size ).ok_or_else( || format_error( "Bad LHA extended header" ) )?;
+                data_start += extended_size;
+            }
+            extended_offset += extended_size;
+            extended_size = word( extended, extended_size - 2 );
+        }
+        let packed = slice( data, data_start, packed_size, "LHA file" )?;
+        offset = data_start + packed_size;
+
+        let mut path = directory;
+        if !path.is_empty() && path.last() != Some( &0xff ) {
+            path.push( 0xff );
+        }
+        path.extend( name );
+        let name : String = path.iter().map( | byte | if *byte == 0xff || *byte == b'\\' { '/' } else { *byte as char } ).collect();
+        let unpacked = match &method {
+            b"-lhd-" => continue,
+            b"-lh0-" | b"-lz4-" => packed.to_vec(),
+            b"-lh5-" => unpack_lh( packed, size, 13, 14, 4 )?,
+            b"-lh6-" => unpack_lh( packed, size, 15, 16, 5 )?,
+            b"-lh7-" => unpack_lh( packed, size, 16, 17, 5 )?,
+            _ => return Err( LoadError::Format( format!( "{} uses LHA method {}, which the player can't unpack", name, String::from_utf8_lossy( &method ) ) ) )
+        };
+        if unpacked.len() != size || crc16( &unpacked ) != crc {
+            return Err( LoadError::Format( format!( "{} is damaged", name ) ) );
+        }
+        entries.push( ArchiveEntry{ name, data : unpacked } );
+    }
+    Ok( entries )
+}
+
+struct Bits<'a> {
+    data : &'a [u8],
+    position : usize,               // in bits
+}
+
+impl<'a> Bits<'a> {
+    /**
+     * A value stored highest bit first
+     */
+    fn read( &mut self, bits : usize ) -> Result<usize, LoadError> {
+        let mut value = 0;
+        for _bit in 0..bits {
+            let byte = self.data.get( self.position / 8 ).ok_or_else( || format_error( "LHA data ends early" ) )?;
+            value = ( value << 1 ) | ( ( byte >> ( 7 - self.position % 8 ) ) & 1 ) as usize;
+            self.position += 1;
+        }
+        Ok( value )
+    }
+}
+
+/**
+ * A Huffman code given by the code length of each symbol. Shorter codes come first and codes of the same length
+ * are in symbol order. A table of lengths can be replaced by a single symbol that takes no bits at all
+ */
+enum Huffman {
+    Single( usize ),
+    Codes{ counts : [usize; 17], symbols : Vec<usize> },
+}
+
+impl Huffman {
+    fn new( lengths : &[usize] ) -> Result<Huffman, LoadError> {
+        if lengths.iter().any( | length | *length > 16 ) {
+            return Err( format_error( "Bad LHA code length" ) );
+        }
+        let mut counts = [ 0; 17 ];
+        let mut symbols = Vec::new();
+        for ( length, count ) in counts.iter_mut().enumerate().skip( 1 ) {
+            for ( symbol, _ ) in lengths.iter().enumerate().filter( | ( _, symbol_length ) | **symbol_length == length ) {
+                *count += 1;
+                symbols.push( symbol );
+            }
+        }
+        Ok( Huffman::Codes{ counts, symbols } )
+    }
+
+    fn decode( &self, bits : &mut Bits ) -> Result<usize, LoadError> {
+        let ( counts, symbols ) = match self {
+            Huffman::Single( symbol ) => return Ok( *symbol ),
+            Huffman::Codes{ counts, symbols } => ( counts, symbols )
+        };
+        let ( mut code, mut first, mut index ) = ( 0, 0, 0 );
+        for count in &counts[ 1.. ] {
+            code |= bits.read( 1 )?;
+            if code < first + count {
+                return Ok( symbols[ index + code - first ] );
+            }
+            index += count;
+            first = ( first + count ) << 1;
+            code <<= 1;
+        }
+        Err( format_error( "Bad LHA code" ) )
+    }
+}
+
+const NUM_LENGTH_CODES : usize = 19;
+const NUM_CHARACTERS : usize = 510;          // 256 bytes and the copy lengths 3 - 256
+
+/**
+ * The lengths of a code that codes lengths or offsets: a count, then 3 bit lengths where 7 is followed by a one bit
+ * for every extra 1. After the third length of the length code comes a 2 bit count of zero lengths
+ */
+fn read_lengths( bits : &mut Bits, num_symbols : usize, count_bits : usize, zeros_after : Option<usize> ) -> Result<Huffman, LoadError> {
+    let count = bits.read( count_bits )?;
+    if count == 0 {
+        return Ok( Huffman::Single( bits.read( count_bits )? ) );
+    }
+    if count > num_symbols {
+        return Err( format_error( "Bad LHA code table" ) );
+    }
+    let mut lengths = vec![ 0; num_symbols ];
+    let mut index = 0;
+    while index < count {
+        let mut length = bits.read( 3 )?;
+        if length == 7 {
+            while bits.read( 1 )? == 1 {
+                length += 1;
+            }
+        }
+        lengths[ index ] = length;
+        index += 1;
+        if Some( index ) == zeros_after {
+            index += bits.read( 2 )?;
+        }
+    }
+    Huffman::new( &lengths )
+}
+
+/**
+ * The code for bytes and copy lengths, with its own code lengths coded by the length code: 0 is a single zero,
+ * 1 and 2 a run of 3 - 18 or 20 - 531 zeros and the rest a length 2 shorter than the symbol
+ */
+fn read_character_lengths( bits : &mut Bits, length_code : &Huffman ) -> Result<Huffman, LoadError> {
+    let count = bits.read( 9 )?;
+    if count == 0 {
+        return Ok( Huffman::Single( bits.read( 9 )? ) );
+    }
+    if count > NUM_CHARACTERS {
+        return Err( format_error( "Bad LHA code table" ) );
+    }
+    let mut lengths = vec![ 0; NUM_CHARACTERS ];
+    let mut index = 0;
+    while index < count {
+        match length_code.decode( bits )? {
+            0 => index += 1,
+            1 => index += bits.read( 4 )? + 3,
+            2 => index += bits.read( 9 )? + 20,
+            length => {
+                lengths[ index ] = length - 2;
+                index += 1;
+            }
+        }
+    }
+    if index > count {
+        return Err( format_error( "Bad LHA code table" ) );
+    }
+    Huffman::new( &lengths )
+}
+
+/**
+ * Unpack -lh5-, -lh6- and -lh7- data: blocks of bytes and copies of earlier output, each block starting with its
+ * own Huffman codes. Copy offsets are coded as their bit length and the bits below the top one. The methods only
+ * differ in how far back copies reach
+ */
+fn unpack_lh( data : &[u8], size : usize, dictionary_bits : usize, num_offset_codes : usize, offset_count_bits : usize ) -> Result<Vec<u8>, LoadError> {
+    let mut bits = Bits{ data, position : 0 };
+    // every code takes at least a bit and gives at most 256 bytes, whatever size the header claims
+    let mut output = Vec::with_capacity( size.min( data.len().saturating_mul( 8 * 256 ) ) );
+    let mut block_left = 0;
+    let mut codes = None;
+    while output.len() < size {
+        if block_left == 0 {
+            block_left = bits.read( 16 )?;
+            if block_left == 0 {
+                return Err( format_error( "Empty LHA block" ) );
+            }
+            let length_code = read_lengths( &mut bits, NUM_LENGTH_CODES, 5, Some( 3 ) )?;
+            let characters = read_character_lengths( &mut bits, &length_code )?;
+            let offsets = read_lengths( &mut bits, num_offset_codes, offset_count_bits, None )?;
+            codes = Some( ( characters, offsets ) );
+        }
+        let ( characters, offsets ) = codes.as_ref().unwrap();
+        block_left -= 1;
+        let character = characters.decode( &mut bits )?;
+        if character < 256 {
+            output.push( character as u8 );
+            continue;
+        }
+        let length = character - 253;
+        let offset = match offsets.decode( &mut bits )? {
+            0 => 0,
+            offset_bits => ( 1 << ( offset_bits - 1 ) ) + bits.read( offset_bits - 1 )?
+        };
+        if offset >= output.len() || offset >= 1 << dictionary_bits {
+            return Err( format_error( "LHA copy from before the start of the file" ) );
+        }
+        let start = output.len() - offset - 1;
+        for index in 0..length.min( size - output.len() ) {
+            output.push( output[ start + index ] );
+        }
+    }
+    Ok( output )
+}
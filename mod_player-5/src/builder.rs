@@ -0,0 +1,242 @@
+use std::error;
+use std::fmt;
+
+use crate::song::{Song, Sample, Pattern, Note, Effect, FormatDescription, ModuleKind, default_global_volume, default_speed, default_bpm};
+use crate::textout::note_period;
+
+const MAX_SAMPLES : usize = 31;
+const MAX_ORDERS : usize = 128;
+const MAX_PATTERNS : usize = 128;
+const MAX_CHANNELS : u32 = 32;
+const ROWS_PER_PATTERN : usize = 64;
+const MAX_SAMPLE_SIZE : usize = 0xffff * 2;    // sample lengths are stored in words
+
+#[derive(Debug)]
+pub enum BuildError {
+    Limit( String ),            // the song would not fit in a mod file
+    Invalid( String ),          // an argument refers to something that does not exist or can't be stored
+}
+
+impl fmt::Display for BuildError {
+    fn fmt( &self, f : &mut fmt::Formatter ) -> fmt::Result {
+        match self {
+            BuildError::Limit( message ) => write!( f, "{}", message ),
+            BuildError::Invalid( message ) => write!( f, "{}", message )
+        }
+    }
+}
+
+impl error::Error for BuildError {}
+
+/**
+ * Builds a song in code. Samples and patterns are numbered in the order they are added; samples from 1 as in the
+ * patterns and patterns from 0 as in the order table. Everything is checked against the limits of a mod file as it is added
+ */
+pub struct SongBuilder {
+    name : String,
+    num_channels : u32,
+    samples : Vec<Sample>,
+    patterns : Vec<Pattern>,
+    pattern_table : Vec<u8>,
+}
+
+impl SongBuilder {
+    pub fn new( name : &str, num_channels : u32 ) -> Result<SongBuilder, BuildError> {
+        if num_channels == 0 || num_channels > MAX_CHANNELS {
+            return Err( BuildError::Limit( format!( "A song can have 1 to {} channels, not {}", MAX_CHANNELS, num_channels ) ) );
+        }
+        Ok( SongBuilder{ name : String::from( name ), num_channels, samples : Vec::new(), patterns : Vec::new(), pattern_table : Vec::new() } )
+    }
+
+    /**
+     * Add a sample at full volume without a loop. Returns the sample number used in notes
+     */
+    pub fn add_sample( &mut self, name : &str, data : Vec<i8> ) -> Result<u8, BuildError> {
+        if self.samples.len() >= MAX_SAMPLES {
+            return Err( BuildError::Limit( format!( "A song can have at most {} samples", MAX_SAMPLES ) ) );
+        }
+        if data.len() > MAX_SAMPLE_SIZE {
+            return Err( BuildError::Limit( format!( "Sample {} is {} bytes, the limit is {}", name, data.len(), MAX_SAMPLE_SIZE ) ) );
+        }
+        self.samples.push( Sample::from_data( name, data ) );
+        Ok( self.samples.len() as u8 )
+    }
+
+    /**
+     * Add a sample from floating point data in the range -1.0 to 1.0. Values outside the range are clipped
+     */
+    pub fn add_sample_f32( &mut self, name : &str, data : &[f32] ) -> Result<u8, BuildError> {
+        let data = data.iter().map( | value | ( value.clamp( -1.0, 1.0 ) * 127.0 ).round() as i8 ).collect();
+        self.add_sample( name, data )
+    }
+
+    pub fn set_sample_volume( &mut self, sample_number : u8, volume : u8 ) -> Result<(), BuildError> {
+        if volume > 64 {
+            return Err( BuildError::Invalid( format!( "Volume {} is over 64", volume ) ) );
+        }
+        self.sample_mut( sample_number )?.volume = volume;
+        Ok( () )
+    }
+
+    /**
+     * Fine tune in eighths of a semitone, from -8 to 7
+     */
+    pub fn set_sample_fine_tune( &mut self, sample_number : u8, fine_tune : i8 ) -> Result<(), BuildError> {
+        if !( -8..=7 ).contains( &fine_tune ) {
+            return Err( BuildError::Invalid( format!( "Fine tune {} is not between -8 and 7", fine_tune ) ) );
+        }
+        self.sample_mut( sample_number )?.fine_tune = fine_tune as u8 & 0x0f;
+        Ok( () )
+    }
+
+    /**
+     * Loop the sample from offset for length bytes. Both are rounded down to whole words as that is how they are stored
+     */
+    pub fn set_sample_loop( &mut self, sample_number : u8, offset : u32, length : u32 ) -> Result<(), BuildError> {
+        let sample = self.sample_mut( sample_number )?;
+        let ( offset, length ) = ( offset & !1, length & !1 );
+        if length < 2 || offset + length > sample.size {
+            return Err( BuildError::Invalid( format!( "Loop {}..{} does not fit in sample {} of {} bytes", offset, offset + length, sample_number, sample.size ) ) );
+        }
+        sample.repeat_offset = offset;
+        sample.repeat_size = length;
+        Ok( () )
+    }
+
+    /**
+     * Add an empty pattern. Returns the pattern number used in the order table
+     */
+    pub fn add_pattern( &mut self ) -> Result<u8, BuildError> {
+        if self.patterns.len() >= MAX_PATTERNS {
+            return Err( BuildError::Limit( format!( "A song can have at most {} patterns", MAX_PATTERNS ) ) );
+        }
+        self.patterns.push( Pattern::with_channels( self.num_channels ) );
+        Ok( ( self.patterns.len() - 1 ) as u8 )
+    }
+
+    /**
+     * Set a note using the textout names, for example "C-3" or "A#2". Sample 0 keeps the sample already playing on the channel
+     */
+    pub fn set_note( &mut self, pattern : u8, channel : u32, row : u32, note_name : &str, sample_number : u8, effect : Effect ) -> Result<(), BuildError> {
+        let period = note_period( note_name ).ok_or_else( || BuildError::Invalid( format!( "Unknown note {}", note_name ) ) )?;
+        self.set_note_period( pattern, channel, row, period, sample_number, effect )
+    }
+
+    /**
+     * Set a note by its Amiga period. Period 0 leaves the channel playing the note it has
+     */
+    pub fn set_note_period( &mut self, pattern : u8, channel : u32, row : u32, period : u32, sample_number : u8, effect : Effect ) -> Result<(), BuildError> {
+        if sample_number as usize > self.samples.len() {
+            return Err( BuildError::Invalid( format!( "Sample {} has not been added", sample_number ) ) );
+        }
+        if period > 0xfff {
+            return Err( BuildError::Invalid( format!( "Period {} does not fit in 12 bits", period ) ) );
+        }
+        check_effect( &effect )?;
+        *self.note_mut( pattern, channel, row )? = Note::new( sample_number, period, effect );
+        Ok( () )
+    }
+
+    /**
+     * Set the effect of a note, keeping the note and sample
+     */
+    pub fn set_effect( &mut self, pattern : u8, channel : u32, row : u32, effect : Effect ) -> Result<(), BuildError> {
+        check_effect( &effect )?;
+        self.note_mut( pattern, channel, row )?.effect = effect;
+        Ok( () )
+    }
+
+    /**
+     * Append a pattern to the order table
+     */
+    pub fn add_order( &mut self, pattern : u8 ) -> Result<(), BuildError> {
+        if self.pattern_table.len() >= MAX_ORDERS {
+            return Err( BuildError::Limit( format!( "The order table holds at most {} positions", MAX_ORDERS ) ) );
+        }
+        if pattern as usize >= self.patterns.len() {
+            return Err( BuildError::Invalid( format!( "Pattern {} has not been added", pattern ) ) );
+        }
+        self.pattern_table.push( pattern );
+        Ok( () )
+    }
+
+    /**
+     * Replace the order table
+     */
+    pub fn set_orders( &mut self, patterns : &[u8] ) -> Result<(), BuildError> {
+        let old_table = std::mem::take( &mut self.pattern_table );
+        for pattern in patterns {
+            if let Err( error ) = self.add_order( *pattern ) {
+                self.pattern_table = old_table;
+                return Err( error );
+            }
+        }
+        Ok( () )
+    }
+
+    /**
+     * Finish the song. It needs at least one position in the order table
+     */
+    pub fn build( self ) -> Result<Song, BuildError> {
+        if self.pattern_table.is_empty() {
+            return Err( BuildError::Invalid( String::from( "The order table is empty" ) ) );
+        }
+        let mut samples = self.samples;
+        while samples.len() < MAX_SAMPLES {
+            samples.push( Sample::from_data( "", Vec::new() ) );
+        }
+        let num_used_patterns = self.pattern_table.len() as u32;
+        let mut pattern_table = self.pattern_table;
+        pattern_table.resize( MAX_ORDERS, 0 );
+        Ok( Song {
+            name : self.name,
+            format : FormatDescription{ num_channels : self.num_channels, num_samples : MAX_SAMPLES as u32, has_tag : true, tag : None, kind : ModuleKind::Mod },
+            samples,
+            patterns : self.patterns,
+            pattern_table,
+            num_used_patterns,
+            end_position : 127,
+            channel_panning : Vec::new(),
+            global_volume : default_global_volume(),
+            initial_speed : default_speed(),
+            initial_bpm : default_bpm(),
+            instruments : Vec::new(),
+            linear_periods : false,
+        } )
+    }
+
+    fn sample_mut( &mut self, sample_number : u8 ) -> Result<&mut Sample, BuildError> {
+        match sample_number {
+            0 => None,
+            _ => self.samples.get_mut( sample_number as usize - 1 )
+        }.ok_or_else( || BuildError::Invalid( format!( "Sample {} has not been added", sample_number ) ) )
+    }
+
+    fn note_mut( &mut self, pattern : u8, channel : u32, row : u32 ) -> Result<&mut Note, BuildError> {
+        if channel >= self.num_channels {
+            return Err( BuildError::Invalid( format!( "Channel {} does not exist, the song has {} channels", channel, self.num_channels ) ) );
+        }
+        if row as usize >= ROWS_PER_PATTERN {
+            return Err( BuildError::Limit( format!( "Row {} is past the {} rows of a pattern", row, ROWS_PER_PATTERN ) ) );
+        }
+        let pattern = self.patterns.get_mut( pattern as usize ).ok_or_else( || BuildError::Invalid( format!( "Pattern {} has not been added", pattern ) ) )?;
+        Ok( &mut pattern.lines[ row as usize ][ channel as usize ] )
+    }
+}
+
+/**
+ * Only effects that come back unchanged from the pattern data can be stored. Nibble arguments have to be under 16,
+ * pattern breaks under 64 and an arpeggio with two zero offsets is no effect at all
+ */
+fn check_effect( effect : &Effect ) -> Result<(), BuildError> {
+    let ( number, argument ) = effect.to_raw();
+    if Effect::new( number, argument ) != *effect {
+        return Err( BuildError::Invalid( format!( "{:?} can't be stored in a pattern", effect ) ) );
+    }
+    if let Effect::PatternBreak{ next_pattern_pos } = effect {
+        if *next_pattern_pos >= ROWS_PER_PATTERN as u8 {
+            return Err( BuildError::Invalid( format!( "Pattern break to line {} is past the end of the pattern", next_pattern_pos ) ) );
+        }
+    }
+    Ok( () )
+}
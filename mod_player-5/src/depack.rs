@@ -0,0 +1,387 @@
+use std::convert::TryInto;
+
+use crate::song::{LoadError, FREQUENCY_TABLE};
+
+/**
+ * Compressed and packed forms of mods that unpack_module turns back into ordinary mod data. PowerPacker crunches
+ * the whole file, the others are mods whose patterns were squeezed into a track format of their own
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PackedFormat {
+    PowerPacker,            // "PP20", which can hold any file
+    ProPacker1,             // ProPacker 1.0: patterns stored as tracks of 64 notes
+    ProPacker2,             // ProPacker 2.1: tracks of references into a table of notes
+    NoisePacker2,           // NoisePacker 2: tracks of 3 byte notes
+}
+
+const PATTERN_SIZE : usize = 64 * 4 * 4;
+const TRACK_SIZE : usize = 64 * 4;
+const PROPACKER_TRACKS_OFFSET : usize = 250;
+const PROPACKER_TRACK_DATA_OFFSET : usize = 250 + 4 * 128;
+
+fn word( data : &[u8], offset : usize ) -> usize {
+    u16::from_be_bytes( [ data[ offset ], data[ offset + 1 ] ] ) as usize
+}
+
+fn format_error( message : &str ) -> LoadError {
+    LoadError::Format( String::from( message ) )
+}
+
+/**
+ * Sample headers in the 8 bytes of a mod sample header that follow the name: length, finetune, volume and loop, with
+ * the lengths in words. A sample shorter than its loop is not a sample
+ */
+fn is_sample_header( header : &[u8] ) -> bool {
+    let ( size, repeat_offset, repeat_size ) = ( word( header, 0 ), word( header, 4 ), word( header, 6 ) );
+    size <= 0x8000 && header[ 2 ] <= 15 && header[ 3 ] <= 64 && ( size == 0 || repeat_offset + repeat_size <= size + 1 )
+}
+
+fn sample_data_size( headers : &[u8], header_size : usize, size_offset : usize ) -> usize {
+    headers.chunks_exact( header_size ).map( | header | word( header, size_offset ) * 2 ).sum()
+}
+
+/**
+ * A mod with the "M.K." tag from its parts. The sample headers are the 8 bytes after the name, the names are empty
+ */
+fn build_mod( sample_headers : &[[u8; 8]], song_length : u8, restart : u8, pattern_table : &[u8], patterns : &[Vec<u8>], sample_data : &[u8] ) -> Vec<u8> {
+    let mut data = vec![ 0u8; 20 ];
+    for index in 0..31 {
+        data.extend( [ 0u8; 22 ] );
+        data.extend( sample_headers.get( index ).copied().unwrap_or( [ 0, 0, 0, 0, 0, 0, 0, 1 ] ) );
+    }
+    data.extend( [ song_length, restart ] );
+    let mut table = pattern_table.to_vec();
+    table.resize( 128, 0 );
+    data.extend( table );
+    data.extend( b"M.K." );
+    for pattern in patterns {
+        data.extend( pattern );
+    }
+    data.extend( sample_data );
+    data
+}
+
+/**
+ * Reads the bits of a PowerPacker file from the end backwards, lowest bit of each byte first
+ */
+struct BackwardBits<'a> {
+    data : &'a [u8],
+    position : usize,               // bytes left to read
+    buffer : u32,
+    bits_left : u32,
+}
+
+impl<'a> BackwardBits<'a> {
+    fn read( &mut self, bits : u32 ) -> Result<usize, LoadError> {
+        while self.bits_left < bits {
+            if self.position == 0 {
+                return Err( format_error( "PowerPacker data ends early" ) );
+            }
+            self.position -= 1;
+            self.buffer |= ( self.data[ self.position ] as u32 ) << self.bits_left;
+            self.bits_left += 8;
+        }
+        let mut value = 0;
+        for _bit in 0..bits {
+            value = ( value << 1 ) | ( self.buffer & 1 ) as usize;
+            self.buffer >>= 1;
+        }
+        self.bits_left -= bits;
+        Ok( value )
+    }
+}
+
+/**
+ * Uncrunch a PowerPacker file: "PP20", the bit lengths of the four match offset sizes, the crunched data and then
+ * the unpacked length in 3 bytes and how many bits to skip. The data is decoded from its end and the output filled
+ * from its end, as runs of bytes alternating with copies of earlier output
+ */
+pub fn depack_powerpacker( data : &[u8] ) -> Result<Vec<u8>, LoadError> {
+    if data.len() < 12 || &data[ 0..4 ] != b"PP20" {
+        return Err( format_error( "Not a PowerPacker file" ) );
+    }
+    let offset_bits = &data[ 4..8 ];
+    let trailer = data.len() - 4;
+    let output_length = ( data[ trailer ] as usize ) << 16 | ( data[ trailer + 1 ] as usize ) << 8 | data[ trailer + 2 ] as usize;
+    // the bit reader holds at most 32 bits
+    if offset_bits.iter().any( | bits | *bits > 15 ) || data[ trailer + 3 ] > 32 {
+        return Err( format_error( "PowerPacker header has bit counts that are too large" ) );
+    }
+    let mut bits = BackwardBits{ data : &data[ 8..trailer ], position : trailer - 8, buffer : 0, bits_left : 0 };
+    bits.read( data[ trailer + 3 ] as u32 )?;
+
+    let mut output = vec![ 0u8; output_length ];
+    let mut position = output_length;       // where the last byte written is
+    while position > 0 {
+        if bits.read( 1 )? == 0 {
+            let mut count = 1;
+            loop {
+                let more = bits.read( 2 )?;
+                count += more;
+                if more != 3 {
+                    break;
+                }
+            }
+            for _byte in 0..count {
+                if position == 0 {
+                    return Err( format_error( "PowerPacker data unpacks to more than its length" ) );
+                }
+                position -= 1;
+                output[ position ] = bits.read( 8 )? as u8;
+            }
+            if position == 0 {
+                break;
+            }
+        }
+        // a copy of output that has already been written, which comes after this point in the file
+        let size = bits.read( 2 )?;
+        let mut count = size + 2;
+        let offset = if size == 3 {
+            let offset_size = if bits.read( 1 )? == 0 { 7 } else { offset_bits[ 3 ] as u32 };
+            let offset = bits.read( offset_size )?;
+            loop {
+                let more = bits.read( 3 )?;
+                count += more;
+                if more != 7 {
+                    break;
+                }
+            }
+            offset
+        } else {
+            bits.read( offset_bits[ size ] as u32 )?
+        };
+        for _byte in 0..count {
+            if position == 0 || position + offset >= output_length {
+                return Err( format_error( "PowerPacker copy goes past the end of the data" ) );
+            }
+            output[ position - 1 ] = output[ position + offset ];
+            position -= 1;
+        }
+    }
+    Ok( output )
+}
+
+/**
+ * The track numbers of the four channels for every position in a ProPacker file, and the highest one
+ */
+fn propacker_tracks( data : &[u8] ) -> ( &[u8], usize ) {
+    let tracks = &data[ PROPACKER_TRACKS_OFFSET..PROPACKER_TRACK_DATA_OFFSET ];
+    ( tracks, tracks.iter().max().copied().unwrap_or( 0 ) as usize )
+}
+
+/**
+ * ProPacker files start with 31 sample headers, the song length, the restart position and the track of each channel
+ * for all 128 positions. Version 1.0 follows with the tracks themselves, 64 notes of 4 bytes each
+ */
+fn is_propacker_1( data : &[u8] ) -> bool {
+    if data.len() < PROPACKER_TRACK_DATA_OFFSET || !data[ 0..248 ].chunks_exact( 8 ).all( is_sample_header ) || !( 1..=128 ).contains( &data[ 248 ] ) {
+        return false;
+    }
+    let ( _, max_track ) = propacker_tracks( data );
+    let track_data_end = PROPACKER_TRACK_DATA_OFFSET + ( max_track + 1 ) * TRACK_SIZE;
+    let expected_length = track_data_end + sample_data_size( &data[ 0..248 ], 8, 0 );
+    data.len() >= track_data_end && data.len() <= expected_length + 256
+        && data[ PROPACKER_TRACK_DATA_OFFSET..track_data_end ].chunks_exact( 4 ).all( | note | {
+            let period = ( ( note[ 0 ] as usize & 0x0f ) << 8 ) | note[ 1 ] as usize;
+            ( note[ 0 ] & 0xf0 ) <= 0x10 && ( period == 0 || ( 108..=907 ).contains( &period ) )
+        } )
+}
+
+/**
+ * ProPacker 2.1 tracks are 64 references into a table of 4 byte notes, which comes after its size
+ */
+fn is_propacker_2( data : &[u8] ) -> bool {
+    if data.len() < PROPACKER_TRACK_DATA_OFFSET || !data[ 0..248 ].chunks_exact( 8 ).all( is_sample_header ) || !( 1..=128 ).contains( &data[ 248 ] ) {
+        return false;
+    }
+    let ( _, max_track ) = propacker_tracks( data );
+    let references_end = PROPACKER_TRACK_DATA_OFFSET + ( max_track + 1 ) * 64 * 2;
+    if data.len() < references_end + 4 {
+        return false;
+    }
+    let max_reference = data[ PROPACKER_TRACK_DATA_OFFSET..references_end ].chunks_exact( 2 ).map( | reference | word( reference, 0 ) ).max().unwrap_or( 0 );
+    let table_size = u32::from_be_bytes( [ data[ references_end ], data[ references_end + 1 ], data[ references_end + 2 ], data[ references_end + 3 ] ] ) as usize;
+    let expected_length = references_end + 4 + table_size + sample_data_size( &data[ 0..248 ], 8, 0 );
+    table_size.is_multiple_of( 4 ) && table_size >= ( max_reference + 1 ) * 4 && data.len() >= references_end + 4 + table_size && data.len() <= expected_length + 256
+}
+
+/**
+ * One pattern for each position, as ProPacker does not say which positions share a pattern
+ */
+fn depack_propacker( data : &[u8], note : impl Fn( usize, usize ) -> [u8; 4], sample_data_offset : usize ) -> Vec<u8> {
+    let sample_headers : Vec<[u8; 8]> = data[ 0..248 ].chunks_exact( 8 ).map( | header | header.try_into().unwrap() ).collect();
+    let song_length = data[ 248 ];
+    let ( tracks, _ ) = propacker_tracks( data );
+    let patterns : Vec<Vec<u8>> = ( 0..song_length as usize ).map( | position | {
+        let mut pattern = Vec::with_capacity( PATTERN_SIZE );
+        for line in 0..64 {
+            for channel in 0..4 {
+                pattern.extend( note( tracks[ channel * 128 + position ] as usize, line ) );
+            }
+        }
+        pattern
+    } ).collect();
+    let pattern_table : Vec<u8> = ( 0..song_length ).collect();
+    build_mod( &sample_headers, song_length, data[ 249 ], &pattern_table, &patterns, &data[ sample_data_offset.min( data.len() ).. ] )
+}
+
+fn depack_propacker_1( data : &[u8] ) -> Vec<u8> {
+    let ( _, max_track ) = propacker_tracks( data );
+    let note = | track : usize, line : usize | {
+        let offset = PROPACKER_TRACK_DATA_OFFSET + track * TRACK_SIZE + line * 4;
+        data[ offset..offset + 4 ].try_into().unwrap()
+    };
+    depack_propacker( data, note, PROPACKER_TRACK_DATA_OFFSET + ( max_track + 1 ) * TRACK_SIZE )
+}
+
+fn depack_propacker_2( data : &[u8] ) -> Vec<u8> {
+    let ( _, max_track ) = propacker_tracks( data );
+    let table_offset = PROPACKER_TRACK_DATA_OFFSET + ( max_track + 1 ) * 64 * 2 + 4;
+    let table_size = u32::from_be_bytes( data[ table_offset - 4..table_offset ].try_into().unwrap() ) as usize;
+    let note = | track : usize, line : usize | {
+        let reference = word( data, PROPACKER_TRACK_DATA_OFFSET + ( track * 64 + line ) * 2 );
+        let offset = table_offset + reference * 4;
+        data[ offset..offset + 4 ].try_into().unwrap()
+    };
+    depack_propacker( data, note, table_offset + table_size )
+}
+
+/**
+ * Where the parts of a NoisePacker 2 file are. The header gives the number of samples in the top bits of its first
+ * word and the sizes of the position list, the track table and the track data. The 16 byte sample headers follow
+ */
+struct NoisePackerLayout {
+    positions : usize,              // offset of the position list
+    num_positions : usize,
+    track_table : usize,
+    num_patterns : usize,
+    track_data : usize,
+    sample_data : usize,
+}
+
+fn noisepacker_layout( data : &[u8] ) -> Option<NoisePackerLayout> {
+    if data.len() < 8 || word( data, 0 ) & 0x0f != 0x0c {
+        return None;
+    }
+    let num_samples = word( data, 0 ) >> 4;
+    let ( positions_size, track_table_size, track_data_size ) = ( word( data, 2 ), word( data, 4 ), word( data, 6 ) );
+    if !( 1..=31 ).contains( &num_samples ) || positions_size == 0 || !positions_size.is_multiple_of( 2 ) || positions_size > 256 || track_table_size == 0 || !track_table_size.is_multiple_of( 8 ) {
+        return None;
+    }
+    let positions = 8 + num_samples * 16;
+    let track_table = positions + positions_size;
+    let track_data = track_table + track_table_size;
+    let sample_data = track_data + track_data_size;
+    if data.len() < sample_data {
+        return None;
+    }
+    Some( NoisePackerLayout {
+        positions,
+        num_positions : positions_size / 2,
+        track_table,
+        num_patterns : track_table_size / 8,
+        track_data,
+        sample_data,
+    } )
+}
+
+/**
+ * NoisePacker 2 sample headers: the address of the sample, its length, finetune and volume, the address of the
+ * loop, then its length and its start in bytes
+ */
+fn noisepacker_sample( header : &[u8] ) -> [u8; 8] {
+    let repeat_offset = ( word( header, 14 ) / 2 ) as u16;
+    let mut sample = [ header[ 4 ], header[ 5 ], header[ 6 ], header[ 7 ], 0, 0, header[ 12 ], header[ 13 ] ];
+    sample[ 4..6 ].copy_from_slice( &repeat_offset.to_be_bytes() );
+    sample
+}
+
+fn is_noisepacker_2( data : &[u8] ) -> bool {
+    let layout = match noisepacker_layout( data ) {
+        Some( layout ) => layout,
+        None => return false
+    };
+    let headers = &data[ 8..layout.positions ];
+    let samples_fit = headers.chunks_exact( 16 ).all( | header | is_sample_header( &noisepacker_sample( header ) ) );
+    let positions_fit = data[ layout.positions..layout.track_table ].chunks_exact( 2 ).all( | position | {
+        let position = word( position, 0 );
+        position.is_multiple_of( 8 ) && position / 8 < layout.num_patterns
+    } );
+    let track_data_size = layout.sample_data - layout.track_data;
+    let tracks_fit = data[ layout.track_table..layout.track_data ].chunks_exact( 2 ).all( | offset | word( offset, 0 ) + 64 * 3 <= track_data_size );
+    let expected_length = layout.sample_data + sample_data_size( headers, 16, 4 );
+    samples_fit && positions_fit && tracks_fit && data.len() <= expected_length + 256
+}
+
+/**
+ * A NoisePacker 2 note: the note number and the top bit of the sample, the rest of the sample and the effect, and
+ * the effect's argument. Volume slides are stored as signed amounts and position jumps doubled
+ */
+fn noisepacker_note( bytes : &[u8] ) -> [u8; 4] {
+    let note = bytes[ 0 ] as usize >> 1;
+    let sample = ( ( bytes[ 0 ] & 1 ) << 4 ) | ( bytes[ 1 ] >> 4 );
+    let mut effect = bytes[ 1 ] & 0x0f;
+    let mut argument = bytes[ 2 ];
+    match effect {
+        5..=7 => {
+            if effect == 7 {
+                effect = 0x0a;
+            }
+            argument = if argument > 0x80 { 0u8.wrapping_sub( argument ) } else { ( argument << 4 ) & 0xf0 };
+        }
+        0x0b => argument = ( ( argument as u32 + 4 ) / 2 ) as u8,
+        _ => ()
+    }
+    // notes count up from C-1 in ProTracker's three octaves
+    let period = if ( 1..=36 ).contains( &note ) { FREQUENCY_TABLE[ 48 - note ] } else { 0 };
+    [ ( sample & 0xf0 ) | ( period >> 8 ) as u8, period as u8, ( sample << 4 ) | effect, argument ]
+}
+
+fn depack_noisepacker_2( data : &[u8] ) -> Vec<u8> {
+    let layout = noisepacker_layout( data ).unwrap();
+    let sample_headers : Vec<[u8; 8]> = data[ 8..layout.positions ].chunks_exact( 16 ).map( noisepacker_sample ).collect();
+    let pattern_table : Vec<u8> = data[ layout.positions..layout.track_table ].chunks_exact( 2 ).map( | position | ( word( position, 0 ) / 8 ) as u8 ).collect();
+    let patterns : Vec<Vec<u8>> = ( 0..layout.num_patterns ).map( | pattern | {
+        // the track table lists the channels last to first
+        let tracks : Vec<usize> = ( 0..4 ).map( | channel | layout.track_data + word( data, layout.track_table + pattern * 8 + ( 3 - channel ) * 2 ) ).collect();
+        let mut notes = Vec::with_capacity( PATTERN_SIZE );
+        for line in 0..64 {
+            for track in &tracks {
+                notes.extend( noisepacker_note( &data[ track + line * 3..track + line * 3 + 3 ] ) );
+            }
+        }
+        notes
+    } ).collect();
+    build_mod( &sample_headers, layout.num_positions as u8, 0x7f, &pattern_table, &patterns, &data[ layout.sample_data.. ] )
+}
+
+/**
+ * Which packed format the data is in, if any. PowerPacker files have their own tag, the packed mods are recognised
+ * by how well their headers hold together
+ */
+pub fn packed_format( data : &[u8] ) -> Option<PackedFormat> {
+    if data.starts_with( b"PP20" ) {
+        Some( PackedFormat::PowerPacker )
+    } else if is_propacker_2( data ) {
+        Some( PackedFormat::ProPacker2 )
+    } else if is_propacker_1( data ) {
+        Some( PackedFormat::ProPacker1 )
+    } else if is_noisepacker_2( data ) {
+        Some( PackedFormat::NoisePacker2 )
+    } else {
+        None
+    }
+}
+
+/**
+ * Unpack the data if it is in one of the packed formats. None if it is not packed
+ */
+pub fn unpack_module( data : &[u8] ) -> Result<Option<Vec<u8>>, LoadError> {
+    Ok( match packed_format( data ) {
+        Some( PackedFormat::PowerPacker ) => Some( depack_powerpacker( data )? ),
+        Some( PackedFormat::ProPacker1 ) => Some( depack_propacker_1( data ) ),
+        Some( PackedFormat::ProPacker2 ) => Some( depack_propacker_2( data ) ),
+        Some( PackedFormat::NoisePacker2 ) => Some( depack_noisepacker_2( data ) ),
+        None => None
+    } )
+}
@@ -0,0 +1,227 @@
+use std::fs;
+
+use crate::song::{Song, ModuleKind, LoadError, get_format, read_mod_data};
+use crate::s3m::read_s3m_data;
+use crate::xm::read_xm_data;
+use crate::it::read_it_data;
+use crate::depack::{PackedFormat, packed_format, unpack_module, depack_powerpacker};
+use crate::archive::{ArchiveEntry, archive_kind, read_archive};
+
+/**
+ * The format some data looks like and how sure the guess is, from 0 to 100. Formats with a tag of their own score
+ * highest, original 15 sample mods have no tag and only get a score from how sane their header looks
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FormatGuess {
+    pub kind : ModuleKind,
+    pub confidence : u8,
+}
+
+/**
+ * Guesses below this are not worth loading
+ */
+const MIN_CONFIDENCE : u8 = 25;
+
+fn word( data : &[u8], offset : usize ) -> usize {
+    u16::from_le_bytes( [ data[ offset ], data[ offset + 1 ] ] ) as usize
+}
+
+fn big_endian_word( data : &[u8], offset : usize ) -> u32 {
+    u16::from_be_bytes( [ data[ offset ], data[ offset + 1 ] ] ) as u32
+}
+
+/**
+ * Impulse Tracker modules start with "IMPM" and the order list and the offsets of everything else follow the header
+ */
+fn guess_it( data : &[u8] ) -> Option<u8> {
+    if data.len() < 0xc0 || &data[ 0..4 ] != b"IMPM" {
+        return None;
+    }
+    let counts = [ 0x20, 0x22, 0x24, 0x26 ].map( | offset | word( data, offset ) );
+    let tables_end = 0xc0 + counts[ 0 ] + 4 * ( counts[ 1 ] + counts[ 2 ] + counts[ 3 ] );
+    Some( if tables_end <= data.len() { 100 } else { 60 } )
+}
+
+fn guess_xm( data : &[u8] ) -> Option<u8> {
+    if data.len() < 60 || &data[ 0..17 ] != b"Extended Module: " {
+        return None;
+    }
+    Some( if data[ 37 ] == 0x1a { 100 } else { 90 } )
+}
+
+/**
+ * Scream Tracker 3 modules have "SCRM" in their header, after the name and a byte that is 16 for modules
+ */
+fn guess_s3m( data : &[u8] ) -> Option<u8> {
+    if data.len() < 0x60 || &data[ 0x2c..0x30 ] != b"SCRM" {
+        return None;
+    }
+    Some( if data[ 0x1d ] == 16 { 100 } else { 90 } )
+}
+
+/**
+ * Mods are checked the way the loader reads them: the song length has to be 1 - 128 and the pattern table can only
+ * refer to the patterns that fit in the file. With a tag that is all it takes. Untagged 15 sample mods also need
+ * sample volumes and loops that make sense, and score higher for printable names and a file length that matches
+ * the patterns and samples exactly
+ */
+fn guess_mod( data : &[u8] ) -> Option<u8> {
+    let format = get_format( data );
+    let num_samples = format.num_samples as usize;
+    let header_size = 20 + 30 * num_samples + 2 + 128 + if format.has_tag { 4 } else { 0 };
+    if data.len() < header_size {
+        return None;
+    }
+    let sample_headers : Vec<&[u8]> = data[ 20..20 + 30 * num_samples ].chunks_exact( 30 ).collect();
+    let total_sample_size : u32 = sample_headers.iter().map( | header | big_endian_word( header, 22 ) * 2 ).sum();
+    let song_length = data[ 20 + 30 * num_samples ] as usize;
+    let pattern_table = &data[ 22 + 30 * num_samples..150 + 30 * num_samples ];
+    let pattern_size = format.num_channels * 4 * 64;
+    let num_patterns = ( data.len() as u32 - header_size as u32 ).saturating_sub( total_sample_size ) / pattern_size;
+    if num_patterns == 0 || song_length == 0 || song_length > 128 || pattern_table[ ..song_length ].iter().any( | pattern | *pattern as u32 >= num_patterns ) {
+        return None;
+    }
+    if format.has_tag {
+        return Some( 90 );
+    }
+
+    let loops_fit = sample_headers.iter().all( | header | {
+        let size = big_endian_word( header, 22 );
+        let ( repeat_offset, repeat_size ) = ( big_endian_word( header, 26 ), big_endian_word( header, 28 ) );
+        // Soundtracker stored the loop start in bytes, later trackers in words
+        header[ 25 ] <= 64 && ( repeat_size <= 1 || repeat_offset + repeat_size <= size * 2 )
+    } );
+    if !loops_fit || pattern_table.iter().any( | pattern | *pattern > 127 ) {
+        return None;
+    }
+    let printable = | name : &[u8] | name.iter().all( | byte | *byte == 0 || ( 32..127 ).contains( byte ) );
+    let mut confidence = 20;
+    if printable( &data[ 0..20 ] ) && sample_headers.iter().all( | header | printable( &header[ 0..22 ] ) ) {
+        confidence += 30;
+    }
+    // the patterns stored are the ones the table uses
+    let highest_pattern = pattern_table.iter().max().copied().unwrap_or( 0 ) as u32;
+    let expected_size = header_size as u32 + ( highest_pattern + 1 ) * pattern_size + total_sample_size;
+    if data.len() as u32 == expected_size {
+        confidence += 30;
+    } else if ( data.len() as u32 ) < expected_size {
+        confidence += 10;          // cut short, which the loader copes with
+    }
+    Some( confidence )
+}
+
+/**
+ * Packed data is as good as what it unpacks to. The packed mods are only recognised by their layout, so they are
+ * never a sure thing
+ */
+fn guess_packed( data : &[u8] ) -> Option<FormatGuess> {
+    let format = packed_format( data )?;
+    let unpacked = unpack_module( data ).ok()??;
+    let guess = guess_format( &unpacked )?;
+    let confidence = if format == PackedFormat::PowerPacker { guess.confidence } else { guess.confidence.min( 60 ) };
+    Some( FormatGuess{ kind : guess.kind, confidence } )
+}
+
+/**
+ * Work out the format of a module from its contents. Returns None when the data does not look like any module the
+ * player can load
+ */
+pub fn guess_format( data : &[u8] ) -> Option<FormatGuess> {
+    let guesses = [
+        ( ModuleKind::It, guess_it( data ) ),
+        ( ModuleKind::Xm, guess_xm( data ) ),
+        ( ModuleKind::S3m, guess_s3m( data ) ),
+        ( ModuleKind::Mod, guess_mod( data ) ),
+        guess_packed( data ).map( | guess | ( guess.kind, Some( guess.confidence ) ) ).unwrap_or( ( ModuleKind::Mod, None ) ),
+    ];
+    guesses.iter().filter_map( | ( kind, confidence ) | confidence.map( | confidence | FormatGuess{ kind : *kind, confidence } ) )
+        .filter( | guess | guess.confidence >= MIN_CONFIDENCE )
+        .max_by_key( | guess | guess.confidence )
+}
+
+/**
+ * The format of a module from its contents, see guess_format for how sure the guess is
+ */
+pub fn detect_format( data : &[u8] ) -> Option<ModuleKind> {
+    guess_format( data ).map( | guess | guess.kind )
+}
+
+pub fn read_module_file( file_name : &str ) -> Result<Song, LoadError> {
+    let file_data : Vec<u8> = fs::read( file_name )?;
+    read_module_data( &file_data )
+}
+
+/**
+ * Load a module of any of the formats the player knows, going by its contents rather than its name. Packed
+ * modules are unpacked first, and from an archive the first module in it is loaded
+ */
+pub fn read_module_data( file_data : &[u8] ) -> Result<Song, LoadError> {
+    read_nested_module_data( file_data, 0 )
+}
+
+/**
+ * Archives and packed files are only opened this many levels inside each other, so that a file which contains
+ * itself can't recurse until the stack runs out
+ */
+const MAX_NESTING : usize = 8;
+
+fn read_nested_module_data( file_data : &[u8], depth : usize ) -> Result<Song, LoadError> {
+    if archive_kind( file_data ).is_some() {
+        return read_nested_archived_module( file_data, None, depth );
+    }
+    // PowerPacker can hold any format, the packed mods are unpacked by the mod loader
+    if packed_format( file_data ) == Some( PackedFormat::PowerPacker ) {
+        if depth >= MAX_NESTING {
+            return Err( LoadError::Format( String::from( "Packed files are nested too deeply" ) ) );
+        }
+        return read_nested_module_data( &depack_powerpacker( file_data )?, depth + 1 );
+    }
+    match detect_format( file_data ) {
+        Some( ModuleKind::It ) => read_it_data( file_data ),
+        Some( ModuleKind::Xm ) => read_xm_data( file_data ),
+        Some( ModuleKind::S3m ) => read_s3m_data( file_data ),
+        Some( ModuleKind::Mod ) => read_mod_data( file_data ),
+        None => Err( LoadError::Format( String::from( "Not a module format the player knows" ) ) )
+    }
+}
+
+/**
+ * The files in an archive that look like modules, in the order they are stored
+ */
+pub fn archived_modules( archive_data : &[u8] ) -> Result<Vec<ArchiveEntry>, LoadError> {
+    nested_archived_modules( archive_data, 0 )
+}
+
+fn nested_archived_modules( archive_data : &[u8], depth : usize ) -> Result<Vec<ArchiveEntry>, LoadError> {
+    if depth >= MAX_NESTING {
+        return Err( LoadError::Format( String::from( "Archives are nested too deeply" ) ) );
+    }
+    Ok( read_archive( archive_data )?.into_iter().filter( | entry | is_module( &entry.data, depth + 1 ) ).collect() )
+}
+
+/**
+ * Packed modules and archives inside archives count as modules when what they unpack to is one
+ */
+fn is_module( data : &[u8], depth : usize ) -> bool {
+    guess_format( data ).is_some() || packed_format( data ).is_some() || nested_archived_modules( data, depth ).is_ok_and( | modules | !modules.is_empty() )
+}
+
+/**
+ * Load a module from an archive: the entry with the given name, or the first module when no name is given. Names
+ * match either the full path in the archive or the file name without the directories, ignoring case
+ */
+pub fn read_archived_module( archive_data : &[u8], entry_name : Option<&str> ) -> Result<Song, LoadError> {
+    read_nested_archived_module( archive_data, entry_name, 0 )
+}
+
+fn read_nested_archived_module( archive_data : &[u8], entry_name : Option<&str>, depth : usize ) -> Result<Song, LoadError> {
+    let modules = nested_archived_modules( archive_data, depth )?;
+    let entry = match entry_name {
+        Some( entry_name ) => modules.iter().find( | entry | {
+            let file_name = entry.name.rsplit( '/' ).next().unwrap_or( "" );
+            entry.name.eq_ignore_ascii_case( entry_name ) || file_name.eq_ignore_ascii_case( entry_name )
+        } ).ok_or_else( || LoadError::Format( format!( "No module called {} in the archive", entry_name ) ) )?,
+        None => modules.first().ok_or_else( || LoadError::Format( String::from( "No modules in the archive" ) ) )?
+    };
+    read_nested_module_data( &entry.data, depth + 1 )
+}
@@ -0,0 +1,389 @@
+use std::error;
+use std::fmt;
+
+use crate::song::{Song, Pattern, Note, Effect, Key, ModuleKind, FREQUENCY_TABLE};
+
+#[derive(Debug)]
+pub struct EditError {
+    message : String,
+}
+
+impl fmt::Display for EditError {
+    fn fmt( &self, f : &mut fmt::Formatter ) -> fmt::Result {
+        write!( f, "{}", self.message )
+    }
+}
+
+impl error::Error for EditError {}
+
+fn invalid( message : String ) -> EditError {
+    EditError{ message }
+}
+
+/**
+ * A rectangle of notes in a pattern. Both ends are included
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Block {
+    pub first_channel : usize,
+    pub last_channel : usize,
+    pub first_row : usize,
+    pub last_row : usize,
+}
+
+impl Block {
+    pub fn new( first_channel : usize, last_channel : usize, first_row : usize, last_row : usize ) -> Block {
+        Block{ first_channel, last_channel, first_row, last_row }
+    }
+
+    /**
+     * A single channel of a pattern from the first row to the last. Patterns of the later formats need not have 64 rows
+     */
+    pub fn channel( song : &Song, pattern : usize, channel : usize ) -> Block {
+        let rows = song.patterns.get( pattern ).map_or( 0, | pattern | pattern.lines.len() );
+        Block{ first_channel : channel, last_channel : channel, first_row : 0, last_row : rows.saturating_sub( 1 ) }
+    }
+}
+
+/**
+ * Notes copied out of a pattern, one vector per row
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Clip {
+    rows : Vec<Vec<Note>>,
+}
+
+impl Clip {
+    pub fn rows( &self ) -> &[Vec<Note>] {
+        &self.rows
+    }
+}
+
+/**
+ * Copy a block of notes for pasting elsewhere
+ */
+pub fn copy_block( song : &Song, pattern : usize, block : Block ) -> Result<Clip, EditError> {
+    check_block( song, pattern, block )?;
+    let rows = song.patterns[ pattern ].lines[ block.first_row..=block.last_row ].iter()
+        .map( | line | line[ block.first_channel..=block.last_channel ].to_vec() )
+        .collect();
+    Ok( Clip{ rows } )
+}
+
+/**
+ * An edit to a song. Applying it through an EditHistory makes it undoable
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum EditCommand {
+    SetNote{ pattern : usize, channel : usize, row : usize, note : Note },
+    ClearNote{ pattern : usize, channel : usize, row : usize },
+    InsertRow{ pattern : usize, channel : Option<usize>, row : usize },     // rows below move down and the last row is lost. All channels if None
+    DeleteRow{ pattern : usize, channel : Option<usize>, row : usize },     // rows below move up and an empty row is added at the end
+    Transpose{ pattern : usize, block : Block, semitones : i32 },           // notes that would leave the period table or the 120 keys are left alone
+    Paste{ pattern : usize, channel : usize, row : usize, clip : Clip },    // the part of the clip that falls outside the pattern is dropped
+    CopyPattern{ from : usize, to : usize },                                // overwrite a pattern with a copy of another
+    ClonePattern{ pattern : usize },                                        // add a copy of the pattern after the last one
+    SetOrder{ positions : Vec<u8> },                                        // replace the played part of the pattern table
+    ChangeSample{ pattern : usize, block : Block, sample_number : u8 },     // notes that set a sample get this one instead
+}
+
+/**
+ * What is needed to take an edit back
+ */
+enum Undo {
+    Pattern{ index : usize, pattern : Pattern },
+    RemoveLastPattern,
+    Order{ pattern_table : Vec<u8>, num_used_patterns : u32 },
+}
+
+fn check_pattern( song : &Song, pattern : usize ) -> Result<(), EditError> {
+    if pattern >= song.patterns.len() {
+        return Err( invalid( format!( "Pattern {} does not exist, the song has {}", pattern, song.patterns.len() ) ) );
+    }
+    Ok( () )
+}
+
+fn check_position( song : &Song, pattern : usize, channel : usize, row : usize ) -> Result<(), EditError> {
+    check_pattern( song, pattern )?;
+    if channel >= song.num_channels() as usize {
+        return Err( invalid( format!( "Channel {} does not exist, the song has {} channels", channel, song.num_channels() ) ) );
+    }
+    if row >= song.patterns[ pattern ].lines.len() {
+        return Err( invalid( format!( "Row {} is past the end of pattern {}", row, pattern ) ) );
+    }
+    Ok( () )
+}
+
+fn check_block( song : &Song, pattern : usize, block : Block ) -> Result<(), EditError> {
+    if block.first_channel > block.last_channel || block.first_row > block.last_row {
+        return Err( invalid( format!( "Empty block {:?}", block ) ) );
+    }
+    check_position( song, pattern, block.first_channel, block.first_row )?;
+    check_position( song, pattern, block.last_channel, block.last_row )
+}
+
+/**
+ * A note may only use the samples or instruments the song has, and a mod note has 12 bits for its period
+ */
+fn check_note( song : &Song, note : &Note ) -> Result<(), EditError> {
+    if note.sample_number as usize > song.num_sounds() {
+        return Err( invalid( format!( "Sample {} does not exist, the song has {}", note.sample_number, song.num_sounds() ) ) );
+    }
+    if song.format.kind == ModuleKind::Mod && note.period > 0xfff {
+        return Err( invalid( format!( "Period {} does not fit in a mod", note.period ) ) );
+    }
+    Ok( () )
+}
+
+fn empty_note() -> Note {
+    Note::new( 0, 0, Effect::None )
+}
+
+/**
+ * Move a period by semitones using the period table. Periods between table entries use the closest one
+ */
+fn transpose_period( period : u32, semitones : i32 ) -> Option<u32> {
+    let ( index, _ ) = FREQUENCY_TABLE.iter().enumerate().min_by_key( | ( _, table_period ) | ( **table_period as i32 - period as i32 ).abs() )?;
+    // the table runs from the highest note to the lowest
+    let new_index = index as i32 - semitones;
+    if new_index < 0 || new_index >= FREQUENCY_TABLE.len() as i32 {
+        return None;
+    }
+    Some( FREQUENCY_TABLE[ new_index as usize ] )
+}
+
+/**
+ * Move a key of the formats that store notes by semitones, keeping to the 120 keys of an instrument
+ */
+fn transpose_key( key : u8, semitones : i32 ) -> Option<u8> {
+    let new_key = key as i32 + semitones;
+    ( 0..120 ).contains( &new_key ).then_some( new_key as u8 )
+}
+
+impl EditCommand {
+    /**
+     * Check the command against the song and apply it. Returns what is needed to undo it
+     */
+    fn apply( &self, song : &mut Song ) -> Result<Undo, EditError> {
+        match self {
+            EditCommand::SetNote{ pattern, channel, row, note } => {
+                check_position( song, *pattern, *channel, *row )?;
+                check_note( song, note )?;
+            }
+            EditCommand::ClearNote{ pattern, channel, row } => check_position( song, *pattern, *channel, *row )?,
+            EditCommand::InsertRow{ pattern, channel, row } | EditCommand::DeleteRow{ pattern, channel, row } => check_position( song, *pattern, channel.unwrap_or( 0 ), *row )?,
+            EditCommand::Transpose{ pattern, block, .. } => check_block( song, *pattern, *block )?,
+            EditCommand::ChangeSample{ pattern, block, sample_number } => {
+                check_block( song, *pattern, *block )?;
+                check_note( song, &Note::new( *sample_number, 0, Effect::None ) )?;
+            }
+            EditCommand::Paste{ pattern, channel, row, clip } => {
+                check_position( song, *pattern, *channel, *row )?;
+                for note in clip.rows.iter().flatten() {
+                    check_note( song, note )?;
+                }
+            }
+            EditCommand::CopyPattern{ from, to } => {
+                check_pattern( song, *from )?;
+                check_pattern( song, *to )?;
+            }
+            EditCommand::ClonePattern{ pattern } => {
+                check_pattern( song, *pattern )?;
+                if song.patterns.len() >= 256 {
+                    return Err( invalid( String::from( "A song can have at most 256 patterns" ) ) );
+                }
+            }
+            EditCommand::SetOrder{ positions } => {
+                if positions.is_empty() || positions.len() > 128 {
+                    return Err( invalid( format!( "The order table needs 1 to 128 positions, not {}", positions.len() ) ) );
+                }
+                if let Some( pattern ) = positions.iter().find( | pattern | **pattern as usize >= song.patterns.len() ) {
+                    return Err( invalid( format!( "Pattern {} does not exist, the song has {}", pattern, song.patterns.len() ) ) );
+                }
+            }
+        }
+
+        let undo = match self {
+            EditCommand::ClonePattern{ .. } => Undo::RemoveLastPattern,
+            EditCommand::SetOrder{ .. } => Undo::Order{ pattern_table : song.pattern_table.clone(), num_used_patterns : song.num_used_patterns },
+            EditCommand::CopyPattern{ to, .. } => Undo::Pattern{ index : *to, pattern : song.patterns[ *to ].clone() },
+            EditCommand::SetNote{ pattern, .. } | EditCommand::ClearNote{ pattern, .. } | EditCommand::InsertRow{ pattern, .. } | EditCommand::DeleteRow{ pattern, .. } |
+            EditCommand::Transpose{ pattern, .. } | EditCommand::Paste{ pattern, .. } | EditCommand::ChangeSample{ pattern, .. } =>
+                Undo::Pattern{ index : *pattern, pattern : song.patterns[ *pattern ].clone() },
+        };
+
+        match self {
+            EditCommand::SetNote{ pattern, channel, row, note } => song.patterns[ *pattern ].lines[ *row ][ *channel ] = *note,
+            EditCommand::ClearNote{ pattern, channel, row } => song.patterns[ *pattern ].lines[ *row ][ *channel ] = empty_note(),
+            EditCommand::InsertRow{ pattern, channel, row } => {
+                let lines = &mut song.patterns[ *pattern ].lines;
+                let channels = match channel { Some( channel ) => *channel..*channel + 1, None => 0..lines[ 0 ].len() };
+                for line in ( *row + 1..lines.len() ).rev() {
+                    for channel in channels.clone() {
+                        lines[ line ][ channel ] = lines[ line - 1 ][ channel ];
+                    }
+                }
+                for channel in channels {
+                    lines[ *row ][ channel ] = empty_note();
+                }
+            }
+            EditCommand::DeleteRow{ pattern, channel, row } => {
+                let lines = &mut song.patterns[ *pattern ].lines;
+                let channels = match channel { Some( channel ) => *channel..*channel + 1, None => 0..lines[ 0 ].len() };
+                for line in *row..lines.len() - 1 {
+                    for channel in channels.clone() {
+                        lines[ line ][ channel ] = lines[ line + 1 ][ channel ];
+                    }
+                }
+                let last = lines.len() - 1;
+                for channel in channels {
+                    lines[ last ][ channel ] = empty_note();
+                }
+            }
+            EditCommand::Transpose{ pattern, block, semitones } => {
+                for line in &mut song.patterns[ *pattern ].lines[ block.first_row..=block.last_row ] {
+                    for note in &mut line[ block.first_channel..=block.last_channel ] {
+                        if note.period != 0 {
+                            note.period = transpose_period( note.period, *semitones ).unwrap_or( note.period );
+                        }
+                        if let Key::Note( key ) = note.key {
+                            note.key = Key::Note( transpose_key( key, *semitones ).unwrap_or( key ) );
+                        }
+                    }
+                }
+            }
+            EditCommand::Paste{ pattern, channel, row, clip } => {
+                let lines = &mut song.patterns[ *pattern ].lines;
+                for ( line, clip_row ) in lines[ *row.. ].iter_mut().zip( clip.rows.iter() ) {
+                    for ( note, clip_note ) in line[ *channel.. ].iter_mut().zip( clip_row.iter() ) {
+                        *note = *clip_note;
+                    }
+                }
+            }
+            EditCommand::CopyPattern{ from, to } => song.patterns[ *to ] = song.patterns[ *from ].clone(),
+            EditCommand::ClonePattern{ pattern } => {
+                let copy = song.patterns[ *pattern ].clone();
+                song.patterns.push( copy );
+            }
+            EditCommand::SetOrder{ positions } => {
+                song.pattern_table = positions.clone();
+                song.pattern_table.resize( 128, 0 );
+                song.num_used_patterns = positions.len() as u32;
+            }
+            EditCommand::ChangeSample{ pattern, block, sample_number } => {
+                for line in &mut song.patterns[ *pattern ].lines[ block.first_row..=block.last_row ] {
+                    for note in &mut line[ block.first_channel..=block.last_channel ] {
+                        if note.sample_number != 0 {
+                            note.sample_number = *sample_number;
+                        }
+                    }
+                }
+            }
+        }
+        Ok( undo )
+    }
+}
+
+impl Undo {
+    fn revert( self, song : &mut Song ) {
+        match self {
+            Undo::Pattern{ index, pattern } => song.patterns[ index ] = pattern,
+            Undo::RemoveLastPattern => { song.patterns.pop(); }
+            Undo::Order{ pattern_table, num_used_patterns } => {
+                song.pattern_table = pattern_table;
+                song.num_used_patterns = num_used_patterns;
+            }
+        }
+    }
+}
+
+/**
+ * Applies edits to a song and keeps them for undo and redo. Applying a new edit clears the redo list.
+ * The history assumes it is the only thing changing the song
+ */
+pub struct EditHistory {
+    done : Vec<( EditCommand, Undo )>,
+    undone : Vec<EditCommand>,
+    limit : usize,
+}
+
+impl Default for EditHistory {
+    fn default() -> EditHistory {
+        EditHistory::new()
+    }
+}
+
+impl EditHistory {
+    /**
+     * A history that keeps the last 1000 edits
+     */
+    pub fn new() -> EditHistory {
+        EditHistory::with_limit( 1000 )
+    }
+
+    pub fn with_limit( limit : usize ) -> EditHistory {
+        EditHistory{ done : Vec::new(), undone : Vec::new(), limit }
+    }
+
+    /**
+     * Apply the edit. A command that does not fit the song is rejected without changing anything
+     */
+    pub fn apply( &mut self, song : &mut Song, command : EditCommand ) -> Result<(), EditError> {
+        let undo = command.apply( song )?;
+        self.push_done( command, undo );
+        self.undone.clear();
+        Ok( () )
+    }
+
+    /**
+     * Keep an applied edit for undo, dropping the oldest one past the limit
+     */
+    fn push_done( &mut self, command : EditCommand, undo : Undo ) {
+        self.done.push( ( command, undo ) );
+        if self.done.len() > self.limit {
+            self.done.remove( 0 );
+        }
+    }
+
+    /**
+     * Take back the latest edit. Returns false if there is nothing to undo
+     */
+    pub fn undo( &mut self, song : &mut Song ) -> bool {
+        match self.done.pop() {
+            Some( ( command, undo ) ) => {
+                undo.revert( song );
+                self.undone.push( command );
+                true
+            }
+            None => false
+        }
+    }
+
+    /**
+     * Apply the latest undone edit again. Returns false if there is nothing to redo
+     */
+    pub fn redo( &mut self, song : &mut Song ) -> Result<bool, EditError> {
+        let command = match self.undone.pop() {
+            Some( command ) => command,
+            None => return Ok( false )
+        };
+        match command.apply( song ) {
+            Ok( undo ) => {
+                self.push_done( command, undo );
+                Ok( true )
+            }
+            Err( error ) => {
+                self.undone.push( command );
+                Err( error )
+            }
+        }
+    }
+
+    pub fn can_undo( &self ) -> bool {
+        !self.done.is_empty()
+    }
+
+    pub fn can_redo( &self ) -> bool {
+        !self.undone.is_empty()
+    }
+}
@@ -0,0 +1,84 @@
+use std::collections::BTreeMap;
+
+use crate::player::song_duration;
+use crate::song::{Song, Effect, ModuleKind};
+
+/**
+ * Summary of a sample for catalogs
+ */
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SampleInfo {
+    pub number : u32,           // as used in the patterns, from 1
+    pub name : String,
+    pub size : u32,
+    pub volume : u8,
+    pub fine_tune : u8,
+    pub repeat_offset : u32,
+    pub repeat_size : u32,
+}
+
+/**
+ * Summary of a song for catalogs. Unlike the song itself it has no pattern or sample data
+ */
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SongInfo {
+    pub title : String,
+    pub format : String,                // "S3M", "XM" or "IT", or for mods the tag, or "15 samples" for original modules without one
+    pub channels : u32,
+    pub samples : Vec<SampleInfo>,      // only the samples that have a name or data
+    pub order : Vec<u8>,                // the positions that are played
+    pub num_patterns : u32,
+    pub duration_seconds : f64,         // until the song ends or loops
+    pub effect_usage : BTreeMap<String, u32>,       // how many notes in the stored patterns use each effect
+}
+
+/**
+ * Names are cut at the first zero byte and trailing spaces removed
+ */
+fn clean_name( name : &str ) -> String {
+    name.split( '\0' ).next().unwrap_or( "" ).trim_end().to_string()
+}
+
+impl SongInfo {
+    pub fn new( song : &Song ) -> SongInfo {
+        let format = match ( song.format.kind, song.format.tag ) {
+            ( ModuleKind::S3m, _ ) => String::from( "S3M" ),
+            ( ModuleKind::Xm, _ ) => String::from( "XM" ),
+            ( ModuleKind::It, _ ) => String::from( "IT" ),
+            ( ModuleKind::Mod, Some( tag ) ) => tag.iter().map( | byte | *byte as char ).collect(),
+            ( ModuleKind::Mod, None ) => format!( "{} samples", song.format.num_samples )
+        };
+        let samples = song.samples.iter().enumerate()
+            .filter( | ( _, sample ) | !clean_name( &sample.name ).is_empty() || sample.size > 0 )
+            .map( | ( index, sample ) | SampleInfo {
+                number : index as u32 + 1,
+                name : clean_name( &sample.name ),
+                size : sample.size,
+                volume : sample.volume,
+                fine_tune : sample.fine_tune,
+                repeat_offset : sample.repeat_offset,
+                repeat_size : sample.repeat_size,
+            } )
+            .collect();
+
+        let mut effect_usage = BTreeMap::new();
+        for note in song.patterns.iter().flat_map( | pattern | pattern.lines.iter().flatten() ) {
+            if note.effect != Effect::None {
+                *effect_usage.entry( note.effect.name().to_string() ).or_insert( 0 ) += 1;
+            }
+        }
+
+        SongInfo {
+            title : clean_name( &song.name ),
+            format,
+            channels : song.format.num_channels,
+            samples,
+            order : song.pattern_table[ 0..song.num_used_patterns as usize ].to_vec(),
+            num_patterns : song.patterns.len() as u32,
+            duration_seconds : song_duration( song ),
+            effect_usage,
+        }
+    }
+}
@@ -0,0 +1,538 @@
+use std::fs;
+
+use crate::song::{Song, Sample, Pattern, Note, Effect, Key, Instrument, Envelope, AutoVibrato, NewNoteAction, DuplicateCheck, FormatDescription, ModuleKind, LoadError, decode_name};
+use crate::s3m;
+
+const HEADER_SIZE : usize = 0xc0;
+const SAMPLE_HEADER_SIZE : usize = 0x50;
+const INSTRUMENT_SIZE : usize = 0x22a;
+const ENVELOPE_POINTS : usize = 25;
+const MAX_CHANNELS : usize = 64;
+const ORDER_SKIP : u8 = 254;            // "+++" in the order list, skipped when playing
+const ORDER_END : u8 = 255;
+const NOTE_CUT : u8 = 254;
+const NOTE_OFF : u8 = 255;
+
+fn word( data : &[u8], offset : usize ) -> u16 {
+    u16::from_le_bytes( [ data[ offset ], data[ offset + 1 ] ] )
+}
+
+fn double_word( data : &[u8], offset : usize ) -> u32 {
+    u32::from_le_bytes( [ data[ offset ], data[ offset + 1 ], data[ offset + 2 ], data[ offset + 3 ] ] )
+}
+
+fn format_error( message : String ) -> LoadError {
+    LoadError::Format( message )
+}
+
+/**
+ * Decode an effect letter, 1 for A up to 26 for Z, and its argument. Impulse Tracker has the effects of Scream
+ * Tracker 3 with a few changes: C takes the line in hex, V goes up to 128, X up to 255, P slides the panning and Z
+ * sets the filter through the default macros. Channel volume, panbrello and the S7x new note action overrides are
+ * not supported
+ */
+pub(crate) fn it_effect( command : u8, argument : u8 ) -> Effect {
+    let high = argument >> 4;
+    let low = argument & 0x0f;
+    match command {
+        3 => Effect::PatternBreak{ next_pattern_pos : argument },
+        // P0x slides right and Px0 left, the fine slides are not supported
+        16 if high == 0 || low == 0 => Effect::PanningSlide{ left : high, right : low },
+        19 if matches!( high, 7 | 9 | 0x0a | 0x0f ) => Effect::None,
+        22 => Effect::SetGlobalVolume{ volume : argument.min( 128 ) / 2 },
+        23 => Effect::GlobalVolumeSlide{ up : high, down : low },
+        24 => Effect::SetPanning{ position : argument },
+        26 if argument < 0x80 => Effect::SetFilterCutoff{ cutoff : argument },
+        26 if argument < 0x90 => Effect::SetFilterResonance{ resonance : low * 8 },
+        13 | 14 | 16 | 25 | 26 => Effect::None,
+        _ => s3m::s3m_effect( command, argument )
+    }
+}
+
+/**
+ * Letter and argument of the effects that repeat their last non zero argument when they are given 0. Unlike Scream
+ * Tracker most effects have a memory of their own: E and F share one, and K and L use the one of D
+ */
+pub(crate) fn effect_memory( effect : &Effect ) -> Option<( usize, u8, u8 )> {
+    let ( command, argument ) = match *effect {
+        Effect::GlobalVolumeSlide{ up, down } => ( 23, ( up << 4 ) | down ),
+        Effect::PanningSlide{ left, right } => ( 16, ( left << 4 ) | right ),
+        _ => s3m::shared_argument( effect )?
+    };
+    let slot = match command {
+        4 | 11 | 12 => 0,
+        5 | 6 => 1,
+        9 => 2,
+        10 => 3,
+        16 => 4,
+        17 => 5,
+        18 => 6,
+        _ => 7,
+    };
+    Some( ( slot, command, argument ) )
+}
+
+/**
+ * The volume column holds a volume, or a slide, panning, portamento or vibrato in fixed ranges. The portamento
+ * speeds come from a table
+ */
+fn volume_column( value : u8 ) -> Effect {
+    const PORTAMENTO_SPEEDS : [ u8; 10 ] = [ 0, 1, 4, 8, 16, 32, 64, 96, 128, 255 ];
+    match value {
+        0..=64 => Effect::SetVolume{ volume : value },
+        65..=74 => Effect::FineVolumeSlideUp{ change : value - 65 },
+        75..=84 => Effect::FineVolumeSlideDown{ change : value - 75 },
+        85..=94 => Effect::VolumeSlide{ up : value - 85, down : 0 },
+        95..=104 => Effect::VolumeSlide{ up : 0, down : value - 95 },
+        105..=114 => Effect::SlideDown{ speed : ( value - 105 ) * 4 },
+        115..=124 => Effect::SlideUp{ speed : ( value - 115 ) * 4 },
+        128..=192 => Effect::SetPanning{ position : ( ( value - 128 ) as u32 * 255 / 64 ) as u8 },
+        193..=202 => Effect::TonePortamento{ speed : PORTAMENTO_SPEEDS[ ( value - 193 ) as usize ] },
+        203..=212 => Effect::Vibrato{ speed : 0, amplitude : value - 203 },
+        _ => Effect::None
+    }
+}
+
+fn read_key( value : u8 ) -> Key {
+    match value {
+        0..=119 => Key::Note( value ),
+        NOTE_OFF => Key::Off,
+        NOTE_CUT => Key::Cut,
+        _ => Key::Fade
+    }
+}
+
+/**
+ * Panning of 0 - 64 from left to right, 100 is surround which plays in the middle here
+ */
+fn panning( value : u8 ) -> u8 {
+    match value {
+        0..=64 => ( value as u32 * 255 / 64 ) as u8,
+        _ => 128
+    }
+}
+
+/**
+ * Unpack a pattern into its lines, with the channel of each note. Every channel remembers its last mask and the
+ * last value of each field, which a later entry can use again
+ */
+fn read_pattern( file_data : &[u8], offset : usize, num_samples : usize ) -> Vec<Vec<( usize, Note )>> {
+    if offset == 0 || offset + 8 > file_data.len() {
+        return vec![ Vec::new(); 64 ];
+    }
+    let size = word( file_data, offset ) as usize;
+    let num_lines = ( word( file_data, offset + 2 ) as usize ).clamp( 1, 200 );
+    let data = &file_data[ offset + 8..( offset + 8 + size ).min( file_data.len() ) ];
+    let mut lines = vec![ Vec::new(); num_lines ];
+    let mut masks = [ 0u8; MAX_CHANNELS ];
+    let mut last_notes = [ Note::with_key( 0, Key::None, Effect::None, Effect::None ); MAX_CHANNELS ];
+    let mut last_effects = [ ( 0u8, 0u8 ); MAX_CHANNELS ];
+    let mut fields = data.iter().copied();
+    let mut line = 0;
+    while line < num_lines {
+        let channel_variable = match fields.next() {
+            Some( value ) => value,
+            None => break
+        };
+        if channel_variable == 0 {
+            line += 1;
+            continue;
+        }
+        let channel = ( channel_variable - 1 ) as usize & 63;
+        if channel_variable & 0x80 != 0 {
+            masks[ channel ] = fields.next().unwrap_or( 0 );
+        }
+        let mask = masks[ channel ];
+        let last = &mut last_notes[ channel ];
+        if mask & 1 != 0 {
+            last.key = read_key( fields.next().unwrap_or( 0 ) );
+        }
+        if mask & 2 != 0 {
+            last.sample_number = fields.next().filter( | number | ( *number as usize ) <= num_samples ).unwrap_or( 0 );
+        }
+        if mask & 4 != 0 {
+            last.volume_effect = volume_column( fields.next().unwrap_or( 255 ) );
+        }
+        if mask & 8 != 0 {
+            last_effects[ channel ] = ( fields.next().unwrap_or( 0 ), fields.next().unwrap_or( 0 ) );
+        }
+        let mut note = Note::with_key( 0, Key::None, Effect::None, Effect::None );
+        if mask & 0x11 != 0 {
+            note.key = last.key;
+        }
+        if mask & 0x22 != 0 {
+            note.sample_number = last.sample_number;
+        }
+        if mask & 0x44 != 0 {
+            note.volume_effect = last.volume_effect;
+        }
+        if mask & 0x88 != 0 {
+            let ( command, argument ) = last_effects[ channel ];
+            note.effect = it_effect( command, argument );
+        }
+        lines[ line ].push( ( channel, note ) );
+    }
+    lines
+}
+
+/**
+ * Reads the bits of a compressed block, lowest bit first. Reading past the end gives zeros
+ */
+struct BitReader<'a> {
+    data : &'a [u8],
+    position : usize,           // in bits
+}
+
+impl<'a> BitReader<'a> {
+    fn read( &mut self, bits : u32 ) -> u32 {
+        let mut value = 0;
+        for bit in 0..bits {
+            let byte = self.data.get( self.position / 8 ).copied().unwrap_or( 0 );
+            value |= ( ( byte >> ( self.position % 8 ) ) as u32 & 1 ) << bit;
+            self.position += 1;
+        }
+        value
+    }
+}
+
+/**
+ * Unpack an Impulse Tracker 2.14 compressed sample into 16 bit values. The data is in blocks, each with its own
+ * length, that store the difference from one value to the next with a bit width that changes as it goes. IT 2.15
+ * stores the differences of the differences. A block with an invalid width ends early, and the sample ends with the
+ * last block there is data for whatever length the header gives
+ */
+fn decompress( data : &[u8], length : usize, sixteen_bit : bool, it215 : bool ) -> Vec<i16> {
+    let ( block_length, value_bits, width_bits, border_offset ) = if sixteen_bit { ( 0x4000, 16, 4, 8 ) } else { ( 0x8000, 8, 3, 4 ) };
+    let full_width = value_bits + 1;
+    // every block has at least its 2 byte size
+    let mut output = Vec::with_capacity( length.min( data.len() / 2 * block_length ) );
+    let mut position = 0;
+    while output.len() < length && position + 2 <= data.len() {
+        let size = word( data, position ) as usize;
+        let mut bits = BitReader{ data : &data[ position + 2..( position + 2 + size ).min( data.len() ) ], position : 0 };
+        position += 2 + size;
+
+        let count = block_length.min( length - output.len() );
+        let block_start = output.len();
+        let mut width : u32 = full_width;
+        let ( mut difference, mut value ) = ( 0i32, 0i32 );
+        while output.len() - block_start < count {
+            if width == 0 || width > full_width {
+                break;
+            }
+            let bits_value = bits.read( width );
+            if width < 7 {
+                // the value with only the top bit set is followed by the new width
+                if bits_value == 1 << ( width - 1 ) {
+                    let new_width = bits.read( width_bits ) + 1;
+                    width = if new_width < width { new_width } else { new_width + 1 };
+                    continue;
+                }
+            } else if width < full_width {
+                // the values just below the top of the range are new widths
+                let border = ( ( 1u32 << value_bits ) - 1 ) >> ( full_width - width );
+                let border = border - border_offset;
+                if bits_value > border && bits_value <= border + 2 * border_offset {
+                    let new_width = bits_value - border;
+                    width = if new_width < width { new_width } else { new_width + 1 };
+                    continue;
+                }
+            } else if bits_value & ( 1 << value_bits ) != 0 {
+                width = ( bits_value + 1 ) & ( ( 1 << value_bits ) - 1 );
+                continue;
+            }
+            let used_bits = width.min( value_bits );
+            let change = ( ( bits_value << ( 32 - used_bits ) ) as i32 ) >> ( 32 - used_bits );
+            // the sums wrap round at the sample's bit depth
+            let wrap = | sum : i32 | if sixteen_bit { sum as i16 as i32 } else { sum as i8 as i32 };
+            difference = wrap( difference + change );
+            value = wrap( value + difference );
+            let result = if it215 { value } else { difference };
+            output.push( if sixteen_bit { result as i16 } else { ( result << 8 ) as i16 } );
+        }
+        output.resize( block_start + count, 0 );
+    }
+    output
+}
+
+/**
+ * Read a sample header and its data. Stereo samples are mixed down and 16 bit ones reduced to the 8 bits the
+ * player mixes at. The sustain loop is played as a normal loop when the sample has no other loop, and nothing after
+ * the end of the loop is kept
+ */
+fn read_sample( file_data : &[u8], offset : usize ) -> Result<( Sample, AutoVibrato ), LoadError> {
+    let header = file_data.get( offset..offset + SAMPLE_HEADER_SIZE ).ok_or_else( || format_error( format!( "Sample header at {} is past the end of the file", offset ) ) )?;
+    if &header[ 0..4 ] != b"IMPS" {
+        return Err( format_error( format!( "No sample header at {}", offset ) ) );
+    }
+    let name = decode_name( &header[ 0x14..0x2e ] );
+    let flags = header[ 0x12 ];
+    let conversion = header[ 0x2e ];
+    let stored_length = double_word( header, 0x30 ) as usize;
+    let data_offset = double_word( header, 0x48 ) as usize;
+    let sixteen_bit = flags & 2 != 0;
+    let channels = if flags & 4 != 0 { 2 } else { 1 };
+    let stored = file_data.get( data_offset.min( file_data.len() ).. ).unwrap_or( &[] );
+
+    let values : Vec<i32> = if flags & 1 == 0 {
+        Vec::new()
+    } else if flags & 8 != 0 {
+        // compressed stereo samples only keep the left channel
+        decompress( stored, stored_length, sixteen_bit, conversion & 4 != 0 ).into_iter().map( | value | value as i32 ).collect()
+    } else {
+        let bytes_per_value = if sixteen_bit { 2 } else { 1 };
+        let value = | index : usize | -> i32 {
+            let position = index * bytes_per_value;
+            let raw = match bytes_per_value {
+                2 => stored.get( position..position + 2 ).map( | bytes | word( bytes, 0 ) as i32 ),
+                _ => stored.get( position ).map( | byte | ( *byte as i32 ) << 8 )
+            };
+            match raw {
+                Some( raw ) if conversion & 1 != 0 => raw as i16 as i32,
+                Some( raw ) => raw - 0x8000,
+                None => 0
+            }
+        };
+        // files that are cut short keep the part of the sample that is there
+        let length = stored_length.min( stored.len() / bytes_per_value );
+        ( 0..length ).map( | index | ( 0..channels ).map( | channel | value( channel * stored_length + index ) ).sum::<i32>() / channels as i32 ).collect()
+    };
+    let data = values.iter().map( | value | ( ( value + 0x80 ) >> 8 ).clamp( -128, 127 ) as i8 ).collect();
+
+    let mut sample = Sample::from_data( &name, data );
+    sample.volume = header[ 0x13 ].min( 64 );
+    sample.global_volume = header[ 0x11 ].min( 64 );
+    if header[ 0x2f ] & 0x80 != 0 {
+        sample.panning = Some( panning( header[ 0x2f ] & 0x7f ) );
+    }
+    sample.c2_speed = match double_word( header, 0x3c ) {
+        0 => sample.c2_speed,
+        c5_speed => c5_speed
+    };
+    let loop_offset = if flags & 0x10 != 0 { Some( ( 0x34, flags & 0x40 != 0 ) ) } else if flags & 0x20 != 0 { Some( ( 0x40, flags & 0x80 != 0 ) ) } else { None };
+    if let Some( ( loop_offset, ping_pong ) ) = loop_offset {
+        let loop_start = double_word( header, loop_offset );
+        let loop_end = double_word( header, loop_offset + 4 ).min( sample.size );
+        if loop_start + 2 < loop_end {
+            sample.samples.truncate( loop_end as usize );
+            sample.size = loop_end;
+            sample.repeat_offset = loop_start;
+            sample.repeat_size = loop_end - loop_start;
+            sample.ping_pong = ping_pong;
+        }
+    }
+
+    // the sample's vibrato as a FastTracker 2 one: a depth of 0 - 15 that takes sweep ticks to build up
+    let ( speed, depth, rate ) = ( header[ 0x4c ], header[ 0x4d ], header[ 0x4e ] );
+    let vibrato = AutoVibrato {
+        wave : match header[ 0x4f ] { 1 => 2, 2 => 1, _ => 0 },
+        sweep : if rate == 0 { 0 } else { ( depth as u32 * 256 / rate as u32 ).min( 255 ) as u8 },
+        depth : depth / 4,
+        rate : speed,
+    };
+    Ok( ( sample, vibrato ) )
+}
+
+/**
+ * Envelope of a new format instrument: flags, number of points, loop and sustain loop, then 25 points of a value
+ * and a tick. Panning, pitch and filter values go from -32 to 32
+ */
+fn read_envelope( header : &[u8], offset : usize, signed : bool ) -> Envelope {
+    let flags = header[ offset ];
+    if flags & 1 == 0 {
+        return Envelope::default();
+    }
+    let num_points = ( header[ offset + 1 ] as usize ).min( ENVELOPE_POINTS );
+    let points = ( 0..num_points ).map( | point | {
+        let position = offset + 6 + point * 3;
+        let value = if signed { ( header[ position ] as i8 ).clamp( -32, 32 ) + 32 } else { header[ position ].min( 64 ) as i8 };
+        ( word( header, position + 1 ), value as u8 )
+    } ).collect();
+    let range = | start : u8, end : u8 | if ( start as usize ) <= ( end as usize ) && ( end as usize ) < num_points { Some( ( start as usize, end as usize ) ) } else { None };
+    Envelope {
+        points,
+        loop_points : if flags & 2 != 0 { range( header[ offset + 2 ], header[ offset + 3 ] ) } else { None },
+        sustain : if flags & 4 != 0 { range( header[ offset + 4 ], header[ offset + 5 ] ) } else { None },
+    }
+}
+
+fn new_note_action( value : u8 ) -> NewNoteAction {
+    match value {
+        1 => NewNoteAction::Continue,
+        2 => NewNoteAction::NoteOff,
+        3 => NewNoteAction::Fade,
+        _ => NewNoteAction::Cut
+    }
+}
+
+/**
+ * Read an instrument. Files from before Impulse Tracker 2 have an older layout with only a volume envelope, and
+ * their fadeout counts against 512 instead of 1024
+ */
+fn read_instrument( file_data : &[u8], offset : usize, old_format : bool ) -> Result<Instrument, LoadError> {
+    let header = file_data.get( offset..offset + INSTRUMENT_SIZE ).ok_or_else( || format_error( format!( "Instrument header at {} is past the end of the file", offset ) ) )?;
+    if &header[ 0..4 ] != b"IMPI" {
+        return Err( format_error( format!( "No instrument header at {}", offset ) ) );
+    }
+    let keys = &header[ 0x40..0x130 ];
+    let keymap = keys.chunks_exact( 2 ).map( | key | key[ 1 ] ).collect();
+    let notes : Vec<u8> = keys.chunks_exact( 2 ).map( | key | key[ 0 ].min( 119 ) ).collect();
+    let mut instrument = Instrument {
+        name : decode_name( &header[ 0x20..0x3a ] ),
+        keymap,
+        notes : if notes.iter().enumerate().all( | ( key, note ) | *note as usize == key ) { Vec::new() } else { notes },
+        ..Instrument::default()
+    };
+
+    if old_format {
+        let flags = header[ 0x11 ];
+        if flags & 1 != 0 {
+            let points : Vec<( u16, u8 )> = header[ 0x1f8..0x22a ].chunks_exact( 2 ).take_while( | point | point[ 0 ] != 0xff ).map( | point | ( point[ 0 ] as u16, point[ 1 ].min( 64 ) ) ).collect();
+            let range = | start : u8, end : u8 | if start <= end && ( end as usize ) < points.len() { Some( ( start as usize, end as usize ) ) } else { None };
+            instrument.volume_envelope = Envelope {
+                loop_points : if flags & 2 != 0 { range( header[ 0x12 ], header[ 0x13 ] ) } else { None },
+                sustain : if flags & 4 != 0 { range( header[ 0x14 ], header[ 0x15 ] ) } else { None },
+                points,
+            };
+        }
+        instrument.fadeout = word( header, 0x18 ) as u32 * 64;
+        instrument.new_note_action = new_note_action( header[ 0x1a ] );
+        if header[ 0x1b ] != 0 {
+            instrument.duplicate_check = DuplicateCheck::Note;
+        }
+        return Ok( instrument );
+    }
+
+    instrument.new_note_action = new_note_action( header[ 0x11 ] );
+    instrument.duplicate_check = match header[ 0x12 ] {
+        1 => DuplicateCheck::Note,
+        2 => DuplicateCheck::Sample,
+        3 => DuplicateCheck::Instrument,
+        _ => DuplicateCheck::Off
+    };
+    // duplicates are cut, released or faded
+    instrument.duplicate_action = new_note_action( match header[ 0x13 ] { 0 => 0, action => action + 1 } );
+    instrument.fadeout = ( word( header, 0x14 ) as u32 ).min( 1024 ) * 32;
+    instrument.global_volume = header[ 0x18 ].min( 128 ) / 2;
+    if header[ 0x19 ] & 0x80 == 0 {
+        instrument.panning = Some( panning( header[ 0x19 ] ) );
+    }
+    if header[ 0x3a ] & 0x80 != 0 {
+        instrument.filter_cutoff = Some( header[ 0x3a ] & 0x7f );
+    }
+    if header[ 0x3b ] & 0x80 != 0 {
+        instrument.filter_resonance = Some( header[ 0x3b ] & 0x7f );
+    }
+    instrument.volume_envelope = read_envelope( header, 0x130, false );
+    instrument.panning_envelope = read_envelope( header, 0x182, true );
+    // the last envelope changes the pitch, or the filter cutoff when its top flag is set
+    let envelope = read_envelope( header, 0x1d4, true );
+    if header[ 0x1d4 ] & 0x80 != 0 {
+        instrument.filter_envelope = envelope;
+    } else {
+        instrument.pitch_envelope = envelope;
+    }
+    Ok( instrument )
+}
+
+pub fn read_it_file( file_name : &str ) -> Result<Song, LoadError> {
+    let file_data : Vec<u8> = fs::read( file_name )?;
+    read_it_data( &file_data )
+}
+
+/**
+ * Parse an Impulse Tracker module that has already been loaded into memory. The song has as many channels as the
+ * patterns use, up to the last one that is switched on. Position jumps are renumbered so they still land on the same
+ * pattern once the "+++" markers have been taken out of the order list. The channel volumes are not supported
+ */
+pub fn read_it_data( file_data : &[u8] ) -> Result<Song, LoadError> {
+    if file_data.len() < HEADER_SIZE || &file_data[ 0..4 ] != b"IMPM" {
+        return Err( format_error( String::from( "Not an Impulse Tracker module" ) ) );
+    }
+    let num_orders = word( file_data, 0x20 ) as usize;
+    let num_instruments = word( file_data, 0x22 ) as usize;
+    let num_samples = word( file_data, 0x24 ) as usize;
+    let num_patterns = word( file_data, 0x26 ) as usize;
+    let compatible_version = word( file_data, 0x2a );
+    let flags = word( file_data, 0x2c );
+    let pointers_offset = HEADER_SIZE + num_orders;
+    let pointers_end = pointers_offset + 4 * ( num_instruments + num_samples + num_patterns );
+    if file_data.len() < pointers_end {
+        return Err( format_error( format!( "File is too short for {} orders, {} instruments, {} samples and {} patterns", num_orders, num_instruments, num_samples, num_patterns ) ) );
+    }
+    if num_patterns > 256 || num_samples > 255 || num_instruments > 255 {
+        return Err( format_error( format!( "The song has {} patterns, {} samples and {} instruments, the player handles 256, 255 and 255", num_patterns, num_samples, num_instruments ) ) );
+    }
+
+    let mut pattern_table = Vec::new();
+    let mut order_positions = Vec::new();       // the position in the pattern table of each entry of the order list
+    for order in &file_data[ HEADER_SIZE..pointers_offset ] {
+        order_positions.push( pattern_table.len() );
+        match *order {
+            ORDER_END => break,
+            ORDER_SKIP => (),
+            pattern if pattern as usize >= num_patterns => return Err( format_error( format!( "Order list refers to pattern {} but the file only has {}", pattern, num_patterns ) ) ),
+            pattern => pattern_table.push( pattern ),
+        }
+    }
+    if pattern_table.is_empty() {
+        return Err( format_error( String::from( "The order list is empty" ) ) );
+    }
+
+    let pointer = | index : usize | double_word( file_data, pointers_offset + 4 * index ) as usize;
+    let instrument_mode = flags & 4 != 0;
+    let instruments = if instrument_mode {
+        ( 0..num_instruments ).map( | index | read_instrument( file_data, pointer( index ), compatible_version < 0x200 ) ).collect::<Result<Vec<Instrument>, LoadError>>()?
+    } else {
+        Vec::new()
+    };
+    let ( samples, vibratos ) : ( Vec<Sample>, Vec<AutoVibrato> ) = ( 0..num_samples ).map( | index | read_sample( file_data, pointer( num_instruments + index ) ) )
+        .collect::<Result<Vec<( Sample, AutoVibrato )>, LoadError>>()?.into_iter().unzip();
+    // the vibrato belongs to the samples, an instrument takes the one of the sample its middle C plays
+    let instruments = instruments.into_iter().map( | instrument | {
+        let sample = instrument.sample_for_key( 60 );
+        let vibrato = ( sample as usize ).checked_sub( 1 ).and_then( | index | vibratos.get( index ) ).copied().unwrap_or_default();
+        Instrument{ vibrato, ..instrument }
+    } ).collect();
+
+    // Channels with the top bit of their panning set are switched off
+    let channel_settings = &file_data[ 0x40..0x80 ];
+    let num_note_sources = if instrument_mode { num_instruments } else { num_samples };
+    let pattern_lines : Vec<Vec<Vec<( usize, Note )>>> = ( 0..num_patterns ).map( | index | read_pattern( file_data, pointer( num_instruments + num_samples + index ), num_note_sources ) ).collect();
+    let num_channels = pattern_lines.iter().flatten().flatten()
+        .filter( | ( channel, _ ) | channel_settings[ *channel ] & 0x80 == 0 )
+        .map( | ( channel, _ ) | channel + 1 ).max().unwrap_or( 1 );
+    let mut patterns : Vec<Pattern> = pattern_lines.iter().map( | lines | {
+        let mut pattern = Pattern::with_lines( num_channels as u32, lines.len() );
+        for ( line, notes ) in pattern.lines.iter_mut().zip( lines ) {
+            for ( channel, note ) in notes {
+                if *channel < num_channels && channel_settings[ *channel ] & 0x80 == 0 {
+                    line[ *channel ] = *note;
+                }
+            }
+        }
+        pattern
+    } ).collect();
+    for note in patterns.iter_mut().flat_map( | pattern | pattern.lines.iter_mut().flatten() ) {
+        if let Effect::PositionJump{ next_pattern } = &mut note.effect {
+            *next_pattern = order_positions.get( *next_pattern as usize ).copied().unwrap_or( pattern_table.len() ).min( 255 ) as u8;
+        }
+    }
+    let channel_panning = channel_settings[ ..num_channels ].iter().map( | setting | panning( setting & 0x7f ) ).collect();
+
+    Ok( Song {
+        name : decode_name( &file_data[ 4..30 ] ),
+        format : FormatDescription{ num_channels : num_channels as u32, num_samples : samples.len() as u32, has_tag : true, tag : Some( *b"IMPM" ), kind : ModuleKind::It },
+        samples,
+        patterns,
+        num_used_patterns : pattern_table.len() as u32,
+        pattern_table,
+        end_position : 0,
+        channel_panning,
+        global_volume : file_data[ 0x30 ].min( 128 ) / 2,
+        initial_speed : match file_data[ 0x32 ] { 0 => 6, speed => speed as u32 },
+        initial_bpm : match file_data[ 0x33 ] { 0..=31 => 125, bpm => bpm as u32 },
+        instruments,
+        linear_periods : flags & 8 != 0,
+    } )
+}
@@ -0,0 +1,51 @@
+//! Player for Amiga ProTracker modules, Scream Tracker 3 modules, FastTracker 2 modules and Impulse Tracker modules.
+//!
+//! Load a song with `read_mod_file`, `read_s3m_file`, `read_xm_file` or `read_it_file`,
+//! or `read_module_file` which goes by the contents ( see `guess_format` ), then either pull samples from a `Player` or use the optional
+//! `render` ( feature "hound" ) and `playback` ( feature "cpal" ) modules to write WAV files or play through the sound card.
+//! The `sink` module has the outputs behind both of them, plus raw PCM and null outputs that need no features.
+//! PowerPacker files and ProPacker and NoisePacker mods are unpacked when they are loaded ( see `unpack_module` ).
+//! `read_module_file` also opens zip, gzip and lha archives ( see `read_archive` ); zip and gzip need the "deflate" feature.
+//! `sample_wav` exports samples as WAV files with their loop points and turns WAV files back into samples.
+//! `midi` converts a song to a standard MIDI file with one track per channel.
+//! With the "serde" feature songs can be serialised; `SongInfo` is a smaller summary for catalogs.
+//! `TickTracker` and `HeardSampleClock` follow the position being heard, which `playback` uses and other outputs can too.
+//! `SongStream` turns a song into an iterator of stereo samples for other audio pipelines, and into a rodio source with the "rodio" feature.
+
+mod song;
+mod player;
+mod stream;
+mod builder;
+mod edit;
+mod info;
+mod xm;
+mod s3m;
+mod it;
+mod detect;
+mod depack;
+mod archive;
+mod timing;
+pub mod textout;
+pub mod sink;
+pub mod sample_wav;
+pub mod midi;
+#[cfg(feature = "hound")]
+pub mod render;
+#[cfg(feature = "cpal")]
+pub mod playback;
+
+pub use song::{Song, Sample, Pattern, Note, Effect, Key, ModuleKind, Instrument, Envelope, AutoVibrato, NewNoteAction, DuplicateCheck, FormatDescription, LoadError, read_mod_file, read_mod_data};
+pub use s3m::{read_s3m_file, read_s3m_data};
+pub use xm::{read_xm_file, read_xm_data};
+pub use it::{read_it_file, read_it_data};
+pub use detect::{FormatGuess, guess_format, detect_format, read_module_file, read_module_data, archived_modules, read_archived_module};
+pub use depack::{PackedFormat, packed_format, unpack_module, depack_powerpacker};
+pub use archive::{ArchiveKind, ArchiveEntry, archive_kind, read_archive};
+pub use player::{Player, PlayerOptions, PeriodLimits, PlayerCommand, PlayerState, PlayerStatus, StemMode, next_sample, next_sample_stems, song_duration};
+pub use stream::SongStream;
+pub use builder::{SongBuilder, BuildError};
+pub use edit::{EditCommand, EditHistory, EditError, Block, Clip, copy_block};
+pub use info::{SongInfo, SampleInfo};
+pub use timing::{TickTracker, TickStart, HeardSampleClock};
+#[cfg(feature = "rodio")]
+pub use stream::RodioSource;
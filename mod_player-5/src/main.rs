@@ -1,108 +1,502 @@
-extern crate cpal;
-use hound;
-
-use std::fs;
+use std::env;
+use std::path::Path;
+use std::process;
 use std::thread;
-use std::sync::mpsc;
 use std::sync;
+use std::collections::VecDeque;
+use std::time::Duration;
 
-mod mod_player;
+use mod_player::{PlayerCommand, PlayerOptions, PeriodLimits, StemMode};
+use mod_player::playback::{self, EventReceiver, PlayerEvent};
+use mod_player::sink::{AudioSink, PcmFormat, PcmSink};
+use mod_player::sample_wav::{SampleBits, SampleImportOptions};
 
-enum PlayerCommand{
-    PlayInstrument{ index : u8 }
-}
+const PREVIEW_PERIOD : u32 = 428;       // samples are previewed at this period ( 8363Hz ) unless a note is given
+
+const COMMAND_HELP : &str = "Commands:
+  <sample> [note]   play a sample on the preview voice, e.g. \"3 C-3\". Sample 0 stops the preview
+  pause | resume    pause or resume the song
+  stop | restart    stop and rewind, or play again from the start
+  next | prev       jump to the next or previous position in the pattern table
+  speed <n>         override the song speed in vblanks per line, 0 to use the song speed
+  bpm <n>           override the song tempo, 0 to use the song tempo
+  vol <percent>     set the master volume
+  m <channel>       toggle channel mute
+  s <channel>       toggle channel solo
+  quit              exit the player";
 
 /**
- * Write the mod file into a wav file 
+ * Parse a line typed by the user. Channels are numbered from 1 for the user and from 0 in the commands
  */
-fn write_song_to_wav( file_name : &str, song : &mod_player::Song ) {
-    let spec = hound::WavSpec {
-        channels: 2,
-        sample_rate: 48100,
-        bits_per_sample: 32,
-        sample_format: hound::SampleFormat::Float,
+fn parse_command( line : &str ) -> Result<PlayerCommand, String> {
+    let mut parts = line.split_whitespace();
+    let name = parts.next().unwrap_or( "" );
+    let argument = parts.next();
+    let number_argument = || -> Result<u32, String> {
+        match argument {
+            Some( value ) => value.parse::<u32>().map_err( | _ | format!( "Expected a number, got {}", value ) ),
+            None => Err( format!( "{} needs an argument", name ) )
+        }
+    };
+    let channel_argument = || -> Result<usize, String> {
+        match number_argument()? {
+            0 => Err( String::from( "Channels are numbered from 1" ) ),
+            channel => Ok( ( channel - 1 ) as usize )
+        }
     };
 
-    let mut writer = hound::WavWriter::create( file_name, spec).unwrap();
-    let mut player_state : mod_player::PlayerState = mod_player::PlayerState::new( song.format.num_channels, spec.sample_rate );
-    loop {
-        let ( left, right ) = mod_player::next_sample(&song, &mut player_state);
-        writer.write_sample( left  );
-        writer.write_sample( right  );
-        if player_state.song_has_ended || player_state.has_looped { 
-            break;
+    match name {
+        "pause" => Ok( PlayerCommand::Pause ),
+        "resume" | "play" => Ok( PlayerCommand::Resume ),
+        "stop" => Ok( PlayerCommand::Stop ),
+        "restart" => Ok( PlayerCommand::Restart ),
+        "next" => Ok( PlayerCommand::NextPosition ),
+        "prev" => Ok( PlayerCommand::PreviousPosition ),
+        "speed" => number_argument().map( | speed | PlayerCommand::SetSpeed{ speed : if speed == 0 { None } else { Some( speed ) } } ),
+        "bpm" => number_argument().map( | bpm | PlayerCommand::SetBpm{ bpm : if bpm == 0 { None } else { Some( bpm ) } } ),
+        "vol" => number_argument().map( | volume | PlayerCommand::SetVolume{ volume : volume as f32 / 100.0 } ),
+        "m" => channel_argument().map( | channel | PlayerCommand::ToggleMute{ channel } ),
+        "s" => channel_argument().map( | channel | PlayerCommand::ToggleSolo{ channel } ),
+        _ => {
+            let index = name.parse::<u8>().map_err( | _ | format!( "Unknown command {}", name ) )?;
+            let period = match argument {
+                Some( note_name ) => mod_player::textout::note_period( note_name ).ok_or( format!( "Unknown note {}", note_name ) )?,
+                None => PREVIEW_PERIOD
+            };
+            Ok( PlayerCommand::PlayInstrument{ index, period } )
         }
     }
 }
 
-fn setup_stream( song : sync::Arc<mod_player::Song> ) -> mpsc::Sender<PlayerCommand> {
-    let device = cpal::default_output_device().expect("Failed to get default output device");
-    println!("Sound device: {}", device.name());
+fn channel_list( channels : u64, num_channels : usize ) -> String {
+    let list : Vec<String> = ( 0..num_channels.min( 64 ) ).filter( | channel | channels & ( 1 << channel ) != 0 ).map( | channel | ( channel + 1 ).to_string() ).collect();
+    if list.is_empty() { String::from( "-" ) } else { list.join( "," ) }
+}
 
-    let format  = device.default_output_format().expect("Failed to get default output format");
-    let fmt = match format.data_type {
-        cpal::SampleFormat::I16 => "i16",
-        cpal::SampleFormat::U16 => "u16",
-        cpal::SampleFormat::F32 => "f32"
-    };
+fn print_status( status : &mod_player::PlayerStatus, song : &mod_player::Song ) {
+    let num_channels = song.num_channels() as usize;
+    println!( "{} | position {:02}/{:02} line {:02} | speed {} bpm {} | volume {:.0}% | muted {} | solo {}",
+        if status.paused { "paused" } else { "playing" }, status.song_pattern_position, song.song_length(), status.line,
+        status.speed, status.bpm, status.master_volume * 100.0, channel_list( status.muted_channels, num_channels ), channel_list( status.solo_channels, num_channels ) );
+}
 
-    println!("Sample rate: {}    Sample format: {}       Channels: {}", format.sample_rate.0, fmt, format.channels);
-    
-    let event_loop = cpal::EventLoop::new();
-    let stream_id = event_loop.build_output_stream(&device, &format).unwrap();
-    event_loop.play_stream(stream_id.clone());
-
-    let mut instrument_number = 0;
-    let mut instrument_pos = 0;
-    let mut player_state : mod_player::PlayerState = mod_player::PlayerState::new( song.format.num_channels, format.sample_rate.0);
-    let mut last_line_pos = 999;
-
-    let (tx,rx) = mpsc::channel();
-    thread::spawn( move || {
-        event_loop.run(move |_, data| {
-            let message = rx.try_recv();
-            if message.is_ok() {
-                match message.unwrap() {
-                    PlayerCommand::PlayInstrument{ index } => { 
-                        instrument_number = index;
-                        instrument_pos = 0;
-                        println!( "Playing instrument {}", index );   //Set up instrument playing here
-                    }
-                };
+/**
+ * Prints the pattern lines when they are heard rather than when they are rendered, and the replies to commands
+ */
+fn run_ui( song : sync::Arc<mod_player::Song>, mut events : EventReceiver ) {
+    let mut pending_ticks : VecDeque<PlayerEvent> = VecDeque::new();
+    let mut last_row = None;
+    loop {
+        while let Some( event ) = events.next_event() {
+            match event {
+                PlayerEvent::Tick{ .. } => pending_ticks.push_back( event ),
+                PlayerEvent::InstrumentSelected{ index, period } => println!( "Playing instrument {} at period {}", index, period ),
+                PlayerEvent::Status{ status } => print_status( &status, &song ),
             }
-            if player_state.current_line != last_line_pos {
-                if player_state.current_line == 0 {
-                    println!("");
-                }
-                mod_player::textout::print_line( player_state.get_song_line(&song));
-                last_line_pos = player_state.current_line;
+        }
+
+        let heard_sample = events.heard_sample();
+        while let Some( PlayerEvent::Tick{ sample_index, song_pattern_position, line, tick } ) = pending_ticks.front() {
+            if *sample_index > heard_sample {
+                break;
             }
-            match data {
-                cpal::StreamData::Output { buffer: cpal::UnknownTypeOutputBuffer::F32(mut buffer) } => {
-                    for sample in buffer.chunks_mut(format.channels as usize) {
-                        let ( left, right ) = mod_player::next_sample(&song, &mut player_state);
-                        sample[0] = left;
-                        sample[1] = right;
-                    }
+            // A line is printed on its first tick, or on a later one if the first tick was dropped
+            let row = ( *song_pattern_position, *line );
+            if *tick == 0 || last_row != Some( row ) {
+                if row.1 == 0 {
+                    println!();
                 }
-                _ => (),
+                mod_player::textout::print_line( song.get_line( row.0, row.1 ) );
+                last_row = Some( row );
             }
-        });
-    });
-    tx
+            pending_ticks.pop_front();
+        }
+        thread::sleep( Duration::from_millis( 2 ) );
+    }
 }
 
-fn main() {
-    let song = sync::Arc::new( mod_player::read_mod_file("stardstm.MOD") );
-//    write_song_to_wav( "test.wav", &song );           // use this to write the file to WAV 
-    let tx = setup_stream(song.clone());
+const USAGE : &str = "Usage:
+  mod_player-5 play <file> [--periods amiga|extended|unlimited]
+  mod_player-5 render <file> -o <out.wav> [--rate <hz>] [--stems channel|sample] [--periods amiga|extended|unlimited] [--mute <channels>] [--solo <channels>]
+  mod_player-5 render <file> -o - [--pcm s16|f32] [--rate <hz>] [--periods amiga|extended|unlimited] [--mute <channels>] [--solo <channels>]
+  mod_player-5 info <file> [--json]
+  mod_player-5 patterns <file>
+  mod_player-5 dump <file>
+  mod_player-5 import <file.txt> -o <out.mod>
+  mod_player-5 samples <file> -o <dir> [--16bit]
+  mod_player-5 load-sample <file> <number|new> <sample.wav> -o <out.mod> [--note <note>|--rate <hz>] [--dither]
+  mod_player-5 midi <file> -o <out.mid> [--programs <map.txt>]
+  mod_player-5 xm <file> -o <out.xm>
+  mod_player-5 list <archive>
+
+Channels are numbered from 1 and separated by commas, for example --mute 1,3
+With --stems the output name is used as the base name for one file per channel or sample
+Mods, Scream Tracker 3, FastTracker 2 and Impulse Tracker modules are recognised by their contents, whatever their name
+Zip, gzip and lha archives can be played directly: the first module is used, or name one as <archive>:<module>
+dump prints the whole song as text that import turns back into a module
+samples writes each sample as an 8 bit wav file at the C-3 rate with its loop, or 16 bit with --16bit
+load-sample resamples the wav file so it plays at its own pitch on the given note, C-3 by default
+The midi program map has a sample number and a program on each line, programs are numbered 1 - 128
+--periods sets how far slides and vibrato take the notes of a mod: the 3 octaves of ProTracker ( the default ), 5 octaves or no limit
+With -o - raw stereo pcm is written to stdout, for example: render song.mod -o - | aplay -f S16_LE -c 2 -r 48000";
+
+enum CliError{
+    Usage( String ),            // bad command line, exit code 2
+    Failed( String ),           // the command itself failed, exit code 1
+}
+
+/**
+ * A module in an archive is named as the archive and the entry with a colon in between, e.g. songs.zip:intro.mod.
+ * Names that are files of their own are never split
+ */
+fn split_archive_entry( file_name : &str ) -> ( &str, Option<&str> ) {
+    if Path::new( file_name ).exists() {
+        return ( file_name, None );
+    }
+    file_name.match_indices( ':' ).map( | ( index, _ ) | index ).find( | index | Path::new( &file_name[ ..*index ] ).is_file() )
+        .map_or( ( file_name, None ), | index | ( &file_name[ ..index ], Some( &file_name[ index + 1.. ] ) ) )
+}
+
+/**
+ * Load a module of any of the formats the player knows, going by its contents
+ */
+fn load_song( file_name : &str ) -> Result<mod_player::Song, CliError> {
+    let result = match split_archive_entry( file_name ) {
+        ( archive_name, Some( entry_name ) ) => std::fs::read( archive_name ).map_err( mod_player::LoadError::from )
+            .and_then( | archive_data | mod_player::read_archived_module( &archive_data, Some( entry_name ) ) ),
+        ( file_name, None ) => mod_player::read_module_file( file_name )
+    };
+    result.map_err( | error | CliError::Failed( format!( "Can't load {}: {}", file_name, error ) ) )
+}
+
+/**
+ * List the modules in an archive with their format and size
+ */
+fn list( file_name : &str ) -> Result<(), CliError> {
+    let modules = std::fs::read( file_name ).map_err( mod_player::LoadError::from ).and_then( | archive_data | mod_player::archived_modules( &archive_data ) )
+        .map_err( | error | CliError::Failed( format!( "Can't read {}: {}", file_name, error ) ) )?;
+    for entry in modules {
+        let kind = mod_player::detect_format( &entry.data ).map_or( String::from( "packed" ), | kind | format!( "{:?}", kind ) );
+        println!( "{:<40} {:<6} {:>8}", entry.name, kind, entry.data.len() );
+    }
+    Ok( () )
+}
+
+/**
+ * Parse a list of channels such as "1,3" into zero based channel numbers
+ */
+fn parse_channel_list( list : &str ) -> Result<Vec<usize>, CliError> {
+    list.split( ',' ).map( | channel | {
+        match channel.trim().parse::<usize>() {
+            Ok( channel ) if channel > 0 => Ok( channel - 1 ),
+            _ => Err( CliError::Usage( format!( "Invalid channel {}", channel ) ) )
+        }
+    } ).collect()
+}
+
+/**
+ * Parse the name of the period limits given with --periods
+ */
+fn parse_period_limits( name : &str ) -> Result<PeriodLimits, CliError> {
+    match name {
+        "amiga" => Ok( PeriodLimits::Amiga ),
+        "extended" => Ok( PeriodLimits::Extended ),
+        "unlimited" => Ok( PeriodLimits::Unlimited ),
+        _ => Err( CliError::Usage( format!( "Unknown period limits {}", name ) ) )
+    }
+}
+
+fn play( file_name : &str, args : &[String] ) -> Result<(), CliError> {
+    let options = match args {
+        [] => PlayerOptions::default(),
+        [ option, value ] if option == "--periods" => PlayerOptions{ period_limits : parse_period_limits( value )?, ..PlayerOptions::default() },
+        _ => return Err( CliError::Usage( format!( "Unknown play options {}", args.join( " " ) ) ) )
+    };
+    let song = sync::Arc::new( load_song( file_name )? );
+    let playback = playback::start_playback( song.clone(), &options ).map_err( | error | CliError::Failed( error.to_string() ) )?;
+    println!("Sound device: {}", playback.device_name);
+    println!("Sample rate: {}    Sample format: {}       Channels: {}", playback.sample_rate, playback.sample_format, playback.channels);
+    let mut commands = playback.commands;
+    let events = playback.events;
+    thread::spawn( move || run_ui( song, events ) );
+    println!( "Type help for a list of commands" );
     loop{
         let mut command = String::new();
-        std::io::stdin().read_line(& mut command);
+        match std::io::stdin().read_line(& mut command) {
+            Ok( 0 ) => return Ok( () ),        // end of input
+            Ok( _ ) => (),
+            Err( error ) => return Err( CliError::Failed( format!( "Can't read input: {}", error ) ) )
+        }
         command = command.trim_end().to_string();
-        let res  = command.parse::<u8>();
-        if res.is_ok() {
-            tx.send(PlayerCommand::PlayInstrument{ index : res.unwrap() } );
+        if command.is_empty() {
+            continue;
+        }
+        if command == "quit" {
+            return Ok( () );
+        }
+        if command == "help" {
+            println!( "{}", COMMAND_HELP );
+            continue;
+        }
+        match parse_command( &command ) {
+            Ok( player_command ) => if !commands.send( player_command ) {
+                println!( "The player is busy, try again" );
+            }
+            Err( message ) => println!( "{}", message )
+        }
+    }
+}
+
+fn render( file_name : &str, args : &[String] ) -> Result<(), CliError> {
+    let mut output = None;
+    let mut stem_mode = None;
+    let mut pcm_format = PcmFormat::S16;
+    let mut options = PlayerOptions::default();
+    let mut remaining = args.iter();
+    while let Some( arg ) = remaining.next() {
+        let value = remaining.next().ok_or_else( || CliError::Usage( format!( "{} needs a value", arg ) ) )?;
+        match arg.as_str() {
+            "-o" => output = Some( value.clone() ),
+            "--rate" => {
+                options.sample_rate = match value.parse::<u32>() {
+                    Ok( rate ) if rate > 0 => rate,
+                    _ => return Err( CliError::Usage( format!( "Invalid sample rate {}", value ) ) )
+                };
+            }
+            "--stems" => {
+                stem_mode = match value.as_str() {
+                    "channel" => Some( StemMode::Channel ),
+                    "sample" => Some( StemMode::Sample ),
+                    _ => return Err( CliError::Usage( format!( "Unknown stem mode {}", value ) ) )
+                };
+            }
+            "--pcm" => {
+                pcm_format = match value.as_str() {
+                    "s16" => PcmFormat::S16,
+                    "f32" => PcmFormat::F32,
+                    _ => return Err( CliError::Usage( format!( "Unknown pcm format {}", value ) ) )
+                };
+            }
+            "--periods" => options.period_limits = parse_period_limits( value )?,
+            "--mute" => options.muted_channels = parse_channel_list( value )?,
+            "--solo" => options.solo_channels = parse_channel_list( value )?,
+            _ => return Err( CliError::Usage( format!( "Unknown option {}", arg ) ) )
+        }
+    }
+    let output = output.ok_or_else( || CliError::Usage( String::from( "render needs an output file ( -o <out.wav> )" ) ) )?;
+
+    let song = load_song( file_name )?;
+    let num_channels = song.num_channels() as usize;
+    if let Some( channel ) = options.muted_channels.iter().chain( options.solo_channels.iter() ).find( | channel | **channel >= num_channels ) {
+        return Err( CliError::Usage( format!( "Channel {} does not exist, the song has {} channels", channel + 1, num_channels ) ) );
+    }
+
+    let mut player = mod_player::Player::new( sync::Arc::new( song ), &options );
+    let result = match stem_mode {
+        Some( _ ) if output == "-" => return Err( CliError::Usage( String::from( "Stems can't be written to stdout" ) ) ),
+        Some( mode ) => {
+            let base_name = output.strip_suffix( ".wav" ).unwrap_or( &output );
+            mod_player::render::write_song_stems_to_wav( base_name, &mut player, mode )
+        }
+        None if output == "-" => PcmSink::stdout( options.sample_rate, pcm_format ).play( Box::new( player ) ),
+        None => mod_player::render::write_song_to_wav( &output, player )
+    };
+    result.map_err( | error | CliError::Failed( format!( "Can't write {}: {}", output, error ) ) )
+}
+
+fn info( file_name : &str, args : &[String] ) -> Result<(), CliError> {
+    let song = load_song( file_name )?;
+    match args {
+        [] => {
+            mod_player::textout::print_song_info( &song );
+            Ok( () )
+        }
+        [ option ] if option == "--json" => print_json_info( &song ),
+        _ => Err( CliError::Usage( format!( "Unknown info options {}", args.join( " " ) ) ) )
+    }
+}
+
+#[cfg(feature = "serde")]
+fn print_json_info( song : &mod_player::Song ) -> Result<(), CliError> {
+    let info = mod_player::SongInfo::new( song );
+    let json = serde_json::to_string_pretty( &info ).map_err( | error | CliError::Failed( error.to_string() ) )?;
+    println!( "{}", json );
+    Ok( () )
+}
+
+#[cfg(not(feature = "serde"))]
+fn print_json_info( _song : &mod_player::Song ) -> Result<(), CliError> {
+    Err( CliError::Failed( String::from( "info --json needs the player to be built with the serde feature" ) ) )
+}
+
+/**
+ * Write the samples of a song into a directory as wav files
+ */
+fn samples( file_name : &str, args : &[String] ) -> Result<(), CliError> {
+    let mut output = None;
+    let mut bits = SampleBits::Eight;
+    let mut remaining = args.iter();
+    while let Some( arg ) = remaining.next() {
+        match arg.as_str() {
+            "-o" => output = Some( remaining.next().ok_or_else( || CliError::Usage( String::from( "-o needs a value" ) ) )? ),
+            "--16bit" => bits = SampleBits::Sixteen,
+            _ => return Err( CliError::Usage( format!( "Unknown option {}", arg ) ) )
+        }
+    }
+    let output = output.ok_or_else( || CliError::Usage( String::from( "samples needs an output directory ( -o <dir> )" ) ) )?;
+    let song = load_song( file_name )?;
+    let files = mod_player::sample_wav::export_samples( &song, std::path::Path::new( output ), bits )
+        .map_err( | error | CliError::Failed( format!( "Can't write samples to {}: {}", output, error ) ) )?;
+    for file in files {
+        println!( "{}", file.display() );
+    }
+    Ok( () )
+}
+
+/**
+ * Replace a sample, or add one with "new", from a wav file and write the changed song as a new module
+ */
+fn load_sample( file_name : &str, args : &[String] ) -> Result<(), CliError> {
+    let ( slot, wav_name, args ) = match args {
+        [ slot, wav_name, rest @ .. ] => ( slot, wav_name, rest ),
+        _ => return Err( CliError::Usage( String::from( "load-sample needs a sample number and a wav file" ) ) )
+    };
+    let mut output = None;
+    let mut options = SampleImportOptions::default();
+    let mut remaining = args.iter();
+    while let Some( arg ) = remaining.next() {
+        if arg == "--dither" {
+            options.dither = true;
+            continue;
+        }
+        let value = remaining.next().ok_or_else( || CliError::Usage( format!( "{} needs a value", arg ) ) )?;
+        match arg.as_str() {
+            "-o" => output = Some( value ),
+            "--note" => {
+                options.sample_rate = mod_player::sample_wav::note_sample_rate( value ).ok_or_else( || CliError::Usage( format!( "Unknown note {}", value ) ) )?;
+            }
+            "--rate" => {
+                options.sample_rate = match value.parse::<u32>() {
+                    Ok( rate ) if rate > 0 => rate,
+                    _ => return Err( CliError::Usage( format!( "Invalid sample rate {}", value ) ) )
+                };
+            }
+            _ => return Err( CliError::Usage( format!( "Unknown option {}", arg ) ) )
+        }
+    }
+    let output = output.ok_or_else( || CliError::Usage( String::from( "load-sample needs an output file ( -o <out.mod> )" ) ) )?;
+
+    let mut song = load_song( file_name )?;
+    let sample_name = std::path::Path::new( wav_name ).file_stem().map( | stem | stem.to_string_lossy().chars().take( 22 ).collect::<String>() ).unwrap_or_default();
+    let sample = mod_player::sample_wav::read_sample_wav_file( wav_name, &sample_name, &options )
+        .map_err( | error | CliError::Failed( format!( "Can't load {}: {}", wav_name, error ) ) )?;
+    let sample_number = if slot == "new" {
+        song.add_sample( sample ).map_err( | _ | CliError::Failed( String::from( "The song has no free sample slot" ) ) )?
+    } else {
+        let sample_number = slot.parse::<u8>().map_err( | _ | CliError::Usage( format!( "Invalid sample number {}", slot ) ) )?;
+        song.replace_sample( sample_number, sample ).map_err( | _ | CliError::Usage( format!( "The song has no sample {}", sample_number ) ) )?;
+        sample_number
+    };
+    let mut data = Vec::new();
+    song.write_mod( &mut data ).and_then( | _ | std::fs::write( output, &data ) )
+        .map_err( | error | CliError::Failed( format!( "Can't write {}: {}", output, error ) ) )?;
+    println!( "Sample {} is {} bytes", sample_number, song.samples()[ sample_number as usize - 1 ].size() );
+    Ok( () )
+}
+
+/**
+ * Convert the song to a standard midi file, optionally with a file that maps samples to programs
+ */
+fn midi( file_name : &str, args : &[String] ) -> Result<(), CliError> {
+    let mut output = None;
+    let mut options = mod_player::midi::MidiOptions::default();
+    let mut remaining = args.iter();
+    while let Some( arg ) = remaining.next() {
+        let value = remaining.next().ok_or_else( || CliError::Usage( format!( "{} needs a value", arg ) ) )?;
+        match arg.as_str() {
+            "-o" => output = Some( value ),
+            "--programs" => {
+                let text = std::fs::read_to_string( value ).map_err( | error | CliError::Failed( format!( "Can't read {}: {}", value, error ) ) )?;
+                options.programs = mod_player::midi::parse_program_map( &text ).map_err( | error | CliError::Failed( format!( "Can't read {}: {}", value, error ) ) )?;
+            }
+            _ => return Err( CliError::Usage( format!( "Unknown option {}", arg ) ) )
+        }
+    }
+    let output = output.ok_or_else( || CliError::Usage( String::from( "midi needs an output file ( -o <out.mid> )" ) ) )?;
+    let song = load_song( file_name )?;
+    let mut data = Vec::new();
+    mod_player::midi::write_midi( &song, &options, &mut data ).and_then( | _ | std::fs::write( output, &data ) )
+        .map_err( | error | CliError::Failed( format!( "Can't write {}: {}", output, error ) ) )
+}
+
+/**
+ * Convert the song to a FastTracker 2 module
+ */
+fn xm( file_name : &str, args : &[String] ) -> Result<(), CliError> {
+    let output = match args {
+        [ option, output ] if option == "-o" => output,
+        _ => return Err( CliError::Usage( String::from( "xm needs an output file ( -o <out.xm> )" ) ) )
+    };
+    let song = load_song( file_name )?;
+    let mut data = Vec::new();
+    song.write_xm( &mut data ).and_then( | _ | std::fs::write( output, &data ) )
+        .map_err( | error | CliError::Failed( format!( "Can't write {}: {}", output, error ) ) )
+}
+
+/**
+ * Turn a text dump back into a module
+ */
+fn import( file_name : &str, args : &[String] ) -> Result<(), CliError> {
+    let output = match args {
+        [ option, output ] if option == "-o" => output,
+        _ => return Err( CliError::Usage( String::from( "import needs an output file ( -o <out.mod> )" ) ) )
+    };
+    let text = std::fs::read_to_string( file_name ).map_err( | error | CliError::Failed( format!( "Can't read {}: {}", file_name, error ) ) )?;
+    let song = mod_player::Song::from_text( &text ).map_err( | error | CliError::Failed( format!( "Can't import {}: {}", file_name, error ) ) )?;
+    let mut data = Vec::new();
+    song.write_mod( &mut data ).and_then( | _ | std::fs::write( output, &data ) )
+        .map_err( | error | CliError::Failed( format!( "Can't write {}: {}", output, error ) ) )
+}
+
+fn run( args : &[String] ) -> Result<(), CliError> {
+    let ( command, file_name ) = match args {
+        [ command, file_name, .. ] => ( command.as_str(), file_name.as_str() ),
+        _ => return Err( CliError::Usage( String::from( "Missing command or file" ) ) )
+    };
+    match command {
+        "play" => play( file_name, &args[ 2.. ] ),
+        "render" => render( file_name, &args[ 2.. ] ),
+        "info" => info( file_name, &args[ 2.. ] ),
+        "patterns" => {
+            mod_player::textout::print_patterns( &load_song( file_name )? );
+            Ok( () )
+        }
+        "dump" => {
+            let text = load_song( file_name )?.to_text().map_err( | error | CliError::Failed( format!( "Can't dump {}: {}", file_name, error ) ) )?;
+            print!( "{}", text );
+            Ok( () )
+        }
+        "import" => import( file_name, &args[ 2.. ] ),
+        "samples" => samples( file_name, &args[ 2.. ] ),
+        "load-sample" => load_sample( file_name, &args[ 2.. ] ),
+        "midi" => midi( file_name, &args[ 2.. ] ),
+        "xm" => xm( file_name, &args[ 2.. ] ),
+        "list" => list( file_name ),
+        _ => Err( CliError::Usage( format!( "Unknown command {}", command ) ) )
+    }
+}
+
+fn main() {
+    let args : Vec<String> = env::args().skip( 1 ).collect();
+    match run( &args ) {
+        Ok( () ) => (),
+        Err( CliError::Usage( message ) ) => {
+            eprintln!( "{}\n\n{}", message, USAGE );
+            process::exit( 2 );
+        }
+        Err( CliError::Failed( message ) ) => {
+            eprintln!( "{}", message );
+            process::exit( 1 );
         }
     }
 }
@@ -0,0 +1,286 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use crate::player::{PlayerState, PlayerOptions, next_vblank, CLOCK_TICKS_PERS_SECOND};
+use crate::song::{Song, Effect, Key, FREQUENCY_TABLE};
+use crate::textout::TextError;
+
+const TICKS_PER_QUARTER : u64 = 960;
+const LINES_PER_QUARTER : u64 = 4;             // a line is a sixteenth note, so the tempo follows both speed and bpm
+const TICKS_PER_LINE : u64 = TICKS_PER_QUARTER / LINES_PER_QUARTER;
+const MIDI_NOTE_B6 : i32 = 107;                 // the first entry of the frequency table, C-3 is middle C
+const C3_PERIOD : f64 = 856.0;
+const PITCH_BEND_RANGE : f64 = 12.0;            // semitones either way, set with RPN 0 at the start of every track
+const MAX_LINES : u32 = 128 * 64 * 16;          // every line of every position, in case a jump keeps the song from ending
+const MIDI_CHANNELS : [ u8; 15 ] = [ 0, 1, 2, 3, 4, 5, 6, 7, 8, 10, 11, 12, 13, 14, 15 ];     // channel 10 is for drums
+
+/**
+ * Settings for the midi export
+ */
+#[derive(Clone, Debug, Default)]
+pub struct MidiOptions {
+    pub programs : BTreeMap<u8, u8>,        // sample number to program 0 - 127. Samples that are not listed use their number - 1
+}
+
+/**
+ * Read a program mapping: one "<sample> <program>" pair per line with programs numbered 1 - 128 as General MIDI
+ * lists them. Everything after a # is a comment
+ */
+pub fn parse_program_map( text : &str ) -> Result<BTreeMap<u8, u8>, TextError> {
+    let mut programs = BTreeMap::new();
+    for ( index, line ) in text.lines().enumerate() {
+        let line = line.split( '#' ).next().unwrap_or( "" ).trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = | message : String | TextError{ line : index + 1, message };
+        let fields : Vec<&str> = line.split_whitespace().collect();
+        let ( sample, program ) = match fields.as_slice() {
+            [ sample, program ] => ( sample.parse::<u8>().ok(), program.parse::<u8>().ok() ),
+            _ => return Err( error( format!( "Expected a sample and a program, found \"{}\"", line ) ) )
+        };
+        match ( sample, program ) {
+            ( Some( sample @ 1..=31 ), Some( program @ 1..=128 ) ) => { programs.insert( sample, program - 1 ); }
+            _ => return Err( error( format!( "Samples are numbered 1 - 31 and programs 1 - 128, found \"{}\"", line ) ) )
+        }
+    }
+    Ok( programs )
+}
+
+/**
+ * The midi note for a period. Periods in the frequency table map straight to a note, others to the nearest one
+ */
+fn period_note( period : u32 ) -> i32 {
+    match FREQUENCY_TABLE.binary_search( &period ) {
+        Ok( index ) => MIDI_NOTE_B6 - index as i32,
+        Err( _ ) => rate_note( CLOCK_TICKS_PERS_SECOND as f64 / period as f64 )
+    }
+}
+
+/**
+ * The nearest midi note for a playback rate in Hz, for formats whose periods depend on the sample
+ */
+fn rate_note( rate : f64 ) -> i32 {
+    60 + ( 12.0 * ( rate / c3_rate() ).log2() ).round() as i32
+}
+
+fn c3_rate() -> f64 {
+    CLOCK_TICKS_PERS_SECOND as f64 / C3_PERIOD
+}
+
+fn note_rate( note : i32 ) -> f64 {
+    c3_rate() * 2.0f64.powf( ( note - 60 ) as f64 / 12.0 )
+}
+
+fn velocity( volume : f32 ) -> u8 {
+    ( ( volume * 127.0 / 64.0 ).round() as u8 ).clamp( 1, 127 )
+}
+
+/**
+ * Microseconds per quarter note. A vblank lasts 2.5 / bpm seconds
+ */
+fn tempo( speed : u32, bpm : u32 ) -> u32 {
+    ( LINES_PER_QUARTER as f64 * speed.max( 1 ) as f64 * 2_500_000.0 / bpm.max( 1 ) as f64 ).round() as u32
+}
+
+struct Track {
+    events : Vec<( u64, Vec<u8> )>,     // in time order
+}
+
+impl Track {
+    fn new( name : &str ) -> Track {
+        let mut track = Track{ events : Vec::new() };
+        track.meta( 0, 0x03, name.as_bytes() );
+        track
+    }
+
+    fn push( &mut self, tick : u64, event : &[u8] ) {
+        self.events.push( ( tick, event.to_vec() ) );
+    }
+
+    fn meta( &mut self, tick : u64, kind : u8, data : &[u8] ) {
+        let mut event = vec![ 0xff, kind ];
+        write_variable_length( &mut event, data.len() as u64 );
+        event.extend_from_slice( data );
+        self.events.push( ( tick, event ) );
+    }
+
+    fn write<W : Write>( &self, end_tick : u64, writer : &mut W ) -> io::Result<()> {
+        let mut data = Vec::new();
+        let mut last_tick = 0;
+        for ( tick, event ) in &self.events {
+            write_variable_length( &mut data, tick - last_tick );
+            data.extend_from_slice( event );
+            last_tick = *tick;
+        }
+        write_variable_length( &mut data, end_tick - last_tick );
+        data.extend_from_slice( &[ 0xff, 0x2f, 0x00 ] );
+        writer.write_all( b"MTrk" )?;
+        writer.write_all( &( data.len() as u32 ).to_be_bytes() )?;
+        writer.write_all( &data )
+    }
+}
+
+fn write_variable_length( data : &mut Vec<u8>, value : u64 ) {
+    let mut bytes = vec![ ( value & 0x7f ) as u8 ];
+    let mut value = value >> 7;
+    while value > 0 {
+        bytes.push( ( value & 0x7f ) as u8 | 0x80 );
+        value >>= 7;
+    }
+    data.extend( bytes.iter().rev() );
+}
+
+/**
+ * The midi state of one tracker channel
+ */
+struct ChannelTrack {
+    track : Track,
+    midi_channel : u8,
+    note : Option<i32>,         // the note that is sounding
+    start_volume : f32,         // volume when the note started, later volume changes are relative to it
+    program : Option<u8>,
+    bend : u16,
+    volume_control : u8,
+}
+
+impl ChannelTrack {
+    fn new( channel : usize ) -> ChannelTrack {
+        let midi_channel = MIDI_CHANNELS[ channel % MIDI_CHANNELS.len() ];
+        let mut track = Track::new( &format!( "Channel {}", channel + 1 ) );
+        // pitch bend range through RPN 0, then the null RPN so later data entry does nothing
+        for ( control, value ) in [ ( 101, 0 ), ( 100, 0 ), ( 6, PITCH_BEND_RANGE as u8 ), ( 38, 0 ), ( 101, 127 ), ( 100, 127 ), ( 7, 127 ) ] {
+            track.push( 0, &[ 0xb0 | midi_channel, control, value ] );
+        }
+        ChannelTrack{ track, midi_channel, note : None, start_volume : 0.0, program : None, bend : 0x2000, volume_control : 127 }
+    }
+
+    fn stop_note( &mut self, tick : u64 ) {
+        if let Some( note ) = self.note.take() {
+            self.track.push( tick, &[ 0x80 | self.midi_channel, note as u8, 0 ] );
+        }
+    }
+
+    fn start_note( &mut self, tick : u64, note : i32, program : u8, volume : f32 ) {
+        self.stop_note( tick );
+        if self.program != Some( program ) {
+            self.track.push( tick, &[ 0xc0 | self.midi_channel, program ] );
+            self.program = Some( program );
+        }
+        self.set_volume_control( tick, 127 );
+        self.set_bend( tick, 0x2000 );
+        let note = note.clamp( 0, 127 );
+        self.track.push( tick, &[ 0x90 | self.midi_channel, note as u8, velocity( volume ) ] );
+        self.note = Some( note );
+        self.start_volume = volume;
+    }
+
+    fn set_volume_control( &mut self, tick : u64, value : u8 ) {
+        if value != self.volume_control {
+            self.track.push( tick, &[ 0xb0 | self.midi_channel, 7, value ] );
+            self.volume_control = value;
+        }
+    }
+
+    fn set_bend( &mut self, tick : u64, bend : u16 ) {
+        if bend != self.bend {
+            self.track.push( tick, &[ 0xe0 | self.midi_channel, ( bend & 0x7f ) as u8, ( bend >> 7 ) as u8 ] );
+            self.bend = bend;
+        }
+    }
+
+    /**
+     * Follow slides, vibrato and arpeggios with pitch bend and volume changes with CC7 while a note sounds
+     */
+    fn follow( &mut self, tick : u64, rate : f32, volume : f32 ) {
+        let note = match self.note {
+            Some( note ) => note,
+            None => return
+        };
+        if rate > 0.0 {
+            let semitones = 12.0 * ( rate as f64 / note_rate( note ) ).log2();
+            let bend = ( 8192.0 + semitones / PITCH_BEND_RANGE * 8192.0 ).round().clamp( 0.0, 16383.0 ) as u16;
+            self.set_bend( tick, bend );
+        }
+        let value = ( 127.0 * volume / self.start_volume.max( 1.0 ) ).round().clamp( 0.0, 127.0 ) as u8;
+        self.set_volume_control( tick, value );
+    }
+}
+
+/**
+ * Write the song as a type 1 standard midi file. The first track has the tempo and every tracker channel gets a
+ * track of its own. The song is timed the way the player plays it: a line is a sixteenth note, so speed and bpm
+ * changes both become tempo changes, and effects are followed on every vblank
+ */
+pub fn write_midi<W : Write>( song : &Song, options : &MidiOptions, writer : &mut W ) -> io::Result<()> {
+    let num_channels = song.num_channels() as usize;
+    let mut state = PlayerState::with_options( song, &PlayerOptions::default() );
+    let mut tempo_track = Track::new( song.name().trim_end_matches( '\0' ).trim_end() );
+    let mut channels : Vec<ChannelTrack> = ( 0..num_channels ).map( ChannelTrack::new ).collect();
+
+    let mut line_tick = 0;
+    let mut vblank = 0;
+    let mut line_speed = 1;
+    let mut lines = 0;
+    let mut tempo_played = None;
+    let mut ending = false;
+    let end_tick = loop {
+        let new_line = next_vblank( song, &mut state );
+        if new_line {
+            // a pattern delay makes a line last more than speed vblanks
+            if lines > 0 {
+                line_tick += TICKS_PER_LINE * ( vblank + 1 ) / line_speed;
+            }
+            if ending || lines >= MAX_LINES {
+                break line_tick;
+            }
+            lines += 1;
+            vblank = 0;
+            line_speed = state.speed().max( 1 ) as u64;
+        } else if lines == 0 {
+            continue;               // the player waits a line before the first one
+        } else {
+            vblank += 1;
+        }
+        let tick = line_tick + TICKS_PER_LINE * vblank / line_speed;
+
+        if new_line {
+            let line_tempo = tempo( state.speed(), state.bpm() );
+            if tempo_played != Some( line_tempo ) {
+                tempo_track.meta( tick, 0x51, &line_tempo.to_be_bytes()[ 1.. ] );
+                tempo_played = Some( line_tempo );
+            }
+            let ( position, line ) = state.playing_row();
+            for ( channel_number, note ) in song.get_line( position, line ).iter().enumerate() {
+                let ( sample_number, rate, volume ) = state.channel_sound( song, channel_number );
+                let has_note = note.period != 0 || matches!( note.key(), Key::Note( _ ) );
+                // tone portamento slides the note that is playing to the new one
+                if !has_note || sample_number == 0 || matches!( note.effect, Effect::TonePortamento{ .. } | Effect::TonePortamentoVolumeSlide{ .. } ) {
+                    continue;
+                }
+                // mods have the note in the period, the other formats only once the sample has tuned it
+                let note_number = if note.period != 0 { period_note( note.period ) } else { rate_note( rate as f64 ) };
+                let program = options.programs.get( &sample_number ).copied().unwrap_or( ( sample_number - 1 ).min( 127 ) );
+                channels[ channel_number ].start_note( tick, note_number, program, volume );
+            }
+            ending = state.song_has_ended() || state.has_looped();
+        }
+
+        for ( channel_number, channel ) in channels.iter_mut().enumerate() {
+            let ( _, rate, volume ) = state.channel_sound( song, channel_number );
+            channel.follow( tick, rate, volume );
+        }
+    };
+
+    writer.write_all( b"MThd" )?;
+    writer.write_all( &6u32.to_be_bytes() )?;
+    writer.write_all( &1u16.to_be_bytes() )?;
+    writer.write_all( &( num_channels as u16 + 1 ).to_be_bytes() )?;
+    writer.write_all( &( TICKS_PER_QUARTER as u16 ).to_be_bytes() )?;
+    tempo_track.write( end_tick, writer )?;
+    for channel in &mut channels {
+        channel.stop_note( end_tick );
+        channel.track.write( end_tick, writer )?;
+    }
+    Ok( () )
+}
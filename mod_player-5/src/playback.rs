@@ -0,0 +1,148 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use ringbuf::{RingBuffer, Producer, Consumer};
+
+use crate::player::{Player, PlayerCommand, PlayerOptions, PlayerStatus};
+use crate::song::Song;
+use crate::sink::{AudioSink, AudioSource, CpalSink, SinkError};
+use crate::timing::{TickTracker, TickStart, HeardSampleClock};
+
+/**
+ * Messages from the audio thread. Positions are tagged with the index of the device sample where they start
+ */
+pub enum PlayerEvent{
+    Tick{ sample_index : u64, song_pattern_position : u32, line : u32, tick : u32 },
+    InstrumentSelected{ index : u8, period : u32 },
+    Status{ status : PlayerStatus },          // the state after a command has been applied
+}
+
+/**
+ * Written by the audio thread after each buffer so the other threads can work out which sample is being heard
+ */
+struct StreamClock{
+    samples_rendered : AtomicU64,       // device samples rendered so far, including the latest buffer
+    buffer_size : AtomicU64,            // size of the latest device buffer in device samples
+}
+
+/**
+ * Sends commands to the audio thread. They are applied between buffers
+ */
+pub struct CommandSender {
+    commands : Producer<PlayerCommand>,
+}
+
+impl CommandSender {
+    /**
+     * Queue a command for the audio thread. Returns false if the queue is full and the command was dropped
+     */
+    #[must_use]
+    pub fn send( &mut self, command : PlayerCommand ) -> bool {
+        self.commands.push( command ).is_ok()
+    }
+}
+
+/**
+ * Receives events from the audio thread and tracks which device sample is currently being heard
+ */
+pub struct EventReceiver {
+    events : Consumer<PlayerEvent>,
+    clock : Arc<StreamClock>,
+    heard_clock : HeardSampleClock,
+}
+
+impl EventReceiver {
+    pub fn next_event( &mut self ) -> Option<PlayerEvent> {
+        self.events.pop()
+    }
+
+    /**
+     * Estimate the index of the device sample that is being heard ( see `HeardSampleClock` )
+     */
+    pub fn heard_sample( &mut self ) -> u64 {
+        let rendered = self.clock.samples_rendered.load( Ordering::Acquire );
+        let buffer_size = self.clock.buffer_size.load( Ordering::Acquire );
+        self.heard_clock.heard_sample( rendered, buffer_size, Instant::now() )
+    }
+}
+
+/**
+ * Runs on the audio thread. Applies commands between buffers and reports every tick with the index of the frame where it starts
+ */
+struct InteractiveSource {
+    player : Player,
+    commands : Consumer<PlayerCommand>,
+    events : Producer<PlayerEvent>,
+    clock : Arc<StreamClock>,
+    ticks : TickTracker,
+}
+
+impl AudioSource for InteractiveSource {
+    fn fill( &mut self, buffer : &mut [f32] ) -> usize {
+        let player = &mut self.player;
+        let events = &mut self.events;
+        // Commands are only applied between buffers
+        while let Some( command ) = self.commands.pop() {
+            let event = match command {
+                PlayerCommand::PlayInstrument{ index, period } => Some( PlayerEvent::InstrumentSelected{ index, period } ),
+                _ => None
+            };
+            player.apply_command( command );
+            // if the UI has fallen behind the event is dropped
+            let _ = events.push( event.unwrap_or( PlayerEvent::Status{ status : player.status() } ) );
+        }
+
+        for frame in buffer.chunks_exact_mut( 2 ) {
+            let ( ( left, right ), tick_start ) = self.ticks.next_frame( player );
+            frame[ 0 ] = left;
+            frame[ 1 ] = right;
+            if let Some( TickStart{ sample_index, song_pattern_position, line, tick } ) = tick_start {
+                let _ = events.push( PlayerEvent::Tick{ sample_index, song_pattern_position, line, tick } );
+            }
+        }
+        self.clock.samples_rendered.store( self.ticks.frames_mixed(), Ordering::Release );
+        buffer.len() / 2
+    }
+
+    fn start_buffer( &mut self, frames : usize ) {
+        self.clock.buffer_size.store( frames as u64, Ordering::Release );
+    }
+}
+
+/**
+ * A song playing on the default output device
+ */
+pub struct Playback {
+    pub device_name : String,
+    pub sample_rate : u32,
+    pub sample_format : &'static str,
+    pub channels : u16,
+    pub commands : CommandSender,
+    pub events : EventReceiver,
+}
+
+/**
+ * Start playing the song on the default output device. The sample rate in the options is replaced with the device rate.
+ * The audio thread only mixes; commands and events go through lock free queues so it never waits on the other threads
+ */
+pub fn start_playback( song : Arc<Song>, options : &PlayerOptions ) -> Result<Playback, SinkError> {
+    let mut sink = CpalSink::default_device()?;
+    let sample_rate = sink.sample_rate();
+    let player = Player::new( song, &PlayerOptions{ sample_rate, ..options.clone() } );
+
+    let (tx, commands) = RingBuffer::<PlayerCommand>::new( 64 ).split();
+    let (events, ui_events) = RingBuffer::<PlayerEvent>::new( 4096 ).split();
+    let clock = Arc::new( StreamClock{ samples_rendered : AtomicU64::new( 0 ), buffer_size : AtomicU64::new( 0 ) } );
+    let ticks = TickTracker::new( &player );
+    sink.play( Box::new( InteractiveSource{ player, commands, events, clock : clock.clone(), ticks } ) )?;
+
+    Ok( Playback {
+        device_name : sink.device_name(),
+        sample_rate,
+        sample_format : sink.sample_format(),
+        channels : sink.channels(),
+        commands : CommandSender{ commands : tx },
+        events : EventReceiver{ events : ui_events, clock, heard_clock : HeardSampleClock::new( sample_rate, Instant::now() ) },
+    } )
+}
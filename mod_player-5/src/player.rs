@@ -0,0 +1,1888 @@
+use std::sync::Arc;
+
+use crate::song::{Song, Sample, Note, Effect, Key, ModuleKind, Instrument, Envelope, NewNoteAction, DuplicateCheck, FREQUENCY_TABLE};
+use crate::s3m;
+use crate::xm;
+use crate::it;
+
+pub(crate) const CLOCK_TICKS_PERS_SECOND: f32 = 3579545.0;      // Amiga hw clcok ticks per second
+
+static VIBRATO_TABLE: [ i32; 64] = [0,24,49,74,97,120,141,161, 180,197,212,224,235,244,250,253,255,253,250,244,235,224,212,197,180,161,141,120,97,74,49,24,
+    -0,-24,-49,-74,-97,-120,-141,-161, -180,-197,-212,-224,-235,-244,-250,-253,-255,-253,-250,-244,-235,-224,-212,-197,-180,-161,-141,-120,-97,-74,-49,-24];
+
+// Scream Tracker periods of the octave below C-0 for a sample with a C2Spd of 8363
+static S3M_PERIOD_TABLE : [ u32; 12 ] = [ 1712, 1616, 1524, 1440, 1356, 1280, 1208, 1140, 1076, 1016, 960, 907 ];
+
+/**
+ * How far slides, tone portamento, vibrato and arpeggio can take the period of a mod channel
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PeriodLimits {
+    #[default]
+    Amiga,          // 113 - 856, the three octaves of ProTracker
+    Extended,       // 57 - 1712, the five octaves of the frequency table, for the PC trackers
+    Unlimited,      // any period the hardware could not play, as long as it is above 0
+}
+
+impl PeriodLimits {
+    /**
+     * The lowest and highest period allowed
+     */
+    pub fn range( self ) -> ( u32, u32 ) {
+        match self {
+            PeriodLimits::Amiga => ( 113, 856 ),
+            PeriodLimits::Extended => ( FREQUENCY_TABLE[ 0 ], FREQUENCY_TABLE[ FREQUENCY_TABLE.len() - 1 ] ),
+            PeriodLimits::Unlimited => ( 1, i32::MAX as u32 ),
+        }
+    }
+
+    fn clamp( self, period : i32 ) -> u32 {
+        let ( lowest, highest ) = self.range();
+        period.clamp( lowest as i32, highest as i32 ) as u32
+    }
+}
+
+fn change_note( current_period : u32, change : i32, limits : PeriodLimits ) -> u32 {
+    limits.clamp( current_period as i32 + change )
+}
+
+/**
+ * Period of a note for formats that store notes. Scream Tracker periods are in quarters of an Amiga period so
+ * they can take the extra fine slides
+ */
+fn note_period( key : u8, c2_speed : u32 ) -> u32 {
+    8363 * 16 * ( S3M_PERIOD_TABLE[ ( key % 12 ) as usize ] >> ( key / 12 ) ) / c2_speed.max( 1 )
+}
+
+/**
+ * Period of a note in the song's own periods. FastTracker 2's linear periods have 64 to a semitone with C-4 at 4608
+ * for a sample tuned to 8363 Hz. Impulse Tracker tunes its samples at C-5, an octave higher
+ */
+fn key_period( song : &Song, key : u8, c2_speed : u32 ) -> u32 {
+    let key = if song.format.kind == ModuleKind::It { key.saturating_sub( 12 ) } else { key };
+    if song.linear_periods {
+        let tuning = 768.0 * ( c2_speed.max( 1 ) as f32 / 8363.0 ).log2();
+        ( 7680.0 - key as f32 * 64.0 - tuning ).round().max( 1.0 ) as u32
+    } else {
+        note_period( key, c2_speed )
+    }
+}
+
+/**
+ * How many periods of the format make an Amiga period, for the formats that do not use linear periods
+ */
+fn period_scale( kind : ModuleKind ) -> f32 {
+    match kind {
+        ModuleKind::Mod => 1.0,
+        ModuleKind::S3m | ModuleKind::Xm | ModuleKind::It => 4.0,
+    }
+}
+
+/**
+ * Playback rate in Hz of a period
+ */
+fn period_rate( song : &Song, period : u32 ) -> f32 {
+    if song.linear_periods {
+        8363.0 * 2.0f32.powf( ( 4608.0 - period as f32 ) / 768.0 )
+    } else {
+        CLOCK_TICKS_PERS_SECOND * period_scale( song.format.kind ) / period as f32
+    }
+}
+
+fn slide_period( period : u32, change : i32 ) -> u32 {
+    ( period as i32 + change ).clamp( 0x40, 0x7fff ) as u32
+}
+
+/**
+ * Vibrato and tremolo waveforms: 0 is a sine, 1 a ramp down and 2 a square. Random waves play as a sine
+ */
+fn waveform( wave : u8, position : u32 ) -> i32 {
+    let position = ( position & 63 ) as i32;
+    match wave & 3 {
+        1 => 255 - position * 8,
+        2 if position < 32 => 255,
+        2 => -255,
+        _ => VIBRATO_TABLE[ position as usize ]
+    }
+}
+
+/**
+ * Volume after a Scream Tracker retrigger. The change is the high nibble of the Q argument
+ */
+fn retrigger_volume( volume : f32, change : u8 ) -> f32 {
+    let volume = match change {
+        1..=5 => volume - ( 1 << ( change - 1 ) ) as f32,
+        6 => ( volume * 2.0 / 3.0 ).floor(),
+        7 => ( volume / 2.0 ).floor(),
+        9..=13 => volume + ( 1 << ( change - 9 ) ) as f32,
+        14 => ( volume * 3.0 / 2.0 ).floor(),
+        15 => volume * 2.0,
+        _ => volume
+    };
+    volume.clamp( 0.0, 64.0 )
+}
+
+#[derive(Clone)]
+struct ChannelInfo {
+    sample_num: u8,         // which sample is playing 
+    sample_pos: f32,         
+    period : u32,           //
+    size : u32,
+    volume: f32,            // max 1.0
+    volume_change: f32,     // max 1.0
+    note_change : i32,
+    period_target : u32,      // note portamento target
+
+    base_period : u32,
+    vibrato_pos : u32,
+    vibrato_speed : u32,
+    vibrato_depth : i32,
+    vibrato_wave : u8,
+    
+    arpeggio_counter : u32,
+    arpeggio_offsets : [u32;2],
+
+    panning : Option<u8>,               // set by an effect, otherwise the song's panning for the channel is used
+    sample_offset : u32,                // last sample offset in bytes, an offset of 0 uses it again
+    tone_portamento_speed : u32,        // last portamento speed, for the effects that continue the portamento
+    tremolo_pos : u32,
+    tremolo_speed : u32,
+    tremolo_depth : i32,
+    tremolo_wave : u8,
+    tremolo_volume : f32,               // added to the volume while a tremolo runs
+    tremor_on : u32,                    // ticks on and off, 0 when there is no tremor
+    tremor_off : u32,
+    tremor_counter : u32,
+    tremor_silent : bool,
+    retrigger_ticks : u32,              // 0 when the note is not retriggered
+    retrigger_volume_change : u8,
+    retrigger_counter : u32,
+    note_cut : Option<u32>,             // tick the volume drops to 0 on
+    delayed_note : Option<( Note, u32 )>,       // note that waits for its tick
+    loop_line : u32,                    // pattern loop start and how many times the loop still repeats
+    loop_count : u32,
+
+    // formats other than mod
+    shared_argument : u8,               // Scream Tracker's last non zero argument of the effects that share it
+    effect_memory : [ u8; xm::EFFECT_MEMORY_SLOTS ],    // FastTracker 2's last non zero argument of each effect
+    vibrato_memory : ( u32, i32 ),      // last vibrato speed and depth
+    tone_portamento_step : u32,         // period change per tick towards period_target, 0 if there is no portamento
+    panning_change : i32,               // panning slide per tick
+    backwards : bool,                   // playing a ping pong loop backwards
+
+    // instruments
+    instrument : u8,                    // the last instrument given, or sample for formats without instruments
+    key_off : bool,                     // the key has been released
+    key_off_tick : Option<u32>,
+    fadeout_volume : u32,               // out of 32768, goes down once the key is released
+    volume_envelope_tick : u32,
+    panning_envelope_tick : u32,
+    instrument_volume : f32,            // volume envelope and fadeout, 0.0 - 1.0
+    envelope_panning : Option<f32>,     // panning envelope, 0 - 64 centered on 32
+    auto_vibrato_pos : u32,
+    auto_vibrato_ticks : u32,           // ticks since the note started, for the sweep
+    auto_vibrato_offset : i32,          // added to the period
+    fading : bool,                      // fading out without the key being released, for new note actions
+    key : u8,                           // the key that started the note
+    sample_volume : f32,                // global volumes of the sample and the instrument, 0.0 - 1.0
+    pitch_envelope_tick : u32,
+    pitch_envelope_offset : i32,        // pitch envelope, added to the period
+    filter_envelope_tick : u32,
+    filter_envelope_value : Option<f32>,        // filter envelope, 0 - 64 centered on 32
+    filter_cutoff : u8,                 // 0 - 127, the filter is off at 127 without resonance
+    filter_resonance : u8,
+    filter : Option<[ f32; 3 ]>,        // resonant filter coefficients, see update_filter
+    filter_history : ( f32, f32 ),      // the last two filtered values
+
+    muted : bool,           // muted channels keep processing effects but are not mixed in
+    solo : bool,            // if any channel is soloed only the soloed channels are mixed in
+}
+
+impl ChannelInfo{
+    fn new() -> ChannelInfo {
+        ChannelInfo {
+            sample_num: 0,
+            sample_pos: 0.0,
+            period : 0,
+            size : 0,
+            volume: 0.0,
+            volume_change: 0.0,
+            note_change : 0,
+            period_target : 0,
+
+            base_period : 0,
+            vibrato_pos : 0,
+            vibrato_speed : 0,
+            vibrato_depth : 0,
+            vibrato_wave : 0,
+
+            arpeggio_counter : 0,
+            arpeggio_offsets : [ 0, 0] ,
+
+            panning : None,
+            sample_offset : 0,
+            tone_portamento_speed : 0,
+            tremolo_pos : 0,
+            tremolo_speed : 0,
+            tremolo_depth : 0,
+            tremolo_wave : 0,
+            tremolo_volume : 0.0,
+            tremor_on : 0,
+            tremor_off : 0,
+            tremor_counter : 0,
+            tremor_silent : false,
+            retrigger_ticks : 0,
+            retrigger_volume_change : 0,
+            retrigger_counter : 0,
+            note_cut : None,
+            delayed_note : None,
+            loop_line : 0,
+            loop_count : 0,
+
+            shared_argument : 0,
+            effect_memory : [ 0; xm::EFFECT_MEMORY_SLOTS ],
+            vibrato_memory : ( 0, 0 ),
+            tone_portamento_step : 0,
+            panning_change : 0,
+            backwards : false,
+
+            instrument : 0,
+            key_off : false,
+            key_off_tick : None,
+            fadeout_volume : FADEOUT_START,
+            volume_envelope_tick : 0,
+            panning_envelope_tick : 0,
+            instrument_volume : 1.0,
+            envelope_panning : None,
+            auto_vibrato_pos : 0,
+            auto_vibrato_ticks : 0,
+            auto_vibrato_offset : 0,
+            fading : false,
+            key : 0,
+            sample_volume : 1.0,
+            pitch_envelope_tick : 0,
+            pitch_envelope_offset : 0,
+            filter_envelope_tick : 0,
+            filter_envelope_value : None,
+            filter_cutoff : 127,
+            filter_resonance : 0,
+            filter : None,
+            filter_history : ( 0.0, 0.0 ),
+
+            muted : false,
+            solo : false,
+        }
+    }
+
+    /**
+     * Volume the channel is heard at, 0 - 64, after tremolo, tremor and the instrument's envelope and fadeout
+     */
+    fn output_volume( &self ) -> f32 {
+        if self.tremor_silent { 0.0 } else { ( self.volume + self.tremolo_volume ).clamp( 0.0, 64.0 ) * self.instrument_volume * self.sample_volume }
+    }
+
+    /**
+     * Period the channel is heard at, after the instrument's vibrato and pitch envelope
+     */
+    fn output_period( &self ) -> u32 {
+        ( self.period as i32 + self.auto_vibrato_offset + self.pitch_envelope_offset ).max( 1 ) as u32
+    }
+
+    /**
+     * A new instrument starts its envelopes, fadeout and vibrato from the beginning
+     */
+    fn start_instrument( &mut self ) {
+        self.key_off = false;
+        self.fading = false;
+        self.fadeout_volume = FADEOUT_START;
+        self.volume_envelope_tick = 0;
+        self.panning_envelope_tick = 0;
+        self.pitch_envelope_tick = 0;
+        self.filter_envelope_tick = 0;
+        self.auto_vibrato_pos = 0;
+        self.auto_vibrato_ticks = 0;
+    }
+
+    /**
+     * Move the envelopes, fadeout and vibrato of the instrument on by a tick
+     */
+    fn update_instrument( &mut self, instrument : &Instrument, linear : bool ) {
+        let volume = if instrument.volume_envelope.is_enabled() {
+            let ( value, next_tick ) = envelope_step( &instrument.volume_envelope, self.volume_envelope_tick, self.key_off );
+            self.volume_envelope_tick = next_tick;
+            value / 64.0
+        } else {
+            1.0
+        };
+        if self.key_off || self.fading {
+            self.fadeout_volume = self.fadeout_volume.saturating_sub( instrument.fadeout );
+        }
+        self.instrument_volume = volume * self.fadeout_volume as f32 / FADEOUT_START as f32;
+
+        self.envelope_panning = if instrument.panning_envelope.is_enabled() {
+            let ( value, next_tick ) = envelope_step( &instrument.panning_envelope, self.panning_envelope_tick, self.key_off );
+            self.panning_envelope_tick = next_tick;
+            Some( value )
+        } else {
+            None
+        };
+
+        // the pitch envelope is in half semitones either side of 32
+        self.pitch_envelope_offset = if instrument.pitch_envelope.is_enabled() {
+            let ( value, next_tick ) = envelope_step( &instrument.pitch_envelope, self.pitch_envelope_tick, self.key_off );
+            self.pitch_envelope_tick = next_tick;
+            if linear {
+                ( -( value - 32.0 ) * 32.0 ) as i32
+            } else {
+                ( self.period as f32 * ( 2.0f32.powf( -( value - 32.0 ) / 24.0 ) - 1.0 ) ) as i32
+            }
+        } else {
+            0
+        };
+        self.filter_envelope_value = if instrument.filter_envelope.is_enabled() {
+            let ( value, next_tick ) = envelope_step( &instrument.filter_envelope, self.filter_envelope_tick, self.key_off );
+            self.filter_envelope_tick = next_tick;
+            Some( value )
+        } else {
+            None
+        };
+
+        let vibrato = &instrument.vibrato;
+        if vibrato.depth > 0 && vibrato.rate > 0 {
+            self.auto_vibrato_ticks += 1;
+            let depth = if self.auto_vibrato_ticks < vibrato.sweep as u32 {
+                vibrato.depth as i32 * self.auto_vibrato_ticks as i32 / vibrato.sweep as i32
+            } else {
+                vibrato.depth as i32
+            };
+            // the position goes round in 256 steps, the waveforms have 64
+            let position = self.auto_vibrato_pos / 4;
+            let wave = match vibrato.wave {
+                1 => waveform( 2, position ),
+                2 => waveform( 1, position ),
+                3 => -waveform( 1, position ),
+                _ => waveform( 0, position ),
+            };
+            self.auto_vibrato_offset = wave * depth / 256;
+            self.auto_vibrato_pos += vibrato.rate as u32;
+        } else {
+            self.auto_vibrato_offset = 0;
+        }
+    }
+
+    /**
+     * Split a value between the left and right outputs. Panning goes from 0 ( left ) to 255 ( right ), the song's
+     * panning for the channel is used until an effect sets it. Mods play channels hard left or right
+     */
+    fn pan( &self, value : f32, song_panning : u8 ) -> ( f32, f32 ) {
+        let mut panning = self.panning.unwrap_or( song_panning ) as f32;
+        // the panning envelope swings the panning as far as it can go towards the nearest side
+        if let Some( envelope_panning ) = self.envelope_panning {
+            panning += ( envelope_panning - 32.0 ) * ( 128.0 - ( panning - 128.0 ).abs() ) / 32.0;
+        }
+        let panning = panning.clamp( 0.0, 255.0 ) / 255.0;
+        ( value * ( 1.0 - panning ), value * panning )
+    }
+
+    /**
+     * Get the current value of the channel and advance the sample position. Once the end of the sample is reached the
+     * playback continues from the loop. Non looping samples have loops of 2 bytes or less and the channel goes silent
+     */
+    fn next_value( &mut self, current_sample : &Sample, step : f32 ) -> f32 {
+        // Loops that point past the sample data end the sample
+        let position = self.sample_pos as usize;
+        if position >= current_sample.samples.len() {
+            self.size = 0;
+            return 0.0;
+        }
+        // Grab the sample, no filtering
+        let mut channel_value: f32 = current_sample.samples[ position ] as f32;   // [ -127, 127 ] 
+        if let Some( [ gain, feedback_1, feedback_2 ] ) = self.filter {
+            let ( last, before_last ) = self.filter_history;
+            channel_value = channel_value * gain + last * feedback_1 + before_last * feedback_2;
+            self.filter_history = ( channel_value, last );
+        }
+
+    //     let left_pos = self.sample_pos as u32;
+    //     let left_weight: f32 = 1.0 - (self.sample_pos - left_pos as f32);
+    //     let mut channel_value: f32 = current_sample.samples[ left_pos as usize] as f32;   // [ -127, 127 ] 
+    //     if left_pos < (current_sample.size - 1) as u32 {
+    //        let right_value = current_sample.samples[(left_pos + 1) as usize] as f32;
+    //        channel_value = left_weight * channel_value + (1.0 - left_weight) * right_value;
+    //    }
+
+        // max channel vol (64), sample range [ -128,127] scaled to [-1,1] 
+        channel_value *= self.output_volume() / (128.0*64.0);
+
+        if current_sample.ping_pong {
+            self.next_ping_pong_position( current_sample, step );
+            return channel_value;
+        }
+        // update position and check if we have reached the end of the sample ( or the end of the loop )
+        self.sample_pos +=  step;
+
+        if self.sample_pos >= self.size as f32 {
+            let overflow : f32 = self.sample_pos - self.size as f32;
+            self.sample_pos = current_sample.repeat_offset as f32 + overflow;
+            self.size = current_sample.repeat_size + current_sample.repeat_offset;
+        }
+        channel_value
+    }
+
+    /**
+     * Ping pong loops turn round at both ends of the loop instead of going back to the start
+     */
+    fn next_ping_pong_position( &mut self, current_sample : &Sample, step : f32 ) {
+        let loop_start = current_sample.repeat_offset as f32;
+        let loop_end = ( current_sample.repeat_offset + current_sample.repeat_size ) as f32;
+        if self.backwards {
+            self.sample_pos -= step;
+            if self.sample_pos < loop_start {
+                self.sample_pos = ( 2.0 * loop_start - self.sample_pos ).min( loop_end - 1.0 );
+                self.backwards = false;
+            }
+        } else {
+            self.sample_pos += step;
+            if self.sample_pos >= self.size as f32 {
+                self.sample_pos = ( 2.0 * loop_end - self.sample_pos - 1.0 ).max( loop_start );
+                self.size = current_sample.repeat_offset + current_sample.repeat_size;
+                self.backwards = current_sample.repeat_size > 2;
+            }
+        }
+    }
+
+    /**
+     * FastTracker 2 effects repeat their own last non zero argument when they are given 0
+     */
+    fn recall_effect_memory( &mut self, effect : Effect ) -> Effect {
+        match xm::effect_memory( &effect ) {
+            Some( ( slot, number, prefix, 0 ) ) => xm::decode_effect( number, prefix | self.effect_memory[ slot ] ),
+            Some( ( slot, _, _, argument ) ) => {
+                self.effect_memory[ slot ] = argument;
+                effect
+            }
+            None => effect
+        }
+    }
+
+    /**
+     * Scream Tracker effects that share their argument repeat the last non zero one when they are given 0
+     */
+    fn recall_shared_argument( &mut self, effect : Effect ) -> Effect {
+        match s3m::shared_argument( &effect ) {
+            Some( ( command, 0 ) ) => s3m::s3m_effect( command, self.shared_argument ),
+            Some( ( _, argument ) ) => {
+                self.shared_argument = argument;
+                effect
+            }
+            None => effect
+        }
+    }
+
+    /**
+     * Impulse Tracker effects repeat their last non zero argument when they are given 0, most of them with a memory
+     * of their own
+     */
+    fn recall_it_memory( &mut self, effect : Effect ) -> Effect {
+        match it::effect_memory( &effect ) {
+            Some( ( slot, command, 0 ) ) => it::it_effect( command, self.effect_memory[ slot ] ),
+            Some( ( slot, _, argument ) ) => {
+                self.effect_memory[ slot ] = argument;
+                effect
+            }
+            None => effect
+        }
+    }
+
+    /**
+     * Work out the resonant filter for the cutoff, resonance and filter envelope, as Impulse Tracker's two pole
+     * filter. The filter is off while the cutoff is fully open without resonance
+     */
+    fn update_filter( &mut self, sample_rate : u32 ) {
+        if self.filter_cutoff >= 127 && self.filter_resonance == 0 && self.filter_envelope_value.is_none() {
+            self.filter = None;
+            self.filter_history = ( 0.0, 0.0 );
+            return;
+        }
+        let modifier = self.filter_envelope_value.map( | value | ( value - 32.0 ) * 8.0 ).unwrap_or( 0.0 );
+        let frequency = 110.0 * 2.0f32.powf( 0.25 + self.filter_cutoff as f32 * ( modifier + 256.0 ) / ( 256.0 * 24.0 ) );
+        let frequency = frequency.clamp( 120.0, 20000.0 ).min( sample_rate as f32 / 2.0 );
+        let damping = 10.0f32.powf( -( self.filter_resonance as f32 ) * 24.0 / ( 128.0 * 20.0 ) );
+        let r = sample_rate as f32 / ( 2.0 * std::f32::consts::PI * frequency );
+        let d = damping * r + damping - 1.0;
+        let e = r * r;
+        let scale = 1.0 + d + e;
+        self.filter = Some( [ 1.0 / scale, ( d + 2.0 * e ) / scale, -e / scale ] );
+    }
+
+    /**
+     * A note left playing by a new note action has finished once it has faded out, or its volume envelope has ended
+     * at nothing after the key was released
+     */
+    fn has_finished( &self, instrument : &Instrument ) -> bool {
+        let envelope = &instrument.volume_envelope;
+        let envelope_ended = envelope.is_enabled() && self.volume_envelope_tick > envelope.point_tick( envelope.points.len().saturating_sub( 1 ) );
+        self.size <= 2 || self.fadeout_volume == 0 || ( envelope_ended && self.instrument_volume == 0.0 )
+    }
+
+    /**
+     * Forget the effects of the last line. Mods stop every effect at the end of its line, the later trackers let
+     * vibrato, tremolo and retrigger counters carry on where they were
+     */
+    fn start_line( &mut self, kind : ModuleKind, effect : &Effect, volume_effect : &Effect ) {
+        if kind != ModuleKind::Mod && self.arpeggio_offsets != [ 0, 0 ] {
+            self.period = self.base_period;
+        }
+        self.volume_change = 0.0;
+        self.note_change = 0;
+        self.arpeggio_offsets = [ 0, 0 ];
+        self.tremolo_volume = 0.0;
+        self.tremolo_depth = 0;
+        self.tremor_on = 0;
+        self.tremor_silent = false;
+        self.retrigger_ticks = 0;
+        self.note_cut = None;
+        self.delayed_note = None;
+        self.tone_portamento_step = 0;
+        self.panning_change = 0;
+        self.key_off_tick = None;
+        let continues_vibrato = [ effect, volume_effect ].iter().any( | effect | matches!( effect, Effect::Vibrato{ .. } | Effect::FineVibrato{ .. } | Effect::VibratoVolumeSlide{ .. } ) );
+        match kind {
+            ModuleKind::Mod => if !matches!( effect, Effect::VibratoVolumeSlide{ .. } ) {
+                self.vibrato_pos = 0;
+                self.vibrato_speed = 0;
+                self.vibrato_depth = 0;
+            },
+            _ => if !continues_vibrato {
+                // the pitch goes back to the note once the vibrato stops
+                if self.vibrato_depth != 0 {
+                    self.period = self.base_period;
+                }
+                self.vibrato_depth = 0;
+            }
+        }
+    }
+}
+
+const FADEOUT_START : u32 = 32768;
+
+/**
+ * Value of an envelope at a tick and the tick it moves on to. Envelopes go round the sustain loop, which is a single
+ * point in FastTracker 2, until the key is released and go back to the start of the loop from its end
+ */
+fn envelope_step( envelope : &Envelope, tick : u32, key_off : bool ) -> ( f32, u32 ) {
+    let value = envelope.value( tick );
+    let next_tick = match ( envelope.sustain, envelope.loop_points ) {
+        ( Some( ( start, end ) ), _ ) if !key_off && tick == envelope.point_tick( end ) => envelope.point_tick( start ),
+        ( _, Some( ( start, end ) ) ) if tick >= envelope.point_tick( end ) => envelope.point_tick( start ),
+        _ => tick + 1
+    };
+    ( value, next_tick )
+}
+
+/**
+ * Apply the sample finetune to a period. The finetune is a signed nibble in 1/8th semitone steps
+ */
+fn fine_tune_period( period : u32, fine_tune : u8 ) -> u32 {
+    let fine_tune = ( ( fine_tune << 4 ) as i8 >> 4 ) as f32;
+    ( period as f32 * 2.0f32.powf( -fine_tune / ( 12.0 * 8.0 ) ) ).round() as u32
+}
+
+#[derive(Clone, Copy)]
+pub struct PlayerStatus{
+    pub paused : bool,
+    pub song_pattern_position : u32,
+    pub line : u32,
+    pub speed : u32,
+    pub bpm : u32,
+    pub master_volume : f32,
+    pub muted_channels : u64,               // one bit per channel, channel 0 in the lowest bit
+    pub solo_channels : u64,
+}
+
+pub struct PlayerState{
+    channels: Vec<ChannelInfo>,
+    song_pattern_position: u32,             // where in the pattern table are we currently
+    current_line: u32,                      // current position in the pattern
+    song_has_ended : bool,
+    has_looped : bool,
+    song_speed: u32,                        // in vblanks
+    song_bpm : u32,                         // 125 bpm is 50 vblanks per second
+    speed_override : Option<u32>,           // if set, used instead of the song speed
+    bpm_override : Option<u32>,             // if set, used instead of the song bpm
+    paused : bool,                          // the song does not advance while paused. The preview voice still plays
+    master_volume : f32,                    // 1.0 is full volume
+    current_vblank : u32,                   // how many vblanks since last play line
+    device_sample_rate : u32,
+    samples_per_vblank: u32,                // how many device samples per 'vblank'
+    clock_ticks_per_device_sample : f32,    // how many amiga hardware clock ticks per device sample
+    current_vblank_sample : u32,            // how many device samples have we played for the current 'vblank'
+
+    next_pattern_pos : i32,                 // on  next line if == -1 do nothing else  go to next pattern on line next_pattern_pos
+    next_position : i32,                    // on next line if == 1 do nothing else go to beginning of the this pattern
+
+    preview : ChannelInfo,                  // voice for auditioning samples, mixed on top of the song
+
+    playing_pattern_position : u32,         // pattern table position of the line that is playing
+    playing_line : u32,                     // the line that is playing ( current_line already points to the next one )
+    ticks_played : u64,                     // total number of vblanks processed
+
+    global_volume : u8,                     // 0 - 64, all channels are scaled by it
+    global_volume_change : i32,             // global volume slide per tick
+    pattern_delay : u32,                    // how many times the playing line is repeated without playing its notes again
+    next_loop_line : Option<u32>,           // set by a pattern loop, the line played after this one
+    initial_speed : u32,                    // the song's own settings, used when the song starts again
+    initial_bpm : u32,
+    initial_global_volume : u8,
+    voices : Voices,                        // notes left playing by new note actions
+    period_limits : PeriodLimits,
+}
+
+impl PlayerState{
+    pub fn new( num_channels : u32, device_sample_rate : u32 ) -> PlayerState {
+        let mut channels = Vec::new();
+        for _channel in 0..num_channels {
+            channels.push(ChannelInfo::new())
+        }
+        PlayerState{
+            channels,
+            song_pattern_position : 0,
+            current_line: 0,
+            current_vblank : 0,             
+            current_vblank_sample : 0,      
+            song_speed: 6,                  
+            song_bpm : 125,
+            speed_override : None,
+            bpm_override : None,
+            paused : false,
+            master_volume : 1.0,
+            device_sample_rate,
+            samples_per_vblank: device_sample_rate / 50,
+            clock_ticks_per_device_sample : CLOCK_TICKS_PERS_SECOND / device_sample_rate as f32,
+            next_pattern_pos : -1,
+            next_position : -1,
+            song_has_ended : false, 
+            has_looped :false,
+
+            preview : ChannelInfo::new(),
+
+            playing_pattern_position : 0,
+            playing_line : 0,
+            ticks_played : 0,
+
+            global_volume : 64,
+            global_volume_change : 0,
+            pattern_delay : 0,
+            next_loop_line : None,
+            initial_speed : 6,
+            initial_bpm : 125,
+            initial_global_volume : 64,
+            voices : Voices::new(),
+            period_limits : PeriodLimits::default(),
+        }
+    }
+
+    /**
+     * Create the state for playing the song with the given options. Unlike new it starts at the song's own speed,
+     * tempo and global volume, which matters for the formats that store them
+     */
+    pub fn with_options( song : &Song, options : &PlayerOptions ) -> PlayerState {
+        let mut state = PlayerState::new( song.num_channels(), options.sample_rate );
+        state.initial_speed = song.initial_speed;
+        state.initial_bpm = song.initial_bpm;
+        state.initial_global_volume = song.global_volume;
+        state.period_limits = options.period_limits;
+        state.restart();
+        // Channels that the song does not have are ignored
+        for channel in &options.muted_channels {
+            if *channel < state.num_channels() {
+                state.set_channel_muted( *channel, true );
+            }
+        }
+        for channel in &options.solo_channels {
+            if *channel < state.num_channels() {
+                state.set_channel_solo( *channel, true );
+            }
+        }
+        state
+    }
+
+    pub fn sample_rate( &self ) -> u32 {
+        self.device_sample_rate
+    }
+
+    /**
+     * The speed in vblanks per line, including any override
+     */
+    pub fn speed( &self ) -> u32 {
+        self.speed_override.unwrap_or( self.song_speed )
+    }
+
+    /**
+     * The tempo in beats per minute, including any override
+     */
+    pub fn bpm( &self ) -> u32 {
+        self.bpm_override.unwrap_or( self.song_bpm )
+    }
+
+    /**
+     * Set a speed that is used instead of the one the song sets. None goes back to the song speed
+     */
+    pub fn set_speed_override( &mut self, speed : Option<u32> ) {
+        self.speed_override = speed;
+    }
+
+    /**
+     * Set a tempo that is used instead of the one the song sets. None goes back to the song tempo
+     */
+    pub fn set_bpm_override( &mut self, bpm : Option<u32> ) {
+        self.bpm_override = bpm;
+        self.update_samples_per_vblank();
+    }
+
+    /**
+     * Vblanks until the next line, a pattern delay repeats the line
+     */
+    fn line_vblanks( &self ) -> u32 {
+        self.speed() * ( 1 + self.pattern_delay )
+    }
+
+    fn update_samples_per_vblank( &mut self ) {
+        // At 125 bpm there are 50 vblanks per second
+        self.samples_per_vblank = self.device_sample_rate * 5 / ( self.bpm().max( 1 ) * 2 );
+    }
+
+    pub fn set_paused( &mut self, paused : bool ) {
+        self.paused = paused;
+    }
+
+    pub fn is_paused( &self ) -> bool {
+        self.paused
+    }
+
+    pub fn set_master_volume( &mut self, volume : f32 ) {
+        self.master_volume = volume.max( 0.0 );
+    }
+
+    pub fn master_volume( &self ) -> f32 {
+        self.master_volume
+    }
+
+    /**
+     * Go back to the start of the song. Mute, solo, overrides, volume and the paused state are kept
+     */
+    pub fn restart( &mut self ) {
+        for channel in &mut self.channels {
+            let ( muted, solo ) = ( channel.muted, channel.solo );
+            *channel = ChannelInfo::new();
+            channel.muted = muted;
+            channel.solo = solo;
+        }
+        self.song_pattern_position = 0;
+        self.current_line = 0;
+        self.current_vblank = 0;
+        self.current_vblank_sample = 0;
+        self.song_speed = self.initial_speed;
+        self.song_bpm = self.initial_bpm;
+        self.global_volume = self.initial_global_volume;
+        self.pattern_delay = 0;
+        self.next_loop_line = None;
+        self.next_pattern_pos = -1;
+        self.next_position = -1;
+        self.song_has_ended = false;
+        self.has_looped = false;
+        self.playing_pattern_position = 0;
+        self.playing_line = 0;
+        self.voices.voices.clear();
+        self.update_samples_per_vblank();
+    }
+
+    /**
+     * Continue playing from the start of the given position in the pattern table. The first line is played on the next sample
+     */
+    pub fn jump_to_position( &mut self, song : &Song, song_pattern_position : u32 ) {
+        let last_position = song.num_used_patterns.max( 1 ) - 1;
+        self.song_pattern_position = song_pattern_position.min( last_position );
+        self.current_line = 0;
+        self.pattern_delay = 0;
+        self.next_loop_line = None;
+        self.next_pattern_pos = -1;
+        self.next_position = -1;
+        self.song_has_ended = false;
+        self.current_vblank = self.speed();
+        self.current_vblank_sample = self.samples_per_vblank;
+        self.playing_pattern_position = self.song_pattern_position;
+        self.playing_line = 0;
+        self.voices.voices.clear();
+    }
+
+    /**
+     * Forget that the song has ended or looped so the next time can be detected
+     */
+    pub(crate) fn clear_song_end( &mut self ) {
+        self.song_has_ended = false;
+        self.has_looped = false;
+    }
+
+    /**
+     * Set when the song has played its last position and started again from the beginning
+     */
+    pub fn song_has_ended( &self ) -> bool {
+        self.song_has_ended
+    }
+
+    /**
+     * Set when a position jump has gone back to an earlier position
+     */
+    pub fn has_looped( &self ) -> bool {
+        self.has_looped
+    }
+
+    /**
+     * Line that will be played next
+     */
+    pub fn current_line( &self ) -> u32 {
+        self.current_line
+    }
+
+    /**
+     * Position in the pattern table that will be played next
+     */
+    pub fn song_pattern_position( &self ) -> u32 {
+        self.song_pattern_position
+    }
+
+    /**
+     * A snapshot of the state that the player controls change. Does not allocate so it can be taken on the audio thread
+     */
+    pub fn status( &self ) -> PlayerStatus {
+        let mut muted_channels = 0;
+        let mut solo_channels = 0;
+        for ( channel_number, channel ) in self.channels.iter().enumerate().take( 64 ) {
+            if channel.muted { muted_channels |= 1 << channel_number; }
+            if channel.solo { solo_channels |= 1 << channel_number; }
+        }
+        PlayerStatus {
+            paused : self.paused,
+            song_pattern_position : self.playing_pattern_position,
+            line : self.playing_line,
+            speed : self.speed(),
+            bpm : self.bpm(),
+            master_volume : self.master_volume,
+            muted_channels,
+            solo_channels,
+        }
+    }
+
+    /**
+     * Start playing a sample on the preview voice using the sample's own volume, finetune and loop. The period
+     * sets the note ( see textout::note_period ). Sample number 0 stops the preview
+     */
+    pub fn play_preview( &mut self, song : &Song, sample_number : u8, period : u32 ) {
+        if sample_number == 0 || sample_number as usize > song.samples.len() {
+            self.stop_preview();
+            return;
+        }
+        let sample = &song.samples[ ( sample_number - 1 ) as usize ];
+        self.preview.sample_num = sample_number;
+        self.preview.sample_pos = 0.0;
+        self.preview.size = sample.size;
+        self.preview.volume = sample.volume as f32;
+        self.preview.period = match song.format.kind {
+            ModuleKind::Mod => fine_tune_period( period, sample.fine_tune ),
+            _ => period * 8363 / sample.c2_speed.max( 1 ),
+        };
+    }
+
+    pub fn stop_preview( &mut self ) {
+        self.preview.size = 0;
+    }
+
+    pub fn get_song_line<'a>( &self, song : &'a Song ) -> &'a [Note] {
+        song.get_line( self.song_pattern_position, self.current_line )
+    }
+
+    /**
+     * The pattern table position and line of the most recently played line
+     */
+    pub fn playing_row( &self ) -> ( u32, u32 ) {
+        ( self.playing_pattern_position, self.playing_line )
+    }
+
+    /**
+     * Which vblank of the playing line we are on, starting from 0
+     */
+    pub fn current_tick( &self ) -> u32 {
+        if self.current_vblank > 0 { self.current_vblank - 1 } else { 0 }
+    }
+
+    /**
+     * Total number of vblanks processed since the start. Changes whenever a new tick starts
+     */
+    pub fn ticks_played( &self ) -> u64 {
+        self.ticks_played
+    }
+
+    pub fn num_channels( &self ) -> usize {
+        self.channels.len()
+    }
+
+    pub fn set_channel_muted( &mut self, channel : usize, muted : bool ) {
+        self.channels[ channel ].muted = muted;
+    }
+
+    pub fn is_channel_muted( &self, channel : usize ) -> bool {
+        self.channels[ channel ].muted
+    }
+
+    pub fn set_channel_solo( &mut self, channel : usize, solo : bool ) {
+        self.channels[ channel ].solo = solo;
+    }
+
+    pub fn is_channel_solo( &self, channel : usize ) -> bool {
+        self.channels[ channel ].solo
+    }
+
+    /**
+     * Sample number, playback rate in Hz and volume ( 0 - 64 ) of a channel as the effects have left them
+     */
+    pub(crate) fn channel_sound( &self, song : &Song, channel : usize ) -> ( u8, f32, f32 ) {
+        let channel = &self.channels[ channel ];
+        let rate = if channel.period > 0 { period_rate( song, channel.output_period() ) } else { 0.0 };
+        ( channel.sample_num, rate, channel.output_volume() )
+    }
+
+    /**
+     * How far a channel moves through its sample for every device sample
+     */
+    fn sample_step( &self, song : &Song, period : u32 ) -> f32 {
+        if song.linear_periods {
+            period_rate( song, period ) / self.device_sample_rate as f32
+        } else {
+            self.clock_ticks_per_device_sample * period_scale( song.format.kind ) / period as f32
+        }
+    }
+
+    /**
+     * A channel is heard if it is not muted and either it is soloed or no channel is soloed
+     */
+    pub fn is_channel_audible( &self, channel : usize ) -> bool {
+        let any_solo = self.channels.iter().any( | channel_info | channel_info.solo );
+        let channel_info = &self.channels[ channel ];
+        !channel_info.muted && ( channel_info.solo || !any_solo )
+    }
+
+}
+
+/**
+ * Start the note: pick up the sample, set the period and apply the volume column and the effects that act on the channel
+ * straight away. Mods keep the quirks of the original player: a tone portamento restarts the sample and a sample
+ * number without a note changes the sample size
+ */
+fn trigger_note( note : &Note, effect : &Effect, channel : &mut ChannelInfo, channel_number : usize, song : &Song, voices : &mut Voices, limits : PeriodLimits ) {
+    let kind = song.format.kind;
+    let old_period = channel.period;
+    let tone_portamento = [ effect, &note.volume_effect ].iter().any( | effect | matches!( effect, Effect::TonePortamento{ .. } | Effect::TonePortamentoVolumeSlide{ .. } ) );
+    if let Effect::SetSampleOffset{ offset } = *effect {
+        if offset > 0 {
+            channel.sample_offset = offset as u32 * 256;
+        }
+    }
+
+    match kind {
+        ModuleKind::Mod => {
+            if note.sample_number > 0 {
+                // sample number 0, means that the sample keeps playing. The sample indices starts at one, so subtract 1 to get to 0 based index
+                let sample = &song.samples[ ( note.sample_number - 1 ) as usize ];
+                channel.volume = sample.volume as f32;    // Get volume from sample
+                channel.size = sample.size;
+                channel.sample_num = note.sample_number;
+            }
+            if note.period != 0 {
+                channel.period = note.period;
+                channel.sample_pos = 0.0;
+                // A retriggered note starts from the beginning of the sample, not from the loop
+                if channel.sample_num > 0 {
+                    channel.size = song.samples[ ( channel.sample_num - 1 ) as usize ].size;
+                }
+                if matches!( effect, Effect::SetSampleOffset{ .. } ) {
+                    channel.sample_pos = channel.sample_offset as f32;
+                }
+                channel.retrigger_counter = 0;
+            }
+            // A tone portamento without a note slides on without a target, as the original player did
+            if matches!( effect, Effect::TonePortamento{ .. } ) || ( tone_portamento && note.period != 0 ) {
+                channel.period_target = note.period;
+                channel.period = old_period;
+            }
+        }
+        _ => {
+            let instrument_number = if note.sample_number > 0 { note.sample_number } else { channel.instrument };
+            let instrument = ( instrument_number as usize ).checked_sub( 1 ).and_then( | index | song.instruments.get( index ) );
+            // formats with instruments look the sample up in the instrument's keymap
+            let sample_number = match ( instrument, note.key ) {
+                ( Some( instrument ), Key::Note( key ) ) => instrument.sample_for_key( key ),
+                ( Some( _ ), _ ) => channel.sample_num,
+                ( None, _ ) => instrument_number,
+            };
+            let sample = ( sample_number as usize ).checked_sub( 1 ).and_then( | index | song.samples.get( index ) );
+            // a portamento slides the note that is playing instead of starting a new one
+            let new_note = matches!( note.key, Key::Note( _ ) ) && sample.is_some() && !( tone_portamento && channel.size > 2 );
+            if let ( true, ModuleKind::It, Some( _ ), Key::Note( key ) ) = ( new_note, kind, instrument, note.key ) {
+                voices.start_note( song, channel_number, channel, instrument_number, key );
+            }
+            if note.sample_number > 0 {
+                channel.instrument = note.sample_number;
+                if let Some( sample ) = sample {
+                    channel.volume = sample.volume as f32;
+                    channel.sample_volume = sample.global_volume as f32 / 64.0 * instrument.map( | instrument | instrument.global_volume as f32 / 64.0 ).unwrap_or( 1.0 );
+                    // the sample's panning takes precedence over the instrument's
+                    if let Some( panning ) = sample.panning.or( instrument.and_then( | instrument | instrument.panning ) ) {
+                        channel.panning = Some( panning );
+                    }
+                }
+                if let Some( ( cutoff, resonance ) ) = instrument.map( | instrument | instrument.filter() ) {
+                    channel.filter_cutoff = cutoff.unwrap_or( channel.filter_cutoff );
+                    channel.filter_resonance = resonance.unwrap_or( channel.filter_resonance );
+                }
+                channel.start_instrument();
+            }
+            match ( note.key, sample ) {
+                ( Key::Cut, _ ) => channel.size = 0,
+                ( Key::Off, _ ) => key_off( channel, instrument, kind ),
+                ( Key::Fade, _ ) => channel.fading = instrument.is_some(),
+                ( Key::Note( key ), Some( sample ) ) => {
+                    let played_key = instrument.map( | instrument | instrument.note_for_key( key ) ).unwrap_or( key );
+                    let period = key_period( song, played_key, sample.c2_speed );
+                    if !new_note {
+                        channel.period_target = period;
+                    } else {
+                        channel.key = key;
+                        channel.sample_num = sample_number;
+                        channel.period = period;
+                        channel.base_period = period;
+                        channel.period_target = 0;
+                        channel.size = sample.size;
+                        channel.sample_pos = if matches!( effect, Effect::SetSampleOffset{ .. } ) { channel.sample_offset as f32 } else { 0.0 };
+                        channel.backwards = false;
+                        channel.vibrato_pos = 0;
+                        channel.tremolo_pos = 0;
+                        channel.retrigger_counter = 0;
+                    }
+                }
+                _ => ()
+            }
+        }
+    }
+
+    apply_effect( &note.volume_effect, channel, kind, limits );
+    apply_effect( effect, channel, kind, limits );
+}
+
+/**
+ * Release the key. Instruments without a volume envelope stop straight away, except in Impulse Tracker where they
+ * fade out
+ */
+fn key_off( channel : &mut ChannelInfo, instrument : Option<&Instrument>, kind : ModuleKind ) {
+    channel.key_off = true;
+    let fades = match instrument {
+        Some( instrument ) => kind == ModuleKind::It || instrument.volume_envelope.is_enabled(),
+        None => false
+    };
+    if !fades {
+        channel.volume = 0.0;
+    }
+}
+
+/**
+ * Most notes cut the one before them, but an Impulse Tracker instrument's new note action can leave the old note
+ * playing in the background. These voices only run their envelopes and fadeout, and are mixed with the channel
+ * that started them
+ */
+struct Voices {
+    voices : Vec<( usize, ChannelInfo )>,       // each with the channel that started it
+}
+
+const MAX_VOICES : usize = 64;
+
+impl Voices {
+    /**
+     * Room for all the voices is made up front so that starting a note never allocates on the audio thread
+     */
+    fn new() -> Voices {
+        Voices{ voices : Vec::with_capacity( MAX_VOICES ) }
+    }
+
+    /**
+     * Apply the duplicate check of the new note's instrument to the voices of the channel, then the new note action
+     * of the note that is playing
+     */
+    fn start_note( &mut self, song : &Song, channel_number : usize, channel : &ChannelInfo, instrument_number : u8, key : u8 ) {
+        let instrument = &song.instruments[ instrument_number as usize - 1 ];
+        let ( check, action ) = instrument.duplicate_check();
+        let is_duplicate = | voice : &ChannelInfo | match check {
+            DuplicateCheck::Off => false,
+            DuplicateCheck::Note => voice.instrument == instrument_number && voice.key == key,
+            DuplicateCheck::Sample => voice.sample_num == instrument.sample_for_key( key ),
+            DuplicateCheck::Instrument => voice.instrument == instrument_number,
+        };
+        for ( _, voice ) in self.voices.iter_mut().filter( | ( host, _ ) | *host == channel_number ) {
+            if is_duplicate( voice ) {
+                new_note_action( voice, action, song );
+            }
+        }
+        self.voices.retain( | ( _, voice ) | voice.size > 2 );
+
+        let old_instrument = match ( channel.instrument as usize ).checked_sub( 1 ).and_then( | index | song.instruments.get( index ) ) {
+            Some( old_instrument ) if channel.size > 2 => old_instrument,
+            _ => return
+        };
+        let action = if is_duplicate( channel ) { action } else { old_instrument.new_note_action };
+        if action == NewNoteAction::Cut {
+            return;
+        }
+        if self.voices.len() >= MAX_VOICES {
+            let quietest = self.voices.iter().enumerate()
+                .min_by( | ( _, a ), ( _, b ) | a.1.output_volume().total_cmp( &b.1.output_volume() ) )
+                .map( | ( index, _ ) | index ).unwrap_or( 0 );
+            self.voices.swap_remove( quietest );
+        }
+        let mut voice = channel.clone();
+        voice.key_off_tick = None;
+        voice.note_cut = None;
+        voice.delayed_note = None;
+        new_note_action( &mut voice, action, song );
+        self.voices.push( ( channel_number, voice ) );
+    }
+}
+
+/**
+ * What happens to a note that a new one takes the place of, or that the duplicate check finds
+ */
+fn new_note_action( voice : &mut ChannelInfo, action : NewNoteAction, song : &Song ) {
+    match action {
+        NewNoteAction::Cut => voice.size = 0,
+        NewNoteAction::Continue => (),
+        NewNoteAction::NoteOff => {
+            let instrument = ( voice.instrument as usize ).checked_sub( 1 ).and_then( | index | song.instruments.get( index ) );
+            key_off( voice, instrument, song.format.kind );
+        }
+        NewNoteAction::Fade => voice.fading = true,
+    }
+}
+
+/**
+ * Apply an effect of the volume column or the effect column to the channel
+ */
+fn apply_effect( effect : &Effect, channel : &mut ChannelInfo, kind : ModuleKind, limits : PeriodLimits ) {
+    // Slides are in periods of the format, which are finer than Amiga periods for the later trackers
+    let slide_unit = if kind == ModuleKind::Mod { 1 } else { 4 };
+    match *effect {
+        Effect::Arpeggio{ chord_offset_1, chord_offset_2 } => {
+            channel.base_period = channel.period;
+            channel.arpeggio_offsets[ 0 ] = chord_offset_1 as u32;
+            channel.arpeggio_offsets[ 1 ] = chord_offset_2 as u32;
+            channel.arpeggio_counter = 0;
+        }
+        Effect::SlideUp{ speed } => {
+            channel.note_change = -( speed as i32 ) * slide_unit;
+        }
+        Effect::SlideDown{ speed } => {
+            channel.note_change = speed as i32 * slide_unit;
+        }
+        Effect::TonePortamento{ speed } if kind == ModuleKind::Mod => {
+            channel.note_change = speed as i32;
+            channel.tone_portamento_speed = speed as u32;
+        }
+        Effect::TonePortamento{ speed } => {
+            if speed > 0 {
+                channel.tone_portamento_speed = speed as u32;
+            }
+            channel.tone_portamento_step = channel.tone_portamento_speed * 4;
+        }
+        Effect::TonePortamentoVolumeSlide{ up, down } => {
+            channel.volume_change = volume_slide( up, down );
+            match kind {
+                ModuleKind::Mod => channel.note_change = channel.tone_portamento_speed as i32,
+                _ => channel.tone_portamento_step = channel.tone_portamento_speed * 4,
+            }
+        }
+        Effect::Vibrato{ speed, amplitude } if kind == ModuleKind::Mod => {
+            channel.base_period = channel.period;
+            channel.vibrato_speed = speed as u32;
+            channel.vibrato_depth = amplitude as i32;
+        }
+        Effect::Vibrato{ speed, amplitude } | Effect::FineVibrato{ speed, amplitude } => {
+            // Depths are in 128ths of a period, a fine vibrato is four times shallower
+            let depth_scale = if matches!( effect, Effect::Vibrato{ .. } ) { 4 } else { 1 };
+            let ( old_speed, old_depth ) = channel.vibrato_memory;
+            let speed = if speed > 0 { speed as u32 } else { old_speed };
+            let depth = if amplitude > 0 { amplitude as i32 * depth_scale } else { old_depth };
+            channel.vibrato_memory = ( speed, depth );
+            if channel.vibrato_depth == 0 {
+                channel.base_period = channel.period;
+            }
+            channel.vibrato_speed = speed;
+            channel.vibrato_depth = depth;
+        }
+        Effect::VibratoVolumeSlide{ up, down } => {
+            channel.volume_change = volume_slide( up, down );
+            if kind != ModuleKind::Mod {
+                if channel.vibrato_depth == 0 {
+                    channel.base_period = channel.period;
+                }
+                ( channel.vibrato_speed, channel.vibrato_depth ) = channel.vibrato_memory;
+            }
+        }
+        Effect::Tremolo{ speed, amplitude } => {
+            if speed > 0 {
+                channel.tremolo_speed = speed as u32;
+            }
+            // the later trackers keep the last depth
+            if amplitude > 0 || kind == ModuleKind::Mod {
+                channel.tremolo_depth = amplitude as i32;
+            }
+        }
+        Effect::VolumeSlide{ up, down } => {
+            channel.volume_change = volume_slide( up, down );
+        }
+        Effect::SetVolume{ volume } => {
+            channel.volume = volume as f32;
+        }
+        Effect::SetPanning{ position } => channel.panning = Some( position ),
+        Effect::SetCoarsePanning{ position } => channel.panning = Some( position.min( 15 ) * 17 ),
+        Effect::FineSlideUp{ speed } => channel.period = fine_slide( kind, limits, channel.period, -( speed as i32 ) * slide_unit ),
+        Effect::FineSlideDown{ speed } => channel.period = fine_slide( kind, limits, channel.period, speed as i32 * slide_unit ),
+        Effect::ExtraFineSlideUp{ speed } => channel.period = fine_slide( kind, limits, channel.period, -( speed as i32 ) ),
+        Effect::ExtraFineSlideDown{ speed } => channel.period = fine_slide( kind, limits, channel.period, speed as i32 ),
+        Effect::FineVolumeSlideUp{ change } => channel.volume = ( channel.volume + change as f32 ).min( 64.0 ),
+        Effect::FineVolumeSlideDown{ change } => channel.volume = ( channel.volume - change as f32 ).max( 0.0 ),
+        Effect::SetVibratoWave{ wave } => channel.vibrato_wave = wave,
+        Effect::SetTremoloWave{ wave } => channel.tremolo_wave = wave,
+        Effect::RetriggerNote{ ticks } => channel.retrigger_ticks = ticks as u32,
+        Effect::RetriggerVolumeSlide{ volume_change, ticks } => {
+            channel.retrigger_ticks = ticks as u32;
+            channel.retrigger_volume_change = volume_change;
+        }
+        Effect::Tremor{ on, off } => {
+            channel.tremor_on = on as u32 + 1;
+            channel.tremor_off = off as u32 + 1;
+        }
+        Effect::NoteCut{ tick : 0 } => channel.volume = 0.0,
+        Effect::NoteCut{ tick } => channel.note_cut = Some( tick as u32 ),
+        Effect::PanningSlide{ left, right } => {
+            // Impulse Tracker slides in quarters of its panning range
+            let unit = if kind == ModuleKind::It { 4 } else { 1 };
+            channel.panning_change = if right != 0 { right as i32 * unit } else { -( left as i32 * unit ) };
+        }
+        Effect::KeyOff{ tick } => channel.key_off_tick = Some( tick as u32 ),
+        Effect::SetEnvelopePosition{ position } => {
+            channel.volume_envelope_tick = position as u32;
+            channel.panning_envelope_tick = position as u32;
+        }
+        Effect::SetFilterCutoff{ cutoff } => channel.filter_cutoff = cutoff.min( 127 ),
+        Effect::SetFilterResonance{ resonance } => channel.filter_resonance = resonance.min( 127 ),
+        _ => {}         // handled by play_note, or not supported by the player yet
+    }
+    if kind != ModuleKind::Mod && matches!( effect, Effect::FineSlideUp{ .. } | Effect::FineSlideDown{ .. } | Effect::ExtraFineSlideUp{ .. } | Effect::ExtraFineSlideDown{ .. } ) {
+        channel.base_period = channel.period;
+    }
+}
+
+/**
+ * A slide that happens once when the line is played
+ */
+fn fine_slide( kind : ModuleKind, limits : PeriodLimits, period : u32, change : i32 ) -> u32 {
+    match kind {
+        ModuleKind::Mod => change_note( period, change, limits ),
+        _ => slide_period( period, change ),
+    }
+}
+
+fn play_note(note: &Note, player_state: &mut PlayerState, channel_num: usize, song: &Song) {
+    let kind = song.format.kind;
+    let channel = &mut player_state.channels[ channel_num ];
+    let effect = match kind {
+        ModuleKind::Mod => note.effect,
+        ModuleKind::S3m => channel.recall_shared_argument( note.effect ),
+        ModuleKind::Xm => channel.recall_effect_memory( note.effect ),
+        ModuleKind::It => channel.recall_it_memory( note.effect ),
+    };
+    channel.start_line( kind, &effect, &note.volume_effect );
+    match effect {
+        // the whole note waits for its tick, see update_effects
+        Effect::NoteDelay{ ticks } if ticks > 0 => channel.delayed_note = Some( ( *note, ticks as u32 ) ),
+        _ => trigger_note( note, &effect, channel, channel_num, song, &mut player_state.voices, player_state.period_limits ),
+    }
+
+    match effect {
+        Effect::SetSpeed{ speed } => {
+            // Values from 32 up set the tempo instead of the speed, except in Scream Tracker and Impulse Tracker
+            if speed < 32 || matches!( kind, ModuleKind::S3m | ModuleKind::It ) {
+                player_state.song_speed = speed as u32;
+            } else {
+                player_state.song_bpm = speed as u32;
+                player_state.update_samples_per_vblank();
+            }
+        }
+        // lower values slide the tempo in later trackers, which is not supported
+        Effect::SetTempo{ bpm } if bpm >= 32 => {
+            player_state.song_bpm = bpm as u32;
+            player_state.update_samples_per_vblank();
+        }
+        Effect::PatternBreak{ next_pattern_pos } => {
+            player_state.next_pattern_pos = next_pattern_pos as i32;
+        }
+        Effect::PositionJump{ next_pattern } => {
+            if next_pattern as u32 <= player_state.song_pattern_position  {
+                player_state.has_looped = true;
+            }
+            player_state.next_position = next_pattern as i32;       
+        }
+        Effect::SetGlobalVolume{ volume } => player_state.global_volume = volume.min( 64 ),
+        Effect::GlobalVolumeSlide{ up, down } => player_state.global_volume_change = volume_slide( up, down ) as i32,
+        // the first delay on the line wins
+        Effect::PatternDelay{ lines } if player_state.pattern_delay == 0 => {
+            player_state.pattern_delay = lines as u32;
+        }
+        Effect::PatternLoop{ count } => {
+            let line = player_state.playing_line;
+            let channel = &mut player_state.channels[ channel_num ];
+            if count == 0 {
+                channel.loop_line = line;
+            } else {
+                channel.loop_count = if channel.loop_count == 0 { count as u32 } else { channel.loop_count - 1 };
+                if channel.loop_count > 0 {
+                    player_state.next_loop_line = Some( channel.loop_line );
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/**
+ * Volume change per tick for a volume slide. When both directions are given the slide goes up
+ */
+fn volume_slide( up : u8, down : u8 ) -> f32 {
+    if up != 0 { up as f32 } else { -( down as f32 ) }
+}
+
+
+fn play_line(song: &Song, player_state: &mut PlayerState ) {
+    // is a pattern break active
+    if player_state.next_pattern_pos != -1 {
+        player_state.song_pattern_position += 1;
+        player_state.current_line = player_state.next_pattern_pos as u32;
+        player_state.next_pattern_pos = -1;
+    } else if player_state.next_position != -1  {
+        player_state.song_pattern_position = player_state.next_position as u32;
+        player_state.current_line = 0;
+        player_state.next_position = -1;
+    }
+    // Going past the last position ends the song and starts it again
+    if player_state.song_pattern_position >= song.num_used_patterns {
+        player_state.song_has_ended = true;
+        player_state.song_pattern_position = 0;
+    }
+    // a break to a line the next pattern does not have starts it from the top
+    if player_state.current_line >= song.num_lines( player_state.song_pattern_position ) {
+        player_state.current_line = 0;
+    }
+
+    player_state.playing_pattern_position = player_state.song_pattern_position;
+    player_state.playing_line = player_state.current_line;
+    player_state.pattern_delay = 0;
+    player_state.global_volume_change = 0;
+    let line = player_state.get_song_line( song );
+    for ( channel_number, note ) in line.iter().enumerate() {
+        play_note(note, player_state, channel_number, song);
+    }
+
+    // A pattern loop goes back within the pattern and takes the place of any break or jump on the same line
+    if let Some( loop_line ) = player_state.next_loop_line.take() {
+        player_state.current_line = loop_line;
+        player_state.next_pattern_pos = -1;
+        player_state.next_position = -1;
+        return;
+    }
+    player_state.current_line += 1;
+    if player_state.current_line >= song.num_lines( player_state.song_pattern_position ) {
+        player_state.song_pattern_position += 1;
+        player_state.current_line = 0;
+        if player_state.song_pattern_position >= song.num_used_patterns {
+            player_state.song_has_ended = true;
+            player_state.song_pattern_position = 0;
+        }
+    }
+}
+
+/**
+ * The slides, vibrato and arpeggio of a mod channel. They run on every vblank, including the one that starts the next line
+ */
+fn update_mod_channel( channel : &mut ChannelInfo, limits : PeriodLimits ) {
+    channel.volume = ( channel.volume + channel.volume_change ).clamp( 0.0, 64.0 );
+
+    if channel.arpeggio_offsets[ 0] != 0 || channel.arpeggio_offsets[ 1 ] != 0 {
+        // periods a slide left between notes play the arpeggio from the nearest note
+        let index = FREQUENCY_TABLE.binary_search( &channel.base_period ).unwrap_or_else( | index | index.min( FREQUENCY_TABLE.len() - 1 ) ) as u32;
+        if channel.arpeggio_counter > 0 {
+            let note_offset  = ( index + channel.arpeggio_offsets[ channel.arpeggio_counter as usize]) as usize;
+            channel.period = limits.clamp( FREQUENCY_TABLE[ note_offset.saturating_sub( 1 ).min( FREQUENCY_TABLE.len() - 1 ) ] as i32 );
+        } else {
+            channel.period = channel.base_period;
+        }
+
+        channel.arpeggio_counter += 1;
+        if channel.arpeggio_counter >= 2 {
+            channel.arpeggio_counter = 0;
+        } 
+    }
+    if channel.vibrato_depth > 0 {
+        channel.period = limits.clamp( ( channel.base_period as i32 ) + ( waveform( channel.vibrato_wave, channel.vibrato_pos ) * channel.vibrato_depth ) / 32 );
+        channel.vibrato_pos += channel.vibrato_speed;
+    }
+    else if channel.note_change != 0 {
+        // changing note to a target
+        if channel.period_target != 0 {
+            if channel.period_target > channel.period {
+                channel.period = change_note( channel.period, channel.note_change, limits );
+                if channel.period >= channel.period_target {
+                    channel.period = channel.period_target;
+                    channel.period_target = 0;
+                    channel.note_change = 0;
+                }
+            } else {
+                channel.period = change_note( channel.period, -channel.note_change, limits );
+                if channel.period <= channel.period_target {
+                    channel.period = channel.period_target;
+                    channel.period_target = 0;
+                    channel.note_change = 0;
+                }
+            }
+        } else {
+            // or just moving it
+            channel.period = change_note( channel.period, channel.note_change, limits );
+        }
+    }
+}
+
+/**
+ * The slides, vibrato and arpeggio of the later trackers, which only run on the ticks after the first one of a line.
+ * Vibrato and arpeggio work from the base period, which slides keep up to date
+ */
+fn update_tracker_channel( channel : &mut ChannelInfo, tick : u32, linear : bool ) {
+    channel.volume = ( channel.volume + channel.volume_change ).clamp( 0.0, 64.0 );
+
+    if channel.tone_portamento_step > 0 && channel.period_target != 0 {
+        let step = channel.tone_portamento_step;
+        channel.base_period = if channel.base_period < channel.period_target {
+            ( channel.base_period + step ).min( channel.period_target )
+        } else {
+            channel.base_period.saturating_sub( step ).max( channel.period_target )
+        };
+        channel.period = channel.base_period;
+    } else if channel.note_change != 0 {
+        channel.base_period = slide_period( channel.base_period, channel.note_change );
+        channel.period = channel.base_period;
+    }
+
+    if channel.arpeggio_offsets != [ 0, 0 ] {
+        let semitones = match tick % 3 {
+            0 => 0,
+            1 => channel.arpeggio_offsets[ 0 ],
+            _ => channel.arpeggio_offsets[ 1 ],
+        };
+        channel.period = if linear {
+            channel.base_period.saturating_sub( semitones * 64 ).max( 1 )
+        } else {
+            ( channel.base_period as f32 * 2.0f32.powf( -( semitones as f32 ) / 12.0 ) ).round() as u32
+        };
+    }
+    if channel.vibrato_depth != 0 {
+        channel.period = slide_period( channel.base_period, waveform( channel.vibrato_wave, channel.vibrato_pos ) * channel.vibrato_depth / 128 );
+        channel.vibrato_pos += channel.vibrato_speed;
+    }
+}
+
+fn update_effects( song : &Song, player_state : &mut PlayerState ){
+    let kind = song.format.kind;
+    let vblank = player_state.current_vblank;
+    // Only mods run their slides on the vblank that starts the next line
+    if kind != ModuleKind::Mod && vblank >= player_state.line_vblanks() {
+        return;
+    }
+    let tick = vblank % player_state.speed().max( 1 );
+    if tick > 0 {
+        player_state.global_volume = ( player_state.global_volume as i32 + player_state.global_volume_change ).clamp( 0, 64 ) as u8;
+    }
+    for ( index, channel ) in player_state.channels.iter_mut().enumerate() {
+        if let Some( ( note, delay ) ) = channel.delayed_note {
+            if tick == delay {
+                channel.delayed_note = None;
+                trigger_note( &note, &note.effect, channel, index, song, &mut player_state.voices, player_state.period_limits );
+            }
+        }
+        if channel.sample_num == 0 {
+            continue;
+        }
+        match kind {
+            ModuleKind::Mod => update_mod_channel( channel, player_state.period_limits ),
+            _ if tick > 0 => update_tracker_channel( channel, tick, song.linear_periods ),
+            _ => ()
+        }
+        if tick == 0 {
+            continue;
+        }
+
+        if channel.panning_change != 0 {
+            let panning = channel.panning.unwrap_or( song.channel_panning( index ) ) as i32;
+            channel.panning = Some( ( panning + channel.panning_change ).clamp( 0, 255 ) as u8 );
+        }
+
+        if channel.note_cut == Some( tick ) {
+            channel.volume = 0.0;
+            channel.note_cut = None;
+        }
+        if channel.retrigger_ticks > 0 {
+            channel.retrigger_counter += 1;
+            if channel.retrigger_counter >= channel.retrigger_ticks {
+                channel.retrigger_counter = 0;
+                channel.sample_pos = 0.0;
+                channel.size = song.samples[ ( channel.sample_num - 1 ) as usize ].size;
+                channel.volume = retrigger_volume( channel.volume, channel.retrigger_volume_change );
+            }
+        }
+        if channel.tremolo_depth > 0 {
+            channel.tremolo_volume = ( waveform( channel.tremolo_wave, channel.tremolo_pos ) * channel.tremolo_depth / 64 ) as f32;
+            channel.tremolo_pos += channel.tremolo_speed;
+        }
+        if channel.tremor_on > 0 {
+            channel.tremor_silent = channel.tremor_counter >= channel.tremor_on;
+            channel.tremor_counter = ( channel.tremor_counter + 1 ) % ( channel.tremor_on + channel.tremor_off );
+        }
+    }
+}
+
+/**
+ * Move the envelopes, fadeout and vibrato of the instruments on. They run on every tick, after the line has started.
+ * Impulse Tracker channels work out their filters, and the voices left by new note actions are dropped once they
+ * have finished
+ */
+fn update_instruments( song : &Song, player_state : &mut PlayerState ) {
+    let kind = song.format.kind;
+    if song.instruments.is_empty() && kind != ModuleKind::It {
+        return;
+    }
+    let tick = player_state.current_vblank % player_state.speed().max( 1 );
+    let sample_rate = player_state.device_sample_rate;
+    let instrument_of = | channel : &ChannelInfo | ( channel.instrument as usize ).checked_sub( 1 ).and_then( | index | song.instruments.get( index ) );
+    for channel in &mut player_state.channels {
+        if let Some( instrument ) = instrument_of( channel ) {
+            if channel.key_off_tick == Some( tick ) {
+                key_off( channel, Some( instrument ), kind );
+            }
+            channel.update_instrument( instrument, song.linear_periods );
+        }
+        if kind == ModuleKind::It {
+            channel.update_filter( sample_rate );
+        }
+    }
+    player_state.voices.voices.retain_mut( | ( _, voice ) | match instrument_of( voice ) {
+        Some( instrument ) => {
+            voice.update_instrument( instrument, song.linear_periods );
+            voice.update_filter( sample_rate );
+            !voice.has_finished( instrument )
+        }
+        None => false
+    } );
+}
+
+/**
+ * Process one vblank: update the running effects and play the next line when it is due. Returns true if a line was played
+ */
+pub(crate) fn next_vblank( song : &Song, player_state : &mut PlayerState ) -> bool {
+    update_effects( song, player_state );
+
+    // Is it time to play a new note line
+    let new_line = player_state.current_vblank >= player_state.line_vblanks();
+    if new_line {
+        player_state.current_vblank = 0;
+        play_line( song, player_state );
+    }
+    update_instruments( song, player_state );
+    // apply on every vblank but only after the line has been processed
+    player_state.current_vblank += 1;
+    player_state.ticks_played += 1;
+    new_line
+}
+
+/**
+ * Selects how `next_sample_stems` splits the output into separate stems
+ */
+#[derive(Clone, Copy)]
+pub enum StemMode {
+    Channel,        // one stem per tracker channel
+    Sample,         // one stem per sample/instrument number
+}
+
+impl StemMode {
+    pub fn num_stems( &self, song : &Song ) -> usize {
+        match self {
+            StemMode::Channel => song.format.num_channels as usize,
+            StemMode::Sample => song.samples.len()
+        }
+    }
+}
+
+/**
+ * How long the song plays before it ends or loops, in seconds. Works through the lines without mixing anything
+ */
+pub fn song_duration( song : &Song ) -> f64 {
+    let mut player_state = PlayerState::with_options( song, &PlayerOptions::default() );
+    let mut seconds = 0.0;
+    // every line of every position, in case a jump keeps the song from ending
+    let max_lines = 128 * 64 * 16;
+    for _line in 0..max_lines {
+        play_line( song, &mut player_state );
+        // a vblank lasts 2.5 / bpm seconds, which is 1/50s at 125 bpm
+        seconds += player_state.line_vblanks() as f64 * 2.5 / player_state.bpm() as f64;
+        if player_state.song_has_ended || player_state.has_looped {
+            break;
+        }
+    }
+    seconds
+}
+
+pub fn next_sample(song: &Song, player_state: &mut PlayerState) -> (f32, f32) {
+    mix_sample( song, player_state, None )
+}
+
+/**
+ * Same as next_sample but also writes each channel's contribution into stems. The stems slice must hold
+ * mode.num_stems() entries. Summing all the stems gives the returned mix.
+ */
+pub fn next_sample_stems(song: &Song, player_state: &mut PlayerState, mode : StemMode, stems : &mut [(f32, f32)] ) -> (f32, f32) {
+    for stem in stems.iter_mut() {
+        *stem = ( 0.0, 0.0 );
+    }
+    let master_volume = player_state.master_volume;
+    let mix = mix_sample( song, player_state, Some( ( mode, stems ) ) );
+    for stem in stems.iter_mut() {
+        stem.0 *= master_volume;
+        stem.1 *= master_volume;
+    }
+    mix
+}
+
+fn mix_sample(song: &Song, player_state: &mut PlayerState, stems : Option<( StemMode, &mut [(f32, f32)] )> ) -> (f32, f32) {
+    let ( mut left, mut right ) = if player_state.paused { 
+        ( 0.0, 0.0 ) 
+    } else { 
+        mix_channels( song, player_state, stems ) 
+    };
+
+    // The preview voice is centered and not part of any stem
+    let preview = &mut player_state.preview;
+    if preview.size > 2 {
+        let preview_value = preview.next_value( &song.samples[ ( preview.sample_num - 1 ) as usize ], player_state.clock_ticks_per_device_sample / preview.period as f32 );
+        left += preview_value;
+        right += preview_value;
+    }
+    ( left * player_state.master_volume, right * player_state.master_volume )
+}
+
+/**
+ * Advance the song by one device sample and mix all the channels 
+ */
+fn mix_channels(song: &Song, player_state: &mut PlayerState, mut stems : Option<( StemMode, &mut [(f32, f32)] )> ) -> (f32, f32) {
+    let mut left = 0.0;
+    let mut right = 0.0;
+
+    // Have we reached a new vblank
+    if player_state.current_vblank_sample >= player_state.samples_per_vblank {
+        player_state.current_vblank_sample = 0;
+        next_vblank( song, player_state );
+    }
+    player_state.current_vblank_sample += 1;
+
+    let any_solo = player_state.channels.iter().any( | channel_info | channel_info.solo );
+    // later trackers scale everything by the global volume
+    let global_volume = player_state.global_volume as f32 / 64.0;
+    for channel_number in 0..player_state.channels.len() {
+        let step = player_state.sample_step( song, player_state.channels[ channel_number ].output_period() );
+        let channel_info: &mut ChannelInfo = &mut player_state.channels[channel_number];
+        if channel_info.size > 2 {
+            let current_sample: &Sample = &song.samples[(channel_info.sample_num - 1) as usize];
+            let channel_value = channel_info.next_value( current_sample, step ) * global_volume;
+
+            // Silenced channels still advance so they are in sync when they are switched back on
+            if channel_info.muted || ( any_solo && !channel_info.solo ) {
+                continue;
+            }
+
+            let ( left_value, right_value ) = channel_info.pan( channel_value, song.channel_panning( channel_number ) );
+            left += left_value;
+            right += right_value;
+
+            if let Some( ( mode, stems ) ) = &mut stems {
+                let stem_index = match mode {
+                    StemMode::Channel => channel_number,
+                    StemMode::Sample => ( channel_info.sample_num - 1 ) as usize
+                };
+                stems[ stem_index ].0 += left_value;
+                stems[ stem_index ].1 += right_value;
+            }
+        }
+    }
+
+    // the voices left by new note actions play along with the channel that started them
+    for voice_number in 0..player_state.voices.voices.len() {
+        let step = player_state.sample_step( song, player_state.voices.voices[ voice_number ].1.output_period() );
+        let ( channel_number, voice ) = &mut player_state.voices.voices[ voice_number ];
+        if voice.size <= 2 {
+            continue;
+        }
+        let channel_value = voice.next_value( &song.samples[ ( voice.sample_num - 1 ) as usize ], step ) * global_volume;
+        let host = &player_state.channels[ *channel_number ];
+        if host.muted || ( any_solo && !host.solo ) {
+            continue;
+        }
+        let ( left_value, right_value ) = voice.pan( channel_value, song.channel_panning( *channel_number ) );
+        left += left_value;
+        right += right_value;
+        if let Some( ( mode, stems ) ) = &mut stems {
+            let stem_index = match mode {
+                StemMode::Channel => *channel_number,
+                StemMode::Sample => ( voice.sample_num - 1 ) as usize
+            };
+            stems[ stem_index ].0 += left_value;
+            stems[ stem_index ].1 += right_value;
+        }
+    }
+    (left, right )
+}
+
+/**
+ * Settings for a Player. Channel numbers are zero based
+ */
+#[derive(Clone)]
+pub struct PlayerOptions {
+    pub sample_rate : u32,
+    pub muted_channels : Vec<usize>,
+    pub solo_channels : Vec<usize>,
+    pub period_limits : PeriodLimits,       // only mods are limited, the other formats have limits of their own
+}
+
+impl Default for PlayerOptions {
+    fn default() -> PlayerOptions {
+        PlayerOptions{ sample_rate : 48000, muted_channels : Vec::new(), solo_channels : Vec::new(), period_limits : PeriodLimits::default() }
+    }
+}
+
+/**
+ * Controls for a song that is playing. See Player::apply_command
+ */
+pub enum PlayerCommand{
+    PlayInstrument{ index : u8, period : u32 },     // play a sample on the preview voice. Sample 0 stops the preview
+    Pause,
+    Resume,
+    Stop,                                   // pause and go back to the start of the song
+    Restart,
+    NextPosition,
+    PreviousPosition,
+    SetSpeed{ speed : Option<u32> },        // None goes back to the speed set by the song
+    SetBpm{ bpm : Option<u32> },
+    SetVolume{ volume : f32 },
+    ToggleMute{ channel : usize },
+    ToggleSolo{ channel : usize },
+}
+
+/**
+ * Plays a song. The player shares the song so it can be moved to an audio thread while the song is used elsewhere
+ */
+pub struct Player {
+    song : Arc<Song>,
+    state : PlayerState,
+}
+
+impl Player {
+    pub fn new( song : Arc<Song>, options : &PlayerOptions ) -> Player {
+        let state = PlayerState::with_options( &song, options );
+        Player{ song, state }
+    }
+
+    pub fn song( &self ) -> &Arc<Song> {
+        &self.song
+    }
+
+    pub fn state( &self ) -> &PlayerState {
+        &self.state
+    }
+
+    pub fn state_mut( &mut self ) -> &mut PlayerState {
+        &mut self.state
+    }
+
+    pub fn next_sample( &mut self ) -> ( f32, f32 ) {
+        next_sample( &self.song, &mut self.state )
+    }
+
+    pub fn next_sample_stems( &mut self, mode : StemMode, stems : &mut [(f32, f32)] ) -> ( f32, f32 ) {
+        next_sample_stems( &self.song, &mut self.state, mode, stems )
+    }
+
+    /**
+     * True once the song has played to the end or jumped back to an earlier position
+     */
+    pub fn has_finished( &self ) -> bool {
+        self.state.song_has_ended || self.state.has_looped
+    }
+
+    pub fn status( &self ) -> PlayerStatus {
+        self.state.status()
+    }
+
+    /**
+     * Apply a command to the player. Does not block or allocate so it can be called on the audio thread between buffers
+     */
+    pub fn apply_command( &mut self, command : PlayerCommand ) {
+        let player_state = &mut self.state;
+        match command {
+            PlayerCommand::PlayInstrument{ index, period } => player_state.play_preview( &self.song, index, period ),
+            PlayerCommand::Pause => player_state.set_paused( true ),
+            PlayerCommand::Resume => player_state.set_paused( false ),
+            PlayerCommand::Stop => {
+                player_state.set_paused( true );
+                player_state.restart();
+            }
+            PlayerCommand::Restart => {
+                player_state.restart();
+                player_state.set_paused( false );
+            }
+            PlayerCommand::NextPosition => {
+                let position = player_state.playing_row().0 + 1;
+                player_state.jump_to_position( &self.song, position );
+            }
+            PlayerCommand::PreviousPosition => {
+                let position = player_state.playing_row().0.saturating_sub( 1 );
+                player_state.jump_to_position( &self.song, position );
+            }
+            PlayerCommand::SetSpeed{ speed } => player_state.set_speed_override( speed ),
+            PlayerCommand::SetBpm{ bpm } => player_state.set_bpm_override( bpm ),
+            PlayerCommand::SetVolume{ volume } => player_state.set_master_volume( volume ),
+            PlayerCommand::ToggleMute{ channel } => {
+                if channel < player_state.num_channels() {
+                    let muted = !player_state.is_channel_muted( channel );
+                    player_state.set_channel_muted( channel, muted );
+                }
+            }
+            PlayerCommand::ToggleSolo{ channel } => {
+                if channel < player_state.num_channels() {
+                    let solo = !player_state.is_channel_solo( channel );
+                    player_state.set_channel_solo( channel, solo );
+                }
+            }
+        }
+    }
+}
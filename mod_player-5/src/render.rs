@@ -0,0 +1,49 @@
+use crate::player::{Player, StemMode};
+use crate::sink::{AudioSink, SinkError, WavSink};
+
+/**
+ * Write the song into a wav file. Rendering stops when the song ends or loops
+ */
+pub fn write_song_to_wav( file_name : &str, player : Player ) -> Result<(), SinkError> {
+    let mut sink = WavSink::create( file_name, player.state().sample_rate() )?;
+    sink.play( Box::new( player ) )
+}
+
+/**
+ * Write the song into separate wav files, one per channel or one per sample depending on the mode. 
+ * All stems are rendered in a single pass through the song. Samples that are never used still get a ( silent ) file
+ */
+pub fn write_song_stems_to_wav( base_name : &str, player : &mut Player, mode : StemMode ) -> Result<(), SinkError> {
+    let spec = hound::WavSpec {
+        channels: 2,
+        sample_rate: player.state().sample_rate(),
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+
+    let num_stems = mode.num_stems( player.song() );
+    let mut writers = Vec::new();
+    for stem in 0..num_stems {
+        let file_name = match mode {
+            StemMode::Channel => format!( "{}_channel{:02}.wav", base_name, stem + 1 ),
+            StemMode::Sample => format!( "{}_sample{:02}.wav", base_name, stem + 1 ),
+        };
+        writers.push( hound::WavWriter::create( file_name, spec)? );
+    }
+
+    let mut stems = vec![ ( 0.0, 0.0 ); num_stems ];
+    loop {
+        player.next_sample_stems( mode, &mut stems );
+        for ( writer, ( left, right ) ) in writers.iter_mut().zip( stems.iter() ) {
+            writer.write_sample( *left )?;
+            writer.write_sample( *right )?;
+        }
+        if player.has_finished() { 
+            break;
+        }
+    }
+    for writer in writers {
+        writer.finalize()?;
+    }
+    Ok( () )
+}
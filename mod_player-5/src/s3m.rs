@@ -0,0 +1,333 @@
+use std::fs;
+
+use crate::song::{Song, Sample, Pattern, Note, Effect, Key, FormatDescription, ModuleKind, LoadError, decode_name};
+
+const HEADER_SIZE : usize = 0x60;
+const INSTRUMENT_SIZE : usize = 0x50;
+const ROWS_PER_PATTERN : usize = 64;
+const ORDER_SKIP : u8 = 254;            // "+++" in the order list, skipped when playing
+const ORDER_END : u8 = 255;
+const NOTE_CUT : u8 = 254;              // "^^" in the note column
+const NOTE_EMPTY : u8 = 255;
+
+fn word( data : &[u8], offset : usize ) -> u16 {
+    u16::from_le_bytes( [ data[ offset ], data[ offset + 1 ] ] )
+}
+
+fn double_word( data : &[u8], offset : usize ) -> u32 {
+    u32::from_le_bytes( [ data[ offset ], data[ offset + 1 ], data[ offset + 2 ], data[ offset + 3 ] ] )
+}
+
+fn format_error( message : String ) -> LoadError {
+    LoadError::Format( message )
+}
+
+/**
+ * Volume slide argument of D, which also holds the fine slides: DxF slides up and DFx down once per line
+ */
+fn volume_slide( argument : u8 ) -> Effect {
+    let high = argument >> 4;
+    let low = argument & 0x0f;
+    match ( high, low ) {
+        ( 0x0f, 0 ) => Effect::VolumeSlide{ up : high, down : low },
+        ( 1..=0x0f, 0x0f ) => Effect::FineVolumeSlideUp{ change : high },
+        ( 0x0f, _ ) => Effect::FineVolumeSlideDown{ change : low },
+        _ => Effect::VolumeSlide{ up : high, down : low }
+    }
+}
+
+/**
+ * Portamento argument of E and F. Fx and Ex in the high nibble make fine and extra fine slides
+ */
+fn pitch_slide( argument : u8, up : bool ) -> Effect {
+    let low = argument & 0x0f;
+    match ( argument >> 4, up ) {
+        ( 0x0f, true ) => Effect::FineSlideUp{ speed : low },
+        ( 0x0f, false ) => Effect::FineSlideDown{ speed : low },
+        ( 0x0e, true ) => Effect::ExtraFineSlideUp{ speed : low },
+        ( 0x0e, false ) => Effect::ExtraFineSlideDown{ speed : low },
+        ( _, true ) => Effect::SlideUp{ speed : argument },
+        ( _, false ) => Effect::SlideDown{ speed : argument },
+    }
+}
+
+/**
+ * Decode an effect letter, 1 for A up to 26 for Z, and its argument. Arguments of 0 are kept as they are, for the
+ * effects that share their last argument the player puts that in when it plays the note
+ */
+pub(crate) fn s3m_effect( command : u8, argument : u8 ) -> Effect {
+    let high = argument >> 4;
+    let low = argument & 0x0f;
+    match command {
+        1 if argument > 0 => Effect::SetSpeed{ speed : argument },
+        2 => Effect::PositionJump{ next_pattern : argument },
+        3 => Effect::PatternBreak{ next_pattern_pos : high * 10 + low },
+        4 => volume_slide( argument ),
+        5 => pitch_slide( argument, false ),
+        6 => pitch_slide( argument, true ),
+        7 => Effect::TonePortamento{ speed : argument },
+        8 => Effect::Vibrato{ speed : high, amplitude : low },
+        9 => Effect::Tremor{ on : high, off : low },
+        10 => Effect::Arpeggio{ chord_offset_1 : high, chord_offset_2 : low },
+        11 => Effect::VibratoVolumeSlide{ up : high, down : low },
+        12 => Effect::TonePortamentoVolumeSlide{ up : high, down : low },
+        15 => Effect::SetSampleOffset{ offset : argument },
+        17 => Effect::RetriggerVolumeSlide{ volume_change : high, ticks : low },
+        18 => Effect::Tremolo{ speed : high, amplitude : low },
+        19 => match high {
+            0 => Effect::SetFilter{ value : low },
+            1 => Effect::SetGlissando{ value : low },
+            2 => Effect::SetFineTune{ fine_tune : low },
+            3 => Effect::SetVibratoWave{ wave : low },
+            4 => Effect::SetTremoloWave{ wave : low },
+            8 => Effect::SetCoarsePanning{ position : low },
+            0x0b => Effect::PatternLoop{ count : low },
+            0x0c => Effect::NoteCut{ tick : low },
+            0x0d => Effect::NoteDelay{ ticks : low },
+            0x0e => Effect::PatternDelay{ lines : low },
+            0x0f => Effect::InvertLoop{ speed : low },
+            _ => Effect::None
+        },
+        20 => Effect::SetTempo{ bpm : argument },
+        21 => Effect::FineVibrato{ speed : high, amplitude : low },
+        22 => Effect::SetGlobalVolume{ volume : argument },
+        // 0 - 80 from left to right, A4 is surround which plays in the middle here
+        24 if argument <= 0x80 => Effect::SetPanning{ position : ( argument as u32 * 255 / 0x80 ) as u8 },
+        24 if argument == 0xa4 => Effect::SetPanning{ position : 128 },
+        _ => Effect::None
+    }
+}
+
+/**
+ * Letter and argument of the effects that share the last non zero argument in Scream Tracker: D, E, F, I, J, K, L, Q and R.
+ * An argument of 0 repeats the last one, even if it was given to another of these effects
+ */
+pub(crate) fn shared_argument( effect : &Effect ) -> Option<( u8, u8 )> {
+    let nibbles = | high : u8, low : u8 | ( high << 4 ) | ( low & 0x0f );
+    match *effect {
+        Effect::VolumeSlide{ up, down } => Some( ( 4, nibbles( up, down ) ) ),
+        Effect::FineVolumeSlideUp{ change } => Some( ( 4, nibbles( change, 0x0f ) ) ),
+        Effect::FineVolumeSlideDown{ change } => Some( ( 4, nibbles( 0x0f, change ) ) ),
+        Effect::SlideDown{ speed } => Some( ( 5, speed ) ),
+        Effect::FineSlideDown{ speed } => Some( ( 5, nibbles( 0x0f, speed ) ) ),
+        Effect::ExtraFineSlideDown{ speed } => Some( ( 5, nibbles( 0x0e, speed ) ) ),
+        Effect::SlideUp{ speed } => Some( ( 6, speed ) ),
+        Effect::FineSlideUp{ speed } => Some( ( 6, nibbles( 0x0f, speed ) ) ),
+        Effect::ExtraFineSlideUp{ speed } => Some( ( 6, nibbles( 0x0e, speed ) ) ),
+        Effect::Tremor{ on, off } => Some( ( 9, nibbles( on, off ) ) ),
+        Effect::Arpeggio{ chord_offset_1, chord_offset_2 } => Some( ( 10, nibbles( chord_offset_1, chord_offset_2 ) ) ),
+        Effect::VibratoVolumeSlide{ up, down } => Some( ( 11, nibbles( up, down ) ) ),
+        Effect::TonePortamentoVolumeSlide{ up, down } => Some( ( 12, nibbles( up, down ) ) ),
+        Effect::RetriggerVolumeSlide{ volume_change, ticks } => Some( ( 17, nibbles( volume_change, ticks ) ) ),
+        Effect::Tremolo{ speed, amplitude } => Some( ( 18, nibbles( speed, amplitude ) ) ),
+        _ => None
+    }
+}
+
+fn read_key( value : u8 ) -> Key {
+    match value {
+        NOTE_EMPTY => Key::None,
+        NOTE_CUT => Key::Cut,
+        _ if value & 0x0f < 12 && value >> 4 < 10 => Key::Note( ( value >> 4 ) * 12 + ( value & 0x0f ) ),
+        _ => Key::None
+    }
+}
+
+/**
+ * Read an instrument header and its sample data. AdLib instruments and empty slots become empty samples. Stereo
+ * samples are mixed down and 16 bit ones reduced to the 8 bits the player mixes at
+ */
+fn read_sample( file_data : &[u8], offset : usize, signed : bool ) -> Result<Sample, LoadError> {
+    let header = file_data.get( offset..offset + INSTRUMENT_SIZE ).ok_or_else( || format_error( format!( "Instrument header at {} is past the end of the file", offset ) ) )?;
+    let name = decode_name( &header[ 0x30..0x4c ] );
+    if header[ 0 ] != 1 {
+        return Ok( Sample::from_data( &name, Vec::new() ) );
+    }
+    if header[ 0x1e ] != 0 {
+        return Err( format_error( format!( "Sample {} is packed, which is not supported", name.trim_end_matches( '\0' ) ) ) );
+    }
+    let data_offset = ( ( header[ 0x0d ] as usize ) << 16 | word( header, 0x0e ) as usize ) * 16;
+    let flags = header[ 0x1f ];
+    let channels = if flags & 2 != 0 { 2 } else { 1 };
+    let bytes_per_value = if flags & 4 != 0 { 2 } else { 1 };
+
+    // Files that are cut short keep the part of the sample that is there, and silence for a missing right channel
+    let stored_length = double_word( header, 0x10 ) as usize;
+    let length = stored_length.min( file_data.len().saturating_sub( data_offset ) / bytes_per_value );
+    let value = | index : usize | -> i32 {
+        let position = data_offset + index * bytes_per_value;
+        let raw = match bytes_per_value {
+            2 => file_data.get( position..position + 2 ).map( | bytes | word( bytes, 0 ) as i32 ),
+            _ => file_data.get( position ).map( | byte | ( *byte as i32 ) << 8 )
+        };
+        match raw {
+            Some( raw ) if signed => raw as i16 as i32,
+            Some( raw ) => raw - 0x8000,
+            None => 0
+        }
+    };
+    let data = ( 0..length ).map( | index | {
+        let sum : i32 = ( 0..channels ).map( | channel | value( channel * stored_length + index ) ).sum();
+        ( ( sum / channels as i32 + 0x80 ) >> 8 ).clamp( -128, 127 ) as i8
+    } ).collect();
+
+    let mut sample = Sample::from_data( &name, data );
+    sample.volume = header[ 0x1c ].min( 64 );
+    sample.c2_speed = match double_word( header, 0x20 ) {
+        0 => sample.c2_speed,
+        c2_speed => c2_speed
+    };
+    let loop_start = double_word( header, 0x14 );
+    let loop_end = double_word( header, 0x18 ).min( sample.size );
+    if flags & 1 != 0 && loop_start < loop_end {
+        sample.repeat_offset = loop_start;
+        sample.repeat_size = loop_end - loop_start;
+    }
+    Ok( sample )
+}
+
+/**
+ * Unpack a pattern. Each row is a list of channel entries ended by a zero byte. The notes of channels that are not
+ * played are dropped, and so is anything after the end of the file
+ */
+fn read_pattern( file_data : &[u8], offset : usize, channel_map : &[Option<usize>], num_channels : u32, num_samples : usize ) -> Pattern {
+    let mut pattern = Pattern::with_channels( num_channels );
+    if offset == 0 {
+        return pattern;
+    }
+    let mut position = offset + 2;
+    let mut row = 0;
+    while row < ROWS_PER_PATTERN {
+        let flags = match file_data.get( position ) {
+            Some( flags ) => *flags,
+            None => break
+        };
+        position += 1;
+        if flags == 0 {
+            row += 1;
+            continue;
+        }
+        let size = ( flags & 0x20 != 0 ) as usize * 2 + ( flags & 0x40 != 0 ) as usize + ( flags & 0x80 != 0 ) as usize * 2;
+        let fields = match file_data.get( position..position + size ) {
+            Some( fields ) => fields,
+            None => break
+        };
+        position += size;
+
+        let mut fields = fields.iter().copied();
+        let mut note = Note::with_key( 0, Key::None, Effect::None, Effect::None );
+        if flags & 0x20 != 0 {
+            note.key = read_key( fields.next().unwrap_or( NOTE_EMPTY ) );
+            note.sample_number = fields.next().filter( | sample | ( *sample as usize ) <= num_samples ).unwrap_or( 0 );
+        }
+        if flags & 0x40 != 0 {
+            if let Some( volume @ 0..=64 ) = fields.next() {
+                note.volume_effect = Effect::SetVolume{ volume };
+            }
+        }
+        if flags & 0x80 != 0 {
+            let command = fields.next().unwrap_or( 0 );
+            note.effect = s3m_effect( command, fields.next().unwrap_or( 0 ) );
+        }
+        if let Some( channel ) = channel_map[ ( flags & 0x1f ) as usize ] {
+            pattern.lines[ row ][ channel ] = note;
+        }
+    }
+    pattern
+}
+
+pub fn read_s3m_file( file_name : &str ) -> Result<Song, LoadError> {
+    let file_data : Vec<u8> = fs::read( file_name )?;
+    read_s3m_data( &file_data )
+}
+
+/**
+ * Parse a Scream Tracker 3 module that has already been loaded into memory. Only the PCM channels that are switched
+ * on become channels of the song. Position jumps are renumbered so they still land on the same pattern once the
+ * "+++" markers have been taken out of the order list
+ */
+pub fn read_s3m_data( file_data : &[u8] ) -> Result<Song, LoadError> {
+    if file_data.len() < HEADER_SIZE || &file_data[ 0x2c..0x30 ] != b"SCRM" {
+        return Err( format_error( String::from( "Not a Scream Tracker 3 module" ) ) );
+    }
+    let num_orders = word( file_data, 0x20 ) as usize;
+    let num_instruments = word( file_data, 0x22 ) as usize;
+    let num_patterns = word( file_data, 0x24 ) as usize;
+    let signed_samples = word( file_data, 0x2a ) == 1;
+    let stereo = file_data[ 0x33 ] & 0x80 != 0;
+    let pointers_offset = HEADER_SIZE + num_orders;
+    let panning_offset = pointers_offset + 2 * ( num_instruments + num_patterns );
+    if file_data.len() < panning_offset {
+        return Err( format_error( format!( "File is too short for {} orders, {} instruments and {} patterns", num_orders, num_instruments, num_patterns ) ) );
+    }
+    if num_patterns > 256 {
+        return Err( format_error( format!( "The song has {} patterns, the player handles 256", num_patterns ) ) );
+    }
+
+    // Channels 0 - 7 are the left PCM channels and 8 - 15 the right ones. Anything else is AdLib or switched off
+    let mut channel_map = [ None; 32 ];
+    let mut channel_panning = Vec::new();
+    for ( index, setting ) in file_data[ 0x40..0x60 ].iter().enumerate() {
+        if *setting < 16 {
+            channel_map[ index ] = Some( channel_panning.len() );
+            channel_panning.push( match ( stereo, *setting < 8 ) {
+                ( false, _ ) => 128,
+                ( true, true ) => 0x03 * 17,
+                ( true, false ) => 0x0c * 17,
+            } );
+        }
+    }
+    if channel_panning.is_empty() {
+        return Err( format_error( String::from( "The song has no PCM channels" ) ) );
+    }
+    if file_data[ 0x35 ] == 252 {
+        if let Some( panning ) = file_data.get( panning_offset..panning_offset + 32 ) {
+            for ( index, value ) in panning.iter().enumerate() {
+                if let ( Some( channel ), true ) = ( channel_map[ index ], value & 0x20 != 0 ) {
+                    channel_panning[ channel ] = ( value & 0x0f ) * 17;
+                }
+            }
+        }
+    }
+
+    let mut pattern_table = Vec::new();
+    let mut order_positions = Vec::new();       // the position in the pattern table of each entry of the order list
+    for order in &file_data[ HEADER_SIZE..pointers_offset ] {
+        order_positions.push( pattern_table.len() );
+        match *order {
+            ORDER_END => break,
+            ORDER_SKIP => (),
+            pattern if pattern as usize >= num_patterns => return Err( format_error( format!( "Order list refers to pattern {} but the file only has {}", pattern, num_patterns ) ) ),
+            pattern => pattern_table.push( pattern ),
+        }
+    }
+    if pattern_table.is_empty() {
+        return Err( format_error( String::from( "The order list is empty" ) ) );
+    }
+
+    let pointer = | index : usize | word( file_data, pointers_offset + 2 * index ) as usize * 16;
+    let samples = ( 0..num_instruments ).map( | index | read_sample( file_data, pointer( index ), signed_samples ) ).collect::<Result<Vec<Sample>, LoadError>>()?;
+    let num_channels = channel_panning.len() as u32;
+    let mut patterns : Vec<Pattern> = ( 0..num_patterns ).map( | index | read_pattern( file_data, pointer( num_instruments + index ), &channel_map, num_channels, samples.len() ) ).collect();
+    for note in patterns.iter_mut().flat_map( | pattern | pattern.lines.iter_mut().flatten() ) {
+        if let Effect::PositionJump{ next_pattern } = &mut note.effect {
+            *next_pattern = order_positions.get( *next_pattern as usize ).copied().unwrap_or( pattern_table.len() ).min( 255 ) as u8;
+        }
+    }
+
+    Ok( Song {
+        name : decode_name( &file_data[ 0..28 ] ),
+        format : FormatDescription{ num_channels, num_samples : samples.len() as u32, has_tag : true, tag : Some( *b"SCRM" ), kind : ModuleKind::S3m },
+        samples,
+        patterns,
+        num_used_patterns : pattern_table.len() as u32,
+        pattern_table,
+        end_position : 0,
+        channel_panning,
+        global_volume : file_data[ 0x30 ].min( 64 ),
+        initial_speed : match file_data[ 0x31 ] { 0 | 255 => 6, speed => speed as u32 },
+        initial_bpm : match file_data[ 0x32 ] { 0..=32 => 125, bpm => bpm as u32 },
+        instruments : Vec::new(),
+        linear_periods : false,
+    } )
+}
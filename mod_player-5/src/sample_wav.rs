@@ -0,0 +1,324 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::player::CLOCK_TICKS_PERS_SECOND;
+use crate::sink::Dither;
+use crate::song::{Song, Sample, LoadError};
+use crate::textout::note_period;
+
+const MIDI_UNITY_NOTE : u32 = 60;       // C-3 is written as middle C
+const MAX_SAMPLE_SIZE : usize = 0xffff * 2;    // sample lengths are stored in words
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SampleBits {
+    Eight,              // unsigned 8 bit as wav stores it, the data is unchanged
+    Sixteen,            // signed 16 bit, each value shifted up by 8 bits
+}
+
+/**
+ * The rate that plays a sample at its own pitch when it is played as C-3, ignoring finetune
+ */
+pub fn c3_sample_rate() -> u32 {
+    note_sample_rate( "C-3" ).expect( "C-3 is in the note table" )
+}
+
+/**
+ * The rate the player steps through a sample when it plays the note, for example "A-2"
+ */
+pub fn note_sample_rate( note_name : &str ) -> Option<u32> {
+    note_period( note_name ).map( | period | ( CLOCK_TICKS_PERS_SECOND / period as f32 ).round() as u32 )
+}
+
+/**
+ * Signed finetune in 1/8th semitones from the nibble stored in the file
+ */
+fn signed_fine_tune( fine_tune : u8 ) -> i32 {
+    ( ( fine_tune << 4 ) as i8 >> 4 ) as i32
+}
+
+/**
+ * The loop as first and last sample frame. Mod files use a two byte loop for samples that don't loop
+ */
+fn sample_loop( sample : &Sample ) -> Option<( u32, u32 )> {
+    let length = sample.samples.len() as u32;
+    if sample.repeat_size <= 2 || sample.repeat_offset >= length {
+        return None;
+    }
+    let end = ( sample.repeat_offset + sample.repeat_size ).min( length );
+    Some( ( sample.repeat_offset, end - 1 ) )
+}
+
+/**
+ * The smpl chunk. A sample with finetune played at C-3 sounds finetune / 8 semitones away from the sample as recorded,
+ * so the unity note is moved the other way. The pitch fraction is in 1/2^32 semitones above the unity note
+ */
+fn smpl_chunk( sample : &Sample, sample_rate : u32 ) -> Vec<u8> {
+    let eighths = MIDI_UNITY_NOTE as i32 * 8 - signed_fine_tune( sample.fine_tune );
+    let unity_note = eighths.div_euclid( 8 ) as u32;
+    let pitch_fraction = ( eighths.rem_euclid( 8 ) as u64 * ( 1u64 << 32 ) / 8 ) as u32;
+    let sample_loop = sample_loop( sample );
+
+    let mut chunk = Vec::new();
+    let mut put = | value : u32 | chunk.extend_from_slice( &value.to_le_bytes() );
+    put( 0 );                                   // manufacturer
+    put( 0 );                                   // product
+    put( 1_000_000_000 / sample_rate );         // nanoseconds per sample
+    put( unity_note );
+    put( pitch_fraction );
+    put( 0 );                                   // smpte format
+    put( 0 );                                   // smpte offset
+    put( sample_loop.is_some() as u32 );
+    put( 0 );                                   // sampler data
+    if let Some( ( start, end ) ) = sample_loop {
+        put( 0 );                               // cue point
+        put( 0 );                               // forward loop
+        put( start );
+        put( end );
+        put( 0 );                               // fraction
+        put( 0 );                               // loop forever
+    }
+    chunk
+}
+
+fn write_chunk<W : Write>( writer : &mut W, id : &[u8; 4], data : &[u8] ) -> io::Result<()> {
+    writer.write_all( id )?;
+    writer.write_all( &( data.len() as u32 ).to_le_bytes() )?;
+    writer.write_all( data )?;
+    if data.len() % 2 == 1 {
+        writer.write_all( &[ 0 ] )?;
+    }
+    Ok( () )
+}
+
+/**
+ * Write a sample as a mono wav file at the C-3 rate with its loop and finetune in a smpl chunk.
+ * The file is written by hand as hound has no way to add the smpl chunk
+ */
+pub fn write_sample_wav<W : Write>( sample : &Sample, bits : SampleBits, writer : &mut W ) -> io::Result<()> {
+    let sample_rate = c3_sample_rate();
+    let bytes_per_sample : u16 = match bits { SampleBits::Eight => 1, SampleBits::Sixteen => 2 };
+
+    let mut format = Vec::new();
+    format.extend_from_slice( &1u16.to_le_bytes() );                // pcm
+    format.extend_from_slice( &1u16.to_le_bytes() );                // mono
+    format.extend_from_slice( &sample_rate.to_le_bytes() );
+    format.extend_from_slice( &( sample_rate * bytes_per_sample as u32 ).to_le_bytes() );
+    format.extend_from_slice( &bytes_per_sample.to_le_bytes() );
+    format.extend_from_slice( &( bytes_per_sample * 8 ).to_le_bytes() );
+
+    let data : Vec<u8> = match bits {
+        SampleBits::Eight => sample.samples.iter().map( | value | ( *value as u8 ) ^ 0x80 ).collect(),
+        SampleBits::Sixteen => sample.samples.iter().flat_map( | value | ( ( *value as i16 ) << 8 ).to_le_bytes() ).collect()
+    };
+    let smpl = smpl_chunk( sample, sample_rate );
+
+    let padded = | length : usize | 8 + length + length % 2;
+    let riff_size = 4 + padded( format.len() ) + padded( data.len() ) + padded( smpl.len() );
+    writer.write_all( b"RIFF" )?;
+    writer.write_all( &( riff_size as u32 ).to_le_bytes() )?;
+    writer.write_all( b"WAVE" )?;
+    write_chunk( writer, b"fmt ", &format )?;
+    write_chunk( writer, b"data", &data )?;
+    write_chunk( writer, b"smpl", &smpl )
+}
+
+/**
+ * File name for a sample: the sample number followed by the name with anything but letters, digits and dashes
+ * turned into underscores. The number keeps samples with the same name apart
+ */
+pub fn sample_file_name( sample_number : usize, sample : &Sample ) -> String {
+    let mut name = String::new();
+    for character in sample.name.trim_end_matches( '\0' ).chars() {
+        if character.is_ascii_alphanumeric() || character == '-' {
+            name.push( character );
+        } else if !name.ends_with( '_' ) {
+            name.push( '_' );
+        }
+    }
+    let name = name.trim_matches( '_' );
+    if name.is_empty() {
+        format!( "{:02}.wav", sample_number )
+    } else {
+        format!( "{:02}_{}.wav", sample_number, name )
+    }
+}
+
+/**
+ * Write every sample that has data into the directory, creating it if needed. Returns the files written
+ */
+pub fn export_samples( song : &Song, directory : &Path, bits : SampleBits ) -> io::Result<Vec<PathBuf>> {
+    fs::create_dir_all( directory )?;
+    let mut files = Vec::new();
+    for ( index, sample ) in song.samples.iter().enumerate().filter( | ( _, sample ) | !sample.samples.is_empty() ) {
+        let path = directory.join( sample_file_name( index + 1, sample ) );
+        let mut writer = io::BufWriter::new( fs::File::create( &path )? );
+        write_sample_wav( sample, bits, &mut writer )?;
+        writer.flush()?;
+        files.push( path );
+    }
+    Ok( files )
+}
+
+/**
+ * How a wav file is turned into a sample
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SampleImportOptions {
+    pub sample_rate : u32,      // the file is resampled so that it plays at this rate, see note_sample_rate
+    pub dither : bool,          // noise shaped dither when converting to 8 bits, otherwise values are rounded
+}
+
+impl Default for SampleImportOptions {
+    fn default() -> SampleImportOptions {
+        SampleImportOptions{ sample_rate : c3_sample_rate(), dither : false }
+    }
+}
+
+struct WavFormat {
+    channels : usize,
+    sample_rate : u32,
+    bits : u16,
+    float : bool,
+}
+
+fn read_u16( data : &[u8], offset : usize ) -> u16 {
+    u16::from_le_bytes( [ data[ offset ], data[ offset + 1 ] ] )
+}
+
+fn read_u32( data : &[u8], offset : usize ) -> u32 {
+    u32::from_le_bytes( [ data[ offset ], data[ offset + 1 ], data[ offset + 2 ], data[ offset + 3 ] ] )
+}
+
+/**
+ * A chunk of a RIFF file as id and body
+ */
+type Chunk<'a> = ( &'a [u8], &'a [u8] );
+
+fn wav_chunks( data : &[u8] ) -> Result<Vec<Chunk<'_>>, LoadError> {
+    if data.len() < 12 || &data[ 0..4 ] != b"RIFF" || &data[ 8..12 ] != b"WAVE" {
+        return Err( LoadError::Format( String::from( "Not a wav file" ) ) );
+    }
+    let mut chunks = Vec::new();
+    let mut position = 12;
+    while position + 8 <= data.len() {
+        let size = read_u32( data, position + 4 ) as usize;
+        let body_end = ( position + 8 ).saturating_add( size ).min( data.len() );     // files cut short keep what they have
+        chunks.push( ( &data[ position..position + 4 ], &data[ position + 8..body_end ] ) );
+        position = body_end + size % 2;
+    }
+    Ok( chunks )
+}
+
+fn read_format( chunk : &[u8] ) -> Result<WavFormat, LoadError> {
+    if chunk.len() < 16 {
+        return Err( LoadError::Format( String::from( "The fmt chunk is too short" ) ) );
+    }
+    let mut format_tag = read_u16( chunk, 0 );
+    if format_tag == 0xfffe && chunk.len() >= 26 {
+        format_tag = read_u16( chunk, 24 );         // extensible files keep the real format at the start of the sub format guid
+    }
+    let format = WavFormat {
+        channels : read_u16( chunk, 2 ) as usize,
+        sample_rate : read_u32( chunk, 4 ),
+        bits : read_u16( chunk, 14 ),
+        float : format_tag == 3,
+    };
+    let supported = matches!( ( format_tag, format.bits ), ( 1, 8 ) | ( 1, 16 ) | ( 1, 24 ) | ( 1, 32 ) | ( 3, 32 ) | ( 3, 64 ) );
+    if !supported || format.channels == 0 || format.sample_rate == 0 {
+        return Err( LoadError::Format( format!( "Unsupported wav format {} with {} bits, {} channels at {}Hz", format_tag, format.bits, format.channels, format.sample_rate ) ) );
+    }
+    Ok( format )
+}
+
+/**
+ * Decode one value to the range -1.0 to 1.0
+ */
+fn decode_value( bytes : &[u8], format : &WavFormat ) -> f32 {
+    match ( format.float, format.bits ) {
+        ( false, 8 ) => ( bytes[ 0 ] as f32 - 128.0 ) / 128.0,
+        ( false, 16 ) => i16::from_le_bytes( [ bytes[ 0 ], bytes[ 1 ] ] ) as f32 / 32768.0,
+        ( false, 24 ) => ( i32::from_le_bytes( [ 0, bytes[ 0 ], bytes[ 1 ], bytes[ 2 ] ] ) >> 8 ) as f32 / 8388608.0,
+        ( false, _ ) => i32::from_le_bytes( [ bytes[ 0 ], bytes[ 1 ], bytes[ 2 ], bytes[ 3 ] ] ) as f32 / 2147483648.0,
+        ( true, 32 ) => f32::from_le_bytes( [ bytes[ 0 ], bytes[ 1 ], bytes[ 2 ], bytes[ 3 ] ] ),
+        ( true, _ ) => f64::from_le_bytes( [ bytes[ 0 ], bytes[ 1 ], bytes[ 2 ], bytes[ 3 ], bytes[ 4 ], bytes[ 5 ], bytes[ 6 ], bytes[ 7 ] ] ) as f32
+    }
+}
+
+/**
+ * Linear interpolation to a new rate, stopping at the maximum length. Returns the data cut to the maximum
+ * length when the rates match
+ */
+fn resample( mut data : Vec<f32>, ratio : f64, max_length : usize ) -> Vec<f32> {
+    if ratio == 1.0 || data.is_empty() {
+        data.truncate( max_length );
+        return data;
+    }
+    let length = ( ( data.len() as f64 * ratio ).round() as usize ).clamp( 1, max_length );
+    ( 0..length ).map( | index | {
+        let position = index as f64 / ratio;
+        let first = ( position.floor() as usize ).min( data.len() - 1 );
+        let second = ( first + 1 ).min( data.len() - 1 );
+        let fraction = ( position - first as f64 ) as f32;
+        data[ first ] + ( data[ second ] - data[ first ] ) * fraction
+    } ).collect()
+}
+
+/**
+ * Turn a wav file into a sample. Any number of channels are mixed down to mono and the file is resampled
+ * to the rate in the options. Samples longer than a mod file can store are cut at 128KB. The first loop
+ * in a smpl chunk becomes the sample loop, rounded to whole words, and a unity note and pitch fraction within
+ * a semitone of middle C become the finetune
+ */
+pub fn read_sample_wav( data : &[u8], name : &str, options : &SampleImportOptions ) -> Result<Sample, LoadError> {
+    let chunks = wav_chunks( data )?;
+    let find_chunk = | id : &[u8] | chunks.iter().find( | ( chunk_id, _ ) | *chunk_id == id ).map( | ( _, body ) | *body );
+    let format = read_format( find_chunk( b"fmt " ).ok_or_else( || LoadError::Format( String::from( "The wav file has no fmt chunk" ) ) )? )?;
+    let body = find_chunk( b"data" ).ok_or_else( || LoadError::Format( String::from( "The wav file has no data chunk" ) ) )?;
+
+    let bytes_per_value = format.bits as usize / 8;
+    let frame_size = bytes_per_value * format.channels;
+    let source_frames = body.len() / frame_size;
+    let ratio = options.sample_rate as f64 / format.sample_rate as f64;
+    let needed_frames = ( ( MAX_SAMPLE_SIZE as f64 / ratio ).ceil() as usize ).saturating_add( 1 );     // one more to interpolate towards
+    let mixed : Vec<f32> = body.chunks_exact( frame_size ).take( needed_frames ).map( | frame | {
+        frame.chunks_exact( bytes_per_value ).map( | bytes | decode_value( bytes, &format ) ).sum::<f32>() / format.channels as f32
+    } ).collect();
+    let resampled = resample( mixed, ratio, MAX_SAMPLE_SIZE );
+
+    let mut dither = Dither::new();
+    let mut samples : Vec<i8> = resampled.iter().map( | value | {
+        if options.dither {
+            dither.quantize_i8_shaped( *value )
+        } else {
+            ( value * 128.0 ).round().clamp( -128.0, 127.0 ) as i8
+        }
+    } ).collect();
+    if samples.len() % 2 == 1 {
+        samples.push( 0 );                          // the maximum length is even, so this stays within it
+    }
+
+    let mut sample = Sample::from_data( name, samples );
+    if let Some( smpl ) = find_chunk( b"smpl" ).filter( | smpl | smpl.len() >= 36 ) {
+        let eighths = read_u32( smpl, 12 ) as i64 * 8 + ( read_u32( smpl, 16 ) as i64 * 8 + ( 1 << 31 ) ) / ( 1i64 << 32 );
+        let fine_tune = MIDI_UNITY_NOTE as i64 * 8 - eighths;
+        if ( -8..=7 ).contains( &fine_tune ) {
+            sample.fine_tune = fine_tune as u8 & 0x0f;
+        }
+        if read_u32( smpl, 28 ) > 0 && smpl.len() >= 60 {
+            let start = read_u32( smpl, 44 ) as usize;
+            let end = ( read_u32( smpl, 48 ) as usize + 1 ).min( source_frames );
+            let scale = | frame : usize | ( ( frame as f64 * ratio ).round() as u32 ) & !1;
+            let ( offset, end ) = ( scale( start ), scale( end ).min( sample.size ) );
+            if end >= offset + 2 {
+                sample.repeat_offset = offset;
+                sample.repeat_size = end - offset;
+            }
+        }
+    }
+    Ok( sample )
+}
+
+pub fn read_sample_wav_file( file_name : &str, name : &str, options : &SampleImportOptions ) -> Result<Sample, LoadError> {
+    read_sample_wav( &fs::read( file_name )?, name, options )
+}
@@ -0,0 +1,249 @@
+use std::error;
+use std::fmt;
+use std::io::{self, Write};
+
+use crate::player::Player;
+
+#[cfg(feature = "hound")]
+mod wav;
+#[cfg(feature = "cpal")]
+mod device;
+
+#[cfg(feature = "hound")]
+pub use wav::WavSink;
+#[cfg(feature = "cpal")]
+pub use device::CpalSink;
+
+/**
+ * Produces interleaved stereo frames for a sink
+ */
+pub trait AudioSource : Send {
+    /**
+     * Fill the buffer with interleaved left / right samples. Returns the number of frames written, which is
+     * less than the buffer holds once the source has nothing more to play
+     */
+    fn fill( &mut self, buffer : &mut [f32] ) -> usize;
+
+    /**
+     * Called by device sinks with the size in frames of each device buffer before filling it, which may take
+     * several calls to fill
+     */
+    fn start_buffer( &mut self, _frames : usize ) {}
+}
+
+impl AudioSource for Player {
+    fn fill( &mut self, buffer : &mut [f32] ) -> usize {
+        let mut frames = 0;
+        for frame in buffer.chunks_exact_mut( 2 ) {
+            if self.has_finished() {
+                break;
+            }
+            let ( left, right ) = self.next_sample();
+            frame[ 0 ] = left;
+            frame[ 1 ] = right;
+            frames += 1;
+        }
+        frames
+    }
+}
+
+/**
+ * Somewhere to send audio. File and stream sinks pull from the source until it runs out and then return.
+ * Device sinks start playing on their own thread and return straight away
+ */
+pub trait AudioSink {
+    /**
+     * The rate the source should be rendered at
+     */
+    fn sample_rate( &self ) -> u32;
+
+    fn play( &mut self, source : Box<dyn AudioSource> ) -> Result<(), SinkError>;
+}
+
+#[derive(Debug)]
+pub enum SinkError {
+    Io( io::Error ),
+    Device( String ),           // the sound device could not be opened or has already been started
+    Encoding( String ),         // the output file could not be encoded
+}
+
+impl fmt::Display for SinkError {
+    fn fmt( &self, f : &mut fmt::Formatter ) -> fmt::Result {
+        match self {
+            SinkError::Io( error ) => write!( f, "{}", error ),
+            SinkError::Device( message ) => write!( f, "{}", message ),
+            SinkError::Encoding( message ) => write!( f, "{}", message )
+        }
+    }
+}
+
+impl error::Error for SinkError {}
+
+impl From<io::Error> for SinkError {
+    fn from( error : io::Error ) -> SinkError {
+        SinkError::Io( error )
+    }
+}
+
+#[cfg(feature = "hound")]
+impl From<hound::Error> for SinkError {
+    fn from( error : hound::Error ) -> SinkError {
+        match error {
+            hound::Error::IoError( error ) => SinkError::Io( error ),
+            _ => SinkError::Encoding( error.to_string() )
+        }
+    }
+}
+
+/**
+ * Frames pulled from a source at a time by the file and stream sinks
+ */
+const BLOCK_FRAMES : usize = 4096;
+
+/**
+ * Pull blocks from the source until it runs out, handing each block to the writer
+ */
+fn pull_blocks<F>( source : &mut dyn AudioSource, mut write_block : F ) -> Result<(), SinkError>
+    where F : FnMut( &[f32] ) -> Result<(), SinkError> {
+    let mut buffer = vec![ 0.0; BLOCK_FRAMES * 2 ];
+    loop {
+        let frames = source.fill( &mut buffer );
+        write_block( &buffer[ 0..frames * 2 ] )?;
+        if frames < BLOCK_FRAMES {
+            return Ok( () );
+        }
+    }
+}
+
+/**
+ * Triangular dither for converting float samples to 16 bits. Uses its own xorshift generator so it can run on the audio thread
+ */
+pub(crate) struct Dither {
+    state : u32,
+    error : f32,                // quantisation error fed back by the noise shaped conversion
+}
+
+impl Dither {
+    pub(crate) fn new() -> Dither {
+        Dither{ state : 0x1234_5678, error : 0.0 }
+    }
+
+    fn next_random( &mut self ) -> f32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state as f32 / u32::MAX as f32
+    }
+
+    pub(crate) fn quantize_i16( &mut self, value : f32 ) -> i16 {
+        // the difference of two uniform values gives triangular noise of +-1 lsb
+        let noise = self.next_random() - self.next_random();
+        ( value * 32767.0 + noise ).round().clamp( -32768.0, 32767.0 ) as i16
+    }
+
+    /**
+     * Convert to 8 bits with the error of the last value subtracted from this one. That moves the noise up to
+     * high frequencies where it is less audible, which matters at 8 bits
+     */
+    pub(crate) fn quantize_i8_shaped( &mut self, value : f32 ) -> i8 {
+        let noise = self.next_random() - self.next_random();
+        let target = value * 128.0 - self.error;
+        let quantized = ( target + noise ).round().clamp( -128.0, 127.0 );
+        self.error = ( quantized - target ).clamp( -1.0, 1.0 );
+        quantized as i8
+    }
+
+    #[cfg(feature = "cpal")]
+    pub(crate) fn quantize_u16( &mut self, value : f32 ) -> u16 {
+        ( self.quantize_i16( value ) as i32 + 32768 ) as u16
+    }
+}
+
+/**
+ * Discards the audio. Pulls the source to the end as fast as possible, which is useful for tests and timing
+ */
+pub struct NullSink {
+    sample_rate : u32,
+    frames_played : u64,
+}
+
+impl NullSink {
+    pub fn new( sample_rate : u32 ) -> NullSink {
+        NullSink{ sample_rate, frames_played : 0 }
+    }
+
+    /**
+     * Frames pulled from all the sources played so far
+     */
+    pub fn frames_played( &self ) -> u64 {
+        self.frames_played
+    }
+}
+
+impl AudioSink for NullSink {
+    fn sample_rate( &self ) -> u32 {
+        self.sample_rate
+    }
+
+    fn play( &mut self, mut source : Box<dyn AudioSource> ) -> Result<(), SinkError> {
+        let frames_played = &mut self.frames_played;
+        pull_blocks( source.as_mut(), | block | {
+            *frames_played += ( block.len() / 2 ) as u64;
+            Ok( () )
+        } )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PcmFormat {
+    S16,        // signed 16 bit little endian, dithered
+    F32,        // 32 bit float little endian
+}
+
+/**
+ * Writes headerless interleaved stereo PCM, for example to stdout for piping into aplay or sox
+ */
+pub struct PcmSink<W : Write> {
+    writer : W,
+    sample_rate : u32,
+    format : PcmFormat,
+    dither : Dither,
+}
+
+impl<W : Write> PcmSink<W> {
+    pub fn new( writer : W, sample_rate : u32, format : PcmFormat ) -> PcmSink<W> {
+        PcmSink{ writer, sample_rate, format, dither : Dither::new() }
+    }
+}
+
+impl PcmSink<io::BufWriter<io::Stdout>> {
+    pub fn stdout( sample_rate : u32, format : PcmFormat ) -> PcmSink<io::BufWriter<io::Stdout>> {
+        PcmSink::new( io::BufWriter::new( io::stdout() ), sample_rate, format )
+    }
+}
+
+impl<W : Write> AudioSink for PcmSink<W> {
+    fn sample_rate( &self ) -> u32 {
+        self.sample_rate
+    }
+
+    fn play( &mut self, mut source : Box<dyn AudioSource> ) -> Result<(), SinkError> {
+        let writer = &mut self.writer;
+        let dither = &mut self.dither;
+        let format = self.format;
+        let mut bytes = Vec::with_capacity( BLOCK_FRAMES * 2 * 4 );
+        pull_blocks( source.as_mut(), | block | {
+            bytes.clear();
+            for value in block {
+                match format {
+                    PcmFormat::S16 => bytes.extend_from_slice( &dither.quantize_i16( *value ).to_le_bytes() ),
+                    PcmFormat::F32 => bytes.extend_from_slice( &value.to_le_bytes() )
+                }
+            }
+            writer.write_all( &bytes )?;
+            Ok( () )
+        } )?;
+        self.writer.flush()?;
+        Ok( () )
+    }
+}
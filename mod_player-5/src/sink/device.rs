@@ -0,0 +1,113 @@
+use std::thread;
+
+use super::{AudioSink, AudioSource, SinkError, Dither};
+
+/**
+ * Frames mixed at a time. Device buffers that are larger are filled in pieces, so the audio thread never allocates
+ */
+const MIX_FRAMES : usize = 8192;
+
+/**
+ * Plays on a sound card. The source is mixed in stereo and converted to whatever sample format and channel count the
+ * device uses; mono devices get a downmix and extra channels are left silent
+ */
+pub struct CpalSink {
+    device : cpal::Device,
+    format : cpal::Format,
+    event_loop : Option<cpal::EventLoop>,
+}
+
+impl CpalSink {
+    /**
+     * Open the default output device in its default format
+     */
+    pub fn default_device() -> Result<CpalSink, SinkError> {
+        let device = cpal::default_output_device().ok_or_else( || SinkError::Device( String::from( "Failed to get default output device" ) ) )?;
+        let format = device.default_output_format().map_err( | error | SinkError::Device( format!( "Failed to get default output format: {:?}", error ) ) )?;
+        Ok( CpalSink{ device, format, event_loop : Some( cpal::EventLoop::new() ) } )
+    }
+
+    pub fn device_name( &self ) -> String {
+        self.device.name()
+    }
+
+    pub fn sample_format( &self ) -> &'static str {
+        match self.format.data_type {
+            cpal::SampleFormat::I16 => "i16",
+            cpal::SampleFormat::U16 => "u16",
+            cpal::SampleFormat::F32 => "f32"
+        }
+    }
+
+    pub fn channels( &self ) -> u16 {
+        self.format.channels
+    }
+}
+
+/**
+ * Write a stereo frame into a device frame with any number of channels
+ */
+fn write_frame<T, F>( output : &mut [T], left : f32, right : f32, convert : &mut F ) where F : FnMut( f32 ) -> T {
+    if output.len() == 1 {
+        output[ 0 ] = convert( ( left + right ) * 0.5 );
+        return;
+    }
+    output[ 0 ] = convert( left );
+    output[ 1 ] = convert( right );
+    for value in &mut output[ 2.. ] {
+        *value = convert( 0.0 );
+    }
+}
+
+/**
+ * Fill a device buffer from the source, a piece the size of the mixing space at a time. Whatever the source can't
+ * fill is silent
+ */
+fn write_buffer<T, F>( output : &mut [T], source : &mut dyn AudioSource, mixed : &mut [f32], channels : usize, mut convert : F ) where F : FnMut( f32 ) -> T {
+    source.start_buffer( output.len() / channels );
+    for piece in output.chunks_mut( mixed.len() / 2 * channels ) {
+        let mixed = &mut mixed[ 0..piece.len() / channels * 2 ];
+        let frames = source.fill( mixed );
+        for value in &mut mixed[ frames * 2.. ] {
+            *value = 0.0;
+        }
+        for ( device_frame, frame ) in piece.chunks_mut( channels ).zip( mixed.chunks( 2 ) ) {
+            write_frame( device_frame, frame[ 0 ], frame[ 1 ], &mut convert );
+        }
+    }
+}
+
+impl AudioSink for CpalSink {
+    fn sample_rate( &self ) -> u32 {
+        self.format.sample_rate.0
+    }
+
+    /**
+     * Start the stream and return. The audio thread only pulls from the source and converts the result, so the source
+     * must not block. Once the source runs out the device plays silence
+     */
+    fn play( &mut self, mut source : Box<dyn AudioSource> ) -> Result<(), SinkError> {
+        let event_loop = self.event_loop.take().ok_or_else( || SinkError::Device( String::from( "The device is already playing" ) ) )?;
+        let stream_id = event_loop.build_output_stream( &self.device, &self.format ).map_err( | error | SinkError::Device( format!( "Failed to open output stream: {:?}", error ) ) )?;
+        event_loop.play_stream( stream_id );
+
+        let channels = self.format.channels as usize;
+        let mut mixed = vec![ 0.0; MIX_FRAMES * 2 ];
+        let mut dither = Dither::new();
+        thread::spawn( move || {
+            event_loop.run( move |_, data| {
+                let source = source.as_mut();
+                match data {
+                    cpal::StreamData::Output{ buffer : cpal::UnknownTypeOutputBuffer::I16( mut buffer ) } =>
+                        write_buffer( &mut buffer, source, &mut mixed, channels, | value | dither.quantize_i16( value ) ),
+                    cpal::StreamData::Output{ buffer : cpal::UnknownTypeOutputBuffer::U16( mut buffer ) } =>
+                        write_buffer( &mut buffer, source, &mut mixed, channels, | value | dither.quantize_u16( value ) ),
+                    cpal::StreamData::Output{ buffer : cpal::UnknownTypeOutputBuffer::F32( mut buffer ) } =>
+                        write_buffer( &mut buffer, source, &mut mixed, channels, | value | value ),
+                    _ => ()
+                }
+            });
+        });
+        Ok( () )
+    }
+}
@@ -0,0 +1,46 @@
+use std::fs::File;
+use std::io::BufWriter;
+
+use super::{AudioSink, AudioSource, SinkError, pull_blocks};
+
+/**
+ * Writes 32 bit float stereo WAV files. The file is finished when the source runs out
+ */
+pub struct WavSink {
+    writer : Option<hound::WavWriter<BufWriter<File>>>,
+    sample_rate : u32,
+}
+
+impl WavSink {
+    pub fn create( file_name : &str, sample_rate : u32 ) -> Result<WavSink, SinkError> {
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let writer = hound::WavWriter::create( file_name, spec )?;
+        Ok( WavSink{ writer : Some( writer ), sample_rate } )
+    }
+}
+
+impl AudioSink for WavSink {
+    fn sample_rate( &self ) -> u32 {
+        self.sample_rate
+    }
+
+    /**
+     * Write the whole source and finish the file. A wav sink can only be played once
+     */
+    fn play( &mut self, mut source : Box<dyn AudioSource> ) -> Result<(), SinkError> {
+        let mut writer = self.writer.take().ok_or_else( || SinkError::Encoding( String::from( "The wav file has already been written" ) ) )?;
+        pull_blocks( source.as_mut(), | block | {
+            for value in block {
+                writer.write_sample( *value )?;
+            }
+            Ok( () )
+        } )?;
+        writer.finalize()?;
+        Ok( () )
+    }
+}
@@ -0,0 +1,1189 @@
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+
+use crate::depack::unpack_module;
+
+pub(crate) static FREQUENCY_TABLE: [u32; 60] = [
+//    B    A#   A    G#    G   F#   F    E    D#   D   C#   C    
+    57,    60,  64,  67,  71,  76,  80,  85,  90,  95, 101, 107,     
+    113,   120, 127, 135, 143, 151, 160, 170, 180, 190, 202, 214,
+    226,   240, 254, 269, 285, 302, 320, 339, 360, 381, 404, 428, 
+    453,   480, 508, 538, 570, 604, 640, 678, 720, 762, 808, 856, 
+    907,   961, 1017, 1077, 1141, 1209, 1281, 1357, 1440, 1525, 1616, 1712
+];
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Sample {
+    pub(crate) name: String,
+    pub(crate) size: u32,
+    pub(crate) volume: u8,
+    pub(crate) fine_tune: u8,
+    pub(crate) repeat_offset: u32,
+    pub(crate) repeat_size: u32,
+    #[cfg_attr(feature = "serde", serde(rename = "data", default, skip_serializing_if = "Vec::is_empty"))]
+    pub(crate) samples: Vec<i8>,        // not serialised when empty, see Song::without_sample_data
+    #[cfg_attr(feature = "serde", serde(default = "default_c2_speed"))]
+    pub(crate) c2_speed: u32,           // playback rate of C-4, only used by formats that store notes
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) ping_pong: bool,         // the loop plays forwards and backwards in turn
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) panning: Option<u8>,     // where the sample starts in the stereo field, None keeps the channel's panning
+    #[cfg_attr(feature = "serde", serde(default = "default_global_volume"))]
+    pub(crate) global_volume: u8,       // 0 - 64, scales every note the sample plays
+}
+
+/**
+ * The rate C-4 plays at on an NTSC Amiga, which is what Scream Tracker calls the C2Spd of a sample without finetune
+ */
+pub(crate) fn default_c2_speed() -> u32 {
+    8363
+}
+
+impl Sample{
+    fn new( sample_info : &[u8] ) -> Sample {
+        let sample_name = decode_name( &sample_info[0..22] );
+        let sample_size: u32 = ((sample_info[23] as u32) + (sample_info[22] as u32) * 256) * 2;
+        let fine_tune = sample_info[24];
+        let volume = sample_info[25];
+
+        let repeat_offset: u32 = ((sample_info[27] as u32) + (sample_info[26] as u32) * 256 )*2;
+        let repeat_size: u32 = ((sample_info[29] as u32) + (sample_info[28] as u32) * 256 )*2;
+
+        Sample {
+            name: sample_name,
+            size: sample_size,
+            volume,
+            fine_tune,
+            repeat_offset,
+            repeat_size,
+            samples: Vec::new(),
+            c2_speed: default_c2_speed(),
+            ping_pong: false,
+            panning: None,
+            global_volume: default_global_volume(),
+        }
+    }
+
+    /**
+     * A sample at full volume without a loop
+     */
+    pub(crate) fn from_data( name : &str, samples : Vec<i8> ) -> Sample {
+        Sample {
+            name: String::from( name ),
+            size: samples.len() as u32,
+            volume: 64,
+            fine_tune: 0,
+            repeat_offset: 0,
+            repeat_size: 2,
+            samples,
+            c2_speed: default_c2_speed(),
+            ping_pong: false,
+            panning: None,
+            global_volume: default_global_volume(),
+        }
+    }
+
+    pub fn name( &self ) -> &str {
+        &self.name
+    }
+
+    /**
+     * Length in bytes
+     */
+    pub fn size( &self ) -> u32 {
+        self.size
+    }
+
+    /**
+     * Default volume, 0 - 64
+     */
+    pub fn volume( &self ) -> u8 {
+        self.volume
+    }
+
+    /**
+     * Finetune as stored in the file. The low nibble is a signed value in 1/8th semitones
+     */
+    pub fn fine_tune( &self ) -> u8 {
+        self.fine_tune
+    }
+
+    pub fn repeat_offset( &self ) -> u32 {
+        self.repeat_offset
+    }
+
+    pub fn repeat_size( &self ) -> u32 {
+        self.repeat_size
+    }
+
+    pub fn data( &self ) -> &[i8] {
+        &self.samples
+    }
+
+    /**
+     * Playback rate in Hz of the note C-4. Mods use the period table and finetune instead
+     */
+    pub fn c2_speed( &self ) -> u32 {
+        self.c2_speed
+    }
+
+    pub fn is_ping_pong( &self ) -> bool {
+        self.ping_pong
+    }
+
+    /**
+     * Panning the sample sets when it is played, 0 is left and 255 right
+     */
+    pub fn panning( &self ) -> Option<u8> {
+        self.panning
+    }
+
+    pub fn global_volume( &self ) -> u8 {
+        self.global_volume
+    }
+}
+
+/**
+ * Volume, panning, pitch or filter of an instrument over time. Points are ( tick, value ) with values from 0 to 64,
+ * the panning, pitch and filter are centered at 32. An envelope without points is switched off
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Envelope {
+    pub(crate) points : Vec<( u16, u8 )>,
+    pub(crate) sustain : Option<( usize, usize )>,      // first and last point of the loop played until the key is released
+    pub(crate) loop_points : Option<( usize, usize )>,  // first and last point of the loop
+}
+
+impl Envelope {
+    pub fn points( &self ) -> &[( u16, u8 )] {
+        &self.points
+    }
+
+    /**
+     * The sustain loop. FastTracker 2 envelopes wait at a single point, which is a loop that starts and ends on it
+     */
+    pub fn sustain( &self ) -> Option<( usize, usize )> {
+        self.sustain
+    }
+
+    pub fn loop_points( &self ) -> Option<( usize, usize )> {
+        self.loop_points
+    }
+
+    pub fn is_enabled( &self ) -> bool {
+        !self.points.is_empty()
+    }
+
+    /**
+     * Value at a tick, interpolated between the points around it. Past the last point the envelope stays at its last value
+     */
+    pub(crate) fn value( &self, tick : u32 ) -> f32 {
+        let next = self.points.iter().position( | point | point.0 as u32 > tick );
+        match next {
+            Some( 0 ) => self.points[ 0 ].1 as f32,
+            Some( index ) => {
+                let ( start_tick, start_value ) = self.points[ index - 1 ];
+                let ( end_tick, end_value ) = self.points[ index ];
+                let weight = ( tick - start_tick as u32 ) as f32 / ( end_tick - start_tick ) as f32;
+                start_value as f32 + ( end_value as f32 - start_value as f32 ) * weight
+            }
+            None => self.points.last().map( | point | point.1 as f32 ).unwrap_or( 64.0 )
+        }
+    }
+
+    /**
+     * Tick of a point, for the sustain and loop points
+     */
+    pub(crate) fn point_tick( &self, point : usize ) -> u32 {
+        self.points.get( point ).map( | point | point.0 as u32 ).unwrap_or( 0 )
+    }
+}
+
+/**
+ * Vibrato an instrument adds to every note. The wave is 0 for a sine, 1 square, 2 ramp down and 3 ramp up. The depth
+ * grows to its full value over sweep ticks
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AutoVibrato {
+    pub wave : u8,
+    pub sweep : u8,
+    pub depth : u8,
+    pub rate : u8,
+}
+
+/**
+ * What happens to the note that is playing when an Impulse Tracker channel starts a new one. All but Cut move the
+ * old note to a voice of its own
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum NewNoteAction {
+    #[default]
+    Cut,
+    Continue,
+    NoteOff,            // the old note is released
+    Fade,               // the old note fades out
+}
+
+/**
+ * Which notes left playing by the new note action a new note stops, using the instrument's duplicate action
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DuplicateCheck {
+    #[default]
+    Off,
+    Note,               // the same note of the same instrument
+    Sample,
+    Instrument,
+}
+
+/**
+ * A FastTracker 2 or Impulse Tracker instrument: which sample each note plays, plus the envelopes, fadeout, vibrato
+ * and filter applied to them
+ */
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct Instrument {
+    pub(crate) name : String,
+    pub(crate) keymap : Vec<u8>,        // sample number in the song for each note, 0 for none
+    pub(crate) notes : Vec<u8>,         // note each key plays, empty if every key plays itself
+    pub(crate) volume_envelope : Envelope,
+    pub(crate) panning_envelope : Envelope,
+    pub(crate) pitch_envelope : Envelope,       // in half semitones from the note
+    pub(crate) filter_envelope : Envelope,      // scales the filter cutoff, 32 leaves it as it is
+    pub(crate) fadeout : u32,           // how much the volume fades per tick after the key is released, out of 32768
+    pub(crate) vibrato : AutoVibrato,
+    pub(crate) global_volume : u8,      // 0 - 64
+    pub(crate) panning : Option<u8>,    // panning a note starts at, the sample's own panning takes precedence
+    pub(crate) new_note_action : NewNoteAction,
+    pub(crate) duplicate_check : DuplicateCheck,
+    pub(crate) duplicate_action : NewNoteAction,
+    pub(crate) filter_cutoff : Option<u8>,      // 0 - 127, set when a note starts
+    pub(crate) filter_resonance : Option<u8>,
+}
+
+impl Default for Instrument {
+    fn default() -> Instrument {
+        Instrument {
+            name : String::new(),
+            keymap : Vec::new(),
+            notes : Vec::new(),
+            volume_envelope : Envelope::default(),
+            panning_envelope : Envelope::default(),
+            pitch_envelope : Envelope::default(),
+            filter_envelope : Envelope::default(),
+            fadeout : 0,
+            vibrato : AutoVibrato::default(),
+            global_volume : default_global_volume(),
+            panning : None,
+            new_note_action : NewNoteAction::Cut,
+            duplicate_check : DuplicateCheck::Off,
+            duplicate_action : NewNoteAction::Cut,
+            filter_cutoff : None,
+            filter_resonance : None,
+        }
+    }
+}
+
+impl Instrument {
+    pub fn name( &self ) -> &str {
+        &self.name
+    }
+
+    /**
+     * Number of the song's sample that plays a note, counting from 1. 0 if the note plays nothing
+     */
+    pub fn sample_for_key( &self, key : u8 ) -> u8 {
+        self.keymap.get( key as usize ).copied().unwrap_or( 0 )
+    }
+
+    /**
+     * Note a key plays, which Impulse Tracker instruments can map to a different one
+     */
+    pub fn note_for_key( &self, key : u8 ) -> u8 {
+        self.notes.get( key as usize ).copied().unwrap_or( key )
+    }
+
+    pub fn volume_envelope( &self ) -> &Envelope {
+        &self.volume_envelope
+    }
+
+    pub fn panning_envelope( &self ) -> &Envelope {
+        &self.panning_envelope
+    }
+
+    pub fn fadeout( &self ) -> u32 {
+        self.fadeout
+    }
+
+    pub fn vibrato( &self ) -> &AutoVibrato {
+        &self.vibrato
+    }
+
+    pub fn pitch_envelope( &self ) -> &Envelope {
+        &self.pitch_envelope
+    }
+
+    pub fn filter_envelope( &self ) -> &Envelope {
+        &self.filter_envelope
+    }
+
+    pub fn global_volume( &self ) -> u8 {
+        self.global_volume
+    }
+
+    pub fn panning( &self ) -> Option<u8> {
+        self.panning
+    }
+
+    pub fn new_note_action( &self ) -> NewNoteAction {
+        self.new_note_action
+    }
+
+    pub fn duplicate_check( &self ) -> ( DuplicateCheck, NewNoteAction ) {
+        ( self.duplicate_check, self.duplicate_action )
+    }
+
+    pub fn filter( &self ) -> ( Option<u8>, Option<u8> ) {
+        ( self.filter_cutoff, self.filter_resonance )
+    }
+}
+
+/**
+ * Effect column of a note. Every effect a ProTracker file can hold has a variant, so a note can be written back
+ * out exactly as it was read, whether or not the player does anything with it. The variants after SetSpeed come
+ * from later trackers and have no ProTracker number
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Effect{
+    #[default]
+    None, // 0
+    Arpeggio{ chord_offset_1 : u8, chord_offset_2 : u8 },
+    SlideUp{ speed : u8  },             // 1
+    SlideDown{ speed: u8  },            // 2
+    TonePortamento{ speed: u8 },        // 3 
+    Vibrato{ speed : u8, amplitude : u8 },      // 4
+    TonePortamentoVolumeSlide{ up : u8, down : u8 },    // 5
+    VibratoVolumeSlide{ up : u8, down : u8 },   // 6
+    Tremolo{ speed : u8, amplitude : u8 },      // 7
+    SetPanning{ position : u8 },        // 8
+    SetSampleOffset{ offset : u8 },     // 9, in units of 256 bytes
+    VolumeSlide{ up : u8, down : u8 },  // 10
+    PositionJump{ next_pattern : u8 },  // 11,
+    SetVolume{ volume : u8 },           // 12
+    PatternBreak{ next_pattern_pos : u8  },     //13
+    SetFilter{ value : u8 },            // 14 0
+    FineSlideUp{ speed : u8 },          // 14 1
+    FineSlideDown{ speed : u8 },        // 14 2
+    SetGlissando{ value : u8 },         // 14 3
+    SetVibratoWave{ wave : u8 },        // 14 4
+    SetFineTune{ fine_tune : u8 },      // 14 5
+    PatternLoop{ count : u8 },          // 14 6, 0 marks the start of the loop
+    SetTremoloWave{ wave : u8 },        // 14 7
+    SetCoarsePanning{ position : u8 },  // 14 8, unused by ProTracker
+    RetriggerNote{ ticks : u8 },        // 14 9
+    FineVolumeSlideUp{ change : u8 },   // 14 10
+    FineVolumeSlideDown{ change : u8 }, // 14 11
+    NoteCut{ tick : u8 },               // 14 12
+    NoteDelay{ ticks : u8 },            // 14 13
+    PatternDelay{ lines : u8 },         // 14 14
+    InvertLoop{ speed : u8 },           // 14 15
+    SetSpeed{ speed : u8 },             // 15
+    SetTempo{ bpm : u8 },                       // S3M T
+    ExtraFineSlideUp{ speed : u8 },             // S3M FEx, a quarter of a fine slide
+    ExtraFineSlideDown{ speed : u8 },           // S3M EEx
+    FineVibrato{ speed : u8, amplitude : u8 },  // S3M U, a quarter of the depth of a vibrato
+    Tremor{ on : u8, off : u8 },                // S3M I, ticks on and off are one more than the values
+    RetriggerVolumeSlide{ volume_change : u8, ticks : u8 },     // S3M Q
+    SetGlobalVolume{ volume : u8 },             // S3M V, 0 - 64
+    GlobalVolumeSlide{ up : u8, down : u8 },    // XM H
+    PanningSlide{ left : u8, right : u8 },      // XM P
+    KeyOff{ tick : u8 },                        // XM K
+    SetEnvelopePosition{ position : u8 },       // XM L, the tick the envelopes continue from
+    SetFilterCutoff{ cutoff : u8 },             // IT Z00 - Z7F with the default macros
+    SetFilterResonance{ resonance : u8 },       // IT Z80 - Z8F, 0 - 127
+}
+
+impl Effect{
+    /**
+     * Decode the effect number and argument stored in a pattern
+     */
+    pub fn new( effect_number : u8, effect_argument : u8 ) -> Effect {
+        let high = effect_argument >> 4;
+        let low = effect_argument & 0x0f;
+        match effect_number  {
+            0 => match effect_argument {
+                0 => Effect::None,
+                _ => Effect::Arpeggio{ chord_offset_1 : high, chord_offset_2 : low },
+            },
+            1 => Effect::SlideUp{ speed : effect_argument },          // decrease period, increase frequency, higher note
+            2 => Effect::SlideDown{ speed : effect_argument },
+            3 => Effect::TonePortamento{ speed : effect_argument },
+            4 => Effect::Vibrato{ speed : high, amplitude : low },
+            5 => Effect::TonePortamentoVolumeSlide{ up : high, down : low },
+            6 => Effect::VibratoVolumeSlide{ up : high, down : low },
+            7 => Effect::Tremolo{ speed : high, amplitude : low },
+            8 => Effect::SetPanning{ position : effect_argument },
+            9 => Effect::SetSampleOffset{ offset : effect_argument },
+            10 => Effect::VolumeSlide{ up : high, down : low },
+            11 => Effect::PositionJump{ next_pattern : effect_argument },
+            12 => Effect::SetVolume{ volume : effect_argument },
+            // the line is stored as two decimal digits
+            13 => Effect::PatternBreak{ next_pattern_pos : high * 10 + low },
+            14 => match high {
+                0 => Effect::SetFilter{ value : low },
+                1 => Effect::FineSlideUp{ speed : low },
+                2 => Effect::FineSlideDown{ speed : low },
+                3 => Effect::SetGlissando{ value : low },
+                4 => Effect::SetVibratoWave{ wave : low },
+                5 => Effect::SetFineTune{ fine_tune : low },
+                6 => Effect::PatternLoop{ count : low },
+                7 => Effect::SetTremoloWave{ wave : low },
+                8 => Effect::SetCoarsePanning{ position : low },
+                9 => Effect::RetriggerNote{ ticks : low },
+                10 => Effect::FineVolumeSlideUp{ change : low },
+                11 => Effect::FineVolumeSlideDown{ change : low },
+                12 => Effect::NoteCut{ tick : low },
+                13 => Effect::NoteDelay{ ticks : low },
+                14 => Effect::PatternDelay{ lines : low },
+                _ => Effect::InvertLoop{ speed : low },
+            },
+            _ => Effect::SetSpeed{ speed : effect_argument }, 
+        }
+    }
+
+    /**
+     * The effect number and argument as they are stored in a pattern. Effects that a mod can't hold give ( 0, 0 )
+     */
+    pub fn to_raw( &self ) -> ( u8, u8 ) {
+        let nibbles = | high : u8, low : u8 | ( high << 4 ) | ( low & 0x0f );
+        match *self {
+            Effect::None => ( 0, 0 ),
+            Effect::Arpeggio{ chord_offset_1, chord_offset_2 } => ( 0, nibbles( chord_offset_1, chord_offset_2 ) ),
+            Effect::SlideUp{ speed } => ( 1, speed ),
+            Effect::SlideDown{ speed } => ( 2, speed ),
+            Effect::TonePortamento{ speed } => ( 3, speed ),
+            Effect::Vibrato{ speed, amplitude } => ( 4, nibbles( speed, amplitude ) ),
+            Effect::TonePortamentoVolumeSlide{ up, down } => ( 5, nibbles( up, down ) ),
+            Effect::VibratoVolumeSlide{ up, down } => ( 6, nibbles( up, down ) ),
+            Effect::Tremolo{ speed, amplitude } => ( 7, nibbles( speed, amplitude ) ),
+            Effect::SetPanning{ position } => ( 8, position ),
+            Effect::SetSampleOffset{ offset } => ( 9, offset ),
+            Effect::VolumeSlide{ up, down } => ( 10, nibbles( up, down ) ),
+            Effect::PositionJump{ next_pattern } => ( 11, next_pattern ),
+            Effect::SetVolume{ volume } => ( 12, volume ),
+            Effect::PatternBreak{ next_pattern_pos } => ( 13, nibbles( next_pattern_pos / 10, next_pattern_pos % 10 ) ),
+            Effect::SetFilter{ value } => ( 14, nibbles( 0, value ) ),
+            Effect::FineSlideUp{ speed } => ( 14, nibbles( 1, speed ) ),
+            Effect::FineSlideDown{ speed } => ( 14, nibbles( 2, speed ) ),
+            Effect::SetGlissando{ value } => ( 14, nibbles( 3, value ) ),
+            Effect::SetVibratoWave{ wave } => ( 14, nibbles( 4, wave ) ),
+            Effect::SetFineTune{ fine_tune } => ( 14, nibbles( 5, fine_tune ) ),
+            Effect::PatternLoop{ count } => ( 14, nibbles( 6, count ) ),
+            Effect::SetTremoloWave{ wave } => ( 14, nibbles( 7, wave ) ),
+            Effect::SetCoarsePanning{ position } => ( 14, nibbles( 8, position ) ),
+            Effect::RetriggerNote{ ticks } => ( 14, nibbles( 9, ticks ) ),
+            Effect::FineVolumeSlideUp{ change } => ( 14, nibbles( 10, change ) ),
+            Effect::FineVolumeSlideDown{ change } => ( 14, nibbles( 11, change ) ),
+            Effect::NoteCut{ tick } => ( 14, nibbles( 12, tick ) ),
+            Effect::NoteDelay{ ticks } => ( 14, nibbles( 13, ticks ) ),
+            Effect::PatternDelay{ lines } => ( 14, nibbles( 14, lines ) ),
+            Effect::InvertLoop{ speed } => ( 14, nibbles( 15, speed ) ),
+            Effect::SetSpeed{ speed } => ( 15, speed ),
+            Effect::SetTempo{ .. } | Effect::ExtraFineSlideUp{ .. } | Effect::ExtraFineSlideDown{ .. } | Effect::FineVibrato{ .. } |
+            Effect::Tremor{ .. } | Effect::RetriggerVolumeSlide{ .. } | Effect::SetGlobalVolume{ .. } | Effect::GlobalVolumeSlide{ .. } |
+            Effect::PanningSlide{ .. } | Effect::KeyOff{ .. } | Effect::SetEnvelopePosition{ .. } | Effect::SetFilterCutoff{ .. } |
+            Effect::SetFilterResonance{ .. } => ( 0, 0 ),
+        }
+    }
+}
+
+/**
+ * Note column of the formats that store notes instead of periods. Notes count semitones up from C-0, so C-4 is 48
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Key {
+    #[default]
+    None,
+    Note( u8 ),
+    Cut,                    // stop the sample that is playing
+    Off,                    // release the key: envelopes move past their sustain point and the fadeout starts
+    Fade,                   // start the fadeout without releasing the key
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Note{
+    pub(crate) sample_number: u8,
+    pub(crate) period: u32,            // how many clock ticks each sample is held for
+    pub(crate) effect: Effect,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) key: Key,                // used instead of the period by formats other than mod
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) volume_effect: Effect,   // the volume column of formats that have one, applied before the effect
+}
+
+impl Note{
+    pub fn new( sample_number : u8, period : u32, effect : Effect ) -> Note {
+        Note{ sample_number, period, effect, key : Key::None, volume_effect : Effect::None }
+    }
+
+    /**
+     * A note of a format that stores notes rather than periods
+     */
+    pub(crate) fn with_key( sample_number : u8, key : Key, volume_effect : Effect, effect : Effect ) -> Note {
+        Note{ sample_number, period : 0, effect, key, volume_effect }
+    }
+
+    fn read( note_data : &[u8]) -> Note {
+        let sample_number = ( (note_data[2] & 0xf0) >> 4 )  + ( note_data[ 0 ] &0xf0);
+        let period = ((note_data[0] & 0x0f) as u32) * 256 + (note_data[1] as u32);
+        let effect_argument = note_data[3];
+        let effect_number = note_data[ 2] & 0x0f;
+        let effect = Effect::new(effect_number, effect_argument);
+        Note::new( sample_number, period, effect )
+    }
+
+    /**
+     * Sample to play, starting from 1. 0 keeps the sample that is already playing
+     */
+    pub fn sample_number( &self ) -> u8 {
+        self.sample_number
+    }
+
+    /**
+     * Amiga period of the note or 0 if there is no new note
+     */
+    pub fn period( &self ) -> u32 {
+        self.period
+    }
+
+    pub fn effect( &self ) -> &Effect {
+        &self.effect
+    }
+
+    /**
+     * The note of formats other than mod, see Key
+     */
+    pub fn key( &self ) -> Key {
+        self.key
+    }
+
+    pub fn volume_effect( &self ) -> &Effect {
+        &self.volume_effect
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Pattern {
+    pub(crate) lines: Vec<Vec<Note>>       // outer vector is the lines (64). Inner vector holds the notes for the line             
+}
+
+impl Pattern{
+    fn new( ) -> Pattern {
+        let mut lines : Vec<Vec<Note>> = Vec::new();
+        for _line in 0..64 {
+            lines.push( Vec::new() );
+        }
+        Pattern{ lines }
+    }
+
+    /**
+     * A pattern of 64 empty lines
+     */
+    pub(crate) fn with_channels( num_channels : u32 ) -> Pattern {
+        let empty = Note::new( 0, 0, Effect::None );
+        Pattern{ lines : vec![ vec![ empty; num_channels as usize ]; 64 ] }
+    }
+
+    /**
+     * A pattern of empty lines, for the formats whose patterns are not all 64 lines long
+     */
+    pub(crate) fn with_lines( num_channels : u32, num_lines : usize ) -> Pattern {
+        let empty = Note::new( 0, 0, Effect::None );
+        Pattern{ lines : vec![ vec![ empty; num_channels as usize ]; num_lines ] }
+    }
+
+    pub fn lines( &self ) -> &[Vec<Note>] {
+        &self.lines
+    }
+}
+
+/**
+ * The tracker a song comes from. The player follows the effect rules of that tracker
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ModuleKind {
+    #[default]
+    Mod,            // ProTracker and the trackers that write the same format
+    S3m,            // Scream Tracker 3
+    Xm,             // FastTracker 2
+    It,             // Impulse Tracker
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FormatDescription{
+    pub num_channels : u32,
+    pub num_samples : u32,
+    pub has_tag : bool,     // Is the format description based on a tag
+    pub tag : Option<[u8; 4]>,      // the tag as it was stored in the file
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub kind : ModuleKind,
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "SongData"))]
+pub struct Song {
+    pub(crate) name: String,
+    pub(crate) format : FormatDescription,
+    pub(crate) samples: Vec<Sample>,
+    pub(crate) patterns: Vec<Pattern>,
+    pub(crate) pattern_table: Vec<u8>,
+    #[cfg_attr(feature = "serde", serde(rename = "song_length"))]
+    pub(crate) num_used_patterns : u32,
+    pub(crate) end_position : u32,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) channel_panning : Vec<u8>,       // 0 is left and 255 right. Empty for mods, see channel_panning()
+    #[cfg_attr(feature = "serde", serde(default = "default_global_volume"))]
+    pub(crate) global_volume : u8,              // 0 - 64
+    #[cfg_attr(feature = "serde", serde(default = "default_speed"))]
+    pub(crate) initial_speed : u32,
+    #[cfg_attr(feature = "serde", serde(default = "default_bpm"))]
+    pub(crate) initial_bpm : u32,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) instruments : Vec<Instrument>,   // empty unless the format has instruments, notes then pick an instrument
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) linear_periods : bool,           // FastTracker 2's linear frequency table, 64 periods to a semitone
+}
+
+pub(crate) fn default_global_volume() -> u8 {
+    64
+}
+
+/**
+ * Mods start at 6 vblanks per line and 125 bpm until an effect changes them
+ */
+pub(crate) fn default_speed() -> u32 {
+    6
+}
+
+pub(crate) fn default_bpm() -> u32 {
+    125
+}
+
+/**
+ * A song as it is deserialised, before it has been checked to be one the player can play
+ */
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct SongData {
+    name: String,
+    format : FormatDescription,
+    samples: Vec<Sample>,
+    patterns: Vec<Pattern>,
+    pattern_table: Vec<u8>,
+    #[serde(rename = "song_length")]
+    num_used_patterns : u32,
+    end_position : u32,
+    #[serde(default)]
+    channel_panning : Vec<u8>,
+    #[serde(default = "default_global_volume")]
+    global_volume : u8,
+    #[serde(default = "default_speed")]
+    initial_speed : u32,
+    #[serde(default = "default_bpm")]
+    initial_bpm : u32,
+    #[serde(default)]
+    instruments : Vec<Instrument>,
+    #[serde(default)]
+    linear_periods : bool,
+}
+
+#[cfg(feature = "serde")]
+impl std::convert::TryFrom<SongData> for Song {
+    type Error = String;
+
+    fn try_from( data : SongData ) -> Result<Song, String> {
+        let song = Song {
+            name : data.name,
+            format : data.format,
+            samples : data.samples,
+            patterns : data.patterns,
+            pattern_table : data.pattern_table,
+            num_used_patterns : data.num_used_patterns,
+            end_position : data.end_position,
+            channel_panning : data.channel_panning,
+            global_volume : data.global_volume,
+            initial_speed : data.initial_speed,
+            initial_bpm : data.initial_bpm,
+            instruments : data.instruments,
+            linear_periods : data.linear_periods,
+        };
+        song.check_structure()?;
+        Ok( song )
+    }
+}
+
+impl Song {
+    pub fn name( &self ) -> &str {
+        &self.name
+    }
+
+    pub fn format( &self ) -> &FormatDescription {
+        &self.format
+    }
+
+    pub fn num_channels( &self ) -> u32 {
+        self.format.num_channels
+    }
+
+    /**
+     * Where a channel starts out in the stereo field, 0 is left and 255 right. Mods have no panning of their own
+     * and play like the Amiga did, with channels 1 and 4 on the left and 2 and 3 on the right
+     */
+    pub fn channel_panning( &self, channel : usize ) -> u8 {
+        match self.channel_panning.get( channel ) {
+            Some( panning ) => *panning,
+            None => if channel.is_multiple_of( 4 ) || channel == 3 { 0 } else { 255 }
+        }
+    }
+
+    /**
+     * Volume that all the channels are scaled by at the start of the song, 0 - 64
+     */
+    pub fn global_volume( &self ) -> u8 {
+        self.global_volume
+    }
+
+    /**
+     * Vblanks per line at the start of the song
+     */
+    pub fn initial_speed( &self ) -> u32 {
+        self.initial_speed
+    }
+
+    pub fn initial_bpm( &self ) -> u32 {
+        self.initial_bpm
+    }
+
+    pub fn samples( &self ) -> &[Sample] {
+        &self.samples
+    }
+
+    /**
+     * The instruments of formats that have them. The sample number of a note is then an instrument number
+     */
+    pub fn instruments( &self ) -> &[Instrument] {
+        &self.instruments
+    }
+
+    pub fn has_linear_periods( &self ) -> bool {
+        self.linear_periods
+    }
+
+    /**
+     * All the patterns stored in the file, in file order
+     */
+    pub fn patterns( &self ) -> &[Pattern] {
+        &self.patterns
+    }
+
+    /**
+     * The pattern numbers in the order they are played. Only the first song_length() entries are used
+     */
+    pub fn pattern_table( &self ) -> &[u8] {
+        &self.pattern_table
+    }
+
+    /**
+     * Number of positions in the pattern table that are played
+     */
+    pub fn song_length( &self ) -> u32 {
+        self.num_used_patterns
+    }
+
+    /**
+     * The restart byte from the header. Most trackers store 127 here
+     */
+    pub fn end_position( &self ) -> u32 {
+        self.end_position
+    }
+
+    /**
+     * How many sample numbers the notes can use: the instruments for the formats that have them, otherwise the samples
+     */
+    pub(crate) fn num_sounds( &self ) -> usize {
+        if self.instruments.is_empty() { self.samples.len() } else { self.instruments.len() }
+    }
+
+    /**
+     * Check what the loaders make sure of for a song read from text or deserialised: there are patterns, the song length
+     * fits the order table, the orders that are played are patterns that exist, every row has a note for each
+     * channel and notes only use samples or instruments the song has
+     */
+    pub(crate) fn check_structure( &self ) -> Result<(), String> {
+        let num_channels = self.format.num_channels as usize;
+        if num_channels == 0 || self.patterns.is_empty() {
+            return Err( String::from( "The song has no channels or no patterns" ) );
+        }
+        if self.num_used_patterns == 0 || self.num_used_patterns as usize > self.pattern_table.len() {
+            return Err( format!( "Song length {} does not match the order table of {} positions", self.num_used_patterns, self.pattern_table.len() ) );
+        }
+        if let Some( pattern ) = self.pattern_table[ ..self.num_used_patterns as usize ].iter().find( | pattern | **pattern as usize >= self.patterns.len() ) {
+            return Err( format!( "Order table refers to pattern {} but there are only {}", pattern, self.patterns.len() ) );
+        }
+        let num_sounds = self.num_sounds();
+        for ( number, pattern ) in self.patterns.iter().enumerate() {
+            if pattern.lines.is_empty() {
+                return Err( format!( "Pattern {} has no rows", number ) );
+            }
+            if let Some( row ) = pattern.lines.iter().position( | line | line.len() != num_channels ) {
+                return Err( format!( "Row {} of pattern {} has {} notes, the song has {} channels", row, number, pattern.lines[ row ].len(), num_channels ) );
+            }
+            if let Some( note ) = pattern.lines.iter().flatten().find( | note | note.sample_number as usize > num_sounds ) {
+                return Err( format!( "Pattern {} uses sample {} but there are only {}", number, note.sample_number, num_sounds ) );
+            }
+        }
+        Ok( () )
+    }
+
+    /**
+     * A copy of the song with the sample bodies left out. Sample sizes and loops are kept so the copy
+     * still describes the samples, which is all a catalog needs when it serialises the song
+     */
+    pub fn without_sample_data( &self ) -> Song {
+        let mut song = self.clone();
+        for sample in &mut song.samples {
+            sample.samples = Vec::new();
+        }
+        song
+    }
+
+    /**
+     * Put a sample in place of the one with the given number, counting from 1. Returns the old sample,
+     * or gives the sample back if the song has no such sample
+     */
+    pub fn replace_sample( &mut self, sample_number : u8, sample : Sample ) -> Result<Sample, Sample> {
+        match ( sample_number as usize ).checked_sub( 1 ).and_then( | index | self.samples.get_mut( index ) ) {
+            Some( old_sample ) => Ok( std::mem::replace( old_sample, sample ) ),
+            None => Err( sample )
+        }
+    }
+
+    /**
+     * Put a sample in the first slot that has no name and no data. Returns the sample number, or gives the
+     * sample back if all the slots are used
+     */
+    pub fn add_sample( &mut self, sample : Sample ) -> Result<u8, Sample> {
+        match self.samples.iter().position( | old_sample | old_sample.samples.is_empty() && old_sample.name.trim_end_matches( '\0' ).is_empty() ) {
+            Some( index ) => {
+                self.samples[ index ] = sample;
+                Ok( index as u8 + 1 )
+            }
+            None => Err( sample )
+        }
+    }
+
+    /**
+     * Number of lines in the pattern at the given position in the pattern table
+     */
+    pub(crate) fn num_lines( &self, song_pattern_position : u32 ) -> u32 {
+        let pattern_idx = self.pattern_table[ song_pattern_position as usize ];
+        self.patterns[ pattern_idx as usize ].lines.len() as u32
+    }
+
+    /**
+     * Get the notes on the given line of the pattern at the given position in the pattern table
+     */
+    pub fn get_line( &self, song_pattern_position : u32, line : u32 ) -> &[Note] {
+        let pattern_idx = self.pattern_table[ song_pattern_position as usize ];
+        let pattern = &self.patterns[ pattern_idx as usize ];
+        &pattern.lines[ line as usize ]
+    }
+
+    /**
+     * Write the song as a 31 sample ProTracker module. The tag read from the file is kept, otherwise M.K. is used for 4 channels
+     * and xCHN / xxCH for others. Songs that can't be stored as a module ( too many samples, samples longer than 128KB, ... )
+     * give an InvalidInput error before anything is written
+     */
+    pub fn write_mod<W : io::Write>( &self, writer : &mut W ) -> io::Result<()> {
+        let invalid = | message : String | io::Error::new( io::ErrorKind::InvalidInput, message );
+        if self.format.kind != ModuleKind::Mod {
+            return Err( invalid( "Only songs loaded from mod files can be written as a mod".to_string() ) );
+        }
+        if self.samples.len() > 31 {
+            return Err( invalid( format!( "A mod file holds 31 samples, the song has {}", self.samples.len() ) ) );
+        }
+        if let Some( ( number, _ ) ) = self.samples.iter().enumerate().find( | ( _, sample ) | sample.samples.len() > 0xffff * 2 ) {
+            return Err( invalid( format!( "Sample {} is longer than 128KB", number + 1 ) ) );
+        }
+        if self.format.num_channels == 0 || self.format.num_channels > 32 {
+            return Err( invalid( format!( "A mod file can't have {} channels", self.format.num_channels ) ) );
+        }
+        if self.patterns.is_empty() || self.patterns.len() > 256 {
+            return Err( invalid( format!( "A mod file can't have {} patterns", self.patterns.len() ) ) );
+        }
+        if self.num_used_patterns == 0 || self.num_used_patterns > 128 {
+            return Err( invalid( format!( "Invalid song length {}", self.num_used_patterns ) ) );
+        }
+        for ( pattern_number, pattern ) in self.patterns.iter().enumerate() {
+            let well_formed = pattern.lines.len() == 64 && pattern.lines.iter().all( | line | line.len() == self.format.num_channels as usize );
+            if !well_formed {
+                return Err( invalid( format!( "Pattern {} does not have 64 lines of {} notes", pattern_number, self.format.num_channels ) ) );
+            }
+            let out_of_range = pattern.lines.iter().flatten().any( | note | note.period > 0xfff );
+            if out_of_range {
+                return Err( invalid( format!( "Pattern {} has a period that does not fit in 12 bits", pattern_number ) ) );
+            }
+        }
+
+        let mut header = encode_name( &self.name, 20 );
+        for sample_number in 0..31 {
+            match self.samples.get( sample_number ) {
+                Some( sample ) => {
+                    let words = | bytes : u32 | ( ( bytes / 2 ).min( 0xffff ) as u16 ).to_be_bytes();
+                    header.extend( encode_name( &sample.name, 22 ) );
+                    header.extend( &words( sample.samples.len() as u32 + 1 ) );     // rounded up to whole words
+                    header.push( sample.fine_tune );
+                    header.push( sample.volume );
+                    header.extend( &words( sample.repeat_offset ) );
+                    header.extend( &words( sample.repeat_size ) );
+                }
+                None => header.extend( &[ 0; 30 ] )
+            }
+        }
+        header.push( self.num_used_patterns as u8 );
+        header.push( self.end_position as u8 );
+        let mut pattern_table = self.pattern_table.clone();
+        pattern_table.resize( 128, 0 );
+        header.extend( &pattern_table );
+        header.extend( &self.format.tag.unwrap_or_else( || self.default_tag() ) );
+        writer.write_all( &header )?;
+
+        let mut pattern_data = Vec::with_capacity( self.patterns.len() * 64 * 4 * self.format.num_channels as usize );
+        for note in self.patterns.iter().flat_map( | pattern | pattern.lines.iter().flatten() ) {
+            let ( effect_number, effect_argument ) = note.effect.to_raw();
+            pattern_data.push( ( note.sample_number & 0xf0 ) | ( ( note.period >> 8 ) as u8 & 0x0f ) );
+            pattern_data.push( note.period as u8 );
+            pattern_data.push( ( note.sample_number << 4 ) | ( effect_number & 0x0f ) );
+            pattern_data.push( effect_argument );
+        }
+        writer.write_all( &pattern_data )?;
+
+        // odd length samples are padded to a whole number of words
+        for sample in &self.samples {
+            let bytes : Vec<u8> = sample.samples.iter().map( | value | *value as u8 ).collect();
+            writer.write_all( &bytes )?;
+            if !bytes.len().is_multiple_of( 2 ) {
+                writer.write_all( &[ 0 ] )?;
+            }
+        }
+        Ok( () )
+    }
+
+    /**
+     * The tag ProTracker and its successors use for the number of channels in the song
+     */
+    fn default_tag( &self ) -> [u8; 4] {
+        let tag = match self.format.num_channels {
+            4 if self.patterns.len() > 64 => String::from( "M!K!" ),
+            4 => String::from( "M.K." ),
+            channels if channels < 10 => format!( "{}CHN", channels ),
+            channels => format!( "{}CH", channels ),
+        };
+        let bytes = tag.as_bytes();
+        [ bytes[ 0 ], bytes[ 1 ], bytes[ 2 ], bytes[ 3 ] ]
+    }
+}
+
+/**
+ * Identify the mod format version based on the tag. If there is not identifiable that it is assumed to be an original mod.
+ * Tags of the form 6CHN or 12CH give the number of channels
+ */
+pub(crate) fn get_format(file_data: &[u8] ) -> FormatDescription {
+    let original = FormatDescription{ num_channels : 4, num_samples : 15, has_tag : false, tag : None, kind : ModuleKind::Mod };
+    if file_data.len() < 1084 {
+        return original;
+    }
+    let tag = [ file_data[1080], file_data[1081], file_data[1082], file_data[1083] ];
+    let num_channels = match &tag {
+        b"M.K." | b"FLT4" | b"M!K!" | b"4CHN" => 4,
+        b"CD81" | b"OKTA" => 8,
+        [ digit, b'C', b'H', b'N' ] if ( b'1'..=b'9' ).contains( digit ) => ( digit - b'0' ) as u32,
+        [ tens, units, b'C', b'H' ] if tens.is_ascii_digit() && units.is_ascii_digit() => ( ( tens - b'0' ) * 10 + units - b'0' ) as u32,
+        _ => return original
+    };
+    if num_channels == 0 || num_channels > 32 {
+        return original;
+    }
+    FormatDescription{ num_channels, num_samples : 31, has_tag : true, tag : Some( tag ), kind : ModuleKind::Mod }
+}
+
+/**
+ * Names are stored as raw bytes. Each byte becomes one character so the name can be written back unchanged
+ */
+pub(crate) fn decode_name( bytes : &[u8] ) -> String {
+    bytes.iter().map( | byte | *byte as char ).collect()
+}
+
+/**
+ * Pad or cut the name to the given length. Characters that do not fit in a byte become '?'
+ */
+pub(crate) fn encode_name( name : &str, length : usize ) -> Vec<u8> {
+    let mut bytes : Vec<u8> = name.chars().map( | c | if ( c as u32 ) < 256 { c as u8 } else { b'?' } ).take( length ).collect();
+    bytes.resize( length, 0 );
+    bytes
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io( io::Error ),
+    Format( String ),           // the data is not a module the player understands
+}
+
+impl fmt::Display for LoadError {
+    fn fmt( &self, f : &mut fmt::Formatter ) -> fmt::Result {
+        match self {
+            LoadError::Io( error ) => write!( f, "{}", error ),
+            LoadError::Format( message ) => write!( f, "{}", message )
+        }
+    }
+}
+
+impl error::Error for LoadError {}
+
+impl From<io::Error> for LoadError {
+    fn from( error : io::Error ) -> LoadError {
+        LoadError::Io( error )
+    }
+}
+
+pub fn read_mod_file(file_name: &str) -> Result<Song, LoadError> {
+    let file_data: Vec<u8> = fs::read(file_name)?;
+    read_mod_data( &file_data )
+}
+
+/**
+ * Parse a mod file that has already been loaded into memory. PowerPacker files and the packed mod formats are
+ * unpacked first, see unpack_module
+ */
+pub fn read_mod_data( file_data : &[u8] ) -> Result<Song, LoadError> {
+    // Packed mods are unpacked first. Untagged mods are read as they are if what they unpack to does not load
+    if file_data.starts_with( b"PP20" ) || !get_format( file_data ).has_tag {
+        if let Some( unpacked ) = unpack_module( file_data )? {
+            match read_mod_data( &unpacked ) {
+                Err( _ ) if !file_data.starts_with( b"PP20" ) => (),
+                song => return song
+            }
+        }
+    }
+    if file_data.len() < 20 {
+        return Err( LoadError::Format( format!( "File is too short for a mod file ( {} bytes )", file_data.len() ) ) );
+    }
+    let song_name = decode_name( &file_data[0..20] );
+    let format = get_format(file_data);
+    let header_size = 20 + 30 * format.num_samples as usize + 2 + 128 + if format.has_tag { 4 } else { 0 };
+    if file_data.len() < header_size {
+        return Err( LoadError::Format( format!( "File is too short for a mod file ( {} bytes )", file_data.len() ) ) );
+    }
+
+    let mut samples: Vec<Sample> = Vec::new();
+    let mut offset : usize = 20;
+    for _sample_num in 0..format.num_samples {
+        samples.push(Sample::new( &file_data[ offset  .. ( offset + 30 )  ]));
+        offset += 30;
+    }
+
+    // Figure out whe / how to stop and repeat pos ( with option to repeat in the player )
+
+    let num_used_patterns: u8 = file_data[offset];
+    let end_position: u8 = file_data[offset + 1];
+    offset += 2;
+    let pattern_table: Vec<u8> = file_data[offset..(offset + 128)].to_vec();
+    offset += 128;
+
+    // Skip the tag if one has been identified
+    if format.has_tag { offset += 4; }
+
+    // Work out how the total size of the sample data at tbe back od the file 
+    let mut total_sample_size = 0;
+    for sample in &mut samples {
+        total_sample_size += sample.size;
+    }
+
+    // The pattern take up all the space that remains after everything else has been accounted for
+    let total_pattern_size = ( file_data.len() as u32  - offset as u32 ).saturating_sub( total_sample_size );
+    let single_pattern_size = format.num_channels *  4 * 64;
+    let num_patterns = total_pattern_size / single_pattern_size;
+    // The pattern space should account for all the remaining space
+    // if total_pattern_size % single_pattern_size != 0 {
+    //     panic!( "Unrecognized file format. Pattern space does not match expected size")
+    // }
+
+    if num_patterns == 0 {
+        return Err( LoadError::Format( String::from( "File does not contain any patterns" ) ) );
+    }
+    if num_used_patterns == 0 || num_used_patterns > 128 {
+        return Err( LoadError::Format( format!( "Invalid song length {}", num_used_patterns ) ) );
+    }
+    if let Some( pattern ) = pattern_table[ 0..num_used_patterns as usize ].iter().find( | pattern | **pattern as u32 >= num_patterns ) {
+        return Err( LoadError::Format( format!( "Pattern table refers to pattern {} but the file only has {}", pattern, num_patterns ) ) );
+    }
+
+    // Read the patterns
+    let mut patterns: Vec<Pattern> = Vec::new();
+    for _pattern_number in 0..num_patterns {
+        let mut pattern = Pattern::new();
+        for line in 0..64 {
+            for _channel in 0..format.num_channels {
+                let mut note = Note::read( &file_data[ offset..(offset+4)]);
+                // like the other loaders, a note whose sample the file does not have plays without one
+                if note.sample_number as u32 > format.num_samples {
+                    note.sample_number = 0;
+                }
+                pattern.lines[ line ].push( note );
+                offset += 4;
+            }
+        }
+        patterns.push(pattern);
+    }
+
+    //Read the sample data. Some files are cut short so the last samples are shortened to fit the data that is there
+    for sample in samples.iter_mut() {
+        let length = ( sample.size as usize ).min( file_data.len() - offset );
+        for _idx in 0..length {
+            sample.samples.push(file_data[offset] as i8);
+            offset += 1;
+        }
+        sample.size = length as u32;
+    }
+
+    Ok( Song {
+        name: song_name,
+        format,
+        samples,
+        patterns,
+        pattern_table,
+        num_used_patterns : num_used_patterns as u32,
+        end_position: end_position as u32,
+        channel_panning : Vec::new(),
+        global_volume : default_global_volume(),
+        initial_speed : default_speed(),
+        initial_bpm : default_bpm(),
+        instruments : Vec::new(),
+        linear_periods : false,
+    } )
+}
@@ -0,0 +1,148 @@
+use std::sync::Arc;
+
+use crate::player::{PlayerOptions, PlayerState, next_sample};
+use crate::sink::AudioSource;
+use crate::song::Song;
+
+/**
+ * A song as an iterator over stereo samples. By default the stream ends when the song has played through once
+ */
+pub struct SongStream {
+    song : Arc<Song>,
+    state : PlayerState,
+    loops : Option<u32>,        // times to play through the song, forever if None
+    loops_played : u32,
+    finished : bool,
+}
+
+impl SongStream {
+    pub fn new( song : Arc<Song>, options : &PlayerOptions ) -> SongStream {
+        let state = PlayerState::with_options( &song, options );
+        SongStream{ song, state, loops : Some( 1 ), loops_played : 0, finished : false }
+    }
+
+    /**
+     * End the stream after the song has played through the given number of times
+     */
+    pub fn with_loops( mut self, loops : u32 ) -> SongStream {
+        self.loops = Some( loops );
+        self.finished = self.loops_played >= loops;
+        self
+    }
+
+    /**
+     * Keep playing the song forever
+     */
+    pub fn endless( mut self ) -> SongStream {
+        self.loops = None;
+        self.finished = false;
+        self
+    }
+
+    pub fn song( &self ) -> &Arc<Song> {
+        &self.song
+    }
+
+    pub fn state( &self ) -> &PlayerState {
+        &self.state
+    }
+
+    pub fn state_mut( &mut self ) -> &mut PlayerState {
+        &mut self.state
+    }
+
+    pub fn sample_rate( &self ) -> u32 {
+        self.state.sample_rate()
+    }
+
+    /**
+     * How many times the song has reached its end or jumped back to an earlier position
+     */
+    pub fn loops_played( &self ) -> u32 {
+        self.loops_played
+    }
+
+    /**
+     * Wrap the stream in an adapter that rodio can play
+     */
+    #[cfg(feature = "rodio")]
+    pub fn into_rodio_source( self ) -> RodioSource {
+        RodioSource{ stream : self, pending_right : None }
+    }
+}
+
+impl Iterator for SongStream {
+    type Item = ( f32, f32 );
+
+    fn next( &mut self ) -> Option<( f32, f32 )> {
+        if self.finished {
+            return None;
+        }
+        let sample = next_sample( &self.song, &mut self.state );
+        if self.state.song_has_ended() || self.state.has_looped() {
+            self.loops_played += 1;
+            self.state.clear_song_end();
+            self.finished = self.loops.is_some_and( | loops | self.loops_played >= loops );
+        }
+        Some( sample )
+    }
+}
+
+impl AudioSource for SongStream {
+    fn fill( &mut self, buffer : &mut [f32] ) -> usize {
+        let mut frames = 0;
+        for frame in buffer.chunks_exact_mut( 2 ) {
+            match self.next() {
+                Some( ( left, right ) ) => {
+                    frame[ 0 ] = left;
+                    frame[ 1 ] = right;
+                    frames += 1;
+                }
+                None => break
+            }
+        }
+        frames
+    }
+}
+
+/**
+ * Interleaved stereo samples for rodio ( feature "rodio" )
+ */
+#[cfg(feature = "rodio")]
+pub struct RodioSource {
+    stream : SongStream,
+    pending_right : Option<f32>,
+}
+
+#[cfg(feature = "rodio")]
+impl Iterator for RodioSource {
+    type Item = f32;
+
+    fn next( &mut self ) -> Option<f32> {
+        if let Some( right ) = self.pending_right.take() {
+            return Some( right );
+        }
+        let ( left, right ) = self.stream.next()?;
+        self.pending_right = Some( right );
+        Some( left )
+    }
+}
+
+#[cfg(feature = "rodio")]
+impl rodio::Source for RodioSource {
+    fn current_frame_len( &self ) -> Option<usize> {
+        None
+    }
+
+    fn channels( &self ) -> u16 {
+        2
+    }
+
+    fn sample_rate( &self ) -> u32 {
+        self.stream.sample_rate()
+    }
+
+    fn total_duration( &self ) -> Option<std::time::Duration> {
+        None
+    }
+}
@@ -0,0 +1,502 @@
+use std::io;
+
+use super::{Note,Song,Effect,Pattern,FormatDescription};
+use super::Sample;
+use crate::song::{ModuleKind, Key, default_global_volume, default_speed, default_bpm};
+
+static NOTE_FREQUENCY_STRINGS : [ (u32, &str ); 60 ]= [
+( 57,  "B-6" ), ( 60,  "A#6" ), ( 64,  "A-6" ),( 67,  "G#6" ), ( 71,  "G-6" ), ( 76,  "F#6" ), ( 80,  "F-6" ), ( 85 , "E-6" ), ( 90,  "D#6" ), ( 95 , "D-6" ), ( 101, "C#6" ), ( 107, "C-6"), 
+( 113, "B-5" ),( 120, "A#5" ), ( 127, "A-5" ), ( 135, "G#5" ), ( 143, "G-5" ), ( 151, "F#5" ),  ( 160, "F-5" ), ( 170, "E-5" ),( 180, "D#5" ), ( 190, "D-5" ), ( 202, "C#5" ), ( 214, "C-5"), 
+( 226, "B-4" ),( 240, "A#4" ),( 254, "A-4" ),  ( 269, "G#4" ), ( 285, "G-4" ),( 302, "F#4" ), ( 320, "F-4" ),( 339, "E-4" ),  ( 360, "D#4" ),( 381, "D-4" ),  ( 404, "C#4" ), ( 428, "C-4"), 
+( 453, "B-3" ),( 480, "A#3" ), ( 508, "A-3" ), ( 538, "G#3" ),  ( 570, "G-3" ),( 604, "F#3" ), ( 640, "F-3" ),( 678, "E-3" ),( 720, "D#3" ),( 762, "D-3" ), ( 808, "C#3" ), ( 856, "C-3"),
+( 907, "B-2" ),( 961, "A#2" ), ( 1017,"A-2" ), ( 1077,"G#2" ),( 1141,"G-2" ), ( 1209,"F#2" ), ( 1281,"F-2" ),( 1357,"E-2" ),( 1440,"D#2" ), ( 1525,"D-2" ), ( 1616,"C#2" ), ( 1712,"C-2"), 
+];
+
+impl Effect{
+    /**
+     * Name of the effect, the same as the variant name
+     */
+    pub fn name( &self ) -> &'static str {
+        match self {
+            Effect::None => "None",
+            Effect::Arpeggio{ .. } => "Arpeggio",
+            Effect::SlideUp{ .. } => "SlideUp",
+            Effect::SlideDown{ .. } => "SlideDown",
+            Effect::TonePortamento{ .. } => "TonePortamento",
+            Effect::Vibrato{ .. } => "Vibrato",
+            Effect::TonePortamentoVolumeSlide{ .. } => "TonePortamentoVolumeSlide",
+            Effect::VibratoVolumeSlide{ .. } => "VibratoVolumeSlide",
+            Effect::Tremolo{ .. } => "Tremolo",
+            Effect::SetPanning{ .. } => "SetPanning",
+            Effect::SetSampleOffset{ .. } => "SetSampleOffset",
+            Effect::VolumeSlide{ .. } => "VolumeSlide",
+            Effect::PositionJump{ .. } => "PositionJump",
+            Effect::SetVolume{ .. } => "SetVolume",
+            Effect::PatternBreak{ .. } => "PatternBreak",
+            Effect::SetFilter{ .. } => "SetFilter",
+            Effect::FineSlideUp{ .. } => "FineSlideUp",
+            Effect::FineSlideDown{ .. } => "FineSlideDown",
+            Effect::SetGlissando{ .. } => "SetGlissando",
+            Effect::SetVibratoWave{ .. } => "SetVibratoWave",
+            Effect::SetFineTune{ .. } => "SetFineTune",
+            Effect::PatternLoop{ .. } => "PatternLoop",
+            Effect::SetTremoloWave{ .. } => "SetTremoloWave",
+            Effect::SetCoarsePanning{ .. } => "SetCoarsePanning",
+            Effect::RetriggerNote{ .. } => "RetriggerNote",
+            Effect::FineVolumeSlideUp{ .. } => "FineVolumeSlideUp",
+            Effect::FineVolumeSlideDown{ .. } => "FineVolumeSlideDown",
+            Effect::NoteCut{ .. } => "NoteCut",
+            Effect::NoteDelay{ .. } => "NoteDelay",
+            Effect::PatternDelay{ .. } => "PatternDelay",
+            Effect::InvertLoop{ .. } => "InvertLoop",
+            Effect::SetSpeed{ .. } => "SetSpeed",
+            Effect::SetTempo{ .. } => "SetTempo",
+            Effect::ExtraFineSlideUp{ .. } => "ExtraFineSlideUp",
+            Effect::ExtraFineSlideDown{ .. } => "ExtraFineSlideDown",
+            Effect::FineVibrato{ .. } => "FineVibrato",
+            Effect::Tremor{ .. } => "Tremor",
+            Effect::RetriggerVolumeSlide{ .. } => "RetriggerVolumeSlide",
+            Effect::SetGlobalVolume{ .. } => "SetGlobalVolume",
+            Effect::GlobalVolumeSlide{ .. } => "GlobalVolumeSlide",
+            Effect::PanningSlide{ .. } => "PanningSlide",
+            Effect::KeyOff{ .. } => "KeyOff",
+            Effect::SetEnvelopePosition{ .. } => "SetEnvelopePosition",
+            Effect::SetFilterCutoff{ .. } => "SetFilterCutoff",
+            Effect::SetFilterResonance{ .. } => "SetFilterResonance",
+        }
+    }
+
+    /**
+     * Five letter name of the effect for the pattern display
+     */
+    fn short_name( &self ) -> &'static str {
+        match self {
+            Effect::SetSpeed{ .. } => "Speed",
+            Effect::Arpeggio{ .. } => "Arpgi",
+            Effect::SlideUp{ .. } => "SldUp",
+            Effect::SlideDown{ .. } => "SldDn",
+            Effect::TonePortamento{ .. } => "TonPo", 
+            Effect::Vibrato{ .. } => "Vibra",
+            Effect::VibratoVolumeSlide{ .. } => "ViVoS",
+            Effect::VolumeSlide{ .. } => "VolSl",
+            Effect::PositionJump{ .. } => "Jump.",
+            Effect::SetVolume{ .. } => "Volme",
+            Effect::PatternBreak{ .. } => "Break",
+            Effect::TonePortamentoVolumeSlide{ .. } => "TPVoS",
+            Effect::Tremolo{ .. } => "Trmlo",
+            Effect::SetPanning{ .. } => "Pan..",
+            Effect::SetSampleOffset{ .. } => "SOffs",
+            Effect::SetFilter{ .. } => "Filtr",
+            Effect::FineSlideUp{ .. } => "FSlUp",
+            Effect::FineSlideDown{ .. } => "FSlDn",
+            Effect::SetGlissando{ .. } => "Gliss",
+            Effect::SetVibratoWave{ .. } => "VibWv",
+            Effect::SetFineTune{ .. } => "FTune",
+            Effect::PatternLoop{ .. } => "PLoop",
+            Effect::SetTremoloWave{ .. } => "TrmWv",
+            Effect::SetCoarsePanning{ .. } => "CPan.",
+            Effect::RetriggerNote{ .. } => "Retrg",
+            Effect::FineVolumeSlideUp{ .. } => "FVoUp",
+            Effect::FineVolumeSlideDown{ .. } => "FVoDn",
+            Effect::NoteCut{ .. } => "NCut.",
+            Effect::NoteDelay{ .. } => "NDely",
+            Effect::PatternDelay{ .. } => "PDely",
+            Effect::InvertLoop{ .. } => "InvLp",
+            Effect::SetTempo{ .. } => "Tempo",
+            Effect::ExtraFineSlideUp{ .. } => "XSlUp",
+            Effect::ExtraFineSlideDown{ .. } => "XSlDn",
+            Effect::FineVibrato{ .. } => "FVibr",
+            Effect::Tremor{ .. } => "Trmor",
+            Effect::RetriggerVolumeSlide{ .. } => "RtVoS",
+            Effect::SetGlobalVolume{ .. } => "GVolm",
+            Effect::GlobalVolumeSlide{ .. } => "GVoSl",
+            Effect::PanningSlide{ .. } => "PanSl",
+            Effect::KeyOff{ .. } => "KyOff",
+            Effect::SetEnvelopePosition{ .. } => "EnvPs",
+            Effect::SetFilterCutoff{ .. } => "FltCt",
+            Effect::SetFilterResonance{ .. } => "FltRs",
+            Effect::None => "....."
+        }
+    }
+}
+
+//impl crate::mod_player::Sample{
+//impl super::Sample{
+impl Sample{
+    fn print(&self) {
+        println!("   sample Name: {}", self.name);
+        println!("   sample Size: {}", self.size);
+        println!("   sample volume: {}, fine tune {}", self.volume, self.fine_tune);
+        println!("   repeat Offset: {}, repeat Size {}", self.repeat_offset, self.repeat_size);
+    }
+}
+
+fn note_string( period : u32 ) -> &'static str{
+    match NOTE_FREQUENCY_STRINGS.binary_search_by( | val | val.0.cmp( &period) ) {
+        Ok( idx ) => NOTE_FREQUENCY_STRINGS[ idx ].1,
+        Err( _ ) => "..."
+    }
+}
+
+/**
+ * Get the period for a note name such as "C-3" or "A#4"
+ */
+pub fn note_period( note_name : &str ) -> Option<u32> {
+    let note_name = note_name.to_uppercase();
+    NOTE_FREQUENCY_STRINGS.iter().find( | ( _, name ) | *name == note_name ).map( | ( period, _ ) | *period )
+}
+
+/**
+ * Name of a note such as "C-4", counting semitones from C-0
+ */
+pub fn key_name( note : u8 ) -> String {
+    const NAMES : [ &str; 12 ] = [ "C-", "C#", "D-", "D#", "E-", "F-", "F#", "G-", "G#", "A-", "A#", "B-" ];
+    format!( "{}{}", NAMES[ ( note % 12 ) as usize ], note / 12 )
+}
+
+fn note_name( note : &Note ) -> String {
+    match note.key {
+        Key::None => String::from( note_string( note.period ) ),
+        Key::Note( key ) => key_name( key ),
+        Key::Cut => String::from( "^^^" ),
+        Key::Off => String::from( "===" ),
+        Key::Fade => String::from( "~~~" ),
+    }
+}
+
+pub fn print_line( line :  &[Note] ) {
+    for note in line.iter() {
+        print!("{} {:02X} {}   ",  note_name( note ), note.sample_number, note.effect.short_name()  );
+    }
+    println!(); 
+}
+
+/**
+ * Print all the patterns in the file in the order they are stored. Each line starts with the line number
+ */
+pub fn print_patterns( song : &Song ) {
+    for ( pattern_number, pattern ) in song.patterns.iter().enumerate() {
+        println!( "Pattern {:02}", pattern_number );
+        for ( line_number, line ) in pattern.lines.iter().enumerate() {
+            print!( "{:02}  ", line_number );
+            print_line( line );
+        }
+        println!();
+    }
+}
+
+pub fn print_song_info( song : &Song ) {
+    println!("Song: {}", song.name);
+
+    println!("Number of channels: {}", song.format.num_channels);
+    println!("Number of samples: {}", song.format.num_samples);
+    for sample in &song.samples {
+        sample.print()
+    }
+
+    println!(" num patterns in song: {}", song.patterns.len());
+    println!(" end position: {}", song.end_position);
+
+}
+/**
+ * A problem in a text pattern or song. Lines are numbered from 1
+ */
+#[derive(Debug)]
+pub struct TextError {
+    pub line : usize,
+    pub message : String,
+}
+
+impl std::fmt::Display for TextError {
+    fn fmt( &self, f : &mut std::fmt::Formatter ) -> std::fmt::Result {
+        write!( f, "line {}: {}", self.line, self.message )
+    }
+}
+
+impl std::error::Error for TextError {}
+
+fn text_error( line : usize, message : String ) -> TextError {
+    TextError{ line, message }
+}
+
+/**
+ * A note in the classic tracker layout "C-3 01 C40": note, sample number and effect in hex. Periods that are not
+ * notes are written as three hex digits and an empty note as "---", so nothing is lost
+ */
+fn note_text( note : &Note ) -> String {
+    let period = match note.period {
+        0 => String::from( "---" ),
+        period => match NOTE_FREQUENCY_STRINGS.binary_search_by( | val | val.0.cmp( &period ) ) {
+            Ok( idx ) => String::from( NOTE_FREQUENCY_STRINGS[ idx ].1 ),
+            Err( _ ) => format!( "{:03X}", period )
+        }
+    };
+    let ( effect_number, effect_argument ) = note.effect.to_raw();
+    format!( "{} {:02X} {:X}{:02X}", period, note.sample_number, effect_number, effect_argument )
+}
+
+fn parse_note( text : &str ) -> Result<Note, String> {
+    let fields : Vec<&str> = text.split_whitespace().collect();
+    if fields.len() != 3 || fields[ 1 ].len() != 2 || fields[ 2 ].len() != 3 {
+        return Err( format!( "\"{}\" is not a note like \"C-3 01 C40\"", text.trim() ) );
+    }
+    let period = match fields[ 0 ] {
+        "---" => 0,
+        name => match note_period( name ) {
+            Some( period ) => period,
+            None => u32::from_str_radix( name, 16 ).ok().filter( | _ | name.len() == 3 ).ok_or( format!( "Unknown note {}", name ) )?
+        }
+    };
+    let sample_number = u8::from_str_radix( fields[ 1 ], 16 ).map_err( | _ | format!( "Bad sample number {}", fields[ 1 ] ) )?;
+    let effect = u16::from_str_radix( fields[ 2 ], 16 ).map_err( | _ | format!( "Bad effect {}", fields[ 2 ] ) )?;
+    Ok( Note::new( sample_number, period, Effect::new( ( effect >> 8 ) as u8, effect as u8 ) ) )
+}
+
+impl Pattern {
+    /**
+     * The pattern as text, one line per row: the row number followed by the notes separated by '|'
+     */
+    pub fn to_text( &self ) -> String {
+        let mut text = String::new();
+        for ( row, line ) in self.lines.iter().enumerate() {
+            text.push_str( &format!( "{:02}", row ) );
+            for note in line {
+                text.push_str( " | " );
+                text.push_str( &note_text( note ) );
+            }
+            text.push( '\n' );
+        }
+        text
+    }
+
+    /**
+     * Read a pattern written by to_text. Blank lines are skipped; every row must have the same number of notes
+     */
+    pub fn from_text( text : &str ) -> Result<Pattern, TextError> {
+        let lines : Vec<( usize, &str )> = text.lines().enumerate().map( | ( index, line ) | ( index + 1, line ) ).collect();
+        parse_pattern( &lines )
+    }
+}
+
+fn parse_pattern( lines : &[( usize, &str )] ) -> Result<Pattern, TextError> {
+    let mut rows : Vec<Vec<Note>> = Vec::new();
+    let mut last_line = 0;
+    for ( line_number, line ) in lines.iter().filter( | ( _, line ) | !line.trim().is_empty() ) {
+        last_line = *line_number;
+        let mut fields = line.split( '|' );
+        let row = fields.next().unwrap_or( "" ).trim();
+        if row.parse::<usize>() != Ok( rows.len() ) {
+            return Err( text_error( *line_number, format!( "Expected row {} but found \"{}\"", rows.len(), row ) ) );
+        }
+        let notes = fields.map( parse_note ).collect::<Result<Vec<Note>, String>>().map_err( | message | text_error( *line_number, message ) )?;
+        if notes.is_empty() || rows.first().is_some_and( | first | first.len() != notes.len() ) {
+            return Err( text_error( *line_number, format!( "Row {} has {} notes", rows.len(), notes.len() ) ) );
+        }
+        rows.push( notes );
+    }
+    if rows.len() != 64 {
+        return Err( text_error( last_line, format!( "A pattern has 64 rows, found {}", rows.len() ) ) );
+    }
+    Ok( Pattern{ lines : rows } )
+}
+
+/**
+ * Names are quoted. Quotes, backslashes and bytes that are not printable are escaped as \xNN. Trailing zero bytes are left out
+ */
+fn quote_name( name : &str ) -> String {
+    let mut quoted = String::from( "\"" );
+    for c in name.trim_end_matches( '\0' ).chars() {
+        match c {
+            '"' | '\\' => quoted.push_str( &format!( "\\x{:02X}", c as u32 ) ),
+            ' '..='~' => quoted.push( c ),
+            _ => quoted.push_str( &format!( "\\x{:02X}", c as u32 & 0xff ) )
+        }
+    }
+    quoted.push( '"' );
+    quoted
+}
+
+fn unquote_name( text : &str, length : usize ) -> Result<String, String> {
+    let inner = text.strip_prefix( '"' ).and_then( | text | text.strip_suffix( '"' ) ).ok_or( format!( "Expected a quoted name, found {}", text ) )?;
+    let mut name = String::new();
+    let mut chars = inner.chars();
+    while let Some( c ) = chars.next() {
+        if c == '\\' {
+            let code : String = chars.by_ref().take( 3 ).collect();
+            let value = code.strip_prefix( 'x' ).and_then( | hex | u8::from_str_radix( hex, 16 ).ok() ).ok_or( format!( "Bad escape \\{}", code ) )?;
+            name.push( value as char );
+        } else {
+            name.push( c );
+        }
+    }
+    if name.chars().count() > length {
+        return Err( format!( "Name {} is longer than {} characters", text, length ) );
+    }
+    while name.chars().count() < length {
+        name.push( '\0' );
+    }
+    Ok( name )
+}
+
+fn hex_bytes( bytes : &[u8] ) -> String {
+    bytes.iter().map( | byte | format!( "{:02X}", byte ) ).collect::<Vec<String>>().join( " " )
+}
+
+fn parse_hex_bytes( text : &str ) -> Result<Vec<u8>, String> {
+    text.split_whitespace().map( | byte | u8::from_str_radix( byte, 16 ).map_err( | _ | format!( "Bad hex byte {}", byte ) ) ).collect()
+}
+
+const SAMPLE_BYTES_PER_LINE : usize = 32;
+
+impl Song {
+    /**
+     * The whole song as text: header, samples with their data in hex and then the patterns. The text can be read back with
+     * from_text and gives the same song, so it works for keeping songs in version control. Only mods can be written,
+     * the text has no room for the keys, volume column and instruments of the other formats
+     */
+    pub fn to_text( &self ) -> io::Result<String> {
+        if self.format.kind != ModuleKind::Mod {
+            return Err( io::Error::new( io::ErrorKind::InvalidInput, "Only songs loaded from mod files can be written as text" ) );
+        }
+        let mut text = String::new();
+        text.push_str( &format!( "title {}\n", quote_name( &self.name ) ) );
+        text.push_str( &format!( "channels {}\n", self.format.num_channels ) );
+        match self.format.tag {
+            Some( tag ) => text.push_str( &format!( "tag {}\n", quote_name( &tag.iter().map( | byte | *byte as char ).collect::<String>() ) ) ),
+            None => text.push_str( "tag none\n" )
+        }
+        text.push_str( &format!( "restart {}\n", self.end_position ) );
+        text.push_str( &format!( "length {}\n", self.num_used_patterns ) );
+        // positions past the song length are kept if anything is stored in them
+        let stored_orders = self.pattern_table.iter().rposition( | pattern | *pattern != 0 ).map_or( 0, | last | last + 1 ).max( self.num_used_patterns as usize );
+        text.push_str( &format!( "order {}\n", hex_bytes( &self.pattern_table[ 0..stored_orders ] ) ) );
+
+        for ( number, sample ) in self.samples.iter().enumerate() {
+            text.push_str( &format!( "\nsample {:02X} {}\n", number + 1, quote_name( &sample.name ) ) );
+            text.push_str( &format!( "volume {}\n", sample.volume ) );
+            text.push_str( &format!( "finetune {}\n", sample.fine_tune ) );
+            text.push_str( &format!( "loop {} {}\n", sample.repeat_offset, sample.repeat_size ) );
+            text.push_str( &format!( "data {}\n", sample.samples.len() ) );
+            for chunk in sample.samples.chunks( SAMPLE_BYTES_PER_LINE ) {
+                let bytes : Vec<u8> = chunk.iter().map( | value | *value as u8 ).collect();
+                text.push_str( &hex_bytes( &bytes ) );
+                text.push( '\n' );
+            }
+        }
+
+        for ( number, pattern ) in self.patterns.iter().enumerate() {
+            text.push_str( &format!( "\npattern {:02X}\n", number ) );
+            text.push_str( &pattern.to_text() );
+        }
+        Ok( text )
+    }
+
+    /**
+     * Read a song written by to_text
+     */
+    pub fn from_text( text : &str ) -> Result<Song, TextError> {
+        let lines : Vec<( usize, &str )> = text.lines().enumerate().map( | ( index, line ) | ( index + 1, line ) ).collect();
+        let mut song = Song {
+            name : String::new(),
+            format : FormatDescription{ num_channels : 0, num_samples : 0, has_tag : false, tag : None, kind : ModuleKind::Mod },
+            samples : Vec::new(),
+            patterns : Vec::new(),
+            pattern_table : Vec::new(),
+            num_used_patterns : 0,
+            end_position : 0,
+            channel_panning : Vec::new(),
+            global_volume : default_global_volume(),
+            initial_speed : default_speed(),
+            initial_bpm : default_bpm(),
+            instruments : Vec::new(),
+            linear_periods : false,
+        };
+        let mut index = 0;
+        while index < lines.len() {
+            let ( line_number, line ) = lines[ index ];
+            index += 1;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let ( keyword, value ) = line.split_once( ' ' ).unwrap_or( ( line, "" ) );
+            let value = value.trim();
+            let error = | message : String | text_error( line_number, message );
+            let number = | value : &str | value.parse::<u32>().map_err( | _ | error( format!( "Expected a number, found \"{}\"", value ) ) );
+            let number_up_to = | value : &str, max : u32 | match number( value )? {
+                number if number > max => Err( error( format!( "{} is more than the {} a {} can be", number, max, keyword ) ) ),
+                number => Ok( number )
+            };
+            match keyword {
+                "title" => song.name = unquote_name( value, 20 ).map_err( error )?,
+                "channels" => song.format.num_channels = number( value )?,
+                "tag" if value == "none" => (),
+                "tag" => {
+                    let tag : Vec<u8> = unquote_name( value, 4 ).map_err( error )?.chars().map( | c | c as u8 ).collect();
+                    song.format.tag = Some( [ tag[ 0 ], tag[ 1 ], tag[ 2 ], tag[ 3 ] ] );
+                    song.format.has_tag = true;
+                }
+                "restart" => song.end_position = number( value )?,
+                "length" => song.num_used_patterns = number( value )?,
+                "order" => song.pattern_table = parse_hex_bytes( value ).map_err( error )?,
+                "sample" => {
+                    let ( sample_number, name ) = value.split_once( ' ' ).ok_or_else( || error( String::from( "Expected a sample number and name" ) ) )?;
+                    if u32::from_str_radix( sample_number, 16 ).ok() != Some( song.samples.len() as u32 + 1 ) {
+                        return Err( error( format!( "Expected sample {:02X}", song.samples.len() + 1 ) ) );
+                    }
+                    song.samples.push( Sample::from_data( &unquote_name( name.trim(), 22 ).map_err( error )?, Vec::new() ) );
+                }
+                "volume" | "finetune" | "loop" | "data" => {
+                    let sample = song.samples.last_mut().ok_or_else( || error( format!( "{} before the first sample", keyword ) ) )?;
+                    match keyword {
+                        "volume" => sample.volume = number_up_to( value, 64 )? as u8,
+                        "finetune" => sample.fine_tune = number_up_to( value, 15 )? as u8,
+                        "loop" => {
+                            let ( offset, size ) = value.split_once( ' ' ).ok_or_else( || error( String::from( "Expected the loop offset and size" ) ) )?;
+                            sample.repeat_offset = number( offset.trim() )?;
+                            sample.repeat_size = number( size.trim() )?;
+                        }
+                        _ => {
+                            let size = number_up_to( value, 0xffff * 2 )? as usize;      // mod sample lengths are stored in words
+                            let mut data = Vec::with_capacity( size );
+                            while data.len() < size {
+                                let ( data_line, hex ) = lines.get( index ).ok_or_else( || error( format!( "The sample data ends after {} of {} bytes", data.len(), size ) ) )?;
+                                data.extend( parse_hex_bytes( hex ).map_err( | message | text_error( *data_line, message ) )?.iter().map( | byte | *byte as i8 ) );
+                                index += 1;
+                            }
+                            if data.len() != size {
+                                return Err( error( format!( "Sample has {} bytes of data, expected {}", data.len(), size ) ) );
+                            }
+                            sample.size = size as u32;
+                            sample.samples = data;
+                        }
+                    }
+                }
+                "pattern" => {
+                    if u32::from_str_radix( value, 16 ).ok() != Some( song.patterns.len() as u32 ) {
+                        return Err( error( format!( "Expected pattern {:02X}", song.patterns.len() ) ) );
+                    }
+                    let start = index;
+                    while index < lines.len() && !lines[ index ].1.trim_start().starts_with( "pattern" ) {
+                        index += 1;
+                    }
+                    let pattern = parse_pattern( &lines[ start..index ] )?;
+                    if pattern.lines[ 0 ].len() != song.format.num_channels as usize {
+                        return Err( error( format!( "Pattern has {} channels, the song has {}", pattern.lines[ 0 ].len(), song.format.num_channels ) ) );
+                    }
+                    song.patterns.push( pattern );
+                }
+                _ => return Err( error( format!( "Unknown keyword {}", keyword ) ) )
+            }
+        }
+
+        let last_line = lines.len();
+        if song.num_used_patterns > 128 {
+            return Err( text_error( last_line, format!( "Song length {} is more than the 128 positions of a mod", song.num_used_patterns ) ) );
+        }
+        song.check_structure().map_err( | message | text_error( last_line, message ) )?;
+        song.pattern_table.resize( 128, 0 );
+        song.format.num_samples = song.samples.len() as u32;
+        Ok( song )
+    }
+}
@@ -0,0 +1,81 @@
+use std::time::Instant;
+
+use crate::player::Player;
+
+/**
+ * A tick and the index of the frame where it starts
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TickStart {
+    pub sample_index : u64,
+    pub song_pattern_position : u32,
+    pub line : u32,
+    pub tick : u32,
+}
+
+/**
+ * Mixes a player a frame at a time and notices when a new tick starts, so that positions can be tagged with
+ * the frame where they are heard
+ */
+pub struct TickTracker {
+    last_tick : u64,
+    frames_mixed : u64,
+}
+
+impl TickTracker {
+    pub fn new( player : &Player ) -> TickTracker {
+        TickTracker{ last_tick : player.state().ticks_played(), frames_mixed : 0 }
+    }
+
+    /**
+     * Mix the next frame. Returns it along with the tick that starts on it, if one does
+     */
+    pub fn next_frame( &mut self, player : &mut Player ) -> ( ( f32, f32 ), Option<TickStart> ) {
+        let frame = player.next_sample();
+        let mut tick_start = None;
+        if player.state().ticks_played() != self.last_tick {
+            self.last_tick = player.state().ticks_played();
+            let ( song_pattern_position, line ) = player.state().playing_row();
+            tick_start = Some( TickStart{ sample_index : self.frames_mixed, song_pattern_position, line, tick : player.state().current_tick() } );
+        }
+        self.frames_mixed += 1;
+        ( frame, tick_start )
+    }
+
+    /**
+     * Frames mixed so far
+     */
+    pub fn frames_mixed( &self ) -> u64 {
+        self.frames_mixed
+    }
+}
+
+/**
+ * Estimates the frame a device is playing from the frames rendered for it. The device is assumed to be playing the
+ * previous buffer while the next one is filled, so the audible position trails the rendered one by a buffer. Between
+ * buffers the position moves on with the clock, but never past the buffer being played
+ */
+pub struct HeardSampleClock {
+    sample_rate : u32,
+    last_rendered : u64,
+    last_callback_time : Instant,
+}
+
+impl HeardSampleClock {
+    pub fn new( sample_rate : u32, now : Instant ) -> HeardSampleClock {
+        HeardSampleClock{ sample_rate, last_rendered : 0, last_callback_time : now }
+    }
+
+    /**
+     * The frame being heard at the given time, from the frames rendered so far including the latest device buffer
+     * and the size of that buffer
+     */
+    pub fn heard_sample( &mut self, rendered : u64, buffer_size : u64, now : Instant ) -> u64 {
+        if rendered != self.last_rendered {
+            self.last_rendered = rendered;
+            self.last_callback_time = now;
+        }
+        let elapsed = ( now.saturating_duration_since( self.last_callback_time ).as_secs_f64() * self.sample_rate as f64 ) as u64;
+        ( rendered + elapsed.min( buffer_size ) ).saturating_sub( 2 * buffer_size )
+    }
+}
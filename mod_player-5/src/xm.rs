@@ -0,0 +1,467 @@
+use std::fs;
+use std::io;
+
+use crate::song::{Song, Sample, Pattern, Note, Effect, Key, Instrument, Envelope, AutoVibrato, FormatDescription, ModuleKind, LoadError,
+    FREQUENCY_TABLE, encode_name, decode_name};
+
+const XM_NOTE_B6 : usize = 84;          // the first entry of the frequency table. Period 856 is C-3, which plays at 8363 / 2 Hz in XM too
+const INSTRUMENT_HEADER_SIZE : u32 = 263;
+const SAMPLE_HEADER_SIZE : u32 = 40;
+
+/**
+ * The XM note for a period, 1 is C-0. Periods that are not in the table get the nearest note
+ */
+fn xm_note( period : u32 ) -> u8 {
+    if period == 0 {
+        return 0;
+    }
+    let index = match FREQUENCY_TABLE.binary_search( &period ) {
+        Ok( index ) => index,
+        Err( 0 ) => 0,
+        Err( index ) if index == FREQUENCY_TABLE.len() => index - 1,
+        // between two table entries, the nearer one wins
+        Err( index ) => if period - FREQUENCY_TABLE[ index - 1 ] < FREQUENCY_TABLE[ index ] - period { index - 1 } else { index }
+    };
+    ( XM_NOTE_B6 - index ) as u8
+}
+
+/**
+ * The effect as FastTracker 2 stores it. Most mod effects are the same in XM, the ones that are not:
+ * slides with a zero argument do nothing in a mod but repeat the last slide in XM, 5xy and 6xy without
+ * a slide are plain portamento and vibrato, E8x panning becomes 8xx, and the filter and invert loop
+ * have no XM counterpart
+ */
+fn xm_effect( effect : &Effect ) -> ( u8, u8 ) {
+    match *effect {
+        Effect::SlideUp{ speed : 0 } | Effect::SlideDown{ speed : 0 } | Effect::VolumeSlide{ up : 0, down : 0 } |
+        Effect::FineSlideUp{ speed : 0 } | Effect::FineSlideDown{ speed : 0 } |
+        Effect::FineVolumeSlideUp{ change : 0 } | Effect::FineVolumeSlideDown{ change : 0 } |
+        Effect::SetFilter{ .. } | Effect::InvertLoop{ .. } => ( 0, 0 ),
+        Effect::TonePortamentoVolumeSlide{ up : 0, down : 0 } => ( 3, 0 ),
+        Effect::VibratoVolumeSlide{ up : 0, down : 0 } => ( 4, 0 ),
+        Effect::SetCoarsePanning{ position } => ( 8, position * 17 ),
+        _ => effect.to_raw()
+    }
+}
+
+/**
+ * Pack a note the FastTracker 2 way: a flag byte says which of the five fields follow, unless they all do
+ */
+fn pack_note( note : &Note, data : &mut Vec<u8> ) {
+    let ( effect, argument ) = xm_effect( &note.effect );
+    let fields = [ xm_note( note.period ), note.sample_number, 0, effect, argument ];
+    if fields.iter().all( | field | *field != 0 ) {
+        data.extend_from_slice( &fields );
+        return;
+    }
+    let flags = fields.iter().enumerate().fold( 0x80, | flags, ( bit, field ) | if *field != 0 { flags | 1 << bit } else { flags } );
+    data.push( flags );
+    data.extend( fields.iter().filter( | field | **field != 0 ) );
+}
+
+fn sample_header( sample : &Sample ) -> Vec<u8> {
+    let ( loop_start, loop_length, loop_type ) = if sample.repeat_size > 2 && sample.repeat_offset < sample.size {
+        ( sample.repeat_offset, sample.repeat_size.min( sample.size - sample.repeat_offset ), 1 )
+    } else {
+        ( 0, 0, 0 )
+    };
+    let fine_tune = ( ( sample.fine_tune << 4 ) as i8 >> 4 ) * 16;        // eighths of a semitone to 128ths
+
+    let mut header = Vec::new();
+    header.extend_from_slice( &( sample.samples.len() as u32 ).to_le_bytes() );
+    header.extend_from_slice( &loop_start.to_le_bytes() );
+    header.extend_from_slice( &loop_length.to_le_bytes() );
+    header.push( sample.volume.min( 64 ) );
+    header.push( fine_tune as u8 );
+    header.push( loop_type );
+    header.push( 128 );                 // centre panning
+    header.push( 0 );                   // relative note, the note numbers already match
+    header.push( 0 );
+    header.extend( encode_name( &sample.name, 22 ) );
+    header
+}
+
+/**
+ * An instrument that plays one sample over the whole keyboard without envelopes
+ */
+fn instrument( sample : &Sample ) -> Vec<u8> {
+    let has_sample = !sample.samples.is_empty();
+    let mut data = Vec::new();
+    data.extend_from_slice( &( if has_sample { INSTRUMENT_HEADER_SIZE } else { 29 } ).to_le_bytes() );
+    data.extend( encode_name( &sample.name, 22 ) );
+    data.push( 0 );
+    data.extend_from_slice( &( has_sample as u16 ).to_le_bytes() );
+    if !has_sample {
+        return data;
+    }
+    data.extend_from_slice( &SAMPLE_HEADER_SIZE.to_le_bytes() );
+    data.resize( INSTRUMENT_HEADER_SIZE as usize, 0 );     // keyboard map, envelopes, vibrato and fadeout are all zero
+    data.extend( sample_header( sample ) );
+    // sample data is stored as differences between values
+    let mut last = 0i8;
+    for value in &sample.samples {
+        data.push( value.wrapping_sub( last ) as u8 );
+        last = *value;
+    }
+    data
+}
+
+impl Song {
+    /**
+     * Write the song as a FastTracker 2 module with the Amiga frequency table. Every sample becomes an instrument
+     * with the same number. FastTracker 2 needs an even number of channels, so odd channel counts get an empty
+     * channel at the end
+     */
+    pub fn write_xm<W : io::Write>( &self, writer : &mut W ) -> io::Result<()> {
+        let invalid = | message : String | io::Error::new( io::ErrorKind::InvalidInput, message );
+        if self.format.kind != ModuleKind::Mod {
+            return Err( invalid( "Only songs loaded from mod files can be converted to XM".to_string() ) );
+        }
+        if self.patterns.len() > 256 {
+            return Err( invalid( format!( "An XM file holds 256 patterns, the song has {}", self.patterns.len() ) ) );
+        }
+        if self.samples.len() > 128 {
+            return Err( invalid( format!( "An XM file holds 128 instruments, the song has {} samples", self.samples.len() ) ) );
+        }
+        let num_channels = self.format.num_channels as usize;
+        let xm_channels = num_channels + num_channels % 2;
+        let song_length = ( self.num_used_patterns as usize ).min( self.pattern_table.len() );
+        let restart = if self.end_position < self.num_used_patterns { self.end_position } else { 0 };
+
+        let mut data = Vec::new();
+        data.extend_from_slice( b"Extended Module: " );
+        data.extend( encode_name( &self.name, 20 ) );
+        data.push( 0x1a );
+        data.extend( encode_name( "mod_player", 20 ) );
+        data.extend_from_slice( &0x0104u16.to_le_bytes() );
+        data.extend_from_slice( &276u32.to_le_bytes() );
+        for value in [ song_length, restart as usize, xm_channels, self.patterns.len(), self.samples.len(), 0, 6, 125 ] {
+            data.extend_from_slice( &( value as u16 ).to_le_bytes() );      // flags 0 is the Amiga frequency table
+        }
+        let mut order = self.pattern_table[ 0..song_length ].to_vec();
+        order.resize( 256, 0 );
+        data.extend( order );
+
+        for pattern in &self.patterns {
+            let mut packed = Vec::new();
+            for line in &pattern.lines {
+                for note in line {
+                    pack_note( note, &mut packed );
+                }
+                packed.resize( packed.len() + xm_channels - num_channels, 0x80 );
+            }
+            data.extend_from_slice( &9u32.to_le_bytes() );
+            data.push( 0 );
+            data.extend_from_slice( &( pattern.lines.len() as u16 ).to_le_bytes() );
+            data.extend_from_slice( &( packed.len() as u16 ).to_le_bytes() );
+            data.extend( packed );
+        }
+
+        for sample in &self.samples {
+            data.extend( instrument( sample ) );
+        }
+        writer.write_all( &data )
+    }
+}
+
+const XM_HEADER_START : usize = 60;        // the header size is stored here, the header itself follows it
+const NOTE_OFF : u8 = 97;
+const ENVELOPE_POINTS : usize = 12;
+
+fn word( data : &[u8], offset : usize ) -> u16 {
+    u16::from_le_bytes( [ data[ offset ], data[ offset + 1 ] ] )
+}
+
+fn double_word( data : &[u8], offset : usize ) -> u32 {
+    u32::from_le_bytes( [ data[ offset ], data[ offset + 1 ], data[ offset + 2 ], data[ offset + 3 ] ] )
+}
+
+fn format_error( message : String ) -> LoadError {
+    LoadError::Format( message )
+}
+
+/**
+ * Decode an effect number and argument as FastTracker 2 stores them. 0 - F are the mod effects, the later ones
+ * are numbered on through the alphabet with G as 16
+ */
+pub(crate) fn decode_effect( number : u8, argument : u8 ) -> Effect {
+    let high = argument >> 4;
+    let low = argument & 0x0f;
+    match number {
+        0..=15 => Effect::new( number, argument ),
+        16 => Effect::SetGlobalVolume{ volume : argument.min( 64 ) },
+        17 => Effect::GlobalVolumeSlide{ up : high, down : low },
+        20 => Effect::KeyOff{ tick : argument },
+        21 => Effect::SetEnvelopePosition{ position : argument },
+        25 => Effect::PanningSlide{ left : low, right : high },
+        27 => Effect::RetriggerVolumeSlide{ volume_change : high, ticks : low },
+        29 => Effect::Tremor{ on : high, off : low },
+        33 => match high {
+            1 => Effect::ExtraFineSlideUp{ speed : low },
+            2 => Effect::ExtraFineSlideDown{ speed : low },
+            _ => Effect::None
+        },
+        _ => Effect::None
+    }
+}
+
+/**
+ * The volume column: volumes from 0x10, then a command in the high nibble with its argument in the low one
+ */
+fn volume_column( value : u8 ) -> Effect {
+    let low = value & 0x0f;
+    match value >> 4 {
+        1..=4 => Effect::SetVolume{ volume : value - 0x10 },
+        5 if low == 0 => Effect::SetVolume{ volume : 64 },
+        6 => Effect::VolumeSlide{ up : 0, down : low },
+        7 => Effect::VolumeSlide{ up : low, down : 0 },
+        8 => Effect::FineVolumeSlideDown{ change : low },
+        9 => Effect::FineVolumeSlideUp{ change : low },
+        0x0a => Effect::Vibrato{ speed : low, amplitude : 0 },
+        0x0b => Effect::Vibrato{ speed : 0, amplitude : low },
+        0x0c => Effect::SetPanning{ position : low * 17 },
+        0x0d => Effect::PanningSlide{ left : low, right : 0 },
+        0x0e => Effect::PanningSlide{ left : 0, right : low },
+        0x0f => Effect::TonePortamento{ speed : low * 16 },
+        _ => Effect::None
+    }
+}
+
+/**
+ * Where a channel remembers the argument of an effect that repeats its last argument when it is given 0. Gives the
+ * memory slot, the effect number, the part of the argument that is not remembered and the remembered part.
+ * 5xy and 6xy share the memory of Axy
+ */
+pub(crate) fn effect_memory( effect : &Effect ) -> Option<( usize, u8, u8, u8 )> {
+    let nibbles = | high : u8, low : u8 | ( high << 4 ) | ( low & 0x0f );
+    let ( slot, number, argument ) = match *effect {
+        Effect::SlideUp{ speed } => ( 0, 1, speed ),
+        Effect::SlideDown{ speed } => ( 1, 2, speed ),
+        Effect::TonePortamentoVolumeSlide{ up, down } => ( 2, 5, nibbles( up, down ) ),
+        Effect::VibratoVolumeSlide{ up, down } => ( 2, 6, nibbles( up, down ) ),
+        Effect::VolumeSlide{ up, down } => ( 2, 10, nibbles( up, down ) ),
+        Effect::FineSlideUp{ speed } => return Some( ( 3, 14, 0x10, speed ) ),
+        Effect::FineSlideDown{ speed } => return Some( ( 4, 14, 0x20, speed ) ),
+        Effect::FineVolumeSlideUp{ change } => return Some( ( 5, 14, 0xa0, change ) ),
+        Effect::FineVolumeSlideDown{ change } => return Some( ( 6, 14, 0xb0, change ) ),
+        Effect::GlobalVolumeSlide{ up, down } => ( 7, 17, nibbles( up, down ) ),
+        Effect::PanningSlide{ left, right } => ( 8, 25, nibbles( right, left ) ),
+        Effect::RetriggerVolumeSlide{ volume_change, ticks } => ( 9, 27, nibbles( volume_change, ticks ) ),
+        Effect::ExtraFineSlideUp{ speed } => return Some( ( 10, 33, 0x10, speed ) ),
+        Effect::ExtraFineSlideDown{ speed } => return Some( ( 11, 33, 0x20, speed ) ),
+        _ => return None
+    };
+    Some( ( slot, number, 0, argument ) )
+}
+
+pub(crate) const EFFECT_MEMORY_SLOTS : usize = 12;
+
+/**
+ * Instrument envelope. The points are stored as 12 pairs of words, followed by the number of points and then the
+ * sustain point and loop of the envelope. The flags switch the envelope, its sustain point and its loop on
+ */
+fn read_envelope( header : &[u8], points_offset : usize, count_offset : usize, settings_offset : usize, flags : u8 ) -> Envelope {
+    if flags & 1 == 0 {
+        return Envelope::default();
+    }
+    let num_points = ( header[ count_offset ] as usize ).min( ENVELOPE_POINTS );
+    let points = ( 0..num_points ).map( | point | {
+        let offset = points_offset + point * 4;
+        ( word( header, offset ), word( header, offset + 2 ).min( 64 ) as u8 )
+    } ).collect();
+    let sustain = header[ settings_offset ] as usize;
+    let ( loop_start, loop_end ) = ( header[ settings_offset + 1 ] as usize, header[ settings_offset + 2 ] as usize );
+    Envelope {
+        points,
+        sustain : if flags & 2 != 0 && sustain < num_points { Some( ( sustain, sustain ) ) } else { None },
+        loop_points : if flags & 4 != 0 && loop_start <= loop_end && loop_end < num_points { Some( ( loop_start, loop_end ) ) } else { None },
+    }
+}
+
+/**
+ * Sample data is stored as differences between values. 16 bit samples are reduced to the 8 bits the player mixes at
+ */
+fn read_sample_data( data : &[u8], sixteen_bit : bool ) -> Vec<i8> {
+    if sixteen_bit {
+        let mut value = 0i16;
+        data.chunks_exact( 2 ).map( | bytes | {
+            value = value.wrapping_add( i16::from_le_bytes( [ bytes[ 0 ], bytes[ 1 ] ] ) );
+            ( ( value as i32 + 0x80 ) >> 8 ).clamp( -128, 127 ) as i8
+        } ).collect()
+    } else {
+        let mut value = 0i8;
+        data.iter().map( | byte | {
+            value = value.wrapping_add( *byte as i8 );
+            value
+        } ).collect()
+    }
+}
+
+/**
+ * Read a sample header and give the sample without its data, with the data size in bytes
+ */
+fn read_sample_header( header : &[u8] ) -> Result<( Sample, usize, bool ), LoadError> {
+    let length = double_word( header, 0 ) as usize;
+    let flags = header[ 14 ];
+    let sixteen_bit = flags & 0x10 != 0;
+    let name = decode_name( &header[ 18..40 ] );
+    if header[ 17 ] == 0xad {
+        return Err( format_error( format!( "Sample {} is ADPCM compressed, which is not supported", name.trim_end_matches( '\0' ) ) ) );
+    }
+    let bytes_per_value = if sixteen_bit { 2 } else { 1 };
+    let mut sample = Sample::from_data( &name, Vec::new() );
+    sample.volume = header[ 12 ].min( 64 );
+    sample.panning = Some( header[ 15 ] );
+    // relative note and finetune in 128ths of a semitone become the rate of C-4
+    let tuning = header[ 16 ] as i8 as f64 * 128.0 + header[ 13 ] as i8 as f64;
+    sample.c2_speed = ( 8363.0 * 2.0f64.powf( tuning / 1536.0 ) ).round() as u32;
+    let loop_start = double_word( header, 4 ) / bytes_per_value;
+    let loop_length = double_word( header, 8 ) / bytes_per_value;
+    if flags & 3 != 0 && loop_length > 0 {
+        sample.repeat_offset = loop_start;
+        sample.repeat_size = loop_length;
+        sample.ping_pong = flags & 2 != 0;
+    }
+    Ok( ( sample, length, sixteen_bit ) )
+}
+
+pub fn read_xm_file( file_name : &str ) -> Result<Song, LoadError> {
+    let file_data : Vec<u8> = fs::read( file_name )?;
+    read_xm_data( &file_data )
+}
+
+/**
+ * Parse a FastTracker 2 module that has already been loaded into memory. The samples of all the instruments are
+ * put one after the other in the song's samples and each instrument's keymap points into them
+ */
+pub fn read_xm_data( file_data : &[u8] ) -> Result<Song, LoadError> {
+    if file_data.len() < XM_HEADER_START + 20 || &file_data[ 0..17 ] != b"Extended Module: " {
+        return Err( format_error( String::from( "Not a FastTracker 2 module" ) ) );
+    }
+    let header_end = XM_HEADER_START + double_word( file_data, XM_HEADER_START ) as usize;
+    let song_length = word( file_data, 64 ) as usize;
+    let restart = word( file_data, 66 ) as u32;
+    let num_channels = word( file_data, 68 ) as u32;
+    let num_patterns = word( file_data, 70 ) as usize;
+    let num_instruments = word( file_data, 72 ) as usize;
+    let linear_periods = word( file_data, 74 ) & 1 != 0;
+    if file_data.len() < header_end || header_end < XM_HEADER_START + 20 + song_length.min( 256 ) {
+        return Err( format_error( String::from( "The header is cut short" ) ) );
+    }
+    if num_channels == 0 || num_channels > 32 {
+        return Err( format_error( format!( "The song has {} channels, the player handles 1 - 32", num_channels ) ) );
+    }
+    if num_patterns > 256 || song_length == 0 || song_length > 256 {
+        return Err( format_error( format!( "Invalid song length {} or pattern count {}", song_length, num_patterns ) ) );
+    }
+    let pattern_table = file_data[ 80..80 + song_length ].to_vec();
+    if let Some( pattern ) = pattern_table.iter().find( | pattern | **pattern as usize >= num_patterns ) {
+        return Err( format_error( format!( "Order list refers to pattern {} but the file only has {}", pattern, num_patterns ) ) );
+    }
+    let cut_short = | what : String | format_error( format!( "The file ends in {}", what ) );
+
+    let mut position = header_end;
+    let mut patterns = Vec::new();
+    for number in 0..num_patterns {
+        let header = file_data.get( position..position + 9 ).ok_or_else( || cut_short( format!( "the header of pattern {}", number ) ) )?;
+        let num_lines = ( word( header, 5 ) as usize ).clamp( 1, 256 );
+        let data_start = position + double_word( header, 0 ) as usize;
+        let data_end = data_start + word( header, 7 ) as usize;
+        let data = file_data.get( data_start..data_end ).ok_or_else( || cut_short( format!( "pattern {}", number ) ) )?;
+        let mut pattern = Pattern::with_lines( num_channels, num_lines );
+        let mut fields = data.iter().copied();
+        'lines: for line in pattern.lines.iter_mut() {
+            for note in line.iter_mut() {
+                let first = match fields.next() {
+                    Some( first ) => first,
+                    None => break 'lines
+                };
+                // a flag byte says which of the five fields follow, otherwise all five do
+                let flags = if first & 0x80 != 0 { first } else { 0x1f };
+                let mut field = | bit : u8 | if flags & bit == 0 { 0 } else if bit == 1 && first & 0x80 == 0 { first } else { fields.next().unwrap_or( 0 ) };
+                let ( key, instrument, volume, effect, argument ) = ( field( 1 ), field( 2 ), field( 4 ), field( 8 ), field( 16 ) );
+                let key = match key {
+                    1..=96 => Key::Note( key - 1 ),
+                    NOTE_OFF => Key::Off,
+                    _ => Key::None
+                };
+                let instrument = if instrument as usize <= num_instruments { instrument } else { 0 };
+                *note = Note::with_key( instrument, key, volume_column( volume ), decode_effect( effect, argument ) );
+            }
+        }
+        patterns.push( pattern );
+        position = data_end;
+    }
+
+    let mut instruments = Vec::new();
+    let mut samples : Vec<Sample> = Vec::new();
+    for number in 1..=num_instruments {
+        let size = file_data.get( position..position + 29 ).map( | header | double_word( header, 0 ) as usize ).ok_or_else( || cut_short( format!( "instrument {}", number ) ) )?;
+        let name = decode_name( &file_data[ position + 4..position + 26 ] );
+        let num_samples = word( file_data, position + 27 ) as usize;
+        if num_samples == 0 {
+            instruments.push( Instrument{ name, keymap : vec![ 0; 96 ], ..Instrument::default() } );
+            position += size.max( 29 );
+            continue;
+        }
+        let header = file_data.get( position..position + 243 ).ok_or_else( || cut_short( format!( "instrument {}", number ) ) )?;
+        if samples.len() + num_samples > 255 {
+            return Err( format_error( String::from( "The song has more than 255 samples" ) ) );
+        }
+        let first_sample = samples.len() as u8 + 1;
+        let keymap = header[ 33..129 ].iter().map( | sample | if ( *sample as usize ) < num_samples { first_sample + sample } else { 0 } ).collect();
+        instruments.push( Instrument {
+            name,
+            keymap,
+            volume_envelope : read_envelope( header, 129, 225, 227, header[ 233 ] ),
+            panning_envelope : read_envelope( header, 177, 226, 230, header[ 234 ] ),
+            fadeout : word( header, 239 ) as u32,
+            vibrato : AutoVibrato{ wave : header[ 235 ], sweep : header[ 236 ], depth : header[ 237 ], rate : header[ 238 ] },
+            ..Instrument::default()
+        } );
+
+        let sample_header_size = ( double_word( header, 29 ) as usize ).max( 40 );
+        position += size;
+        let mut headers = Vec::new();
+        for index in 0..num_samples {
+            let start = position + index * sample_header_size;
+            let sample_header = file_data.get( start..start + 40 ).ok_or_else( || cut_short( format!( "the samples of instrument {}", number ) ) )?;
+            headers.push( read_sample_header( sample_header )? );
+        }
+        position += num_samples * sample_header_size;
+        for ( mut sample, length, sixteen_bit ) in headers {
+            // a sample that is cut short keeps the part that is there
+            let data = &file_data[ position.min( file_data.len() )..( position + length ).min( file_data.len() ) ];
+            position += length;
+            sample.samples = read_sample_data( data, sixteen_bit );
+            // nothing after the end of the loop is ever played, loops of 2 values or less don't loop
+            let loop_end = ( sample.repeat_offset + sample.repeat_size ).min( sample.samples.len() as u32 );
+            if sample.repeat_size > 2 && sample.repeat_offset + 2 < loop_end {
+                sample.samples.truncate( loop_end as usize );
+                sample.repeat_size = loop_end - sample.repeat_offset;
+            } else {
+                sample.repeat_offset = 0;
+                sample.repeat_size = 2;
+                sample.ping_pong = false;
+            }
+            sample.size = sample.samples.len() as u32;
+            samples.push( sample );
+        }
+    }
+
+    Ok( Song {
+        name : decode_name( &file_data[ 17..37 ] ),
+        format : FormatDescription{ num_channels, num_samples : samples.len() as u32, has_tag : false, tag : None, kind : ModuleKind::Xm },
+        samples,
+        patterns,
+        num_used_patterns : song_length as u32,
+        pattern_table,
+        end_position : restart,
+        channel_panning : vec![ 128; num_channels as usize ],
+        global_volume : 64,
+        initial_speed : match word( file_data, 76 ) { 0 => 6, speed => speed.min( 31 ) as u32 },
+        initial_bpm : match word( file_data, 78 ) { 0..=31 => 125, bpm => bpm.min( 255 ) as u32 },
+        instruments,
+        linear_periods,
+    } )
+}
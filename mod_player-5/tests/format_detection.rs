@@ -0,0 +1,82 @@
+use std::fs;
+use std::sync::Arc;
+
+use mod_player::{FormatGuess, ModuleKind, Player, PlayerOptions, detect_format, guess_format, read_mod_data, read_module_data};
+
+/**
+ * An original Soundtracker module: 15 samples and no tag, one pattern and an 8 byte sample with a loop
+ */
+fn soundtracker_module() -> Vec<u8> {
+    let mut data = vec![ 0u8; 600 + 1024 + 8 ];
+    data[ 0..5 ].copy_from_slice( b"intro" );
+    data[ 20..26 ].copy_from_slice( b"bass 1" );
+    data[ 43 ] = 4;                             // 4 words long
+    data[ 45 ] = 64;
+    data[ 49 ] = 2;                             // looped over the second half
+    data[ 470 ] = 1;
+    data[ 471 ] = 120;
+    data
+}
+
+#[test]
+fn tagged_formats_are_recognised() {
+    let song_data = fs::read( "stardstm.mod" ).unwrap();
+    assert_eq!( guess_format( &song_data ), Some( FormatGuess{ kind : ModuleKind::Mod, confidence : 90 } ) );
+
+    let mut xm = Vec::new();
+    read_mod_data( &song_data ).unwrap().write_xm( &mut xm ).unwrap();
+    assert_eq!( guess_format( &xm ), Some( FormatGuess{ kind : ModuleKind::Xm, confidence : 100 } ) );
+    assert_eq!( read_module_data( &xm ).unwrap().format().kind, ModuleKind::Xm );
+
+    let mut s3m = vec![ 0u8; 0x60 ];
+    s3m[ 0x1d ] = 16;
+    s3m[ 0x2c..0x30 ].copy_from_slice( b"SCRM" );
+    assert_eq!( detect_format( &s3m ), Some( ModuleKind::S3m ) );
+
+    // an Impulse Tracker header whose order list and offsets run past the end of the data
+    let mut it = vec![ 0u8; 0xc0 ];
+    it[ 0..4 ].copy_from_slice( b"IMPM" );
+    it[ 0x20 ] = 2;
+    assert_eq!( guess_format( &it ), Some( FormatGuess{ kind : ModuleKind::It, confidence : 60 } ) );
+}
+
+#[test]
+fn untagged_modules_score_by_how_sane_they_look() {
+    let data = soundtracker_module();
+    assert_eq!( guess_format( &data ), Some( FormatGuess{ kind : ModuleKind::Mod, confidence : 80 } ) );
+    assert_eq!( read_module_data( &data ).unwrap().format().num_samples, 15 );
+
+    // extra data at the end and a name that is not text make it less likely
+    let mut padded = data.clone();
+    padded.extend( vec![ 0u8; 100 ] );
+    assert_eq!( guess_format( &padded ).unwrap().confidence, 50 );
+    padded[ 2 ] = 0x9b;
+    assert_eq!( detect_format( &padded ), None );
+
+    // loops past the end of the sample or a volume over 64 rule it out
+    let mut bad_loop = data.clone();
+    bad_loop[ 47 ] = 8;
+    assert_eq!( detect_format( &bad_loop ), None );
+    let mut bad_volume = data;
+    bad_volume[ 45 ] = 65;
+    assert_eq!( detect_format( &bad_volume ), None );
+
+    assert_eq!( detect_format( b"just some text, not a module" ), None );
+    assert!( read_module_data( &vec![ b'x'; 2000 ] ).is_err() );
+}
+
+#[test]
+fn notes_using_samples_the_module_lacks_play_without_one() {
+    // C-3 with sample 20 and sample 1 on the first line of a module that has 15 samples
+    let mut data = soundtracker_module();
+    data[ 600..604 ].copy_from_slice( &[ 0x13, 0x58, 0x40, 0 ] );
+    data[ 604..608 ].copy_from_slice( &[ 0x03, 0x58, 0x10, 0 ] );
+    data[ 1624.. ].fill( 64 );
+    let song = read_mod_data( &data ).unwrap();
+    let line = &song.patterns()[ 0 ].lines()[ 0 ];
+    assert_eq!( ( line[ 0 ].sample_number(), line[ 0 ].period() ), ( 0, 856 ) );
+    assert_eq!( line[ 1 ].sample_number(), 1 );
+
+    let mut player = Player::new( Arc::new( song ), &PlayerOptions::default() );
+    assert!( ( 0..48000 ).map( | _ | player.next_sample() ).any( | sample | sample != ( 0.0, 0.0 ) ) );
+}
@@ -0,0 +1,233 @@
+use std::sync::Arc;
+
+use mod_player::{Effect, Key, ModuleKind, NewNoteAction, DuplicateCheck, Player, PlayerOptions, read_it_data, song_duration};
+use mod_player::midi::{MidiOptions, write_midi};
+
+fn put_word( data : &mut [u8], position : usize, value : u16 ) {
+    data[ position..position + 2 ].copy_from_slice( &value.to_le_bytes() );
+}
+
+fn put_double_word( data : &mut [u8], position : usize, value : u32 ) {
+    data[ position..position + 4 ].copy_from_slice( &value.to_le_bytes() );
+}
+
+/**
+ * A packed pattern: one ( line, channel, note, instrument, volume, command, argument ) entry per note, 255 for the
+ * note and volume and 0 for the instrument and command when they are left out
+ */
+fn pattern( num_lines : usize, notes : &[( usize, u8, u8, u8, u8, u8, u8 )] ) -> Vec<u8> {
+    let mut packed = Vec::new();
+    for line in 0..num_lines {
+        for &( _, channel, note, instrument, volume, command, argument ) in notes.iter().filter( | note | note.0 == line ) {
+            let mut mask = 0;
+            let mut fields = Vec::new();
+            if note != 255 {
+                mask |= 1;
+                fields.push( note );
+            }
+            if instrument != 0 {
+                mask |= 2;
+                fields.push( instrument );
+            }
+            if volume != 255 {
+                mask |= 4;
+                fields.push( volume );
+            }
+            if command != 0 {
+                mask |= 8;
+                fields.extend_from_slice( &[ command, argument ] );
+            }
+            packed.extend_from_slice( &[ ( channel + 1 ) | 0x80, mask ] );
+            packed.extend( fields );
+        }
+        packed.push( 0 );
+    }
+    let mut data = vec![ 0u8; 8 ];
+    put_word( &mut data, 0, packed.len() as u16 );
+    put_word( &mut data, 2, num_lines as u16 );
+    data.extend( packed );
+    data
+}
+
+fn sample_header( flags : u8, length : u32, c5_speed : u32, data_offset : u32 ) -> Vec<u8> {
+    let mut header = vec![ 0u8; 0x50 ];
+    header[ 0..4 ].copy_from_slice( b"IMPS" );
+    header[ 0x11 ] = 64;
+    header[ 0x12 ] = flags;
+    header[ 0x13 ] = 64;
+    header[ 0x2e ] = 1;                         // signed
+    put_double_word( &mut header, 0x30, length );
+    put_double_word( &mut header, 0x38, length );       // looped from the start to the end
+    put_double_word( &mut header, 0x3c, c5_speed );
+    put_double_word( &mut header, 0x48, data_offset );
+    header
+}
+
+/**
+ * Values written lowest bit first, as the compressed samples store them
+ */
+fn pack_bits( values : &[( u32, u32 )] ) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut position = 0;
+    for &( value, bits ) in values {
+        for bit in 0..bits {
+            if position / 8 >= data.len() {
+                data.push( 0 );
+            }
+            data[ position / 8 ] |= ( ( value >> bit ) as u8 & 1 ) << ( position % 8 );
+            position += 1;
+        }
+    }
+    data
+}
+
+/**
+ * Two channels and a third that is switched off, at speed 3 with linear slides. The instrument continues its old
+ * notes when a new one starts but cuts a note that is played again, and plays a different looped sample for C-5, C-6
+ * and C-4: a constant 32, a constant 16 stored compressed and a square wave at the output rate
+ */
+fn test_module() -> Vec<u8> {
+    let mut data = vec![ 0u8; 0x480 ];
+    data[ 0..4 ].copy_from_slice( b"IMPM" );
+    data[ 4..8 ].copy_from_slice( b"test" );
+    for ( position, value ) in [ ( 0x20, 2 ), ( 0x22, 1 ), ( 0x24, 3 ), ( 0x26, 1 ), ( 0x28, 0x214 ), ( 0x2a, 0x214 ), ( 0x2c, 4 | 8 ) ] {
+        put_word( &mut data, position, value );
+    }
+    data[ 0x30 ] = 128;
+    data[ 0x32 ] = 3;
+    data[ 0x33 ] = 125;
+    data[ 0x40..0x80 ].fill( 0x80 );
+    data[ 0x40 ] = 0;                           // hard left
+    data[ 0x41 ] = 64;                          // hard right
+    data[ 0xc0..0xc2 ].copy_from_slice( &[ 0, 255 ] );
+    for ( index, offset ) in [ 0x100, 0x340, 0x390, 0x3e0, 0x480 ].iter().enumerate() {
+        put_double_word( &mut data, 0xc2 + index * 4, *offset );
+    }
+
+    let instrument = 0x100;
+    data[ instrument..instrument + 4 ].copy_from_slice( b"IMPI" );
+    data[ instrument + 0x11 ] = 1;              // continue
+    data[ instrument + 0x12 ] = 1;              // duplicate notes are cut
+    put_word( &mut data, instrument + 0x14, 256 );
+    data[ instrument + 0x18 ] = 128;
+    data[ instrument + 0x19 ] = 0x80;           // no panning of its own
+    data[ instrument + 0x20..instrument + 0x24 ].copy_from_slice( b"lead" );
+    data[ instrument + 0x3b ] = 0x80;           // resonance 0
+    for key in 0..120 {
+        data[ instrument + 0x40 + key * 2 ] = key as u8;
+        data[ instrument + 0x41 + key * 2 ] = match key { 60 => 1, 72 => 2, 48 => 3, _ => 0 };
+    }
+    let volume_envelope = instrument + 0x130;
+    data[ volume_envelope..volume_envelope + 6 ].copy_from_slice( &[ 1 | 4, 2, 0, 0, 0, 1 ] );
+    data[ volume_envelope + 6..volume_envelope + 12 ].copy_from_slice( &[ 64, 0, 0, 64, 4, 0 ] );
+    let pitch_envelope = instrument + 0x1d4;
+    data[ pitch_envelope..pitch_envelope + 2 ].copy_from_slice( &[ 1, 2 ] );
+    data[ pitch_envelope + 6..pitch_envelope + 12 ].copy_from_slice( &[ 0, 0, 0, 12, 8, 0 ] );
+
+    let mut vibrato_sample = sample_header( 1 | 0x10, 16, 8363, 0x440 );
+    vibrato_sample[ 0x4d ] = 8;
+    data[ 0x340..0x390 ].copy_from_slice( &vibrato_sample );
+    data[ 0x390..0x3e0 ].copy_from_slice( &sample_header( 1 | 8 | 0x10, 16, 8363, 0x450 ) );
+    data[ 0x3e0..0x430 ].copy_from_slice( &sample_header( 1 | 0x10, 16, 48000, 0x460 ) );
+    data[ 0x440..0x450 ].fill( 32 );
+    // a difference of 16, a change to 6 bit values and no more differences
+    let mut compressed = vec![ ( 16, 9 ), ( 0x100 | 5, 9 ) ];
+    compressed.extend( vec![ ( 0, 6 ); 15 ] );
+    let block = pack_bits( &compressed );
+    put_word( &mut data, 0x450, block.len() as u16 );
+    data[ 0x452..0x452 + block.len() ].copy_from_slice( &block );
+    for ( index, value ) in data[ 0x460..0x470 ].iter_mut().enumerate() {
+        *value = if index % 2 == 0 { 64 } else { ( -64i8 ) as u8 };
+    }
+
+    data.extend( pattern( 32, &[
+        ( 0, 0, 60, 1, 64, 0, 0 ),
+        ( 0, 1, 48, 1, 255, 26, 0x10 ),         // filter cutoff 16
+        ( 0, 2, 60, 1, 255, 0, 0 ),
+        ( 4, 0, 72, 1, 255, 0, 0 ),
+        ( 8, 0, 72, 1, 255, 0, 0 ),
+        ( 31, 1, 255, 0, 255, 3, 0x10 ),        // break to line 16 of the next position, which ends the song
+    ] ) );
+    data
+}
+
+#[test]
+fn loader_reads_instruments_samples_and_patterns() {
+    let song = read_it_data( &test_module() ).unwrap();
+    assert_eq!( song.name().trim_end_matches( '\0' ), "test" );
+    assert_eq!( song.format().kind, ModuleKind::It );
+    assert_eq!( song.num_channels(), 2 );
+    assert!( song.has_linear_periods() );
+    assert_eq!( ( song.channel_panning( 0 ), song.channel_panning( 1 ) ), ( 0, 255 ) );
+    assert_eq!( ( song.global_volume(), song.initial_speed(), song.initial_bpm() ), ( 64, 3, 125 ) );
+    assert_eq!( song.pattern_table(), &[ 0 ] );
+
+    let instrument = &song.instruments()[ 0 ];
+    assert_eq!( instrument.name().trim_end_matches( '\0' ), "lead" );
+    assert_eq!( ( instrument.sample_for_key( 60 ), instrument.sample_for_key( 72 ), instrument.note_for_key( 60 ) ), ( 1, 2, 60 ) );
+    assert_eq!( instrument.new_note_action(), NewNoteAction::Continue );
+    assert_eq!( instrument.duplicate_check(), ( DuplicateCheck::Note, NewNoteAction::Cut ) );
+    assert_eq!( ( instrument.fadeout(), instrument.global_volume(), instrument.panning() ), ( 8192, 64, None ) );
+    assert_eq!( instrument.filter(), ( None, Some( 0 ) ) );
+    assert_eq!( instrument.volume_envelope().sustain(), Some( ( 0, 1 ) ) );
+    assert_eq!( instrument.pitch_envelope().points(), &[ ( 0, 32 ), ( 8, 44 ) ] );
+    assert!( !instrument.filter_envelope().is_enabled() );
+    assert_eq!( instrument.vibrato().depth, 2 );
+
+    let samples = song.samples();
+    assert!( samples[ 0 ].data().iter().all( | value | *value == 32 ) );
+    assert_eq!( samples[ 1 ].data(), &[ 16; 16 ] );
+    assert_eq!( ( samples[ 1 ].repeat_offset(), samples[ 1 ].repeat_size() ), ( 0, 16 ) );
+    assert_eq!( ( samples[ 2 ].c2_speed(), samples[ 2 ].global_volume() ), ( 48000, 64 ) );
+
+    let lines = song.patterns()[ 0 ].lines();
+    assert_eq!( lines.len(), 32 );
+    assert_eq!( lines[ 0 ].len(), 2 );
+    assert_eq!( lines[ 0 ][ 0 ].key(), Key::Note( 60 ) );
+    assert_eq!( lines[ 0 ][ 0 ].volume_effect(), &Effect::SetVolume{ volume : 64 } );
+    assert_eq!( lines[ 0 ][ 1 ].effect(), &Effect::SetFilterCutoff{ cutoff : 0x10 } );
+    assert_eq!( lines[ 31 ][ 1 ].effect(), &Effect::PatternBreak{ next_pattern_pos : 0x10 } );
+    assert!( ( song_duration( &song ) - 32.0 * 3.0 * 0.02 ).abs() < 0.01 );
+    // the break on the last line leaves the only position, which plays on to the end of the song
+    let mut midi = Vec::new();
+    write_midi( &song, &MidiOptions::default(), &mut midi ).unwrap();
+    assert!( midi.starts_with( b"MThd" ) );
+
+    assert!( read_it_data( b"not a module" ).is_err() );
+}
+
+#[test]
+fn compressed_samples_end_with_their_data() {
+    // the compressed sample claims to be 4GB long with a loop to the end of it, and its one block is moved to the end
+    // of the file
+    let mut data = test_module();
+    let block = data[ 0x450..0x452 + data[ 0x450 ] as usize ].to_vec();
+    let end = data.len() as u32;
+    data.extend( block );
+    put_double_word( &mut data, 0x390 + 0x48, end );
+    put_double_word( &mut data, 0x390 + 0x30, u32::MAX );
+    put_double_word( &mut data, 0x390 + 0x38, u32::MAX );
+    let song = read_it_data( &data ).unwrap();
+    let sample = &song.samples()[ 1 ];
+    assert_eq!( sample.data().len(), 0x8000 );
+    assert_eq!( &sample.data()[ ..16 ], &[ 16; 16 ] );
+    assert_eq!( ( sample.repeat_offset(), sample.repeat_size() ), ( 0, 0x8000 ) );
+}
+
+#[test]
+fn new_note_actions_keep_old_notes_playing_and_filters_apply() {
+    let song = read_it_data( &test_module() ).unwrap();
+    let mut player = Player::new( Arc::new( song ), &PlayerOptions::default() );
+    let line = 3 * 960;
+    let output : Vec<( f32, f32 )> = ( 0..line * 12 ).map( | _ | player.next_sample() ).collect();
+    let start = output.iter().position( | value | value.0 != 0.0 ).unwrap();
+    let level = | value : f32 | value / 128.0;
+    assert!( ( output[ start + 10 ].0 - level( 32.0 ) ).abs() < 0.001 );
+    // the C-5 carries on under the C-6
+    assert!( ( output[ start + 4 * line + 10 ].0 - level( 32.0 + 16.0 ) ).abs() < 0.001 );
+    // playing the C-6 again cuts the one that was playing instead of continuing it
+    assert!( ( output[ start + 8 * line + 10 ].0 - level( 32.0 + 16.0 ) ).abs() < 0.001 );
+    // a low cutoff takes out nearly all of the square wave
+    let loudest = output[ start + 3 * line..start + 4 * line ].iter().map( | value | value.1.abs() ).fold( 0.0, f32::max );
+    assert!( loudest < 0.1 * level( 64.0 ) );
+}
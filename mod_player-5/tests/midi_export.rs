@@ -0,0 +1,111 @@
+use mod_player::{SongBuilder, Song, Effect};
+use mod_player::midi::{MidiOptions, write_midi, parse_program_map};
+
+/**
+ * The channel events of a track as ( tick, status, data ), meta events left out
+ */
+fn track_events( track : &[u8] ) -> Vec<( u64, u8, Vec<u8> )> {
+    let mut events = Vec::new();
+    let mut position = 0;
+    let mut tick = 0;
+    let read_length = | position : &mut usize | {
+        let mut value = 0u64;
+        loop {
+            let byte = track[ *position ];
+            *position += 1;
+            value = ( value << 7 ) | ( byte & 0x7f ) as u64;
+            if byte < 0x80 {
+                return value;
+            }
+        }
+    };
+    while position < track.len() {
+        tick += read_length( &mut position );
+        let status = track[ position ];
+        if status == 0xff {
+            position += 2;
+            let length = read_length( &mut position ) as usize;
+            events.push( ( tick, status, track[ position..position + length ].to_vec() ) );
+            position += length;
+        } else {
+            let length = if status & 0xf0 == 0xc0 { 1 } else { 2 };
+            events.push( ( tick, status, track[ position + 1..position + 1 + length ].to_vec() ) );
+            position += 1 + length;
+        }
+    }
+    events
+}
+
+fn tracks( data : &[u8] ) -> Vec<Vec<( u64, u8, Vec<u8> )>> {
+    assert_eq!( &data[ 0..4 ], b"MThd" );
+    let mut tracks = Vec::new();
+    let mut position = 14;
+    while position < data.len() {
+        assert_eq!( &data[ position..position + 4 ], b"MTrk" );
+        let length = u32::from_be_bytes( [ data[ position + 4 ], data[ position + 5 ], data[ position + 6 ], data[ position + 7 ] ] ) as usize;
+        tracks.push( track_events( &data[ position + 8..position + 8 + length ] ) );
+        position += 8 + length;
+    }
+    tracks
+}
+
+fn song() -> Song {
+    let mut builder = SongBuilder::new( "midi", 4 ).unwrap();
+    let lead = builder.add_sample( "lead", vec![ 0, 100, 0, -100 ] ).unwrap();
+    let bass = builder.add_sample( "bass", vec![ 0, 50, 0, -50 ] ).unwrap();
+    let pattern = builder.add_pattern().unwrap();
+    builder.set_note( pattern, 0, 0, "C-3", lead, Effect::SetSpeed{ speed : 3 } ).unwrap();
+    builder.set_note( pattern, 1, 0, "A-2", bass, Effect::SetVolume{ volume : 32 } ).unwrap();
+    builder.set_effect( pattern, 1, 1, Effect::SlideUp{ speed : 4 } ).unwrap();
+    builder.set_note( pattern, 0, 32, "E-3", lead, Effect::SetSpeed{ speed : 150 } ).unwrap();
+    builder.set_orders( &[ pattern ] ).unwrap();
+    builder.build().unwrap()
+}
+
+#[test]
+fn channels_become_tracks_timed_like_the_player() {
+    let options = MidiOptions{ programs : parse_program_map( "# lead is a square wave\n1 81\n" ).unwrap() };
+    let mut data = Vec::new();
+    write_midi( &song(), &options, &mut data ).unwrap();
+    let tracks = tracks( &data );
+    assert_eq!( tracks.len(), 5 );
+
+    // a line is a sixteenth: speed 3 at 125 bpm is 250 quarters a minute, 150 bpm at the same speed is 300
+    let tempos : Vec<( u64, Vec<u8> )> = tracks[ 0 ].iter().filter( | event | event.1 == 0xff && event.2.len() == 3 ).map( | event | ( event.0, event.2.clone() ) ).collect();
+    assert_eq!( tempos, vec![ ( 0, 240_000u32.to_be_bytes()[ 1.. ].to_vec() ), ( 32 * 240, 200_000u32.to_be_bytes()[ 1.. ].to_vec() ) ] );
+
+    let notes_on = | track : &Vec<( u64, u8, Vec<u8> )> | track.iter().filter( | event | event.1 & 0xf0 == 0x90 ).map( | event | ( event.0, event.2.clone() ) ).collect::<Vec<_>>();
+    assert_eq!( notes_on( &tracks[ 1 ] ), vec![ ( 0, vec![ 60, 127 ] ), ( 32 * 240, vec![ 64, 127 ] ) ] );
+    assert_eq!( notes_on( &tracks[ 2 ] ), vec![ ( 0, vec![ 57, 64 ] ) ] );
+    assert!( tracks[ 1 ].iter().any( | event | event.1 == 0xc0 && event.2 == vec![ 80 ] ) );
+    assert!( tracks[ 2 ].iter().any( | event | event.1 == 0xc1 && event.2 == vec![ 1 ] ) );
+
+    // the slide up bends the bass note up and the note ends with the song
+    let bends : Vec<u16> = tracks[ 2 ].iter().filter( | event | event.1 == 0xe1 ).map( | event | event.2[ 0 ] as u16 | ( event.2[ 1 ] as u16 ) << 7 ).collect();
+    assert!( bends.len() > 2 && bends.windows( 2 ).all( | pair | pair[ 1 ] > pair[ 0 ] ) );
+    let end = tracks[ 0 ].last().unwrap().0;
+    assert_eq!( end, 64 * 240 );
+    assert!( tracks[ 2 ].iter().any( | event | event.0 == end && event.1 == 0x81 ) );
+
+    assert!( parse_program_map( "1 129" ).is_err() );
+    assert_eq!( parse_program_map( "1\n" ).unwrap_err().line, 1 );
+}
+
+#[test]
+fn pattern_delays_lengthen_their_line() {
+    let mut builder = SongBuilder::new( "delay", 4 ).unwrap();
+    let lead = builder.add_sample( "lead", vec![ 0, 100, 0, -100 ] ).unwrap();
+    let pattern = builder.add_pattern().unwrap();
+    builder.set_note( pattern, 0, 0, "C-3", lead, Effect::None ).unwrap();
+    builder.set_note( pattern, 0, 1, "D-3", lead, Effect::PatternDelay{ lines : 2 } ).unwrap();
+    builder.set_note( pattern, 0, 2, "E-3", lead, Effect::None ).unwrap();
+    builder.set_orders( &[ pattern ] ).unwrap();
+    let mut data = Vec::new();
+    write_midi( &builder.build().unwrap(), &MidiOptions::default(), &mut data ).unwrap();
+    let tracks = tracks( &data );
+
+    // the delayed line lasts three lines
+    let notes_on : Vec<( u64, u8 )> = tracks[ 1 ].iter().filter( | event | event.1 & 0xf0 == 0x90 ).map( | event | ( event.0, event.2[ 0 ] ) ).collect();
+    assert_eq!( notes_on, vec![ ( 0, 60 ), ( 240, 62 ), ( 4 * 240, 64 ) ] );
+    assert_eq!( tracks[ 0 ].last().unwrap().0, 66 * 240 );
+}
@@ -0,0 +1,86 @@
+use std::fs;
+
+use mod_player::read_mod_data;
+
+/**
+ * Build a module with the given tag and number of channels. Every note in the patterns gets a different
+ * effect number / argument pair so all the effects go through the writer
+ */
+fn synthetic_module( tag : &[u8; 4], num_channels : usize, num_patterns : usize ) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend( b"synthetic\0\0\0\0\0\0\0\0\0\0\0" );
+    for sample in 0..31u8 {
+        let mut name = format!( "sample {}", sample ).into_bytes();
+        name.resize( 22, 0 );
+        data.extend( &name );
+        let words : u16 = if sample % 3 == 0 { 0 } else { 16 + sample as u16 };
+        data.extend( &words.to_be_bytes() );
+        data.push( sample % 16 );               // fine tune
+        data.push( sample * 2 );                // volume
+        data.extend( &( sample as u16 % 4 ).to_be_bytes() );
+        data.extend( &( if sample % 2 == 0 { 1u16 } else { 8 } ).to_be_bytes() );
+    }
+    data.push( num_patterns as u8 + 1 );
+    data.push( 127 );
+    let mut pattern_table : Vec<u8> = ( 0..num_patterns as u8 ).collect();
+    pattern_table.push( 0 );
+    pattern_table.resize( 128, 0 );
+    data.extend( &pattern_table );
+    data.extend( tag );
+
+    let mut counter : u32 = 0;
+    for _pattern in 0..num_patterns {
+        for _note in 0..64 * num_channels {
+            let effect_number = ( counter % 16 ) as u8;
+            let mut effect_argument = ( counter / 16 % 256 ) as u8;
+            if effect_number == 13 {
+                // pattern breaks are stored as decimal digits
+                effect_argument = ( effect_argument % 64 / 10 ) << 4 | ( effect_argument % 10 );
+            }
+            let sample_number = ( counter % 32 ) as u8;
+            let period = FREQUENCIES[ counter as usize % FREQUENCIES.len() ];
+            data.push( ( sample_number & 0xf0 ) | ( period >> 8 ) as u8 );
+            data.push( period as u8 );
+            data.push( ( sample_number << 4 ) | effect_number );
+            data.push( effect_argument );
+            counter += 1;
+        }
+    }
+    for sample in 0..31u8 {
+        let words = if sample % 3 == 0 { 0 } else { 16 + sample as usize };
+        data.extend( ( 0..words * 2 ).map( | index | ( index as u8 ).wrapping_mul( sample ) ) );
+    }
+    data
+}
+
+const FREQUENCIES : [u16; 6] = [ 0, 856, 428, 214, 113, 1712 ];
+
+fn assert_round_trip( original : &[u8] ) {
+    let song = read_mod_data( original ).expect( "the module should load" );
+    let mut written = Vec::new();
+    song.write_mod( &mut written ).expect( "the module should be written" );
+    assert_eq!( written.len(), original.len() );
+    assert!( written == original, "the written module differs from the original" );
+
+    let reloaded = read_mod_data( &written ).expect( "the written module should load" );
+    let mut rewritten = Vec::new();
+    reloaded.write_mod( &mut rewritten ).expect( "the module should be written again" );
+    assert!( rewritten == written );
+}
+
+#[test]
+fn four_channel_module_round_trips() {
+    assert_round_trip( &synthetic_module( b"M.K.", 4, 8 ) );
+}
+
+#[test]
+fn multi_channel_modules_round_trip() {
+    assert_round_trip( &synthetic_module( b"6CHN", 6, 3 ) );
+    assert_round_trip( &synthetic_module( b"12CH", 12, 2 ) );
+}
+
+#[test]
+fn song_file_round_trips() {
+    let original = fs::read( "stardstm.mod" ).unwrap();
+    assert_round_trip( &original );
+}
@@ -0,0 +1,292 @@
+use std::fs;
+
+use mod_player::{ArchiveKind, archive_kind, read_archive, read_archived_module, read_mod_data};
+#[cfg(feature = "deflate")]
+use mod_player::{ModuleKind, archived_modules, read_module_data};
+
+#[cfg(feature = "deflate")]
+fn crc32( data : &[u8] ) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _bit in 0..8 {
+            crc = ( crc >> 1 ) ^ ( 0xedb8_8320 & ( crc & 1 ).wrapping_neg() );
+        }
+    }
+    !crc
+}
+
+fn crc16( data : &[u8] ) -> u16 {
+    let mut crc = 0u16;
+    for byte in data {
+        crc ^= *byte as u16;
+        for _bit in 0..8 {
+            crc = ( crc >> 1 ) ^ ( 0xa001 & ( crc & 1 ).wrapping_neg() );
+        }
+    }
+    crc
+}
+
+/**
+ * A zip file of ( name, data, deflated ) entries with their local headers and the central directory
+ */
+#[cfg(feature = "deflate")]
+fn zip_file( entries : &[( &str, &[u8], bool )] ) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut directory = Vec::new();
+    for ( name, contents, deflated ) in entries {
+        let packed = if *deflated { miniz_oxide::deflate::compress_to_vec( contents, 6 ) } else { contents.to_vec() };
+        let mut header = Vec::new();
+        header.extend( &[ 20, 0, 0, 0 ] );
+        header.extend( &( if *deflated { 8u16 } else { 0 } ).to_le_bytes() );
+        header.extend( &[ 0, 0, 0, 0 ] );
+        header.extend( &crc32( contents ).to_le_bytes() );
+        header.extend( &( packed.len() as u32 ).to_le_bytes() );
+        header.extend( &( contents.len() as u32 ).to_le_bytes() );
+        header.extend( &( name.len() as u16 ).to_le_bytes() );
+        header.extend( &[ 0, 0 ] );
+        directory.extend( b"PK\x01\x02\x14\x00" );
+        directory.extend( &header );
+        directory.extend( &[ 0u8; 10 ] );
+        directory.extend( &( data.len() as u32 ).to_le_bytes() );
+        directory.extend( name.as_bytes() );
+        data.extend( b"PK\x03\x04" );
+        data.extend( &header );
+        data.extend( name.as_bytes() );
+        data.extend( packed );
+    }
+    let directory_offset = data.len() as u32;
+    data.extend( &directory );
+    data.extend( b"PK\x05\x06\0\0\0\0" );
+    data.extend( &( entries.len() as u16 ).to_le_bytes() );
+    data.extend( &( entries.len() as u16 ).to_le_bytes() );
+    data.extend( &( directory.len() as u32 ).to_le_bytes() );
+    data.extend( &directory_offset.to_le_bytes() );
+    data.extend( &[ 0, 0 ] );
+    data
+}
+
+#[cfg(feature = "deflate")]
+fn gzip_file( name : &str, contents : &[u8] ) -> Vec<u8> {
+    let mut data = vec![ 0x1f, 0x8b, 8, 8, 0, 0, 0, 0, 0, 3 ];
+    data.extend( name.as_bytes() );
+    data.push( 0 );
+    data.extend( miniz_oxide::deflate::compress_to_vec( contents, 6 ) );
+    data.extend( &crc32( contents ).to_le_bytes() );
+    data.extend( &( contents.len() as u32 ).to_le_bytes() );
+    data
+}
+
+/**
+ * Bits written highest bit first, as -lh5- reads them
+ */
+struct Bits {
+    data : Vec<u8>,
+    position : usize,
+}
+
+impl Bits {
+    fn put( &mut self, value : usize, bits : usize ) {
+        for bit in ( 0..bits ).rev() {
+            if self.position.is_multiple_of( 8 ) {
+                self.data.push( 0 );
+            }
+            self.data[ self.position / 8 ] |= ( ( value >> bit ) as u8 & 1 ) << ( 7 - self.position % 8 );
+            self.position += 1;
+        }
+    }
+}
+
+/**
+ * A code where every symbol used gets the same length, which is a valid if poor Huffman code: the lengths and each
+ * symbol's code
+ */
+fn flat_code( used : &[bool] ) -> ( usize, Vec<Option<usize>> ) {
+    let count = used.iter().filter( | used | **used ).count();
+    let length = ( 1.. ).find( | length | 1 << length >= count ).unwrap();
+    let mut next = 0;
+    let codes = used.iter().map( | used | if *used { next += 1; Some( next - 1 ) } else { None } ).collect();
+    ( length, codes )
+}
+
+/**
+ * Compress data as -lh5-: copies of 3 - 256 bytes from up to 64 bytes back and single bytes, in blocks of at most
+ * 10000 codes
+ */
+fn lh5( data : &[u8] ) -> Vec<u8> {
+    let mut codes = Vec::new();          // ( character, offset )
+    let mut position = 0;
+    while position < data.len() {
+        let ( length, offset ) = ( 1..=64.min( position ) ).map( | distance | {
+            let length = ( 0..256.min( data.len() - position ) ).take_while( | index | data[ position + index ] == data[ position + index - distance ] ).count();
+            ( length, distance - 1 )
+        } ).max_by_key( | ( length, offset ) | ( *length, usize::MAX - offset ) ).unwrap_or( ( 0, 0 ) );
+        if length >= 3 {
+            codes.push( ( 253 + length, offset ) );
+            position += length;
+        } else {
+            codes.push( ( data[ position ] as usize, 0 ) );
+            position += 1;
+        }
+    }
+    let offset_bits = | offset : usize | ( usize::BITS - offset.leading_zeros() ) as usize;
+    let mut bits = Bits{ data : Vec::new(), position : 0 };
+    for block in codes.chunks( 10000 ) {
+        let mut characters_used = vec![ false; 510 ];
+        let mut offsets_used = vec![ false; 14 ];
+        for ( character, offset ) in block {
+            characters_used[ *character ] = true;
+            if *character >= 256 {
+                offsets_used[ offset_bits( *offset ) ] = true;
+            }
+        }
+        let ( character_length, character_codes ) = flat_code( &characters_used );
+        let ( offset_length, offset_codes ) = flat_code( &offsets_used );
+        bits.put( block.len(), 16 );
+        // the length code has 0 ( an unused character ) as 0 and the length of the used ones as 1
+        bits.put( character_length + 3, 5 );
+        for symbol in 0..character_length + 3 {
+            bits.put( if symbol == 0 || symbol == character_length + 2 { 1 } else { 0 }, 3 );
+            if symbol == 2 {
+                bits.put( 0, 2 );
+            }
+        }
+        let num_characters = characters_used.iter().rposition( | used | *used ).unwrap() + 1;
+        bits.put( num_characters, 9 );
+        for used in &characters_used[ ..num_characters ] {
+            bits.put( *used as usize, 1 );
+        }
+        bits.put( 14, 4 );
+        for used in &offsets_used {
+            bits.put( if *used { offset_length } else { 0 }, 3 );
+        }
+        for ( character, offset ) in block {
+            bits.put( character_codes[ *character ].unwrap(), character_length );
+            if *character >= 256 {
+                let top = offset_bits( *offset );
+                bits.put( offset_codes[ top ].unwrap(), offset_length );
+                if top > 1 {
+                    bits.put( *offset, top - 1 );
+                }
+            }
+        }
+    }
+    bits.data
+}
+
+/**
+ * A stored file with a level 0 header, and a -lh5- file in a directory with a level 2 header
+ */
+fn lha_file( readme : &[u8], module : &[u8] ) -> Vec<u8> {
+    let mut data = Vec::new();
+    let name = b"README";
+    data.extend( &[ 22 + name.len() as u8, 0 ] );
+    data.extend( b"-lh0-" );
+    data.extend( &( readme.len() as u32 ).to_le_bytes() );
+    data.extend( &( readme.len() as u32 ).to_le_bytes() );
+    data.extend( &[ 0, 0, 0, 0, 0x20, 0, name.len() as u8 ] );
+    data.extend( name );
+    data.extend( &crc16( readme ).to_le_bytes() );
+    data.extend( readme );
+
+    let packed = lh5( module );
+    let extended_headers = [ ( 1u8, &b"stardstm.mod"[ .. ] ), ( 2, &b"mods\xff"[ .. ] ) ];
+    let header_size = 26 + extended_headers.iter().map( | ( _, contents ) | contents.len() + 3 ).sum::<usize>();
+    data.extend( &( header_size as u16 ).to_le_bytes() );
+    data.extend( b"-lh5-" );
+    data.extend( &( packed.len() as u32 ).to_le_bytes() );
+    data.extend( &( module.len() as u32 ).to_le_bytes() );
+    data.extend( &[ 0, 0, 0, 0, 0x20, 2 ] );
+    data.extend( &crc16( module ).to_le_bytes() );
+    data.push( b'U' );
+    for ( kind, contents ) in extended_headers.iter() {
+        data.extend( &( contents.len() as u16 + 3 ).to_le_bytes() );
+        data.push( *kind );
+        data.extend( *contents );
+    }
+    data.extend( &[ 0, 0 ] );
+    data.extend( packed );
+    data.push( 0 );
+    data
+}
+
+#[test]
+#[cfg(feature = "deflate")]
+fn modules_load_from_zip_and_gzip_files() {
+    let mod_data = fs::read( "stardstm.mod" ).unwrap();
+    let song = read_mod_data( &mod_data ).unwrap();
+    let mut xm = Vec::new();
+    song.write_xm( &mut xm ).unwrap();
+
+    let zip = zip_file( &[ ( "readme.txt", b"Songs from the demo", false ), ( "songs/stardstm.mod", &mod_data, true ), ( "songs/Stardust.xm", &xm, false ) ] );
+    assert_eq!( archive_kind( &zip ), Some( ArchiveKind::Zip ) );
+    assert_eq!( read_archive( &zip ).unwrap().len(), 3 );
+    let names : Vec<String> = archived_modules( &zip ).unwrap().into_iter().map( | entry | entry.name ).collect();
+    assert_eq!( names, [ "songs/stardstm.mod", "songs/Stardust.xm" ] );
+    // the first module unless one is named
+    assert_eq!( read_module_data( &zip ).unwrap().patterns(), song.patterns() );
+    assert_eq!( read_archived_module( &zip, Some( "stardust.XM" ) ).unwrap().format().kind, ModuleKind::Xm );
+    assert!( read_archived_module( &zip, Some( "readme.txt" ) ).is_err() );
+
+    let gzip = gzip_file( "stardstm.mod", &mod_data );
+    let entries = read_archive( &gzip ).unwrap();
+    assert_eq!( ( entries[ 0 ].name.as_str(), &entries[ 0 ].data ), ( "stardstm.mod", &mod_data ) );
+    assert_eq!( read_module_data( &gzip ).unwrap().patterns(), song.patterns() );
+
+    // a checksum that doesn't match
+    let mut damaged = gzip;
+    let trailer = damaged.len() - 8;
+    damaged[ trailer ] ^= 1;
+    assert!( read_module_data( &damaged ).is_err() );
+
+    // files that unpack to more than their archive says stop at that size
+    let zeros = vec![ 0u8; 1 << 20 ];
+    let mut zip = zip_file( &[ ( "zeros.mod", &zeros, true ) ] );
+    let directory = zip.len() - 22 - 46 - "zeros.mod".len();
+    zip[ directory + 24..directory + 28 ].copy_from_slice( &1000u32.to_le_bytes() );
+    assert!( read_archive( &zip ).is_err() );
+    let mut gzip = gzip_file( "zeros.mod", &zeros );
+    let size = gzip.len() - 4;
+    gzip[ size.. ].copy_from_slice( &1000u32.to_le_bytes() );
+    assert!( read_archive( &gzip ).is_err() );
+}
+
+#[test]
+#[cfg(feature = "deflate")]
+fn archives_inside_archives_load_up_to_a_limit() {
+    let mod_data = fs::read( "stardstm.mod" ).unwrap();
+    let nested = | levels : usize | ( 0..levels ).fold( mod_data.clone(), | data, _ | gzip_file( "stardstm.mod", &data ) );
+    assert_eq!( read_module_data( &nested( 3 ) ).unwrap().patterns(), read_mod_data( &mod_data ).unwrap().patterns() );
+    // deeper than that is given up on, as a file that contains itself would go on forever
+    assert!( read_module_data( &nested( 20 ) ).is_err() );
+    assert!( archived_modules( &nested( 20 ) ).unwrap().is_empty() );
+}
+
+#[test]
+fn modules_load_from_lha_files() {
+    let mod_data = fs::read( "stardstm.mod" ).unwrap();
+    let lha = lha_file( b"Unpack with LHA", &mod_data );
+    assert!( lha.len() < mod_data.len() );
+    assert_eq!( archive_kind( &lha ), Some( ArchiveKind::Lha ) );
+    let entries = read_archive( &lha ).unwrap();
+    assert_eq!( entries[ 0 ].name, "README" );
+    assert_eq!( entries[ 1 ].name, "mods/stardstm.mod" );
+    assert!( entries[ 1 ].data == mod_data );
+    assert_eq!( read_archived_module( &lha, Some( "stardstm.mod" ) ).unwrap().patterns(), read_mod_data( &mod_data ).unwrap().patterns() );
+
+    assert_eq!( archive_kind( &mod_data ), None );
+    let mut damaged = lha.clone();
+    let middle = damaged.len() / 2;
+    damaged[ middle ] ^= 0x55;
+    assert!( read_archive( &damaged ).is_err() );
+    // a -lh5- file that claims to unpack to 4GB
+    let mut too_long = lha;
+    let header = too_long.windows( 5 ).position( | method | method == b"-lh5-" ).unwrap() - 2;
+    too_long[ header + 11..header + 15 ].copy_from_slice( &u32::MAX.to_le_bytes() );
+    assert!( read_archive( &too_long ).is_err() );
+    // a block of no codes
+    let mut empty_block = too_long;
+    let packed = header + u16::from_le_bytes( [ empty_block[ header ], empty_block[ header + 1 ] ] ) as usize;
+    empty_block[ packed..packed + 2 ].copy_from_slice( &[ 0, 0 ] );
+    assert!( read_archive( &empty_block ).is_err() );
+}
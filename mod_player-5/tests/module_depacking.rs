@@ -0,0 +1,308 @@
+use std::collections::HashMap;
+use std::fs;
+
+use mod_player::{Song, ModuleKind, PackedFormat, FormatGuess, packed_format, unpack_module, depack_powerpacker, guess_format, read_mod_data, read_module_data};
+
+// ProTracker's periods from C-1 to B-3
+const PERIODS : [ u16; 36 ] = [ 856, 808, 762, 720, 678, 640, 604, 570, 538, 508, 480, 453, 428, 404, 381, 360, 339, 320, 302, 285, 269, 254, 240, 226,
+    214, 202, 190, 180, 170, 160, 151, 143, 135, 127, 120, 113 ];
+
+type Note = [ u8; 4 ];
+
+fn note( sample : u8, period : u16, effect : u8, argument : u8 ) -> Note {
+    [ ( sample & 0xf0 ) | ( period >> 8 ) as u8, period as u8, ( sample << 4 ) | effect, argument ]
+}
+
+/**
+ * Two patterns played in the order 0, 1, 0 and one looped sample of 8 bytes. Each pattern is 64 lines of 4 notes
+ */
+fn patterns() -> Vec<Vec<Note>> {
+    let mut first = vec![ [ 0u8; 4 ]; 256 ];
+    first[ 0 ] = note( 1, 428, 0x0c, 0x20 );
+    first[ 5 ] = note( 1, 856, 0x0a, 0x02 );         // slides down
+    first[ 10 ] = note( 1, 113, 0x0a, 0x20 );        // and up
+    first[ 255 ] = note( 1, 214, 0, 0 );
+    let mut second = vec![ [ 0u8; 4 ]; 256 ];
+    second[ 3 ] = note( 1, 640, 0x0f, 3 );
+    second[ 128 ] = first[ 0 ];
+    vec![ first, second ]
+}
+
+const ORDER : [ u8; 3 ] = [ 0, 1, 0 ];
+const SAMPLE : [ u8; 8 ] = [ 0, 20, 40, 60, 80, 60, 40, 20 ];
+const SAMPLE_HEADER : [ u8; 8 ] = [ 0, 4, 0, 64, 0, 2, 0, 2 ];        // 4 words at volume 64, looped over the second half
+
+fn mod_file() -> Vec<u8> {
+    let mut data = vec![ 0u8; 20 ];
+    data[ 0..4 ].copy_from_slice( b"test" );
+    for index in 0..31 {
+        data.extend( &[ 0u8; 22 ] );
+        data.extend( &if index == 0 { SAMPLE_HEADER } else { [ 0, 0, 0, 0, 0, 0, 0, 1 ] } );
+    }
+    data.extend( &[ ORDER.len() as u8, 0x7f ] );
+    let mut table = ORDER.to_vec();
+    table.resize( 128, 0 );
+    data.extend( table );
+    data.extend( b"M.K." );
+    for pattern in patterns() {
+        data.extend( pattern.iter().flatten() );
+    }
+    data.extend( &SAMPLE );
+    data
+}
+
+/**
+ * The bits of a PowerPacker stream in the order the depacker reads them, values highest bit first
+ */
+struct Bits( Vec<bool> );
+
+impl Bits {
+    fn put( &mut self, value : usize, bits : usize ) {
+        for bit in ( 0..bits ).rev() {
+            self.0.push( ( value >> bit ) & 1 != 0 );
+        }
+    }
+
+    /**
+     * A count in chunks, every chunk that is all ones is followed by another one
+     */
+    fn put_count( &mut self, mut count : usize, bits : usize ) {
+        let full = ( 1 << bits ) - 1;
+        while count >= full {
+            self.put( full, bits );
+            count -= full;
+        }
+        self.put( count, bits );
+    }
+}
+
+/**
+ * Crunch data the way PowerPacker does, from the end backwards: runs of bytes and copies of at least 5 bytes from
+ * up to 128 bytes further on. The stream starts with 3 bits that are skipped
+ */
+fn powerpack( data : &[u8] ) -> Vec<u8> {
+    let mut bits = Bits( Vec::new() );
+    bits.put( 0b101, 3 );
+    let mut position = data.len();          // everything from here on has been written
+    let mut literals = Vec::new();
+    let mut after_literals = false;
+    while position > 0 {
+        let best = ( 1..=128.min( data.len() - position ) ).map( | distance | {
+            let length = ( 1..=position ).take_while( | back | data[ position - back ] == data[ position - back + distance ] ).count();
+            ( length, distance )
+        } ).max();
+        match best {
+            Some( ( length, distance ) ) if length >= 5 => {
+                if !literals.is_empty() {
+                    bits.put( 0, 1 );
+                    bits.put_count( literals.len() - 1, 2 );
+                    for byte in literals.drain( .. ) {
+                        bits.put( byte as usize, 8 );
+                    }
+                } else if !after_literals {
+                    bits.put( 1, 1 );
+                }
+                bits.put( 3, 2 );
+                bits.put( 0, 1 );               // 7 bit offset
+                bits.put( distance - 1, 7 );
+                bits.put_count( length - 5, 3 );
+                position -= length;
+                after_literals = false;
+            }
+            _ => {
+                position -= 1;
+                literals.push( data[ position ] );
+            }
+        }
+    }
+    if !literals.is_empty() {
+        bits.put( 0, 1 );
+        bits.put_count( literals.len() - 1, 2 );
+        for byte in literals {
+            bits.put( byte as usize, 8 );
+        }
+    }
+    let mut packed = vec![ 0u8; bits.0.len().div_ceil( 8 ) ];
+    let last = packed.len() - 1;
+    for ( index, bit ) in bits.0.iter().enumerate() {
+        packed[ last - index / 8 ] |= ( *bit as u8 ) << ( index % 8 );
+    }
+    let mut file = b"PP20".to_vec();
+    file.extend( &[ 9, 10, 12, 13 ] );
+    file.extend( packed );
+    file.extend( &[ ( data.len() >> 16 ) as u8, ( data.len() >> 8 ) as u8, data.len() as u8, 3 ] );
+    file
+}
+
+/**
+ * The tracks of every position, each a column of 64 notes, numbered in the order they first appear
+ */
+fn tracks() -> ( Vec<Vec<Note>>, Vec<u8> ) {
+    let patterns = patterns();
+    let mut unique : Vec<Vec<Note>> = Vec::new();
+    let mut table = vec![ 0u8; 4 * 128 ];
+    for ( position, pattern ) in ORDER.iter().enumerate() {
+        for channel in 0..4 {
+            let track : Vec<Note> = ( 0..64 ).map( | line | patterns[ *pattern as usize ][ line * 4 + channel ] ).collect();
+            let number = unique.iter().position( | known | *known == track ).unwrap_or_else( || {
+                unique.push( track );
+                unique.len() - 1
+            } );
+            table[ channel * 128 + position ] = number as u8;
+        }
+    }
+    ( unique, table )
+}
+
+fn propacker_header() -> Vec<u8> {
+    let mut data = SAMPLE_HEADER.to_vec();
+    data.extend( vec![ 0u8; 30 * 8 ] );
+    data.extend( &[ ORDER.len() as u8, 0x7f ] );
+    data.extend( tracks().1 );
+    data
+}
+
+fn propacker_1() -> Vec<u8> {
+    let mut data = propacker_header();
+    for track in tracks().0 {
+        data.extend( track.iter().flatten() );
+    }
+    data.extend( &SAMPLE );
+    data
+}
+
+fn propacker_2() -> Vec<u8> {
+    let mut data = propacker_header();
+    let mut notes : Vec<Note> = Vec::new();
+    let mut references = HashMap::new();
+    for track in tracks().0 {
+        for note in track {
+            let reference = *references.entry( note ).or_insert_with( || {
+                notes.push( note );
+                notes.len() - 1
+            } );
+            data.extend( &( reference as u16 ).to_be_bytes() );
+        }
+    }
+    data.extend( &( notes.len() as u32 * 4 ).to_be_bytes() );
+    data.extend( notes.iter().flatten() );
+    data.extend( &SAMPLE );
+    data
+}
+
+/**
+ * NoisePacker 2 keeps the patterns but stores each channel as a track of 3 byte notes, with its own volume slides
+ */
+fn noisepacker_2() -> Vec<u8> {
+    let patterns = patterns();
+    let mut track_data : Vec<u8> = Vec::new();
+    let mut track_table : Vec<u8> = Vec::new();
+    for pattern in &patterns {
+        let mut offsets = Vec::new();
+        for channel in 0..4 {
+            offsets.push( track_data.len() as u16 );
+            for line in 0..64 {
+                let [ high, low, sample_effect, argument ] = pattern[ line * 4 + channel ];
+                let period = ( ( high as u16 & 0x0f ) << 8 ) | low as u16;
+                let number = PERIODS.iter().position( | known | *known == period ).map( | index | index + 1 ).unwrap_or( 0 ) as u8;
+                let sample = ( high & 0xf0 ) | ( sample_effect >> 4 );
+                let ( effect, argument ) = match sample_effect & 0x0f {
+                    0x0a if argument & 0x0f != 0 => ( 7, 0u8.wrapping_sub( argument & 0x0f ) ),
+                    0x0a => ( 7, argument >> 4 ),
+                    effect => ( effect, argument )
+                };
+                track_data.extend( &[ ( number << 1 ) | ( sample >> 4 ), ( ( sample & 0x0f ) << 4 ) | effect, argument ] );
+            }
+        }
+        for offset in offsets.iter().rev() {
+            track_table.extend( &offset.to_be_bytes() );
+        }
+    }
+    let mut data = Vec::new();
+    data.extend( &( ( 1u16 << 4 ) | 0x0c ).to_be_bytes() );
+    data.extend( &( ORDER.len() as u16 * 2 ).to_be_bytes() );
+    data.extend( &( track_table.len() as u16 ).to_be_bytes() );
+    data.extend( &( track_data.len() as u16 ).to_be_bytes() );
+    // the sample header has the loop start in bytes
+    data.extend( &[ 0, 0, 0, 0, 0, 4, 0, 64, 0, 0, 0, 0, 0, 2, 0, 4 ] );
+    for pattern in ORDER.iter() {
+        data.extend( &( *pattern as u16 * 8 ).to_be_bytes() );
+    }
+    data.extend( track_table );
+    data.extend( track_data );
+    data.extend( &SAMPLE );
+    data
+}
+
+/**
+ * The lines played at every position, which stay the same however the patterns are numbered
+ */
+fn played_lines( song : &Song ) -> Vec<Vec<mod_player::Note>> {
+    ( 0..song.song_length() ).flat_map( | position | {
+        let pattern = song.pattern_table()[ position as usize ] as usize;
+        song.patterns()[ pattern ].lines().to_vec()
+    } ).collect()
+}
+
+#[test]
+fn powerpacker_files_unpack_to_the_original_data() {
+    let original = mod_file();
+    let packed = powerpack( &original );
+    assert!( packed.len() < original.len() / 4 );
+    assert_eq!( packed_format( &packed ), Some( PackedFormat::PowerPacker ) );
+    assert_eq!( depack_powerpacker( &packed ).unwrap(), original );
+    assert_eq!( read_mod_data( &packed ).unwrap().patterns(), read_mod_data( &original ).unwrap().patterns() );
+
+    // PowerPacker can hold any format
+    let mut xm = Vec::new();
+    read_mod_data( &original ).unwrap().write_xm( &mut xm ).unwrap();
+    let packed_xm = powerpack( &xm );
+    assert_eq!( guess_format( &packed_xm ), Some( FormatGuess{ kind : ModuleKind::Xm, confidence : 100 } ) );
+    assert_eq!( read_module_data( &packed_xm ).unwrap().format().kind, ModuleKind::Xm );
+
+    // cut short, the stream runs out before the data is complete
+    let mut broken = packed.clone();
+    broken.drain( 8..packed.len() / 2 );
+    assert!( depack_powerpacker( &broken ).is_err() );
+}
+
+#[test]
+fn damaged_powerpacker_files_are_errors() {
+    let packed = powerpack( &mod_file() );
+    // the last 4 bytes of what is left are taken as the length and the bits to skip
+    for length in [ 12, 17, 18, 19 ] {
+        assert!( depack_powerpacker( &packed[ ..length ] ).is_err() );
+        let mut filled = packed[ ..8 ].to_vec();
+        filled.resize( length, 0xff );
+        assert!( depack_powerpacker( &filled ).is_err() );
+    }
+
+    let mut wide_offsets = packed.clone();
+    wide_offsets[ 7 ] = 16;
+    assert!( depack_powerpacker( &wide_offsets ).is_err() );
+    let mut long_skip = packed.clone();
+    *long_skip.last_mut().unwrap() = 33;
+    assert!( depack_powerpacker( &long_skip ).is_err() );
+    let mut length = packed.clone();
+    let trailer = length.len() - 4;
+    length[ trailer ] ^= 0x10;
+    assert!( depack_powerpacker( &length ).is_err() );
+}
+
+#[test]
+fn packed_mods_unpack_to_the_same_song() {
+    let original = read_mod_data( &mod_file() ).unwrap();
+    for ( packed, format ) in [ ( propacker_1(), PackedFormat::ProPacker1 ), ( propacker_2(), PackedFormat::ProPacker2 ), ( noisepacker_2(), PackedFormat::NoisePacker2 ) ].iter() {
+        assert_eq!( packed_format( packed ), Some( *format ) );
+        let song = read_mod_data( packed ).unwrap();
+        assert_eq!( song.format().tag, Some( *b"M.K." ) );
+        assert_eq!( played_lines( &song ), played_lines( &original ) );
+        assert_eq!( song.samples()[ 0 ].data(), original.samples()[ 0 ].data() );
+        assert_eq!( ( song.samples()[ 0 ].repeat_offset(), song.samples()[ 0 ].repeat_size() ), ( 4, 4 ) );
+        assert_eq!( guess_format( packed ), Some( FormatGuess{ kind : ModuleKind::Mod, confidence : 60 } ) );
+    }
+    // ordinary modules are left alone
+    let stardstm = fs::read( "stardstm.mod" ).unwrap();
+    assert_eq!( packed_format( &stardstm ), None );
+    assert!( unpack_module( &mod_file() ).unwrap().is_none() );
+}
@@ -0,0 +1,184 @@
+use mod_player::{SongBuilder, Song, Note, Effect, Key, EditCommand, EditHistory, Block, copy_block, read_mod_data, read_it_data};
+
+fn test_song() -> Song {
+    let mut builder = SongBuilder::new( "edit", 4 ).unwrap();
+    builder.add_sample( "one", vec![ 0; 32 ] ).unwrap();
+    builder.add_sample( "two", vec![ 0; 32 ] ).unwrap();
+    let pattern = builder.add_pattern().unwrap();
+    builder.set_note( pattern, 0, 0, "C-3", 1, Effect::None ).unwrap();
+    builder.set_note( pattern, 0, 1, "D-3", 1, Effect::SetVolume{ volume : 32 } ).unwrap();
+    builder.set_note( pattern, 1, 0, "B-5", 2, Effect::None ).unwrap();
+    builder.add_order( pattern ).unwrap();
+    builder.build().unwrap()
+}
+
+fn note( song : &Song, pattern : usize, channel : usize, row : usize ) -> Note {
+    song.patterns()[ pattern ].lines()[ row ][ channel ]
+}
+
+#[test]
+fn edits_can_be_undone_and_redone() {
+    let mut song = test_song();
+    let original = note( &song, 0, 0, 0 );
+    let mut history = EditHistory::new();
+
+    history.apply( &mut song, EditCommand::SetNote{ pattern : 0, channel : 0, row : 0, note : Note::new( 2, 428, Effect::None ) } ).unwrap();
+    history.apply( &mut song, EditCommand::ClearNote{ pattern : 0, channel : 0, row : 1 } ).unwrap();
+    assert_eq!( note( &song, 0, 0, 0 ).period(), 428 );
+    assert_eq!( note( &song, 0, 0, 1 ).period(), 0 );
+
+    assert!( history.undo( &mut song ) );
+    assert!( history.undo( &mut song ) );
+    assert!( !history.undo( &mut song ) );
+    assert_eq!( note( &song, 0, 0, 0 ), original );
+    assert_eq!( note( &song, 0, 0, 1 ).period(), 762 );
+
+    assert!( history.redo( &mut song ).unwrap() );
+    assert_eq!( note( &song, 0, 0, 0 ).period(), 428 );
+    history.apply( &mut song, EditCommand::ClearNote{ pattern : 0, channel : 1, row : 0 } ).unwrap();
+    assert!( !history.can_redo() );
+
+    // a limited history keeps its limit through undo and redo
+    let mut history = EditHistory::with_limit( 1 );
+    history.apply( &mut song, EditCommand::ClearNote{ pattern : 0, channel : 0, row : 0 } ).unwrap();
+    history.apply( &mut song, EditCommand::ClearNote{ pattern : 0, channel : 1, row : 0 } ).unwrap();
+    assert!( history.undo( &mut song ) );
+    assert!( history.redo( &mut song ).unwrap() );
+    assert!( history.undo( &mut song ) );
+    assert!( !history.undo( &mut song ) );
+}
+
+#[test]
+fn rows_are_inserted_and_deleted() {
+    let mut song = test_song();
+    let mut history = EditHistory::new();
+    history.apply( &mut song, EditCommand::InsertRow{ pattern : 0, channel : Some( 0 ), row : 0 } ).unwrap();
+    assert_eq!( note( &song, 0, 0, 0 ).period(), 0 );
+    assert_eq!( note( &song, 0, 0, 1 ).period(), 856 );
+    assert_eq!( note( &song, 0, 1, 0 ).period(), 113 );
+
+    history.apply( &mut song, EditCommand::DeleteRow{ pattern : 0, channel : None, row : 0 } ).unwrap();
+    assert_eq!( note( &song, 0, 0, 0 ).period(), 856 );
+    assert_eq!( note( &song, 0, 1, 0 ).period(), 0 );
+}
+
+#[test]
+fn transpose_uses_the_period_table_and_keeps_notes_in_range() {
+    let mut song = test_song();
+    let mut history = EditHistory::new();
+    history.apply( &mut song, EditCommand::Transpose{ pattern : 0, block : Block::new( 0, 1, 0, 1 ), semitones : 12 } ).unwrap();
+    assert_eq!( note( &song, 0, 0, 0 ).period(), 428 );
+    assert_eq!( note( &song, 0, 0, 1 ).period(), 381 );
+    assert_eq!( note( &song, 0, 1, 0 ).period(), 57 );
+    // B-6 is the highest note in the table so it stays where it is
+    let block = Block::channel( &song, 0, 1 );
+    history.apply( &mut song, EditCommand::Transpose{ pattern : 0, block, semitones : 1 } ).unwrap();
+    assert_eq!( note( &song, 0, 1, 0 ).period(), 57 );
+}
+
+/**
+ * An Impulse Tracker module with a single pattern of 32 rows that plays C-5, C-6 and C-6 again on its first channel
+ */
+fn short_pattern_song() -> Song {
+    let mut data = vec![ 0u8; 0xc6 ];
+    data[ 0..4 ].copy_from_slice( b"IMPM" );
+    for ( position, value ) in [ ( 0x20, 2u16 ), ( 0x26, 1 ), ( 0x28, 0x214 ), ( 0x2a, 0x214 ) ] {
+        data[ position..position + 2 ].copy_from_slice( &value.to_le_bytes() );
+    }
+    data[ 0x30 ] = 128;
+    data[ 0x32 ] = 6;
+    data[ 0x33 ] = 125;
+    data[ 0x41..0x80 ].fill( 0x80 );           // only the first channel is on
+    data[ 0xc0..0xc2 ].copy_from_slice( &[ 0, 255 ] );
+    data[ 0xc2..0xc6 ].copy_from_slice( &0xc6u32.to_le_bytes() );
+    let mut packed = Vec::new();
+    for row in 0..32 {
+        match row {
+            0 => packed.extend_from_slice( &[ 0x81, 1, 60 ] ),
+            4 | 8 => packed.extend_from_slice( &[ 0x81, 1, 72 ] ),
+            _ => ()
+        }
+        packed.push( 0 );
+    }
+    data.extend_from_slice( &( packed.len() as u16 ).to_le_bytes() );
+    data.extend_from_slice( &[ 32, 0, 0, 0, 0, 0 ] );
+    data.extend( packed );
+    read_it_data( &data ).unwrap()
+}
+
+#[test]
+fn edits_cover_the_rows_of_short_patterns_and_transpose_keys() {
+    let mut song = short_pattern_song();
+    let block = Block::channel( &song, 0, 0 );
+    assert_eq!( block.last_row, 31 );
+    let mut history = EditHistory::new();
+    history.apply( &mut song, EditCommand::Transpose{ pattern : 0, block, semitones : 12 } ).unwrap();
+    let keys : Vec<Key> = [ 0, 4, 8 ].iter().map( | row | note( &song, 0, 0, *row ).key() ).collect();
+    assert_eq!( keys, [ Key::Note( 72 ), Key::Note( 84 ), Key::Note( 84 ) ] );
+    // keys past the 120 an instrument has are left alone
+    history.apply( &mut song, EditCommand::Transpose{ pattern : 0, block, semitones : 40 } ).unwrap();
+    assert_eq!( note( &song, 0, 0, 4 ).key(), Key::Note( 84 ) );
+    assert_eq!( note( &song, 0, 0, 0 ).key(), Key::Note( 112 ) );
+}
+
+#[test]
+fn patterns_are_copied_pasted_cloned_and_ordered() {
+    let mut song = test_song();
+    let mut history = EditHistory::new();
+    let clip = copy_block( &song, 0, Block::new( 0, 1, 0, 1 ) ).unwrap();
+    history.apply( &mut song, EditCommand::Paste{ pattern : 0, channel : 2, row : 62, clip } ).unwrap();
+    assert_eq!( note( &song, 0, 2, 62 ).period(), 856 );
+    assert_eq!( note( &song, 0, 3, 63 ).period(), 0 );
+
+    history.apply( &mut song, EditCommand::ClonePattern{ pattern : 0 } ).unwrap();
+    assert_eq!( song.patterns().len(), 2 );
+    let block = Block::channel( &song, 1, 0 );
+    history.apply( &mut song, EditCommand::ChangeSample{ pattern : 1, block, sample_number : 2 } ).unwrap();
+    assert_eq!( note( &song, 1, 0, 0 ).sample_number(), 2 );
+    assert_eq!( note( &song, 0, 0, 0 ).sample_number(), 1 );
+
+    history.apply( &mut song, EditCommand::SetOrder{ positions : vec![ 1, 0, 1 ] } ).unwrap();
+    assert_eq!( song.song_length(), 3 );
+    assert_eq!( &song.pattern_table()[ 0..3 ], &[ 1, 0, 1 ] );
+    assert!( history.apply( &mut song, EditCommand::SetOrder{ positions : vec![ 2 ] } ).is_err() );
+
+    while history.undo( &mut song ) {}
+    assert_eq!( song.patterns().len(), 1 );
+    assert_eq!( song.song_length(), 1 );
+    assert_eq!( note( &song, 0, 2, 62 ).period(), 0 );
+}
+
+/**
+ * A Soundtracker module with 15 samples, one pattern and nothing in it
+ */
+fn soundtracker_song() -> Song {
+    let mut data = vec![ 0u8; 600 + 1024 + 8 ];
+    data[ 43 ] = 4;
+    data[ 45 ] = 64;
+    data[ 470 ] = 1;
+    data[ 471 ] = 120;
+    read_mod_data( &data ).unwrap()
+}
+
+#[test]
+fn notes_only_use_samples_the_song_has() {
+    let mut song = test_song();
+    let mut history = EditHistory::new();
+    let block = Block::channel( &song, 0, 0 );
+    assert!( history.apply( &mut song, EditCommand::ChangeSample{ pattern : 0, block, sample_number : 32 } ).is_err() );
+    assert!( history.apply( &mut song, EditCommand::SetNote{ pattern : 0, channel : 0, row : 0, note : Note::new( 32, 428, Effect::None ) } ).is_err() );
+    // a mod stores 12 bits of period
+    assert!( history.apply( &mut song, EditCommand::SetNote{ pattern : 0, channel : 0, row : 0, note : Note::new( 1, 0x1000, Effect::None ) } ).is_err() );
+    assert!( !history.can_undo() );
+    assert_eq!( note( &song, 0, 0, 0 ).sample_number(), 1 );
+    history.apply( &mut song, EditCommand::ChangeSample{ pattern : 0, block, sample_number : 31 } ).unwrap();
+
+    // a clip is checked against the song it is pasted into
+    let clip = copy_block( &song, 0, Block::new( 0, 0, 0, 1 ) ).unwrap();
+    let mut soundtracker = soundtracker_song();
+    assert!( history.apply( &mut soundtracker, EditCommand::Paste{ pattern : 0, channel : 0, row : 0, clip } ).is_err() );
+    assert_eq!( note( &soundtracker, 0, 0, 0 ).sample_number(), 0 );
+    let clip = copy_block( &song, 0, Block::new( 1, 1, 0, 1 ) ).unwrap();
+    history.apply( &mut soundtracker, EditCommand::Paste{ pattern : 0, channel : 0, row : 0, clip } ).unwrap();
+    assert_eq!( note( &soundtracker, 0, 0, 0 ).sample_number(), 2 );
+}
@@ -0,0 +1,109 @@
+use std::sync::Arc;
+
+use mod_player::{SongBuilder, Song, Effect, PeriodLimits, Player, PlayerOptions};
+
+const LINE : usize = 6 * 960;           // samples in a line at speed 6 and 48kHz
+
+/**
+ * One note with a slide on the first line, after which the period stays where the slide left it
+ */
+fn sliding_note( note : &str, effect : Effect ) -> Song {
+    let mut builder = SongBuilder::new( "slide", 4 ).unwrap();
+    let square = builder.add_sample_f32( "square", &( 0..64 ).map( | index | if index < 32 { 0.5 } else { -0.5 } ).collect::<Vec<f32>>() ).unwrap();
+    builder.set_sample_loop( square, 0, 64 ).unwrap();
+    let pattern = builder.add_pattern().unwrap();
+    builder.set_note( pattern, 0, 0, note, square, effect ).unwrap();
+    builder.set_orders( &[ pattern ] ).unwrap();
+    builder.build().unwrap()
+}
+
+/**
+ * A note at a period with an effect that is repeated on every line after it, and a second note on line 2 if there is
+ * one
+ */
+fn held_effect( period : u32, effect : Effect, second_note : Option<( u32, Effect )> ) -> Song {
+    let mut builder = SongBuilder::new( "held", 4 ).unwrap();
+    let square = builder.add_sample_f32( "square", &( 0..64 ).map( | index | if index < 32 { 0.5 } else { -0.5 } ).collect::<Vec<f32>>() ).unwrap();
+    builder.set_sample_loop( square, 0, 64 ).unwrap();
+    let pattern = builder.add_pattern().unwrap();
+    builder.set_note_period( pattern, 0, 0, period, square, effect ).unwrap();
+    for row in 1..64 {
+        builder.set_effect( pattern, 0, row, effect ).unwrap();
+    }
+    if let Some( ( period, effect ) ) = second_note {
+        builder.set_note_period( pattern, 0, 1, period, square, effect ).unwrap();
+    }
+    builder.set_orders( &[ pattern ] ).unwrap();
+    builder.build().unwrap()
+}
+
+/**
+ * The period of lines 3 - 10, worked out from how often the square wave crosses zero
+ */
+fn played_period( song : Song, period_limits : PeriodLimits ) -> f32 {
+    let mut player = Player::new( Arc::new( song ), &PlayerOptions{ period_limits, ..PlayerOptions::default() } );
+    let output : Vec<f32> = ( 0..10 * LINE ).map( | _ | player.next_sample().0 ).collect();
+    let lines = &output[ 2 * LINE.. ];
+    let crossings = lines.windows( 2 ).filter( | pair | ( pair[ 0 ] > 0.0 ) != ( pair[ 1 ] > 0.0 ) ).count();
+    let cycles_per_second = crossings as f32 / 2.0 / ( lines.len() as f32 / 48000.0 );
+    3579545.0 / ( cycles_per_second * 64.0 )
+}
+
+#[test]
+fn slides_stop_at_the_period_limits() {
+    assert_eq!( PlayerOptions::default().period_limits, PeriodLimits::Amiga );
+    assert_eq!( PeriodLimits::Extended.range(), ( 57, 1712 ) );
+
+    // six ticks of slide from B-5 at 113 would reach 53
+    let slide_up = || sliding_note( "B-5", Effect::SlideUp{ speed : 10 } );
+    let close = | period : f32, expected : f32 | ( period - expected ).abs() < expected * 0.03;
+    assert!( close( played_period( slide_up(), PeriodLimits::Amiga ), 113.0 ) );
+    assert!( close( played_period( slide_up(), PeriodLimits::Extended ), 57.0 ) );
+    assert!( close( played_period( slide_up(), PeriodLimits::Unlimited ), 53.0 ) );
+
+    let slide_down = || sliding_note( "C-3", Effect::SlideDown{ speed : 10 } );
+    assert!( close( played_period( slide_down(), PeriodLimits::Amiga ), 856.0 ) );
+    assert!( close( played_period( slide_down(), PeriodLimits::Extended ), 916.0 ) );
+}
+
+#[test]
+fn arpeggios_stay_within_the_period_limits() {
+    // an arpeggio of 0 semitones on the highest note of the frequency table looks one note below it
+    let arpeggio = || held_effect( 57, Effect::Arpeggio{ chord_offset_1 : 3, chord_offset_2 : 0 }, None );
+    let close = | period : f32, expected : f32 | ( period - expected ).abs() < expected * 0.03;
+    assert!( close( played_period( arpeggio(), PeriodLimits::Unlimited ), 57.0 ) );
+    assert!( close( played_period( arpeggio(), PeriodLimits::Extended ), 57.0 ) );
+    // every other tick is held at 113
+    assert!( played_period( arpeggio(), PeriodLimits::Amiga ) > 70.0 );
+}
+
+/**
+ * The shortest period played in the first two lines, from the shortest time between the square wave crossing zero
+ */
+fn shortest_period( song : Song, period_limits : PeriodLimits ) -> f32 {
+    let mut player = Player::new( Arc::new( song ), &PlayerOptions{ period_limits, ..PlayerOptions::default() } );
+    let output : Vec<f32> = ( 0..2 * LINE ).map( | _ | player.next_sample().0 ).collect();
+    let crossings : Vec<usize> = output.windows( 2 ).enumerate().filter( | ( _, pair ) | ( pair[ 0 ] > 0.0 ) != ( pair[ 1 ] > 0.0 ) ).map( | ( index, _ ) | index ).collect();
+    let shortest = crossings.windows( 2 ).map( | pair | pair[ 1 ] - pair[ 0 ] ).min().unwrap();
+    3579545.0 / ( 48000.0 / ( 2 * shortest ) as f32 * 64.0 )
+}
+
+#[test]
+fn vibrato_stays_within_the_period_limits() {
+    // a depth of 8 takes B-5 down to 52 on the fourth tick
+    let vibrato = || sliding_note( "B-5", Effect::Vibrato{ speed : 15, amplitude : 8 } );
+    let close = | period : f32, expected : f32 | ( period - expected ).abs() < expected * 0.05;
+    assert!( close( shortest_period( vibrato(), PeriodLimits::Amiga ), 113.0 ) );
+    assert!( close( shortest_period( vibrato(), PeriodLimits::Extended ), 57.0 ) );
+    assert!( close( shortest_period( vibrato(), PeriodLimits::Unlimited ), 52.0 ) );
+}
+
+#[test]
+fn tone_portamento_stops_at_the_period_limits() {
+    // a portamento from C-4 to a period shorter than any note's stops where the limits do
+    let portamento = || held_effect( 214, Effect::None, Some( ( 40, Effect::TonePortamento{ speed : 255 } ) ) );
+    let close = | period : f32, expected : f32 | ( period - expected ).abs() < expected * 0.05;
+    assert!( close( played_period( portamento(), PeriodLimits::Amiga ), 113.0 ) );
+    assert!( close( played_period( portamento(), PeriodLimits::Extended ), 57.0 ) );
+    assert!( close( played_period( portamento(), PeriodLimits::Unlimited ), 40.0 ) );
+}
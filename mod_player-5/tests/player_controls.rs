@@ -0,0 +1,260 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use mod_player::{Song, Player, PlayerOptions, PlayerCommand, PlayerStatus, TickTracker, TickStart, HeardSampleClock, read_mod_data};
+
+fn stardstm() -> Arc<Song> {
+    Arc::new( read_mod_data( &std::fs::read( "stardstm.mod" ).unwrap() ).unwrap() )
+}
+
+fn player_with( song : &Arc<Song>, muted_channels : &[usize], solo_channels : &[usize] ) -> Player {
+    Player::new( song.clone(), &PlayerOptions{ sample_rate : 8000, muted_channels : muted_channels.to_vec(), solo_channels : solo_channels.to_vec(), ..PlayerOptions::default() } )
+}
+
+fn render( player : &mut Player, samples : usize ) -> Vec<( f32, f32 )> {
+    ( 0..samples ).map( | _ | player.next_sample() ).collect()
+}
+
+fn is_silent( samples : &[( f32, f32 )] ) -> bool {
+    samples.iter().all( | sample | *sample == ( 0.0, 0.0 ) )
+}
+
+#[test]
+fn muted_channels_are_silent_and_stay_in_step() {
+    let song = stardstm();
+    let mut only_channel_1 = player_with( &song, &[ 0, 2, 3 ], &[] );
+    let mut muted = player_with( &song, &[ 0, 1, 2, 3 ], &[] );
+    assert!( muted.state().is_channel_muted( 1 ) );
+
+    // nothing is heard while every channel is muted, but the channels keep playing their notes and effects
+    let expected = render( &mut only_channel_1, 8000 * 3 );
+    assert!( !is_silent( &expected ) );
+    assert!( is_silent( &render( &mut muted, 8000 * 3 ) ) );
+
+    // unmuted in the middle of a line, the channel carries on exactly where it would have been
+    muted.state_mut().set_channel_muted( 1, false );
+    let unmuted = render( &mut muted, 8000 * 3 );
+    assert!( !is_silent( &unmuted ) );
+    assert_eq!( unmuted, render( &mut only_channel_1, 8000 * 3 ) );
+}
+
+#[test]
+fn solo_channels_silence_the_others() {
+    let song = stardstm();
+    let mut solo = player_with( &song, &[], &[ 1 ] );
+    assert!( solo.state().is_channel_audible( 1 ) && !solo.state().is_channel_audible( 0 ) );
+    let mut only_channel_1 = player_with( &song, &[ 0, 2, 3 ], &[] );
+    assert_eq!( render( &mut solo, 8000 * 2 ), render( &mut only_channel_1, 8000 * 2 ) );
+
+    // muting wins over solo, and every soloed channel is heard
+    solo.state_mut().set_channel_muted( 1, true );
+    solo.state_mut().set_channel_solo( 2, true );
+    let mut only_channel_2 = player_with( &song, &[ 0, 1, 3 ], &[] );
+    render( &mut only_channel_2, 8000 * 2 );
+    assert_eq!( render( &mut solo, 8000 * 2 ), render( &mut only_channel_2, 8000 * 2 ) );
+
+    // with no channel soloed everything that is not muted is heard again
+    solo.state_mut().set_channel_solo( 1, false );
+    solo.state_mut().set_channel_solo( 2, false );
+    solo.state_mut().set_channel_muted( 1, false );
+    let mut everything = player_with( &song, &[], &[] );
+    render( &mut everything, 8000 * 4 );
+    assert_eq!( render( &mut solo, 8000 * 2 ), render( &mut everything, 8000 * 2 ) );
+}
+
+#[test]
+fn ticks_are_counted_where_they_start() {
+    let mut player = player_with( &stardstm(), &[], &[] );
+    let mut tracker = TickTracker::new( &player );
+    let ticks : Vec<( usize, ( u32, u32 ), u32 )> = ( 0..8000 * 4 ).filter_map( | _ | tracker.next_frame( &mut player ).1 )
+        .map( | TickStart{ sample_index, song_pattern_position, line, tick } | ( sample_index as usize, ( song_pattern_position, line ), tick ) ).collect();
+    assert_eq!( tracker.frames_mixed(), 8000 * 4 );
+    // 50 ticks a second. The rows move on when the tick count starts again, after a line of waiting before the first one
+    assert_eq!( ticks.len(), 199 );
+    assert!( ticks.iter().enumerate().all( | ( index, tick ) | tick.0 == 160 * ( index + 1 ) ) );
+    for pair in ticks.windows( 2 ) {
+        let ( ( _, row, tick ), ( _, next_row, next_tick ) ) = ( pair[ 0 ], pair[ 1 ] );
+        if next_tick == 0 {
+            assert!( next_row == ( row.0, row.1 + 1 ) || next_row == ( row.0 + 1, 0 ) || ( row == ( 0, 0 ) && tick == 5 ) );
+        } else {
+            assert_eq!( ( next_row, next_tick ), ( row, tick + 1 ) );
+        }
+    }
+    assert!( ticks.last().unwrap().1 > ( 0, 8 ) );
+}
+
+#[test]
+fn heard_sample_trails_the_rendered_one_by_a_buffer() {
+    let start = Instant::now();
+    let at = | milliseconds | start + Duration::from_millis( milliseconds );
+    let mut clock = HeardSampleClock::new( 8000, start );
+    assert_eq!( clock.heard_sample( 0, 0, start ), 0 );
+    assert_eq!( clock.heard_sample( 800, 800, at( 0 ) ), 0 );
+
+    // two buffers of 800 rendered: the first is playing, heard from its start and moving on with the clock
+    assert_eq!( clock.heard_sample( 1600, 800, at( 100 ) ), 0 );
+    assert_eq!( clock.heard_sample( 1600, 800, at( 150 ) ), 400 );
+    // but not past the end of that buffer when the next one is late
+    assert_eq!( clock.heard_sample( 1600, 800, at( 400 ) ), 800 );
+    assert_eq!( clock.heard_sample( 2400, 800, at( 400 ) ), 800 );
+}
+
+/**
+ * A looped sample that is long enough to measure, and stardstm with that sample's volume halved
+ */
+fn looped_sample_and_quieter_song() -> ( u8, Arc<Song> ) {
+    let song = stardstm();
+    let index = song.samples().iter().position( | sample | sample.repeat_size() > 2 && sample.volume() > 1 && sample.size() > 2000 ).unwrap();
+    let mut data = std::fs::read( "stardstm.mod" ).unwrap();
+    data[ 20 + 30 * index + 25 ] = song.samples()[ index ].volume() / 2;
+    ( index as u8 + 1, Arc::new( read_mod_data( &data ).unwrap() ) )
+}
+
+#[test]
+fn preview_voice_plays_while_the_song_is_stopped() {
+    let ( sample_number, quieter_song ) = looped_sample_and_quieter_song();
+    let song = stardstm();
+    let mut player = player_with( &song, &[], &[] );
+    player.apply_command( PlayerCommand::Stop );
+    assert!( is_silent( &render( &mut player, 800 ) ) );
+
+    // centered, and still heard from the loop long after the sample has played through
+    player.apply_command( PlayerCommand::PlayInstrument{ index : sample_number, period : 428 } );
+    let preview = render( &mut player, 8000 * 4 );
+    assert!( preview.iter().all( | sample | sample.0 == sample.1 ) );
+    assert!( !is_silent( &preview[ 8000 * 3.. ] ) );
+
+    // the song has not moved, and sample 0 stops the preview
+    assert!( player.state().is_paused() );
+    assert_eq!( player.state().ticks_played(), 0 );
+    player.apply_command( PlayerCommand::PlayInstrument{ index : 0, period : 428 } );
+    assert!( is_silent( &render( &mut player, 800 ) ) );
+
+    // the sample's own volume is used
+    let volume = song.samples()[ sample_number as usize - 1 ].volume() as f32;
+    let quiet_volume = quieter_song.samples()[ sample_number as usize - 1 ].volume() as f32;
+    let mut quiet = player_with( &quieter_song, &[], &[] );
+    quiet.apply_command( PlayerCommand::Stop );
+    quiet.apply_command( PlayerCommand::PlayInstrument{ index : sample_number, period : 428 } );
+    player.apply_command( PlayerCommand::PlayInstrument{ index : sample_number, period : 428 } );
+    for ( loud, quiet ) in render( &mut player, 4000 ).iter().zip( render( &mut quiet, 4000 ) ) {
+        assert!( ( loud.0 * quiet_volume / volume - quiet.0 ).abs() < 1e-6 );
+    }
+}
+
+#[test]
+fn preview_notes_set_the_pitch() {
+    let ( sample_number, _ ) = looped_sample_and_quieter_song();
+    let song = stardstm();
+    let preview = | period | {
+        let mut player = player_with( &song, &[ 0, 1, 2, 3 ], &[] );
+        player.apply_command( PlayerCommand::PlayInstrument{ index : sample_number, period } );
+        render( &mut player, 2000 )
+    };
+    // an octave up plays every other value of the sample
+    let ( c3, c4 ) = ( preview( 428 ), preview( 214 ) );
+    let matching = ( 0..1000 ).filter( | index | c4[ *index ] == c3[ index * 2 ] ).count();
+    assert!( matching > 950 );
+    assert!( c4[ ..1000 ] != c3[ ..1000 ] );
+}
+
+#[test]
+fn preview_voice_is_mixed_on_top_of_the_song() {
+    let ( sample_number, _ ) = looped_sample_and_quieter_song();
+    let song = stardstm();
+    let mut song_only = player_with( &song, &[], &[] );
+    let mut preview_only = player_with( &song, &[], &[] );
+    let mut both = player_with( &song, &[], &[] );
+    preview_only.apply_command( PlayerCommand::Pause );
+    preview_only.apply_command( PlayerCommand::PlayInstrument{ index : sample_number, period : 339 } );
+    both.apply_command( PlayerCommand::PlayInstrument{ index : sample_number, period : 339 } );
+    for _ in 0..8000 * 2 {
+        let ( song_sample, preview_sample, mixed ) = ( song_only.next_sample(), preview_only.next_sample(), both.next_sample() );
+        assert!( ( song_sample.0 + preview_sample.0 - mixed.0 ).abs() < 1e-6 );
+        assert!( ( song_sample.1 + preview_sample.1 - mixed.1 ).abs() < 1e-6 );
+    }
+}
+
+fn command( player : &mut Player, command : PlayerCommand ) -> PlayerStatus {
+    player.apply_command( command );
+    player.status()
+}
+
+#[test]
+fn commands_change_the_player_state() {
+    let song = stardstm();
+    let mut player = player_with( &song, &[], &[] );
+    let mut reference = player_with( &song, &[], &[] );
+    render( &mut player, 4000 );
+    render( &mut reference, 4000 );
+
+    // paused songs stay where they are and are silent
+    assert!( command( &mut player, PlayerCommand::Pause ).paused );
+    let ( row, ticks_played ) = ( player.state().playing_row(), player.state().ticks_played() );
+    assert!( is_silent( &render( &mut player, 4000 ) ) );
+    assert_eq!( ( player.state().playing_row(), player.state().ticks_played() ), ( row, ticks_played ) );
+    assert!( !command( &mut player, PlayerCommand::Resume ).paused );
+
+    // the master volume scales the output and is never negative
+    assert_eq!( command( &mut player, PlayerCommand::SetVolume{ volume : 0.5 } ).master_volume, 0.5 );
+    for ( half, full ) in render( &mut player, 4000 ).iter().zip( render( &mut reference, 4000 ) ) {
+        assert!( ( half.0 * 2.0 - full.0 ).abs() < 1e-6 && ( half.1 * 2.0 - full.1 ).abs() < 1e-6 );
+    }
+    assert_eq!( command( &mut player, PlayerCommand::SetVolume{ volume : -1.0 } ).master_volume, 0.0 );
+    command( &mut player, PlayerCommand::SetVolume{ volume : 1.0 } );
+
+    // overrides replace the song's speed and tempo until they are cleared. A tick lasts 133 samples at 150 bpm
+    command( &mut player, PlayerCommand::SetSpeed{ speed : Some( 3 ) } );
+    let status = command( &mut player, PlayerCommand::SetBpm{ bpm : Some( 150 ) } );
+    assert_eq!( ( status.speed, status.bpm ), ( 3, 150 ) );
+    let ticks_played = player.state().ticks_played();
+    render( &mut player, 12 * 133 );
+    assert_eq!( player.state().ticks_played() - ticks_played, 12 );
+    command( &mut player, PlayerCommand::SetSpeed{ speed : None } );
+    let status = command( &mut player, PlayerCommand::SetBpm{ bpm : None } );
+    assert_eq!( ( status.speed, status.bpm ), ( 6, 125 ) );
+
+    // mute and solo toggle, channels the song does not have are ignored
+    command( &mut player, PlayerCommand::ToggleMute{ channel : 1 } );
+    command( &mut player, PlayerCommand::ToggleSolo{ channel : 3 } );
+    let status = command( &mut player, PlayerCommand::ToggleMute{ channel : 10 } );
+    assert_eq!( ( status.muted_channels, status.solo_channels ), ( 0b10, 0b1000 ) );
+    command( &mut player, PlayerCommand::ToggleMute{ channel : 1 } );
+    let status = command( &mut player, PlayerCommand::ToggleSolo{ channel : 3 } );
+    assert_eq!( ( status.muted_channels, status.solo_channels ), ( 0, 0 ) );
+}
+
+#[test]
+fn position_commands_move_through_the_pattern_table() {
+    let song = stardstm();
+    let last_position = song.song_length() - 1;
+    let mut player = player_with( &song, &[], &[] );
+    render( &mut player, 4000 );
+    assert_eq!( player.state().playing_row(), ( 0, 2 ) );
+
+    // jumps start the new position on the next sample and stay within the song
+    command( &mut player, PlayerCommand::NextPosition );
+    player.next_sample();
+    assert_eq!( player.state().playing_row(), ( 1, 0 ) );
+    command( &mut player, PlayerCommand::PreviousPosition );
+    command( &mut player, PlayerCommand::PreviousPosition );
+    player.next_sample();
+    assert_eq!( player.state().playing_row(), ( 0, 0 ) );
+    player.state_mut().jump_to_position( &song, last_position );
+    command( &mut player, PlayerCommand::NextPosition );
+    player.next_sample();
+    assert_eq!( player.state().playing_row(), ( last_position, 0 ) );
+
+    // stop goes back to the start and pauses, keeping the overrides. Restart plays from the start again
+    command( &mut player, PlayerCommand::SetSpeed{ speed : Some( 3 ) } );
+    render( &mut player, 4000 );
+    let status = command( &mut player, PlayerCommand::Stop );
+    assert!( status.paused );
+    assert_eq!( ( status.song_pattern_position, status.line, status.speed ), ( 0, 0, 3 ) );
+    command( &mut player, PlayerCommand::NextPosition );
+    let status = command( &mut player, PlayerCommand::Restart );
+    assert!( !status.paused );
+    assert_eq!( player.state().song_pattern_position(), 0 );
+    render( &mut player, 4000 );
+    assert_eq!( player.state().playing_row().0, 0 );
+}
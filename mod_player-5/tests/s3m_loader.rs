@@ -0,0 +1,151 @@
+use std::sync::Arc;
+
+use mod_player::{Effect, Key, ModuleKind, Player, PlayerOptions, read_s3m_data, song_duration};
+
+fn put_word( data : &mut [u8], position : usize, value : u16 ) {
+    data[ position..position + 2 ].copy_from_slice( &value.to_le_bytes() );
+}
+
+/**
+ * A packed pattern: one ( row, channel, note, instrument, volume, command, argument ) entry per note, 255 for
+ * the fields that are left out
+ */
+fn pattern( notes : &[( usize, u8, u8, u8, u8, u8, u8 )] ) -> Vec<u8> {
+    let mut data = vec![ 0, 0 ];
+    for row in 0..64 {
+        for &( _, channel, note, instrument, volume, command, argument ) in notes.iter().filter( | note | note.0 == row ) {
+            let mut flags = channel;
+            let mut fields = Vec::new();
+            if note != 255 || instrument != 0 {
+                flags |= 0x20;
+                fields.extend_from_slice( &[ note, instrument ] );
+            }
+            if volume != 255 {
+                flags |= 0x40;
+                fields.push( volume );
+            }
+            if command != 255 {
+                flags |= 0x80;
+                fields.extend_from_slice( &[ command, argument ] );
+            }
+            data.push( flags );
+            data.extend( fields );
+        }
+        data.push( 0 );
+    }
+    let size = data.len() as u16;
+    put_word( &mut data, 0, size );
+    data
+}
+
+/**
+ * Two PCM channels with an AdLib channel between them, a looped unsigned sample an octave up and two patterns
+ * with a "+++" marker between them in the order list
+ */
+fn test_module() -> Vec<u8> {
+    let mut data = vec![ 0u8; 0x300 ];
+    data[ 0..4 ].copy_from_slice( b"test" );
+    data[ 0x1c ] = 0x1a;
+    data[ 0x1d ] = 16;
+    for ( position, value ) in [ ( 0x20, 4 ), ( 0x22, 1 ), ( 0x24, 2 ), ( 0x28, 0x1320 ), ( 0x2a, 2 ) ] {
+        put_word( &mut data, position, value );
+    }
+    data[ 0x2c..0x30 ].copy_from_slice( b"SCRM" );
+    data[ 0x30 ] = 48;                  // global volume
+    data[ 0x31 ] = 6;
+    data[ 0x32 ] = 125;
+    data[ 0x33 ] = 0x80 | 0x30;         // stereo
+    data[ 0x35 ] = 252;                 // there is a panning table
+    data[ 0x40..0x60 ].fill( 255 );
+    data[ 0x40 ] = 0;
+    data[ 0x41 ] = 0x10;
+    data[ 0x42 ] = 8;
+    data[ 0x60..0x64 ].copy_from_slice( &[ 0, 254, 1, 255 ] );
+    put_word( &mut data, 0x64, 0x10 );          // the instrument at 0x100
+    put_word( &mut data, 0x66, 0x30 );
+    let second_pattern = 0x30 + pattern_size() / 16 + 1;
+    put_word( &mut data, 0x68, second_pattern as u16 );
+    data[ 0x6a ] = 0x20;                        // channel 1 hard left
+    data[ 0x6c ] = 0x20 | 0x0f;                 // channel 3 hard right
+
+    let instrument = 0x100;
+    data[ instrument ] = 1;
+    data[ instrument + 0x0e ] = 0x20;           // sample data at 0x200
+    data[ instrument + 0x10 ] = 32;
+    data[ instrument + 0x18 ] = 32;
+    data[ instrument + 0x1c ] = 64;
+    data[ instrument + 0x1f ] = 1;              // looped
+    put_word( &mut data, instrument + 0x20, 16726 );
+    data[ instrument + 0x30..instrument + 0x34 ].copy_from_slice( b"lead" );
+    data[ instrument + 0x4c..instrument + 0x50 ].copy_from_slice( b"SCRS" );
+    data[ 0x200..0x220 ].fill( 0xff );
+
+    data.extend( first_pattern() );
+    data.resize( second_pattern * 16, 0 );
+    // speed 32 is a speed, not a tempo, and B02 jumps to the order after the marker
+    data.extend( pattern( &[ ( 0, 0, 255, 0, 255, 1, 0x20 ), ( 63, 0, 255, 0, 255, 2, 2 ) ] ) );
+    data
+}
+
+fn first_pattern() -> Vec<u8> {
+    pattern( &[
+        ( 0, 0, 0x40, 1, 32, 4, 0x04 ),         // C-4 at volume 32, sliding down by 4
+        ( 0, 2, 254, 0, 255, 255, 0 ),
+        ( 1, 0, 255, 0, 255, 4, 0 ),            // D00 slides on by the last amount
+        ( 2, 0, 255, 0, 255, 5, 0xf2 ),
+    ] )
+}
+
+fn pattern_size() -> usize {
+    first_pattern().len()
+}
+
+#[test]
+fn loader_reads_channels_samples_and_effects() {
+    let song = read_s3m_data( &test_module() ).unwrap();
+    assert_eq!( song.name().trim_end_matches( '\0' ), "test" );
+    assert_eq!( song.format().kind, ModuleKind::S3m );
+    assert_eq!( song.num_channels(), 2 );
+    assert_eq!( ( song.channel_panning( 0 ), song.channel_panning( 1 ) ), ( 0, 255 ) );
+    assert_eq!( ( song.global_volume(), song.initial_speed(), song.initial_bpm() ), ( 48, 6, 125 ) );
+    assert_eq!( song.pattern_table(), &[ 0, 1 ] );
+
+    let sample = &song.samples()[ 0 ];
+    assert_eq!( sample.name().trim_end_matches( '\0' ), "lead" );
+    assert_eq!( sample.c2_speed(), 16726 );
+    assert_eq!( ( sample.repeat_offset(), sample.repeat_size() ), ( 0, 32 ) );
+    assert!( sample.data().iter().all( | value | *value == 127 ) );
+
+    let lines = song.patterns()[ 0 ].lines();
+    assert_eq!( lines[ 0 ][ 0 ].key(), Key::Note( 48 ) );
+    assert_eq!( lines[ 0 ][ 0 ].volume_effect(), &Effect::SetVolume{ volume : 32 } );
+    assert_eq!( lines[ 0 ][ 0 ].effect(), &Effect::VolumeSlide{ up : 0, down : 4 } );
+    assert_eq!( lines[ 0 ][ 1 ].key(), Key::Cut );
+    assert_eq!( lines[ 1 ][ 0 ].effect(), &Effect::VolumeSlide{ up : 0, down : 0 } );
+    assert_eq!( lines[ 2 ][ 0 ].effect(), &Effect::FineSlideDown{ speed : 2 } );
+    assert_eq!( song.patterns()[ 1 ].lines()[ 63 ][ 0 ].effect(), &Effect::PositionJump{ next_pattern : 1 } );
+
+    assert!( song.write_mod( &mut Vec::new() ).is_err() );
+    assert!( read_s3m_data( b"not a module" ).is_err() );
+}
+
+#[test]
+fn scream_tracker_rules_apply_when_playing() {
+    let song = read_s3m_data( &test_module() ).unwrap();
+    // 64 lines at speed 6, then 64 at speed 32 before the jump back
+    let expected = 64.0 * 6.0 * 0.02 + 64.0 * 32.0 * 0.02;
+    assert!( ( song_duration( &song ) - expected ).abs() < 0.01 );
+
+    let mut player = Player::new( Arc::new( song ), &PlayerOptions::default() );
+    // the first line starts on the seventh vblank
+    let line_samples = 6 * 960;
+    let output : Vec<( f32, f32 )> = ( 0..line_samples * 4 ).map( | _ | player.next_sample() ).collect();
+    let level = | volume : f32 | 127.0 / 128.0 * volume / 64.0 * 0.75;
+    // the first channel is hard left at the volume column's volume, scaled by the global volume
+    let start = output[ 7 * 960 + 10 ];
+    assert_eq!( start.1, 0.0 );
+    assert!( ( start.0 - level( 32.0 ) ).abs() < 0.001 );
+    // the slide only runs after the first tick: 32 - 5 * 4 at the end of the first line, and the D00 repeats it
+    assert!( ( output[ 7 * 960 + line_samples - 10 ].0 - level( 12.0 ) ).abs() < 0.001 );
+    assert_eq!( output[ 7 * 960 + 2 * line_samples - 10 ].0, 0.0 );
+}
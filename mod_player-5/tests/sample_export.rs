@@ -0,0 +1,130 @@
+use mod_player::SongBuilder;
+use mod_player::sample_wav::{SampleBits, SampleImportOptions, write_sample_wav, read_sample_wav, sample_file_name, c3_sample_rate};
+
+fn chunk<'a>( file : &'a [u8], id : &[u8] ) -> &'a [u8] {
+    let mut position = 12;
+    while position + 8 <= file.len() {
+        let size = u32::from_le_bytes( [ file[ position + 4 ], file[ position + 5 ], file[ position + 6 ], file[ position + 7 ] ] ) as usize;
+        if &file[ position..position + 4 ] == id {
+            return &file[ position + 8..position + 8 + size ];
+        }
+        position += 8 + size + size % 2;
+    }
+    panic!( "no {:?} chunk", id );
+}
+
+fn word( data : &[u8], index : usize ) -> u32 {
+    u32::from_le_bytes( [ data[ index * 4 ], data[ index * 4 + 1 ], data[ index * 4 + 2 ], data[ index * 4 + 3 ] ] )
+}
+
+#[test]
+fn exported_sample_keeps_loop_and_finetune() {
+    let mut builder = SongBuilder::new( "export", 4 ).unwrap();
+    let number = builder.add_sample( "Bass: \"deep\" #1", vec![ -128, -1, 0, 1, 127, 64, 32, 16 ] ).unwrap();
+    builder.set_sample_loop( number, 2, 4 ).unwrap();
+    builder.set_sample_fine_tune( number, -1 ).unwrap();
+    let pattern = builder.add_pattern().unwrap();
+    builder.add_order( pattern ).unwrap();
+    let song = builder.build().unwrap();
+    let sample = &song.samples()[ 0 ];
+
+    assert_eq!( sample_file_name( 1, sample ), "01_Bass_deep_1.wav" );
+    assert_eq!( sample_file_name( 2, &song.samples()[ 1 ] ), "02.wav" );
+
+    let mut file = Vec::new();
+    write_sample_wav( sample, SampleBits::Eight, &mut file ).unwrap();
+    assert_eq!( &file[ 0..4 ], b"RIFF" );
+    assert_eq!( word( &file[ 4..8 ], 0 ) as usize, file.len() - 8 );
+    assert_eq!( word( chunk( &file, b"fmt " ), 1 ), c3_sample_rate() );
+    assert_eq!( chunk( &file, b"data" ), &[ 0x00, 0x7f, 0x80, 0x81, 0xff, 0xc0, 0xa0, 0x90 ] );
+
+    let smpl = chunk( &file, b"smpl" );
+    // tuned down an eighth of a semitone, so the recording is an eighth above middle C
+    assert_eq!( word( smpl, 3 ), 60 );
+    assert_eq!( word( smpl, 4 ), 1 << 29 );
+    assert_eq!( word( smpl, 7 ), 1 );
+    assert_eq!( ( word( smpl, 11 ), word( smpl, 12 ) ), ( 2, 5 ) );
+
+    let mut file = Vec::new();
+    write_sample_wav( sample, SampleBits::Sixteen, &mut file ).unwrap();
+    assert_eq!( &chunk( &file, b"data" )[ 0..4 ], &[ 0x00, 0x80, 0x00, 0xff ] );
+}
+
+#[test]
+fn exported_sample_imports_unchanged() {
+    let mut builder = SongBuilder::new( "import", 4 ).unwrap();
+    let number = builder.add_sample( "saw", ( 0..200i32 ).map( | index | ( index * 5 % 256 - 128 ) as i8 ).collect() ).unwrap();
+    builder.set_sample_loop( number, 100, 60 ).unwrap();
+    builder.set_sample_fine_tune( number, 3 ).unwrap();
+    let pattern = builder.add_pattern().unwrap();
+    builder.add_order( pattern ).unwrap();
+    let mut song = builder.build().unwrap();
+
+    for bits in [ SampleBits::Eight, SampleBits::Sixteen ] {
+        let mut file = Vec::new();
+        write_sample_wav( &song.samples()[ 0 ], bits, &mut file ).unwrap();
+        let sample = read_sample_wav( &file, "saw", &SampleImportOptions::default() ).unwrap();
+        assert_eq!( sample.data(), song.samples()[ 0 ].data() );
+        assert_eq!( ( sample.repeat_offset(), sample.repeat_size(), sample.fine_tune() ), ( 100, 60, 3 ) );
+    }
+
+    let copy = song.samples()[ 0 ].clone();
+    assert_eq!( song.add_sample( copy.clone() ).ok(), Some( 2 ) );
+    assert!( song.replace_sample( 32, copy ).is_err() );
+}
+
+/**
+ * A 16 bit stereo wav file with the left channel holding the values and the right one silent
+ */
+fn stereo_wav( sample_rate : u32, values : &[i16], smpl_loop : Option<( u32, u32 )> ) -> Vec<u8> {
+    let mut format = Vec::new();
+    for value in [ 1u16, 2 ] {
+        format.extend_from_slice( &value.to_le_bytes() );
+    }
+    format.extend_from_slice( &sample_rate.to_le_bytes() );
+    format.extend_from_slice( &( sample_rate * 4 ).to_le_bytes() );
+    format.extend_from_slice( &4u16.to_le_bytes() );
+    format.extend_from_slice( &16u16.to_le_bytes() );
+    let data : Vec<u8> = values.iter().flat_map( | value | [ value.to_le_bytes(), [ 0, 0 ] ].concat() ).collect();
+
+    let mut chunks = vec![ ( b"fmt ", format ), ( b"data", data ) ];
+    if let Some( ( start, end ) ) = smpl_loop {
+        let words = [ 0, 0, 0, 60, 0, 0, 0, 1, 0, 0, 0, start, end, 0, 0 ];
+        chunks.push( ( b"smpl", words.iter().flat_map( | word : &u32 | word.to_le_bytes() ).collect() ) );
+    }
+    let mut file = b"RIFF\0\0\0\0WAVE".to_vec();
+    for ( id, body ) in chunks {
+        file.extend_from_slice( id );
+        file.extend_from_slice( &( body.len() as u32 ).to_le_bytes() );
+        file.extend_from_slice( &body );
+    }
+    let size = ( file.len() - 8 ) as u32;
+    file[ 4..8 ].copy_from_slice( &size.to_le_bytes() );
+    file
+}
+
+#[test]
+fn wav_files_are_mixed_down_resampled_and_cut_to_fit() {
+    // 1000 frames at twice the target rate become 500, at half the level after the mix down
+    let values : Vec<i16> = ( 0..1000 ).map( | index | if index % 4 < 2 { 16384 } else { -16384 } ).collect();
+    let options = SampleImportOptions{ sample_rate : 8000, dither : false };
+    let sample = read_sample_wav( &stereo_wav( 16000, &values, Some( ( 200, 599 ) ) ), "mixed", &options ).unwrap();
+    assert_eq!( sample.size(), 500 );
+    assert!( sample.data().iter().all( | value | value.abs() <= 32 ) );
+    assert_eq!( ( sample.repeat_offset(), sample.repeat_size() ), ( 100, 200 ) );
+
+    let dithered = read_sample_wav( &stereo_wav( 16000, &values, None ), "dithered", &SampleImportOptions{ dither : true, ..options } ).unwrap();
+    assert_eq!( dithered.size(), 500 );
+    assert_eq!( dithered.repeat_size(), 2 );
+
+    let long = vec![ 1000; 70000 ];
+    let sample = read_sample_wav( &stereo_wav( 8000, &long, None ), "long", &SampleImportOptions{ sample_rate : 16000, dither : false } ).unwrap();
+    assert_eq!( sample.size(), 0xffff * 2 );
+
+    // four frames at 1Hz raised to a high rate stop at the longest sample instead of interpolating every frame
+    let short = vec![ 1000; 4 ];
+    let sample = read_sample_wav( &stereo_wav( 1, &short, None ), "stretched", &SampleImportOptions{ sample_rate : 1_000_000_000, dither : false } ).unwrap();
+    assert_eq!( sample.size(), 0xffff * 2 );
+
+    assert!( read_sample_wav( b"RIFF\0\0\0\0AVI ", "bad", &options ).is_err() );
+}
@@ -0,0 +1,66 @@
+use std::sync::Arc;
+
+use mod_player::{SongBuilder, Effect, PlayerOptions, SongStream, read_mod_data};
+
+fn square_wave() -> Vec<f32> {
+    ( 0..64 ).map( | index | if index < 32 { 0.5 } else { -0.5 } ).collect()
+}
+
+#[test]
+fn built_song_plays_and_survives_a_mod_round_trip() {
+    let mut builder = SongBuilder::new( "generated", 4 ).unwrap();
+    let square = builder.add_sample_f32( "square", &square_wave() ).unwrap();
+    builder.set_sample_loop( square, 0, 64 ).unwrap();
+    let pattern = builder.add_pattern().unwrap();
+    builder.set_note( pattern, 0, 0, "C-3", square, Effect::SetVolume{ volume : 48 } ).unwrap();
+    builder.set_note( pattern, 1, 16, "a#2", square, Effect::None ).unwrap();
+    builder.set_effect( pattern, 3, 63, Effect::PatternBreak{ next_pattern_pos : 0 } ).unwrap();
+    builder.set_orders( &[ pattern, pattern ] ).unwrap();
+    let song = builder.build().unwrap();
+
+    assert_eq!( song.song_length(), 2 );
+    assert_eq!( song.samples().len(), 31 );
+    assert_eq!( song.samples()[ 0 ].data()[ 0 ], 64 );
+    assert_eq!( song.patterns()[ 0 ].lines()[ 0 ][ 0 ].period(), 856 );
+    assert_eq!( *song.patterns()[ 0 ].lines()[ 63 ][ 3 ].effect(), Effect::PatternBreak{ next_pattern_pos : 0 } );
+
+    let mut data = Vec::new();
+    song.write_mod( &mut data ).unwrap();
+    let reloaded = read_mod_data( &data ).unwrap();
+    assert_eq!( reloaded.name().trim_end_matches( '\0' ), "generated" );
+    assert_eq!( reloaded.patterns()[ 0 ].lines()[ 16 ][ 1 ].period(), 961 );
+
+    let options = PlayerOptions{ sample_rate : 8000, ..PlayerOptions::default() };
+    let loudest = SongStream::new( Arc::new( song ), &options ).map( | ( left, right ) | left.abs().max( right.abs() ) ).fold( 0.0, f32::max );
+    assert!( loudest > 0.0 );
+}
+
+#[test]
+fn limits_are_enforced() {
+    assert!( SongBuilder::new( "too wide", 33 ).is_err() );
+
+    let mut builder = SongBuilder::new( "limits", 4 ).unwrap();
+    for _ in 0..31 {
+        builder.add_sample( "", vec![ 0; 2 ] ).unwrap();
+    }
+    assert!( builder.add_sample( "one too many", vec![ 0; 2 ] ).is_err() );
+
+    let pattern = builder.add_pattern().unwrap();
+    assert!( builder.set_note( pattern, 0, 64, "C-3", 1, Effect::None ).is_err() );
+    assert!( builder.set_note( pattern, 4, 0, "C-3", 1, Effect::None ).is_err() );
+    assert!( builder.set_note( pattern, 0, 0, "H-3", 1, Effect::None ).is_err() );
+    assert!( builder.set_note( pattern, 0, 0, "C-3", 1, Effect::Vibrato{ speed : 16, amplitude : 1 } ).is_err() );
+    assert!( builder.set_orders( &[ pattern + 1 ] ).is_err() );
+
+    for _ in 0..128 {
+        builder.add_order( pattern ).unwrap();
+    }
+    assert!( builder.add_order( pattern ).is_err() );
+}
+
+#[test]
+fn empty_order_table_is_rejected() {
+    let mut builder = SongBuilder::new( "empty", 4 ).unwrap();
+    builder.add_pattern().unwrap();
+    assert!( builder.build().is_err() );
+}
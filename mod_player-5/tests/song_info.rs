@@ -0,0 +1,70 @@
+#![cfg(feature = "serde")]
+
+use mod_player::{SongBuilder, SongInfo, Song, Effect, read_mod_data, read_xm_data};
+
+fn small_song() -> Song {
+    let mut builder = SongBuilder::new( "info", 4 ).unwrap();
+    let sample = builder.add_sample( "blip", vec![ 0, 64, 0, -64 ] ).unwrap();
+    let pattern = builder.add_pattern().unwrap();
+    builder.set_note( pattern, 0, 0, "C-3", sample, Effect::SetVolume{ volume : 32 } ).unwrap();
+    builder.set_note( pattern, 1, 0, "E-3", sample, Effect::SetVolume{ volume : 16 } ).unwrap();
+    builder.set_effect( pattern, 2, 4, Effect::VolumeSlide{ up : 0, down : 2 } ).unwrap();
+    builder.set_orders( &[ pattern ] ).unwrap();
+    builder.build().unwrap()
+}
+
+#[test]
+fn info_summarises_the_song() {
+    let info = SongInfo::new( &small_song() );
+    assert_eq!( info.title, "info" );
+    assert_eq!( info.channels, 4 );
+    assert_eq!( info.samples.len(), 1 );
+    assert_eq!( info.samples[ 0 ].name, "blip" );
+    assert_eq!( info.order, vec![ 0 ] );
+    assert_eq!( info.effect_usage[ "SetVolume" ], 2 );
+    assert_eq!( info.effect_usage[ "VolumeSlide" ], 1 );
+    // one pattern of 64 lines at speed 6 and 125 bpm
+    assert!( ( info.duration_seconds - 64.0 * 6.0 * 0.02 ).abs() < 0.2 );
+
+    let json = serde_json::to_string( &info ).unwrap();
+    assert_eq!( serde_json::from_str::<SongInfo>( &json ).unwrap(), info );
+}
+
+#[test]
+fn songs_serialise_with_and_without_sample_data() {
+    let song = small_song();
+    let json = serde_json::to_string( &song ).unwrap();
+    let reloaded : Song = serde_json::from_str( &json ).unwrap();
+    assert_eq!( reloaded.samples()[ 0 ].data(), song.samples()[ 0 ].data() );
+    assert_eq!( reloaded.patterns(), song.patterns() );
+
+    let json = serde_json::to_string( &song.without_sample_data() ).unwrap();
+    assert!( !json.contains( "\"data\"" ) );
+    let reloaded : Song = serde_json::from_str( &json ).unwrap();
+    assert!( reloaded.samples()[ 0 ].data().is_empty() );
+}
+
+#[test]
+fn songs_that_do_not_fit_together_are_not_deserialised() {
+    let json = serde_json::to_value( small_song() ).unwrap();
+    let load = | change : &dyn Fn( &mut serde_json::Value ) | {
+        let mut json = json.clone();
+        change( &mut json );
+        serde_json::from_value::<Song>( json )
+    };
+    assert!( load( &| _ | () ).is_ok() );
+    assert!( load( &| json | json[ "song_length" ] = 200.into() ).is_err() );
+    assert!( load( &| json | json[ "pattern_table" ][ 0 ] = 5.into() ).is_err() );
+    assert!( load( &| json | json[ "patterns" ] = serde_json::json!( [] ) ).is_err() );
+    assert!( load( &| json | { json[ "patterns" ][ 0 ][ "lines" ][ 3 ].as_array_mut().unwrap().pop(); } ).is_err() );
+    assert!( load( &| json | json[ "patterns" ][ 0 ][ "lines" ][ 0 ][ 0 ][ "sample_number" ] = 40.into() ).is_err() );
+}
+
+#[test]
+fn formats_are_named_by_their_tracker() {
+    let song = read_mod_data( &std::fs::read( "stardstm.mod" ).unwrap() ).unwrap();
+    assert_eq!( SongInfo::new( &song ).format, "M.K." );
+    let mut xm = Vec::new();
+    song.write_xm( &mut xm ).unwrap();
+    assert_eq!( SongInfo::new( &read_xm_data( &xm ).unwrap() ).format, "XM" );
+}
@@ -0,0 +1,40 @@
+use std::sync::Arc;
+
+use mod_player::{SongBuilder, Song, Effect, PlayerOptions, SongStream};
+
+/**
+ * A song of four lines: the break on the fourth ends it
+ */
+fn short_song() -> Arc<Song> {
+    let mut builder = SongBuilder::new( "short", 4 ).unwrap();
+    let square = builder.add_sample_f32( "square", &( 0..64 ).map( | index | if index < 32 { 0.5 } else { -0.5 } ).collect::<Vec<f32>>() ).unwrap();
+    builder.set_sample_loop( square, 0, 64 ).unwrap();
+    let pattern = builder.add_pattern().unwrap();
+    builder.set_note( pattern, 0, 0, "C-3", square, Effect::None ).unwrap();
+    builder.set_effect( pattern, 1, 3, Effect::PatternBreak{ next_pattern_pos : 0 } ).unwrap();
+    builder.set_orders( &[ pattern ] ).unwrap();
+    Arc::new( builder.build().unwrap() )
+}
+
+#[test]
+fn streams_play_the_song_the_given_number_of_times() {
+    // at 8000 Hz a line is 960 samples, and the first time through also has the line the player waits before starting
+    let options = PlayerOptions{ sample_rate : 8000, ..PlayerOptions::default() };
+    let once = SongStream::new( short_song(), &options ).count();
+    let mut three_times = SongStream::new( short_song(), &options ).with_loops( 3 );
+    assert_eq!( three_times.by_ref().count(), once + 2 * 4 * 960 );
+    assert_eq!( three_times.loops_played(), 3 );
+    assert_eq!( SongStream::new( short_song(), &options ).with_loops( 0 ).next(), None );
+}
+
+#[test]
+fn endless_streams_count_every_loop() {
+    let options = PlayerOptions{ sample_rate : 8000, ..PlayerOptions::default() };
+    let once = SongStream::new( short_song(), &options ).count();
+    let mut endless = SongStream::new( short_song(), &options ).endless();
+    assert_eq!( endless.by_ref().take( once + 10 * 4 * 960 + 100 ).count(), once + 10 * 4 * 960 + 100 );
+    assert_eq!( endless.loops_played(), 11 );
+
+    // a limit the stream has already played ends it
+    assert_eq!( endless.with_loops( 11 ).next(), None );
+}
@@ -0,0 +1,41 @@
+use std::sync::Arc;
+
+use mod_player::{Player, PlayerOptions, StemMode, read_mod_file};
+
+fn player() -> Player {
+    Player::new( Arc::new( read_mod_file( "stardstm.mod" ).unwrap() ), &PlayerOptions{ sample_rate : 8000, ..PlayerOptions::default() } )
+}
+
+fn check_stems_sum_to_the_mix( mode : StemMode ) {
+    let mut player = player();
+    let mut stems = vec![ ( 0.0, 0.0 ); mode.num_stems( player.song() ) ];
+    let mut stems_heard = vec![ false; stems.len() ];
+    for _ in 0..8000 * 4 {
+        let ( left, right ) = player.next_sample_stems( mode, &mut stems );
+        let ( stem_left, stem_right ) = stems.iter().fold( ( 0.0, 0.0 ), | sum, stem | ( sum.0 + stem.0, sum.1 + stem.1 ) );
+        assert!( ( left - stem_left ).abs() < 1e-5 && ( right - stem_right ).abs() < 1e-5 );
+        for ( heard, stem ) in stems_heard.iter_mut().zip( &stems ) {
+            *heard |= *stem != ( 0.0, 0.0 );
+        }
+    }
+    assert!( stems_heard.iter().filter( | heard | **heard ).count() > 1 );
+}
+
+#[test]
+fn channel_stems_sum_to_the_mix() {
+    check_stems_sum_to_the_mix( StemMode::Channel );
+}
+
+#[test]
+fn sample_stems_sum_to_the_mix() {
+    check_stems_sum_to_the_mix( StemMode::Sample );
+}
+
+#[test]
+fn stems_leave_the_mix_unchanged() {
+    let ( mut mixed, mut split ) = ( player(), player() );
+    let mut stems = vec![ ( 0.0, 0.0 ); StemMode::Sample.num_stems( split.song() ) ];
+    for _ in 0..8000 * 4 {
+        assert_eq!( mixed.next_sample(), split.next_sample_stems( StemMode::Sample, &mut stems ) );
+    }
+}
@@ -0,0 +1,69 @@
+use std::fs;
+
+use mod_player::{Song, Pattern, read_mod_data, read_module_data};
+
+#[test]
+fn patterns_round_trip_through_text() {
+    let song = read_mod_data( &fs::read( "stardstm.mod" ).unwrap() ).unwrap();
+    for pattern in song.patterns() {
+        let text = pattern.to_text();
+        let parsed = Pattern::from_text( &text ).unwrap();
+        assert_eq!( parsed.lines(), pattern.lines() );
+        assert_eq!( parsed.to_text(), text );
+    }
+}
+
+#[test]
+fn note_layout_matches_the_tracker_clipboard() {
+    let song = read_mod_data( &fs::read( "stardstm.mod" ).unwrap() ).unwrap();
+    let text = song.patterns()[ 0 ].to_text();
+    let first_row = text.lines().next().unwrap();
+    assert!( first_row.starts_with( "00 | " ) );
+    for note in first_row.split( '|' ).skip( 1 ) {
+        assert_eq!( note.trim().len(), "C-3 01 C40".len() );
+    }
+}
+
+#[test]
+fn song_text_dump_can_be_imported() {
+    let original = fs::read( "stardstm.mod" ).unwrap();
+    let song = read_mod_data( &original ).unwrap();
+    let text = song.to_text().unwrap();
+    let imported = Song::from_text( &text ).unwrap();
+    assert_eq!( imported.to_text().unwrap(), text );
+
+    let mut written = Vec::new();
+    imported.write_mod( &mut written ).unwrap();
+    assert!( written == original, "the imported song does not write the original module" );
+
+    // the text only holds what a mod has
+    let mut xm = Vec::new();
+    song.write_xm( &mut xm ).unwrap();
+    assert!( read_module_data( &xm ).unwrap().to_text().is_err() );
+}
+
+#[test]
+fn sample_values_a_mod_cannot_hold_are_rejected() {
+    let text = read_mod_data( &fs::read( "stardstm.mod" ).unwrap() ).unwrap().to_text().unwrap();
+    for ( keyword, value ) in [ ( "volume", "65" ), ( "volume", "300" ), ( "finetune", "16" ), ( "data", "131071" ) ] {
+        let line = text.lines().position( | line | line.starts_with( keyword ) ).unwrap();
+        let changed : Vec<String> = text.lines().enumerate().map( | ( index, text_line ) | {
+            if index == line { format!( "{} {}", keyword, value ) } else { text_line.to_string() }
+        } ).collect();
+        assert_eq!( Song::from_text( &changed.join( "\n" ) ).err().unwrap().line, line + 1, "{} {}", keyword, value );
+    }
+}
+
+#[test]
+fn errors_point_at_the_line() {
+    let mut text = String::new();
+    for row in 0..64 {
+        text.push_str( &format!( "{:02} | C-3 01 C40 | --- 00 000\n", row ) );
+    }
+    assert!( Pattern::from_text( &text ).is_ok() );
+
+    let broken = text.replace( "05 | C-3 01 C40", "05 | H-3 01 C40" );
+    assert_eq!( Pattern::from_text( &broken ).unwrap_err().line, 6 );
+    let short = text.replace( "07 | C-3 01 C40 | --- 00 000", "07 | C-3 01 C40" );
+    assert_eq!( Pattern::from_text( &short ).unwrap_err().line, 8 );
+}
@@ -0,0 +1,146 @@
+use std::fs;
+use std::sync::Arc;
+
+use mod_player::{Song, SongBuilder, Effect, PlayerOptions, SongStream, read_mod_data};
+
+const FREQUENCY_TABLE : [ u32; 60 ] = [
+    57, 60, 64, 67, 71, 76, 80, 85, 90, 95, 101, 107,
+    113, 120, 127, 135, 143, 151, 160, 170, 180, 190, 202, 214,
+    226, 240, 254, 269, 285, 302, 320, 339, 360, 381, 404, 428,
+    453, 480, 508, 538, 570, 604, 640, 678, 720, 762, 808, 856,
+    907, 961, 1017, 1077, 1141, 1209, 1281, 1357, 1440, 1525, 1616, 1712
+];
+
+fn u16_at( data : &[u8], position : usize ) -> usize {
+    u16::from_le_bytes( [ data[ position ], data[ position + 1 ] ] ) as usize
+}
+
+fn u32_at( data : &[u8], position : usize ) -> usize {
+    u32::from_le_bytes( [ data[ position ], data[ position + 1 ], data[ position + 2 ], data[ position + 3 ] ] ) as usize
+}
+
+/**
+ * Just enough of an XM reader to get back what write_xm writes: one sample per instrument, 8 bit samples and
+ * notes in the mod range
+ */
+fn read_xm( data : &[u8] ) -> Song {
+    assert_eq!( &data[ 0..17 ], b"Extended Module: " );
+    let header = 60;
+    let song_length = u16_at( data, header + 4 );
+    let num_channels = u16_at( data, header + 8 );
+    let num_patterns = u16_at( data, header + 10 );
+    let num_instruments = u16_at( data, header + 12 );
+    let mut builder = SongBuilder::new( "xm", num_channels as u32 ).unwrap();
+
+    let mut position = header + u32_at( data, header );
+    let mut patterns = Vec::new();
+    for _ in 0..num_patterns {
+        let rows = u16_at( data, position + 5 );
+        let packed_size = u16_at( data, position + 7 );
+        position += u32_at( data, position );
+        let mut fields = Vec::new();
+        let end = position + packed_size;
+        while position < end {
+            let flags = data[ position ];
+            if flags & 0x80 == 0 {
+                fields.push( data[ position..position + 5 ].to_vec() );
+                position += 5;
+                continue;
+            }
+            position += 1;
+            let mut note = vec![ 0; 5 ];
+            for ( bit, field ) in note.iter_mut().enumerate() {
+                if flags & ( 1 << bit ) != 0 {
+                    *field = data[ position ];
+                    position += 1;
+                }
+            }
+            fields.push( note );
+        }
+        assert_eq!( fields.len(), rows * num_channels );
+        patterns.push( fields );
+    }
+
+    let mut samples = Vec::new();
+    for _ in 0..num_instruments {
+        let header_size = u32_at( data, position );
+        let num_samples = u16_at( data, position + 27 );
+        position += header_size;
+        if num_samples == 0 {
+            samples.push( None );
+            continue;
+        }
+        let length = u32_at( data, position );
+        let header = data[ position..position + 40 ].to_vec();
+        position += 40;
+        let mut last = 0i8;
+        let values : Vec<i8> = data[ position..position + length ].iter().map( | delta | { last = last.wrapping_add( *delta as i8 ); last } ).collect();
+        position += length;
+        samples.push( Some( ( header, values ) ) );
+    }
+
+    for ( index, sample ) in samples.into_iter().enumerate() {
+        let ( header, values ) = sample.unwrap_or_default();
+        let number = builder.add_sample( "", values ).unwrap();
+        assert_eq!( number as usize, index + 1 );
+        if header.is_empty() {
+            continue;
+        }
+        builder.set_sample_volume( number, header[ 12 ] ).unwrap();
+        builder.set_sample_fine_tune( number, header[ 13 ] as i8 / 16 ).unwrap();
+        if header[ 14 ] & 3 == 1 {
+            builder.set_sample_loop( number, u32_at( &header, 4 ) as u32, u32_at( &header, 8 ) as u32 ).unwrap();
+        }
+    }
+    for fields in patterns {
+        let pattern = builder.add_pattern().unwrap();
+        for ( index, note ) in fields.iter().enumerate() {
+            let period = if note[ 0 ] == 0 { 0 } else { FREQUENCY_TABLE[ 84 - note[ 0 ] as usize ] };
+            let ( row, channel ) = ( index / num_channels, index % num_channels );
+            builder.set_note_period( pattern, channel as u32, row as u32, period, note[ 1 ], Effect::new( note[ 3 ], note[ 4 ] ) ).unwrap();
+        }
+    }
+    builder.set_orders( &data[ header + 20..header + 20 + song_length ] ).unwrap();
+    builder.build().unwrap()
+}
+
+fn render( song : Song ) -> Vec<( f32, f32 )> {
+    let options = PlayerOptions{ sample_rate : 8000, ..PlayerOptions::default() };
+    SongStream::new( Arc::new( song ), &options ).collect()
+}
+
+#[test]
+fn converted_song_renders_the_same() {
+    let song = read_mod_data( &fs::read( "stardstm.mod" ).unwrap() ).unwrap();
+    let mut data = Vec::new();
+    song.write_xm( &mut data ).unwrap();
+    let converted = read_xm( &data );
+    assert_eq!( converted.song_length(), song.song_length() );
+    assert_eq!( converted.patterns().len(), song.patterns().len() );
+
+    let original = render( song );
+    let rendered = render( converted );
+    assert_eq!( original.len(), rendered.len() );
+    assert!( original == rendered );
+}
+
+#[test]
+fn slides_without_an_argument_do_not_repeat_in_xm() {
+    let mut builder = SongBuilder::new( "slides", 3 ).unwrap();
+    let sample = builder.add_sample( "s", vec![ 0, 1, 2, 3 ] ).unwrap();
+    let pattern = builder.add_pattern().unwrap();
+    builder.set_note( pattern, 0, 0, "C-3", sample, Effect::VolumeSlide{ up : 0, down : 0 } ).unwrap();
+    builder.set_note( pattern, 1, 0, "C-3", sample, Effect::SetCoarsePanning{ position : 15 } ).unwrap();
+    builder.set_effect( pattern, 2, 0, Effect::VibratoVolumeSlide{ up : 0, down : 0 } ).unwrap();
+    builder.add_order( pattern ).unwrap();
+    let mut data = Vec::new();
+    builder.build().unwrap().write_xm( &mut data ).unwrap();
+
+    let converted = read_xm( &data );
+    assert_eq!( converted.num_channels(), 4 );
+    let line = &converted.patterns()[ 0 ].lines()[ 0 ];
+    assert_eq!( *line[ 0 ].effect(), Effect::None );
+    assert_eq!( *line[ 1 ].effect(), Effect::SetPanning{ position : 255 } );
+    assert_eq!( *line[ 2 ].effect(), Effect::Vibrato{ speed : 0, amplitude : 0 } );
+    assert_eq!( line[ 0 ].period(), 856 );
+}
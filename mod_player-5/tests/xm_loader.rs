@@ -0,0 +1,182 @@
+use std::fs;
+use std::sync::Arc;
+
+use mod_player::{Song, Note, Effect, Key, ModuleKind, Player, PlayerOptions, SongBuilder, read_mod_data, read_xm_data, read_module_data, song_duration};
+
+fn put_word( data : &mut [u8], position : usize, value : u16 ) {
+    data[ position..position + 2 ].copy_from_slice( &value.to_le_bytes() );
+}
+
+fn put_double_word( data : &mut [u8], position : usize, value : u32 ) {
+    data[ position..position + 4 ].copy_from_slice( &value.to_le_bytes() );
+}
+
+/**
+ * A pattern of unpacked notes: ( line, channel, note, instrument, volume, effect, argument )
+ */
+fn pattern( num_lines : usize, notes : &[( usize, usize, u8, u8, u8, u8, u8 )] ) -> Vec<u8> {
+    let mut fields = vec![ 0u8; num_lines * 2 * 5 ];
+    for &( line, channel, note, instrument, volume, effect, argument ) in notes {
+        let position = ( line * 2 + channel ) * 5;
+        fields[ position..position + 5 ].copy_from_slice( &[ note, instrument, volume, effect, argument ] );
+    }
+    let mut data = vec![ 0u8; 9 ];
+    put_double_word( &mut data, 0, 9 );
+    put_word( &mut data, 5, num_lines as u16 );
+    put_word( &mut data, 7, fields.len() as u16 );
+    data.extend( fields );
+    data
+}
+
+fn sample_header( length : u32, loop_start : u32, loop_length : u32, volume : u8, flags : u8, panning : u8, relative_note : i8 ) -> Vec<u8> {
+    let mut header = vec![ 0u8; 40 ];
+    put_double_word( &mut header, 0, length );
+    put_double_word( &mut header, 4, loop_start );
+    put_double_word( &mut header, 8, loop_length );
+    header[ 12 ] = volume;
+    header[ 14 ] = flags;
+    header[ 15 ] = panning;
+    header[ 16 ] = relative_note as u8;
+    header
+}
+
+/**
+ * Two channels, a 16 line pattern at speed 3 and one instrument with two samples: a ping pong looped 8 bit sample
+ * an octave up for the lower keys, and a 16 bit sample from C-4. The volume envelope falls to half and holds there
+ * until the key is released, then the instrument fades out over 8 ticks
+ */
+fn test_module() -> Vec<u8> {
+    let mut data = vec![ 0u8; 336 ];
+    data[ 0..17 ].copy_from_slice( b"Extended Module: " );
+    data[ 17..21 ].copy_from_slice( b"test" );
+    data[ 37 ] = 0x1a;
+    put_double_word( &mut data, 60, 276 );
+    for ( position, value ) in [ ( 64, 1 ), ( 68, 2 ), ( 70, 1 ), ( 72, 1 ), ( 74, 1 ), ( 76, 3 ), ( 78, 125 ) ] {
+        put_word( &mut data, position, value );
+    }
+    data.extend( pattern( 16, &[
+        ( 0, 0, 37, 1, 0x50, 0, 0 ),            // C-3 at volume 64
+        ( 1, 1, 49, 1, 0, 0x14, 2 ),            // C-4, released on the second tick
+        ( 4, 0, 97, 0, 0, 0, 0 ),
+    ] ) );
+
+    let mut instrument = vec![ 0u8; 263 ];
+    put_double_word( &mut instrument, 0, 263 );
+    instrument[ 4..8 ].copy_from_slice( b"lead" );
+    put_word( &mut instrument, 27, 2 );
+    put_double_word( &mut instrument, 29, 40 );
+    instrument[ 33 + 48..33 + 96 ].fill( 1 );
+    for ( point, &( tick, value ) ) in [ ( 0u16, 64u16 ), ( 3, 32 ) ].iter().enumerate() {
+        put_word( &mut instrument, 129 + point * 4, tick );
+        put_word( &mut instrument, 131 + point * 4, value );
+    }
+    instrument[ 225 ] = 2;
+    instrument[ 227 ] = 1;                      // sustain on the second point
+    instrument[ 233 ] = 1 | 2;
+    put_word( &mut instrument, 239, 4096 );
+    data.extend( instrument );
+
+    data.extend( sample_header( 16, 4, 8, 48, 2, 0, 12 ) );
+    data.extend( sample_header( 8, 0, 0, 64, 0x10, 128, 0 ) );
+    data.extend( &[ 64, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0 ] );
+    for delta in [ 0x1000i16, 0x1000, -0x3000, 0 ] {
+        data.extend( &delta.to_le_bytes() );
+    }
+    data
+}
+
+#[test]
+fn loader_reads_instruments_envelopes_and_samples() {
+    let song = read_xm_data( &test_module() ).unwrap();
+    assert_eq!( song.name().trim_end_matches( '\0' ), "test" );
+    assert_eq!( song.format().kind, ModuleKind::Xm );
+    assert_eq!( song.num_channels(), 2 );
+    assert!( song.has_linear_periods() );
+    assert_eq!( ( song.initial_speed(), song.initial_bpm() ), ( 3, 125 ) );
+    assert_eq!( song.patterns()[ 0 ].lines().len(), 16 );
+
+    let instrument = &song.instruments()[ 0 ];
+    assert_eq!( instrument.name().trim_end_matches( '\0' ), "lead" );
+    assert_eq!( ( instrument.sample_for_key( 47 ), instrument.sample_for_key( 48 ) ), ( 1, 2 ) );
+    assert_eq!( instrument.volume_envelope().points(), &[ ( 0, 64 ), ( 3, 32 ) ] );
+    assert_eq!( instrument.volume_envelope().sustain(), Some( ( 1, 1 ) ) );
+    assert!( !instrument.panning_envelope().is_enabled() );
+    assert_eq!( instrument.fadeout(), 4096 );
+
+    // the looped sample ends with its loop, the 16 bit one is reduced to 8 bits
+    let samples = song.samples();
+    assert_eq!( samples[ 0 ].c2_speed(), 16726 );
+    assert!( samples[ 0 ].is_ping_pong() );
+    assert_eq!( ( samples[ 0 ].repeat_offset(), samples[ 0 ].repeat_size(), samples[ 0 ].data().len() ), ( 4, 8, 12 ) );
+    assert_eq!( ( samples[ 0 ].volume(), samples[ 0 ].panning() ), ( 48, Some( 0 ) ) );
+    assert_eq!( samples[ 1 ].data(), &[ 16, 32, -16, -16 ] );
+    assert_eq!( samples[ 1 ].repeat_size(), 2 );
+
+    let lines = song.patterns()[ 0 ].lines();
+    assert_eq!( lines[ 0 ][ 0 ].key(), Key::Note( 36 ) );
+    assert_eq!( lines[ 0 ][ 0 ].volume_effect(), &Effect::SetVolume{ volume : 64 } );
+    assert_eq!( lines[ 1 ][ 1 ].effect(), &Effect::KeyOff{ tick : 2 } );
+    assert_eq!( lines[ 4 ][ 0 ].key(), Key::Off );
+    assert!( ( song_duration( &song ) - 16.0 * 3.0 * 0.02 ).abs() < 0.01 );
+
+    assert!( read_xm_data( b"not a module" ).is_err() );
+}
+
+#[test]
+fn envelope_holds_until_the_key_is_released_then_fades_out() {
+    let song = read_xm_data( &test_module() ).unwrap();
+    let mut player = Player::new( Arc::new( song ), &PlayerOptions::default() );
+    let tick = 960;
+    let output : Vec<( f32, f32 )> = ( 0..tick * 30 ).map( | _ | player.next_sample() ).collect();
+    let start = output.iter().position( | value | value.0 != 0.0 ).unwrap();
+    // the sample's panning puts it hard left, the envelope starts at full volume
+    let level = | volume : f32 | 64.0 / 128.0 * volume / 64.0;
+    assert_eq!( output[ start ].1, 0.0 );
+    assert!( ( output[ start ].0 - level( 64.0 ) ).abs() < 0.001 );
+    // held at the sustain point, line 4 releases the key on its first tick and 8 ticks later it has faded out
+    assert!( ( output[ start + 11 * tick + 10 ].0 - level( 32.0 ) ).abs() < 0.001 );
+    assert!( ( output[ start + 13 * tick + 10 ].0 - level( 32.0 * 0.75 ) ).abs() < 0.001 );
+    assert_eq!( output[ start + 21 * tick + 10 ].0, 0.0 );
+}
+
+#[test]
+fn converted_mod_loads_back() {
+    let song = read_mod_data( &fs::read( "stardstm.mod" ).unwrap() ).unwrap();
+    let mut data = Vec::new();
+    song.write_xm( &mut data ).unwrap();
+    let converted = read_xm_data( &data ).unwrap();
+    assert_eq!( converted.pattern_table(), &song.pattern_table()[ ..song.song_length() as usize ] );
+    assert_eq!( converted.instruments().len(), song.samples().len() );
+    assert!( !converted.has_linear_periods() );
+    for ( original, sample ) in song.samples().iter().zip( converted.samples() ) {
+        assert_eq!( original.data()[ ..sample.data().len() ], *sample.data() );
+        assert_eq!( sample.repeat_size() > 2, original.repeat_size() > 2 );
+    }
+    // period 428 is C-4
+    let notes = | song : &Song | song.patterns().iter().flat_map( | pattern | pattern.lines().iter().flatten().copied().collect::<Vec<Note>>() ).collect::<Vec<Note>>();
+    let position = notes( &song ).iter().position( | note | note.period() == 428 ).unwrap();
+    assert_eq!( notes( &converted )[ position ].key(), Key::Note( 48 ) );
+    assert!( ( song_duration( &converted ) - song_duration( &song ) ).abs() < 0.1 );
+}
+
+#[test]
+fn break_on_the_last_position_ends_the_song() {
+    // unlike mods, the pattern table of an xm has no unused positions after the last one
+    let mut builder = SongBuilder::new( "break", 4 ).unwrap();
+    let pattern = builder.add_pattern().unwrap();
+    builder.set_effect( pattern, 0, 7, Effect::PatternBreak{ next_pattern_pos : 0 } ).unwrap();
+    builder.set_orders( &[ pattern ] ).unwrap();
+    let mut data = Vec::new();
+    builder.build().unwrap().write_xm( &mut data ).unwrap();
+    let song = read_module_data( &data ).unwrap();
+    assert_eq!( song.pattern_table().len(), 1 );
+
+    let mut player = Player::new( Arc::new( song ), &PlayerOptions::default() );
+    let mut samples = 0;
+    while !player.has_finished() && samples < 48000 * 10 {
+        player.next_sample();
+        samples += 1;
+    }
+    assert!( player.has_finished() );
+    assert_eq!( player.state().song_pattern_position(), 0 );
+}